@@ -0,0 +1,26 @@
+//! Embeds the git commit of the build (`CANVAS_GIT_SHA`).
+use std::process::Command;
+
+fn main() {
+    // Docker builds have no git, the commit is passed in `GIT_SHA`.
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+            match output.status.success() {
+                true => String::from_utf8(output.stdout).ok(),
+                false => None,
+            }
+        })
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=CANVAS_GIT_SHA={sha}");
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
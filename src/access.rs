@@ -0,0 +1,174 @@
+//! Last access times.
+//!
+//! Every served image updates two Redis sorted sets scored by the unix time:
+//! `accessed:originals` with image hashes and `accessed:derivatives` with cache keys
+//! of processed images (see `get_image_id`). They give the least recently used
+//! originals to the admin listing and the least recently used derivatives to
+//! the cache eviction. Serving an image costs one `ZADD` per set, nothing else is written.
+//!
+//! Hashes of all originals are also kept in the `images:hashes` sorted set with equal
+//! scores, the admin listing pages through it in hash order (`ZRANGEBYLEX`).
+//! It is filled from the upload directory on startup.
+use crate::{clock::unix_now, hash, AppState};
+use log::{error, info, warn};
+use mobc_redis::redis::{self, aio::Connection, AsyncCommands, RedisResult};
+use std::{sync::Arc, time::Duration};
+
+/// Sorted set with hashes of originals.
+pub const ORIGINALS: &str = "accessed:originals";
+/// Sorted set with hashes of originals, all with the score 0 to be ordered by hash.
+const HASHES: &str = "images:hashes";
+/// Number of hashes added to the index at once on startup.
+const INDEX_BATCH: usize = 1000;
+/// Sorted set with cache keys of processed images.
+pub const DERIVATIVES: &str = "accessed:derivatives";
+/// How often to evict derivatives over the limit.
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of derivatives evicted at once.
+const EVICT_BATCH: usize = 1000;
+
+/// Record that the image was served, in one round trip.
+/// `image_id` is `None` for responses that don't come from the cache, like 304.
+pub async fn record(con: &mut Connection, hash: &str, image_id: Option<&str>) -> RedisResult<()> {
+    let now = unix_now();
+    let mut pipe = redis::pipe();
+    pipe.zadd(ORIGINALS, hash, now).ignore();
+    if let Some(image_id) = image_id {
+        pipe.zadd(DERIVATIVES, image_id, now).ignore();
+    }
+    pipe.query_async(con).await
+}
+
+/// Record the access of a processed image without an original, like a proxied one.
+pub async fn record_derivative(con: &mut Connection, image_id: &str) -> RedisResult<()> {
+    con.zadd(DERIVATIVES, image_id, unix_now()).await
+}
+
+/// Add the new upload to the index and count it as accessed, so that it is not the first to go.
+pub async fn record_upload(con: &mut Connection, hash: &str) -> RedisResult<()> {
+    redis::pipe()
+        .cmd("ZADD")
+        .arg(ORIGINALS)
+        .arg("NX")
+        .arg(unix_now())
+        .arg(hash)
+        .ignore()
+        .zadd(HASHES, hash, 0)
+        .ignore()
+        .query_async(con)
+        .await
+}
+
+/// Forget the original.
+pub async fn remove(con: &mut Connection, hash: &str) -> RedisResult<()> {
+    redis::pipe()
+        .zrem(ORIGINALS, hash)
+        .ignore()
+        .zrem(HASHES, hash)
+        .ignore()
+        .query_async(con)
+        .await
+}
+
+/// Last access times of the originals (unix timestamps), `None` if unknown.
+pub async fn accessed_at(con: &mut Connection, hashes: &[String]) -> RedisResult<Vec<Option<u64>>> {
+    let mut pipe = redis::pipe();
+    for hash in hashes {
+        pipe.zscore(ORIGINALS, hash);
+    }
+    pipe.query_async(con).await
+}
+
+/// Get hashes of the originals after `cursor` in hash order.
+pub async fn list(con: &mut Connection, cursor: &str, limit: usize) -> RedisResult<Vec<String>> {
+    let min = match cursor {
+        "" => "-".to_string(),
+        cursor => format!("({cursor}"),
+    };
+    con.zrangebylex_limit(HASHES, min, "+", 0, limit as isize)
+        .await
+}
+
+/// Get hashes of the originals, least recently used first.
+pub async fn least_recent(
+    con: &mut Connection,
+    offset: usize,
+    limit: usize,
+) -> RedisResult<Vec<String>> {
+    con.zrange(ORIGINALS, offset as isize, (offset + limit) as isize - 1)
+        .await
+}
+
+/// Periodically remove the least recently used derivatives over the limit.
+pub async fn evict_loop(state: Arc<AppState>, max_derivatives: usize) {
+    let mut interval = tokio::time::interval(EVICT_INTERVAL);
+    loop {
+        interval.tick().await;
+        match evict(&state, max_derivatives).await {
+            Ok(0) => {}
+            Ok(evicted) => info!("Evicted {evicted} least recently used cached images"),
+            Err(err) => warn!("Failed to evict cached images: {err}"),
+        }
+    }
+}
+
+async fn evict(state: &AppState, max_derivatives: usize) -> anyhow::Result<usize> {
+    let mut redis_con = state.redis.get().await?;
+    let mut evicted = 0;
+    loop {
+        let count: usize = redis_con.zcard(DERIVATIVES).await?;
+        if count <= max_derivatives {
+            return Ok(evicted);
+        }
+
+        let batch = (count - max_derivatives).min(EVICT_BATCH);
+        let keys: Vec<String> = redis_con.zrange(DERIVATIVES, 0, batch as isize - 1).await?;
+        if keys.is_empty() {
+            return Ok(evicted);
+        }
+        // Keys could have expired already, DEL skips them.
+        redis::pipe()
+            .atomic()
+            .del(&keys)
+            .ignore()
+            .zrem(DERIVATIVES, &keys)
+            .ignore()
+            .query_async::<_, ()>(&mut *redis_con)
+            .await?;
+        evicted += keys.len();
+    }
+}
+
+/// Add the originals of the upload directory to the index,
+/// they could have been uploaded before it existed.
+pub async fn index_backfill(state: Arc<AppState>) {
+    match fill_index(&state).await {
+        Ok(indexed) => info!("Indexed {indexed} images"),
+        Err(err) => error!("Failed to index images: {err}"),
+    }
+}
+
+async fn fill_index(state: &AppState) -> anyhow::Result<usize> {
+    let mut entries = tokio::fs::read_dir(&state.cfg.upload_dir).await?;
+    let mut indexed = 0;
+    let mut batch = Vec::with_capacity(INDEX_BATCH);
+    loop {
+        let entry = entries.next_entry().await?;
+        if let Some(name) = entry
+            .as_ref()
+            .and_then(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+            .filter(|name| hash::is_valid(name))
+        {
+            batch.push((0, name));
+        }
+        if batch.len() == INDEX_BATCH || (entry.is_none() && !batch.is_empty()) {
+            let mut redis_con = state.redis.get().await?;
+            let _: () = redis_con.zadd_multiple(HASHES, &batch).await?;
+            indexed += batch.len();
+            batch.clear();
+        }
+        if entry.is_none() {
+            return Ok(indexed);
+        }
+    }
+}
@@ -1,3 +1,13 @@
+pub mod admin;
+pub mod delete;
+pub mod diff;
+pub mod events;
 pub mod health;
 pub mod image;
+pub mod imgproxy;
+pub mod info;
+pub mod metrics;
+pub mod proxy;
+pub mod thumbor;
+pub mod transform;
 pub mod upload;
@@ -0,0 +1,228 @@
+use crate::{access, audit, metadata, reload, AppState, HttpError};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use mobc_redis::redis::aio::Connection;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, time::UNIX_EPOCH};
+
+/// Default number of images per page.
+const DEFAULT_LIMIT: usize = 100;
+/// Maximum number of images per page.
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+pub struct ImageInfo {
+    pub hash: String,
+    /// File size in bytes.
+    pub size: u64,
+    /// Upload time (unix timestamp).
+    pub uploaded_at: Option<u64>,
+    /// Last time the image was served (unix timestamp).
+    pub accessed_at: Option<u64>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImageList {
+    pub images: Vec<ImageInfo>,
+    /// Pass as the 'cursor' parameter to get the next page.
+    pub next_cursor: Option<String>,
+}
+
+/// List stored images ordered by hash.
+/// Url: /admin/images
+/// Method: GET
+/// Parameters: cursor - hash of the last image on the previous page, limit - page size,
+/// tag - list only images with the tag, order=lru - least recently used images first
+/// (the cursor is then the position in the list)
+pub async fn list_images(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ImageList>, HttpError> {
+    let limit = params
+        .get("limit")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+    let cursor = params.get("cursor").cloned().unwrap_or_default();
+
+    let mut redis_con = state.redis.get().await?;
+
+    // Least recently used images first, for cleanup tools.
+    if params.get("order").map(|order| order.as_str()) == Some("lru") {
+        let offset = match cursor.as_str() {
+            "" => 0,
+            cursor => match cursor.parse() {
+                Ok(offset) => offset,
+                Err(_) => return Err(HttpError::bad_request("Invalid 'cursor' parameter")),
+            },
+        };
+        // One more to know if there is a next page.
+        let mut hashes = access::least_recent(&mut redis_con, offset, limit + 1).await?;
+        let next_cursor = match hashes.len() > limit {
+            true => Some((offset + limit).to_string()),
+            false => None,
+        };
+        hashes.truncate(limit);
+        let images = get_image_infos(&state, &mut redis_con, hashes).await?;
+        return Ok(Json(ImageList {
+            images,
+            next_cursor,
+        }));
+    }
+
+    // Collect hashes after the cursor, one more to know if there is a next page.
+    let mut hashes = match params.get("tag") {
+        Some(tag) => {
+            let mut hashes = metadata::tagged(&mut redis_con, tag).await?;
+            hashes.retain(|hash| hash.as_str() > cursor.as_str());
+            hashes.sort();
+            hashes
+        }
+        None => access::list(&mut redis_con, &cursor, limit + 1).await?,
+    };
+    let next_cursor = match hashes.len() > limit {
+        true => Some(hashes[limit - 1].clone()),
+        false => None,
+    };
+    hashes.truncate(limit);
+    let images = get_image_infos(&state, &mut redis_con, hashes).await?;
+
+    Ok(Json(ImageList {
+        images,
+        next_cursor,
+    }))
+}
+
+/// Get sizes and metadata of the images, skipping the deleted ones.
+async fn get_image_infos(
+    state: &AppState,
+    redis_con: &mut Connection,
+    hashes: Vec<String>,
+) -> Result<Vec<ImageInfo>, HttpError> {
+    let accessed_at = access::accessed_at(redis_con, &hashes).await?;
+    let mut images = Vec::with_capacity(hashes.len());
+    for (hash, accessed_at) in hashes.into_iter().zip(accessed_at) {
+        // The file may have been deleted in the meantime.
+        let file_metadata = match tokio::fs::metadata(state.get_file_path(&hash)).await {
+            Ok(file_metadata) => file_metadata,
+            Err(_) => continue,
+        };
+        let meta = metadata::get(redis_con, &hash).await?;
+        let uploaded_at = file_metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+
+        images.push(ImageInfo {
+            hash,
+            size: file_metadata.len(),
+            uploaded_at,
+            accessed_at,
+            tags: meta.tags,
+        });
+    }
+    Ok(images)
+}
+
+#[derive(Serialize)]
+pub struct AuditLog {
+    pub entries: Vec<audit::Entry>,
+    /// Pass as the 'cursor' parameter to get older entries.
+    pub next_cursor: Option<String>,
+}
+
+/// Read the audit log, newest entries first.
+/// Url: /admin/audit
+/// Method: GET
+/// Parameters: action, target, actor, ip - filters (exact match),
+/// since, until - unix timestamps, cursor - 'next_cursor' of the previous page, limit - page size
+pub async fn get_audit_log(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<AuditLog>, HttpError> {
+    let limit = params
+        .get("limit")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+
+    // Stream ids start with the time in milliseconds.
+    let mut end = match (params.get("cursor"), params.get("until")) {
+        (Some(cursor), _) => format!("({cursor}"),
+        (None, Some(until)) => match until.parse::<u64>() {
+            Ok(until) => format!("{}", until * 1000 + 999),
+            Err(_) => return Err(HttpError::bad_request("Invalid 'until' parameter")),
+        },
+        (None, None) => "+".to_string(),
+    };
+    let start = match params.get("since") {
+        Some(since) => match since.parse::<u64>() {
+            Ok(since) => format!("{}", since * 1000),
+            Err(_) => return Err(HttpError::bad_request("Invalid 'since' parameter")),
+        },
+        None => "-".to_string(),
+    };
+
+    let mut redis_con = state.redis.get().await?;
+
+    // Read the stream in batches until the page is full.
+    let mut entries = Vec::new();
+    let mut next_cursor = None;
+    loop {
+        let batch = audit::read(&mut redis_con, &end, &start, MAX_LIMIT).await?;
+        let exhausted = batch.len() < MAX_LIMIT;
+
+        for entry in batch {
+            end = format!("({}", entry.id);
+            if matches_filters(&entry, &params) {
+                entries.push(entry);
+                if entries.len() == limit {
+                    break;
+                }
+            }
+        }
+
+        if entries.len() == limit {
+            next_cursor = entries.last().map(|entry| entry.id.clone());
+            break;
+        }
+        if exhausted {
+            break;
+        }
+    }
+
+    Ok(Json(AuditLog {
+        entries,
+        next_cursor,
+    }))
+}
+
+fn matches_filters(entry: &audit::Entry, params: &HashMap<String, String>) -> bool {
+    let filters = [
+        ("action", Some(&entry.action)),
+        ("target", Some(&entry.target)),
+        ("actor", Some(&entry.actor)),
+        ("ip", entry.ip.as_ref()),
+    ];
+    filters.iter().all(|(name, value)| match params.get(*name) {
+        Some(expected) => *value == Some(expected),
+        None => true,
+    })
+}
+
+/// Read the configuration again and apply reloadable settings.
+/// Url: /admin/reload
+/// Method: POST
+pub async fn reload_config(State(state): State<Arc<AppState>>) -> Result<StatusCode, HttpError> {
+    match reload::reload(state).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err(HttpError::unprocessable_entity(&format!(
+            "Cannot reload configuration: {err}"
+        ))),
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    api::image::check_hash,
+    audit::{self, Actor},
+    auth::{check_modify, Principal},
+    events::{self, EventKind},
+    metadata, trash, AppState, HttpError,
+};
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+};
+use std::sync::Arc;
+
+/// Move the image to the trash.
+/// It can be restored until the retention period expires.
+/// Only tokens and the user who uploaded the image can delete it.
+/// Url: /images/:hash
+/// Method: DELETE
+pub async fn delete_image(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    principal: Option<Extension<Principal>>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, HttpError> {
+    let principal = principal.map(|Extension(principal)| principal);
+    if principal.is_none() {
+        return Err(HttpError::unauthorized("Authentication required"));
+    }
+    check_hash(&hash)?;
+
+    if !state.get_file_path(&hash).exists() {
+        return Err(HttpError::not_found(&format!(
+            "Image {} was not found",
+            hash
+        )));
+    }
+
+    let mut redis_con = state.redis.get().await?;
+    let meta = metadata::get(&mut redis_con, &hash).await?;
+    check_modify(principal.as_ref(), &meta)?;
+
+    audit::record(
+        &mut redis_con,
+        state.cfg.audit_max_entries,
+        &actor,
+        "delete",
+        &hash,
+    )
+    .await?;
+    trash::delete(&state, &mut redis_con, &hash).await?;
+    events::publish(&state.events, EventKind::Delete, &hash);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Restore the deleted image.
+/// Only tokens and the user who uploaded the image can restore it.
+/// Url: /images/:hash/restore
+/// Method: POST
+pub async fn restore_image(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    principal: Option<Extension<Principal>>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, HttpError> {
+    let principal = principal.map(|Extension(principal)| principal);
+    if principal.is_none() {
+        return Err(HttpError::unauthorized("Authentication required"));
+    }
+    check_hash(&hash)?;
+
+    if !state.get_trash_file_path(&hash).exists() {
+        return Err(HttpError::not_found(&format!(
+            "Deleted image {} was not found",
+            hash
+        )));
+    }
+    if state.get_file_path(&hash).exists() {
+        return Err(HttpError::conflict(&format!(
+            "Image {} was uploaded again",
+            hash
+        )));
+    }
+
+    let mut redis_con = state.redis.get().await?;
+    let meta = metadata::get(&mut redis_con, &hash).await?;
+    check_modify(principal.as_ref(), &meta)?;
+
+    audit::record(
+        &mut redis_con,
+        state.cfg.audit_max_entries,
+        &actor,
+        "restore",
+        &hash,
+    )
+    .await?;
+    trash::restore(&state, &mut redis_con, &hash).await?;
+    events::publish(&state.events, EventKind::Restore, &hash);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,108 @@
+use crate::{
+    api::image::{check_access, check_hash},
+    auth::Principal,
+    diff::{self, DifferentSizes, Metric},
+    metadata, params, throttle, AppState, HttpError,
+};
+use axum::{
+    body::{boxed, Full},
+    extract::{Extension, Query, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+
+/// Response header with the score of the visual diff.
+const DIFF_SCORE: HeaderName = HeaderName::from_static("x-canvas-diff-score");
+
+#[derive(Serialize)]
+pub struct DiffResponse {
+    pub a: String,
+    pub b: String,
+    pub metric: String,
+    /// 0 for identical images, see `diff` module.
+    pub score: f64,
+}
+
+/// Compare two uploaded images.
+/// Url: /diff
+/// Method: GET
+/// Parameters: a, b - hashes of the images, metric - dssim (default) or ae,
+/// image - respond with the visual diff (PNG) instead of JSON
+/// Requires authentication.
+pub async fn get_diff(
+    State(state): State<Arc<AppState>>,
+    principal: Option<Extension<Principal>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, HttpError> {
+    let principal = match principal {
+        Some(Extension(principal)) => principal,
+        None => return Err(HttpError::unauthorized("Access token required")),
+    };
+    let mut redis_con = state.redis.get().await?;
+    let mut hashes = Vec::new();
+    for name in ["a", "b"] {
+        let hash = match params.get(name) {
+            Some(hash) => hash.clone(),
+            None => {
+                return Err(
+                    HttpError::bad_request(&format!("Parameter '{name}' is required"))
+                        .with_field(name),
+                )
+            }
+        };
+        check_hash(&hash).map_err(|err| err.with_field(name))?;
+        // Same checks as for serving the image.
+        let meta = metadata::get(&mut redis_con, &hash).await?;
+        if meta.deleted_at.is_some() || !state.get_file_path(&hash).exists() {
+            return Err(
+                HttpError::not_found(&format!("Image {} was not found", hash)).with_field(name),
+            );
+        }
+        check_access(&hash, &meta, false, Some(&principal)).map_err(|err| err.with_field(name))?;
+        hashes.push(hash);
+    }
+    drop(redis_con);
+    let metric = params::get(&params, "metric", "dssim or ae", |value| value.parse().ok())?
+        .unwrap_or(Metric::Dssim);
+    let render = params.contains_key("image");
+
+    let permit = match state.throttle.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => {
+            return Err(HttpError::overloaded(
+                "Server is busy, try again later",
+                throttle::RETRY_AFTER_SECS,
+            ))
+        }
+    };
+    let a = tokio::fs::read(state.get_file_path(&hashes[0])).await?;
+    let b = tokio::fs::read(state.get_file_path(&hashes[1])).await?;
+    let comparison = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        diff::compare(&a, &b, metric, render)
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+    .map_err(|err| match err.is::<DifferentSizes>() {
+        true => HttpError::unprocessable_entity(&err.to_string()),
+        // Broken images, details of libvips are only logged.
+        false => HttpError::unprocessable_image(&err.to_string()),
+    })?;
+
+    if let Some(image) = comparison.image {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "image/png".parse().unwrap());
+        headers.insert(DIFF_SCORE, comparison.score.to_string().parse().unwrap());
+        return Ok((StatusCode::OK, headers, boxed(Full::from(image))).into_response());
+    }
+    let [a, b]: [String; 2] = hashes.try_into().unwrap();
+    Ok(Json(DiffResponse {
+        a,
+        b,
+        metric: metric.to_string(),
+        score: comparison.score,
+    })
+    .into_response())
+}
@@ -0,0 +1,38 @@
+use crate::{events::ImageEvent, AppState};
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::stream::{self, Stream};
+use log::warn;
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+/// Stream image events.
+/// Url: /admin/events
+/// Method: GET
+pub async fn get_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.events.subscribe();
+
+    let stream = stream::unfold(receiver, |mut receiver: Receiver<ImageEvent>| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let sse_event = Event::default()
+                        .event("image")
+                        .json_data(&event)
+                        .unwrap_or_default();
+                    return Some((Ok(sse_event), receiver));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event subscriber is too slow, skipped {skipped} events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -1,11 +1,58 @@
-use axum::response::Json;
+use crate::{
+    build_info::BuildInfo,
+    capabilities::{self, Capabilities},
+    AppState,
+};
+use axum::{extract::State, http::StatusCode, response::Json};
 use serde::Serialize;
+use std::sync::Arc;
 
 #[derive(Serialize)]
 pub struct Response {
     pub ok: bool,
+    /// Version of the server.
+    pub version: &'static str,
+    /// Git commit of the build.
+    pub git_sha: &'static str,
 }
 
-pub async fn get_health() -> Json<Response> {
-    Json(Response { ok: true })
+pub async fn get_health(State(state): State<Arc<AppState>>) -> Json<Response> {
+    Json(Response {
+        ok: true,
+        version: state.build.version,
+        git_sha: state.build.git_sha,
+    })
+}
+
+/// Get versions and enabled features of the server.
+/// Url: /version
+/// Method: GET
+pub async fn get_version(State(state): State<Arc<AppState>>) -> Json<BuildInfo> {
+    Json(state.build.clone())
+}
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    /// Required formats that libvips doesn't support.
+    pub missing_formats: Vec<String>,
+    /// Loaders and savers of the known formats.
+    pub formats: Capabilities,
+}
+
+/// Check that the server can handle traffic: libvips supports the required formats.
+/// Url: /ready
+/// Method: GET
+pub async fn get_ready(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadyResponse>) {
+    let missing_formats = capabilities::missing(&state.capabilities, &state.required_formats);
+    let status = match missing_formats.is_empty() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let response = ReadyResponse {
+        ready: missing_formats.is_empty(),
+        missing_formats,
+        formats: state.capabilities.clone(),
+    };
+    (status, Json(response))
 }
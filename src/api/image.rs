@@ -1,20 +1,99 @@
-use crate::{AppState, HttpError};
+#[cfg(feature = "jxl")]
+use crate::encoder::JxlOptions;
+use crate::{
+    access,
+    api::proxy,
+    app_config::WatermarkBlend,
+    auth::Principal,
+    budget::{Budget, OverBudget},
+    cache,
+    cancel::{self, CancelFlag, Cancelled},
+    denoise,
+    effects::{self, Shadow},
+    encoder::{
+        AvifOptions, EncoderOptions, GifOptions, JpegOptions, PngOptions, Subsampling,
+        TiffCompression, TiffOptions, WebpOptions, WebpPreset,
+    },
+    enhance, fingerprint, hash, hotlink,
+    metadata::{self, ImageMetadata},
+    metrics, missing,
+    moderation::Verdict,
+    origin, overlay,
+    params::{self, ImageParams},
+    pipeline::{self, Kernel, Pipeline, PIPELINE_PARAM},
+    policy, preset, quality,
+    reload::{self, Settings},
+    render_lock, slow, slug,
+    smart::{self, ContentClass},
+    sniff, throttle,
+    variant::{self, Variant},
+    AppState, HttpError,
+};
 use axum::{
-    extract::{Path, Query, State},
+    body::{boxed, BoxBody, Bytes, Empty, Full},
+    extract::{Extension, Path, State},
     http::{
-        header::{self, HeaderMap},
+        header::{self, HeaderMap, HeaderValue},
         status::StatusCode,
     },
-    response::IntoResponse,
 };
 use libvips::{ops, VipsImage};
-use mobc_redis::redis::AsyncCommands;
-use std::{cmp, collections::HashMap, fmt, path::PathBuf, sync::Arc};
+use log::debug;
+use mobc_redis::redis::aio::Connection;
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+};
+use url::Url;
 
 #[derive(Debug)]
 pub enum ImageFormat {
     Webp,
     Jpeg,
+    Avif,
+    Png,
+    Gif,
+    Tiff,
+    #[cfg(feature = "jxl")]
+    Jxl,
+}
+
+/// Query parameter with the hash of the uploaded watermark.
+pub const WATERMARK_HASH_PARAM: &str = "watermark_hash";
+/// Query parameter forcing the processing instead of the cache, see `skips_cache`.
+pub const SKIP_CACHE_PARAM: &str = "skip_cache";
+/// Query parameter with the hash of the uploaded SVG overlay.
+pub const OVERLAY_SVG_PARAM: &str = "overlay_svg";
+/// Range of the `zoom` parameter.
+const MIN_ZOOM: f64 = 1.0;
+const MAX_ZOOM: f64 = 10.0;
+/// Width of the SVG overlay in percent of the output width, if not given.
+const DEFAULT_OVERLAY_SVG_WIDTH: u8 = 20;
+
+/// Valid values of the `format` parameter.
+#[cfg(not(feature = "jxl"))]
+const FORMATS: &str = "jpg, jpeg, webp, avif, png, gif, tif, tiff, auto or smart";
+#[cfg(feature = "jxl")]
+const FORMATS: &str = "jpg, jpeg, webp, avif, png, gif, tif, tiff, jxl, auto or smart";
+
+impl ImageFormat {
+    /// Parse the value of the `format` parameter.
+    pub fn parse(value: &str) -> Option<ImageFormat> {
+        match value {
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "webp" => Some(ImageFormat::Webp),
+            "avif" => Some(ImageFormat::Avif),
+            "png" => Some(ImageFormat::Png),
+            "gif" => Some(ImageFormat::Gif),
+            "tif" | "tiff" => Some(ImageFormat::Tiff),
+            #[cfg(feature = "jxl")]
+            "jxl" => Some(ImageFormat::Jxl),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for ImageFormat {
@@ -25,23 +104,235 @@ impl fmt::Display for ImageFormat {
             match self {
                 ImageFormat::Jpeg => "jpeg",
                 ImageFormat::Webp => "webp",
+                ImageFormat::Avif => "avif",
+                ImageFormat::Png => "png",
+                ImageFormat::Gif => "gif",
+                ImageFormat::Tiff => "tiff",
+                #[cfg(feature = "jxl")]
+                ImageFormat::Jxl => "jxl",
             }
         )
     }
 }
 
+/// How the image is fitted into the requested size.
+#[derive(Debug)]
+pub enum Fit {
+    /// Cover the whole area, cropping the big side.
+    Cover,
+    /// Fit the whole image and fill the rest with its blurred copy.
+    BlurPad,
+}
+
+/// Edge constrained by the `long` and `short` parameters.
+/// The image is resized to keep its aspect ratio, without cropping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// The longest edge is at most the size.
+    Long,
+    /// The shortest edge is at most the size.
+    Short,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edge::Long => write!(f, "long"),
+            Edge::Short => write!(f, "short"),
+        }
+    }
+}
+
+/// How the watermark is placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkMode {
+    /// One watermark in the top left corner.
+    Single,
+    /// Watermarks repeated across the whole image, so that they cannot be cropped out.
+    Tile,
+}
+
+/// Position of the image inside the area.
+#[derive(Debug, Clone, Copy)]
+pub enum Gravity {
+    Centre,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+/// Valid values of the gravity parameters.
+const GRAVITIES: &str =
+    "centre, north, south, east, west, northeast, northwest, southeast or southwest";
+
+impl Gravity {
+    pub fn parse(value: &str) -> Option<Gravity> {
+        match value {
+            "centre" => Some(Gravity::Centre),
+            "north" => Some(Gravity::North),
+            "south" => Some(Gravity::South),
+            "east" => Some(Gravity::East),
+            "west" => Some(Gravity::West),
+            "northeast" => Some(Gravity::NorthEast),
+            "northwest" => Some(Gravity::NorthWest),
+            "southeast" => Some(Gravity::SouthEast),
+            "southwest" => Some(Gravity::SouthWest),
+            _ => None,
+        }
+    }
+
+    /// Position of the top left corner, given the free space around the image.
+    fn offset(&self, free_x: i32, free_y: i32) -> (i32, i32) {
+        match self {
+            Gravity::Centre => (free_x / 2, free_y / 2),
+            Gravity::North => (free_x / 2, 0),
+            Gravity::South => (free_x / 2, free_y),
+            Gravity::East => (free_x, free_y / 2),
+            Gravity::West => (0, free_y / 2),
+            Gravity::NorthEast => (free_x, 0),
+            Gravity::NorthWest => (0, 0),
+            Gravity::SouthEast => (free_x, free_y),
+            Gravity::SouthWest => (0, free_y),
+        }
+    }
+}
+
+impl fmt::Display for Gravity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Gravity::Centre => "centre",
+                Gravity::North => "north",
+                Gravity::South => "south",
+                Gravity::East => "east",
+                Gravity::West => "west",
+                Gravity::NorthEast => "northeast",
+                Gravity::NorthWest => "northwest",
+                Gravity::SouthEast => "southeast",
+                Gravity::SouthWest => "southwest",
+            }
+        )
+    }
+}
+
+/// Uploaded SVG composited over the image, like a badge or a ribbon.
+#[derive(Debug)]
+pub struct SvgOverlay {
+    /// Hash of the uploaded SVG.
+    pub hash: String,
+    /// Position over the image.
+    pub gravity: Gravity,
+    /// Width in percent of the output width.
+    pub width_percent: u8,
+}
+
+/// Aspect ratio of the result (`ar=16:9`).
+#[derive(Debug, Clone, Copy)]
+pub struct AspectRatio {
+    width: u16,
+    height: u16,
+}
+
+impl AspectRatio {
+    /// Parse the ratio in the `<width>:<height>` format.
+    pub fn parse(value: &str) -> Option<AspectRatio> {
+        let (width, height) = value.split_once(':')?;
+        let ratio = AspectRatio {
+            width: width.trim().parse().ok()?,
+            height: height.trim().parse().ok()?,
+        };
+        match ratio.width > 0 && ratio.height > 0 {
+            true => Some(ratio),
+            false => None,
+        }
+    }
+
+    /// Height matching the width.
+    fn height_for(&self, width: u16) -> u16 {
+        scale(width, self.height, self.width)
+    }
+
+    /// Width matching the height.
+    fn width_for(&self, height: u16) -> u16 {
+        scale(height, self.width, self.height)
+    }
+}
+
+/// Calculate `value * numerator / denominator`, rounded and kept within `u16`.
+fn scale(value: u16, numerator: u16, denominator: u16) -> u16 {
+    let scaled = (f64::from(value) * f64::from(numerator) / f64::from(denominator)).round();
+    scaled.clamp(1.0, f64::from(u16::MAX)) as u16
+}
+
 #[derive(Debug)]
 pub struct ImageProps {
     pub width: u16,
     pub height: u16,
+    /// Aspect ratio, the size is reduced to match it, see `fit_aspect_ratio`.
+    pub aspect_ratio: Option<AspectRatio>,
     pub quality: u8,
+    /// Choose the lowest quality meeting the target difference (`quality=auto`), see `quality` module.
+    pub auto_quality: bool,
     /// Add a pre-configured watermark on top of a photo?
     pub watermark: bool,
+    /// Hash of the uploaded image used as the watermark instead of the configured one.
+    pub watermark_hash: Option<String>,
+    pub watermark_mode: WatermarkMode,
     pub format: ImageFormat,
+    pub fit: Fit,
+    /// Constrain only one edge (`long` or `short`), `width` and `height` are both the size then.
+    pub edge: Option<Edge>,
+    /// Size in percent of the original (`scale`), the long edge is limited by the size caps.
+    pub scale: Option<u8>,
+    /// Zoom into the area chosen by the smart crop (`fit=cover` only).
+    pub zoom: Option<f64>,
+    /// Resize kernel (`kernel`), the one of the speed profile if not set.
+    pub kernel: Option<Kernel>,
+    /// Operations replacing the resize and crop steps, see `pipeline` module.
+    pub pipeline: Option<Pipeline>,
+    /// Position of the image for `fit=blurpad`.
+    pub gravity: Gravity,
+    /// Page (or frame) of multi-page sources to render, starting from 0.
+    pub page: i32,
+    /// Keep all pages of multi-page sources (`pages=all`), only for TIFF output.
+    pub all_pages: bool,
+    /// Choose the format from the 'Accept' header (`format=auto`), see `Variant`.
+    pub auto_format: bool,
+    /// Choose the format from the content and the 'Accept' header (`format=smart`),
+    /// see `smart` module.
+    pub smart_format: bool,
+    /// Kind of the content, resolved for `format=smart`.
+    pub content_class: Option<ContentClass>,
+    /// Lossless encoding (WebP only).
+    pub lossless: bool,
     pub filename: Option<String>,
+    /// Send the image as an attachment, so that browsers download it.
+    pub download: bool,
     /// Small text to be added to the top left corner.
     /// Can be used instead of a watermark.
     pub overlay: Option<String>,
+    /// SVG rasterized at the output size, so that it stays sharp.
+    pub overlay_svg: Option<SvgOverlay>,
+    /// Licensee id embedded as an invisible watermark, see `fingerprint` module.
+    pub licensee: Option<String>,
+    /// Stretch the contrast and correct the white balance, see `enhance` module.
+    pub enhance: bool,
+    /// Strength of the noise reduction, see `denoise` module.
+    pub denoise: Option<u8>,
+    /// Gamma correction, see `pipeline::gamma`.
+    pub gamma: Option<f64>,
+    /// Exposure correction in stops, see `pipeline::exposure`.
+    pub exposure: Option<f64>,
+    /// Darkening of the corners in percent, see `effects` module.
+    pub vignette: Option<u8>,
+    pub shadow: Option<Shadow>,
 }
 
 impl Default for ImageProps {
@@ -49,164 +340,1182 @@ impl Default for ImageProps {
         ImageProps {
             width: 1024,
             height: 1024,
+            aspect_ratio: None,
             quality: 80,
+            auto_quality: false,
             watermark: false,
+            watermark_hash: None,
+            watermark_mode: WatermarkMode::Single,
             format: ImageFormat::Webp,
+            fit: Fit::Cover,
+            edge: None,
+            scale: None,
+            zoom: None,
+            kernel: None,
+            pipeline: None,
+            gravity: Gravity::Centre,
+            page: 0,
+            all_pages: false,
+            auto_format: false,
+            smart_format: false,
+            content_class: None,
+            lossless: false,
             filename: None,
+            download: false,
             overlay: None,
+            overlay_svg: None,
+            licensee: None,
+            enhance: false,
+            denoise: None,
+            gamma: None,
+            exposure: None,
+            vignette: None,
+            shadow: None,
         }
     }
 }
 
 impl ImageProps {
     /// Parse URL parameters.
-    fn from_params(params: &HashMap<String, String>) -> ImageProps {
+    /// Invalid values are answered with 400 naming the parameter, missing ones get the defaults.
+    /// Repeated parameters are resolved by `ImageParams` before.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<ImageProps, HttpError> {
         let mut image_props = ImageProps::default();
 
-        if let Some(value) = params.get("width") {
-            if let Ok(width) = value.parse() {
-                image_props.width = width;
+        if let Some(width) = params::number(params, "width", 1, u16::MAX)? {
+            image_props.width = width;
+        }
+
+        if let Some(height) = params::number(params, "height", 1, u16::MAX)? {
+            image_props.height = height;
+        }
+
+        // The missing side is calculated from the given one.
+        image_props.aspect_ratio =
+            params::get(params, "ar", "a ratio like 16:9", AspectRatio::parse)?;
+        if let Some(ratio) = image_props.aspect_ratio {
+            match (params.contains_key("width"), params.contains_key("height")) {
+                (true, false) => image_props.height = ratio.height_for(image_props.width),
+                (false, true) => image_props.width = ratio.width_for(image_props.height),
+                _ => {}
             }
+            image_props.fit_aspect_ratio();
         }
 
-        if let Some(value) = params.get("height") {
-            if let Ok(height) = value.parse() {
-                image_props.height = height;
+        // `long`, `short` and `scale` replace the width, the height and the aspect ratio.
+        let long = params::number(params, "long", 1, u16::MAX)?;
+        let short = params::number(params, "short", 1, u16::MAX)?;
+        let scale = params::number(params, "scale", 1, 100)?;
+        let edge = match (long, short, scale) {
+            (None, None, None) => None,
+            (Some(size), None, None) => Some((Edge::Long, size)),
+            (None, Some(size), None) => Some((Edge::Short, size)),
+            // Only the caps limit the size.
+            (None, None, Some(_)) => Some((Edge::Long, u16::MAX)),
+            _ => {
+                return Err(HttpError::bad_request(
+                    "Parameters 'long', 'short' and 'scale' can't be combined",
+                ))
             }
+        };
+        image_props.scale = scale;
+
+        image_props.zoom = params::number(params, "zoom", MIN_ZOOM, MAX_ZOOM)?
+            // Zoom 1 is the same as no zoom, one cached variant is enough.
+            .filter(|zoom| *zoom > MIN_ZOOM);
+        image_props.kernel = params::get(params, "kernel", pipeline::KERNELS, Kernel::parse)?;
+        if let Some((edge, size)) = edge {
+            image_props.edge = Some(edge);
+            image_props.width = size;
+            image_props.height = size;
+            image_props.aspect_ratio = None;
         }
 
-        if let Some(value) = params.get("quality") {
-            if let Ok(quality) = value.parse() {
-                image_props.quality = quality;
+        match params.get("quality").map(String::as_str) {
+            Some("auto") => image_props.auto_quality = true,
+            Some(_) => {
+                if let Some(quality) = params::number(params, "quality", 1, 100)? {
+                    image_props.quality = quality;
+                }
             }
+            None => {}
         }
 
         if params.get("watermark").is_some() {
             image_props.watermark = true;
         }
 
-        if let Some(value) = params.get("format") {
-            image_props.format = match value.as_str() {
-                "jpg" | "jpeg" => ImageFormat::Jpeg,
-                _ => ImageFormat::Webp,
+        image_props.watermark_hash =
+            params::get(params, WATERMARK_HASH_PARAM, "an image hash", |value| {
+                hash::is_valid(value).then(|| value.to_string())
+            })?;
+        if image_props.watermark_hash.is_some() {
+            image_props.watermark = true;
+        }
+
+        let watermark_mode =
+            params::get(
+                params,
+                "watermark_mode",
+                "single or tile",
+                |value| match value {
+                    "single" => Some(WatermarkMode::Single),
+                    "tile" => Some(WatermarkMode::Tile),
+                    _ => None,
+                },
+            )?;
+        if let Some(watermark_mode) = watermark_mode {
+            image_props.watermark_mode = watermark_mode;
+            image_props.watermark = true;
+        }
+
+        match params.get("format").map(String::as_str) {
+            Some("auto") => image_props.auto_format = true,
+            Some("smart") => image_props.smart_format = true,
+            Some(value) => match ImageFormat::parse(value) {
+                Some(format) => image_props.format = format,
+                None => return Err(params::invalid("format", value, FORMATS)),
+            },
+            None => {}
+        }
+
+        let fit = params::get(params, "fit", "cover or blurpad", |value| match value {
+            "cover" => Some(Fit::Cover),
+            "blurpad" => Some(Fit::BlurPad),
+            _ => None,
+        })?;
+        if let Some(fit) = fit {
+            image_props.fit = fit;
+        }
+
+        if let Some(value) = params.get(PIPELINE_PARAM) {
+            match Pipeline::parse(value) {
+                Ok(pipeline) => image_props.pipeline = Some(pipeline),
+                Err(err) => {
+                    return Err(HttpError::bad_request(&err.to_string()).with_field(PIPELINE_PARAM))
+                }
             }
         }
 
+        if let Some(gravity) = params::get(params, "gravity", GRAVITIES, Gravity::parse)? {
+            image_props.gravity = gravity;
+        }
+
+        if let Some(page) = params::number::<u16>(params, "page", 0, u16::MAX)? {
+            image_props.page = page.into();
+        }
+
+        if params::get(params, "pages", "all", |value| {
+            (value == "all").then_some(())
+        })?
+        .is_some()
+        {
+            image_props.all_pages = true;
+        }
+
         if let Some(filename) = params.get("filename") {
             image_props.filename = Some(filename.to_string());
         }
 
+        if params.get("download").is_some() {
+            image_props.download = true;
+        }
+
+        if params.get("enhance").is_some() {
+            image_props.enhance = true;
+        }
+
+        image_props.denoise = params::number(params, "denoise", 1, denoise::MAX_STRENGTH)?;
+        // Rounded to hundredths, so that values differing in far digits share one cached
+        // variant. Gamma 1 and exposure 0 change nothing.
+        image_props.gamma =
+            params::number(params, "gamma", pipeline::MIN_GAMMA, pipeline::MAX_GAMMA)?
+                .map(round_adjustment)
+                .filter(|gamma| *gamma != 1.0);
+        image_props.exposure = params::number(
+            params,
+            "exposure",
+            pipeline::MIN_EXPOSURE,
+            pipeline::MAX_EXPOSURE,
+        )?
+        .map(round_adjustment)
+        .filter(|exposure| *exposure != 0.0);
+
+        image_props.vignette = params::number(params, "vignette", 1, 100)?;
+        image_props.shadow = params::get(params, "shadow", effects::SHADOW_FORMAT, Shadow::parse)?;
+
         if let Some(overlay) = params.get("overlay") {
             image_props.overlay = Some(overlay.to_string());
         }
 
-        image_props
+        let overlay_svg = params::get(params, OVERLAY_SVG_PARAM, "an image hash", |value| {
+            hash::is_valid(value).then(|| value.to_string())
+        })?;
+        if let Some(hash) = overlay_svg {
+            let gravity = params::get(params, "overlay_svg_gravity", GRAVITIES, Gravity::parse)?;
+            let width_percent = params::number(params, "overlay_svg_width", 1, 100)?;
+            image_props.overlay_svg = Some(SvgOverlay {
+                hash,
+                gravity: gravity.unwrap_or(Gravity::NorthEast),
+                width_percent: width_percent.unwrap_or(DEFAULT_OVERLAY_SVG_WIDTH),
+            });
+        }
+
+        image_props.licensee = params::get(
+            params,
+            fingerprint::LICENSEE_PARAM,
+            "1-16 latin letters, digits, '-' or '_'",
+            |value| fingerprint::is_valid_licensee(value).then(|| value.to_string()),
+        )?;
+
+        Ok(image_props)
+    }
+
+    /// Replace the placeholders of the overlay, see `overlay` module.
+    /// Called after `Variant::negotiate`, so that the size is final.
+    pub fn render_overlay(&mut self, hash: &str, custom: &BTreeMap<String, String>) {
+        if let Some(template) = &self.overlay {
+            let fields = overlay::Fields {
+                hash,
+                width: self.width,
+                height: self.height,
+                custom,
+            };
+            self.overlay = Some(overlay::render(template, &fields));
+        }
+    }
+
+    /// Reduce one side of the size to match the aspect ratio, if it is set.
+    pub fn fit_aspect_ratio(&mut self) {
+        if let Some(ratio) = self.aspect_ratio {
+            let height = ratio.height_for(self.width);
+            match height <= self.height {
+                true => self.height = height,
+                false => self.width = ratio.width_for(self.height),
+            }
+        }
     }
 }
 
+pub type ImageResponse = (StatusCode, HeaderMap, BoxBody);
+
 /// Convert image.
 /// Method: GET.
 /// Possible parameters: see ImageProps.
 pub async fn get_image(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
     Path(hash): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
+    ImageParams(params): ImageParams,
+) -> Result<ImageResponse, HttpError> {
+    check_hash(&hash)?;
+    let signed = state.is_signed(&format!("/images/{hash}"), &params);
+    let principal = principal.map(|Extension(principal)| principal);
+    serve_image(state, &headers, principal, signed, hash, &params).await
+}
+
+/// Convert image by its slug.
+/// Method: GET.
+/// Possible parameters: see ImageProps.
+pub async fn get_image_by_slug(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    Path(slug): Path<String>,
+    ImageParams(params): ImageParams,
+) -> Result<ImageResponse, HttpError> {
+    if !slug::is_valid(&slug) {
+        return Err(HttpError::bad_request("Invalid slug"));
+    }
+    let mut redis_con = state.redis.get().await?;
+    let hash = match slug::resolve(&mut redis_con, &slug).await? {
+        Some(hash) => hash,
+        None => {
+            return Err(HttpError::not_found(&format!(
+                "Image {} was not found",
+                slug
+            )))
+        }
+    };
+    drop(redis_con);
+
+    let signed = state.is_signed(&format!("/images/by-slug/{slug}"), &params);
+    let principal = principal.map(|Extension(principal)| principal);
+    serve_image(state, &headers, principal, signed, hash, &params).await
+}
+
+/// Get the hash of the image given by its hash or slug.
+pub async fn resolve_image(state: &AppState, image: String) -> Result<String, HttpError> {
+    if hash::is_valid(&image) {
+        return Ok(image);
+    }
+    if !slug::is_valid(&image) {
+        return Err(HttpError::bad_request("Invalid image hash or slug"));
+    }
+
+    let mut redis_con = state.redis.get().await?;
+    match slug::resolve(&mut redis_con, &image).await? {
+        Some(hash) => Ok(hash),
+        None => Err(HttpError::not_found(&format!(
+            "Image {} was not found",
+            image
+        ))),
+    }
+}
+
+/// Check access to the image and respond with the converted image.
+/// `signed` tells if the request is authorized by a signed URL.
+pub async fn serve_image(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    principal: Option<Principal>,
+    signed: bool,
+    hash: String,
+    params: &HashMap<String, String>,
+) -> Result<ImageResponse, HttpError> {
+    let mut redis_con = state.redis.get().await?;
+    let not_found = || HttpError::not_found(&format!("Image {} was not found", hash));
+
     // Check if the image was uploaded to the server.
+    // Missing images are remembered for a while, so that they don't hit the disk every time.
+    let missing_cache_secs = state.settings().missing_cache_secs;
+    let negative_cache = missing_cache_secs > 0;
+    if negative_cache && missing::is_missing(&mut redis_con, &hash).await? {
+        return Err(not_found());
+    }
+    // Deleted images are in the trash, they must not be fetched from the origin again.
+    let meta = metadata::get(&mut redis_con, &hash).await?;
+    if meta.deleted_at.is_some() {
+        return Err(not_found());
+    }
     let filepath = state.get_file_path(&hash);
-    if !filepath.exists() {
-        return Err(HttpError::not_found(&format!(
-            "Image {} was not found",
-            hash
-        )));
+    let found = match filepath.exists() {
+        true => true,
+        false => origin::fetch(&state, &hash, meta.checksum.as_deref()).await?,
+    };
+    if !found {
+        if negative_cache {
+            missing::set_missing(&mut redis_con, &hash, missing_cache_secs).await?;
+        }
+        return Err(not_found());
+    }
+
+    check_access(&hash, &meta, signed, principal.as_ref())?;
+    let private = meta.is_restricted();
+
+    if !state.cfg.allow_arbitrary_params && !signed && preset::has_restricted_params(params) {
+        return Err(HttpError::forbidden("Only presets are allowed"));
     }
+    let skip_cache = skips_cache(headers, params, signed, principal.is_some())?;
 
     // Check if-none-match header
-    let image_props = ImageProps::from_params(&params);
-    let image_id = get_image_id(&hash, &image_props);
-    let response_headers = get_headers(&image_props, &image_id, &hash);
-    if headers.contains_key("If-None-Match") {
-        println!("Found if-none-match header: {}", image_id);
-        return Ok((StatusCode::NOT_MODIFIED, response_headers, Vec::new()));
+    let params = match preset::apply(&state.settings().presets, params) {
+        Some(params) => params,
+        None => return Err(HttpError::bad_request("Unknown preset")),
+    };
+    // Defaults and limits of the image group, see `policy` module.
+    let policy = policy::resolve(&state.cfg.policies, &meta.tags);
+    let params = match &policy {
+        Some(policy) => policy.apply_defaults(&params),
+        None => params,
+    };
+    let mut image_props = ImageProps::from_params(&params)?;
+    if image_props.smart_format {
+        let class = smart::resolve(&state, &mut redis_con, &hash, &meta, None)
+            .await
+            .map_err(|err| HttpError::unprocessable_image(&err.to_string()))?;
+        image_props.content_class = Some(class);
     }
+    check_watermark(
+        &state,
+        &mut redis_con,
+        &image_props,
+        signed,
+        principal.as_ref(),
+    )
+    .await?;
+    check_overlay_svg(&mut redis_con, &image_props, signed, principal.as_ref()).await?;
+    let variant = Variant::negotiate(&mut image_props, &params, headers, &state.cfg);
+    if let Some(policy) = &policy {
+        policy.apply_limits(&mut image_props, &params)?;
+    }
+    image_props.render_overlay(&hash, &meta.custom);
+    // Name the file after the original unless another name is requested.
+    if image_props.filename.is_none() {
+        let ext = image_props.format.to_string();
+        image_props.filename = meta
+            .filename
+            .as_deref()
+            .map(|filename| replace_extension(filename, &ext));
+    }
+    let image_id = get_image_id(&hash, &image_props, &variant);
+    let mut response_headers = get_headers(&image_props, &variant, &image_id, &hash, private);
+    response_headers.extend(state.response_headers.clone());
+    // The connection goes back to the pool while waiting for the lease and rendering.
+    drop(redis_con);
 
-    // Check redis cache.
-    let mut redis_con = state.redis.get().await.unwrap();
-    let exists = redis_con.exists(&image_id).await.unwrap();
+    let rendition = Rendition {
+        source: Source::File(filepath),
+        hash: Some(hash),
+        image_id,
+        image_props,
+        params,
+        response_headers,
+        skip_cache,
+    };
+    render(state, headers, signed, rendition).await
+}
 
-    if exists {
-        println!("Using cached image {}", image_id);
-        let image: Vec<u8> = redis_con.get(&image_id).await.unwrap();
+/// Where the image to render comes from.
+pub enum Source {
+    /// Uploaded original.
+    File(PathBuf),
+    /// Remote image of the proxy mode.
+    Remote(Url),
+}
+
+impl Source {
+    async fn load(&self, state: &AppState) -> Result<Bytes, HttpError> {
+        match self {
+            // The original is read without blocking the runtime and passed to libvips as a buffer.
+            Source::File(path) => Ok(Bytes::from(tokio::fs::read(path).await?)),
+            Source::Remote(url) => proxy::download(state, url).await,
+        }
+    }
+}
+
+/// Converted image to respond with, see `render`.
+pub struct Rendition {
+    pub source: Source,
+    /// Hash of the uploaded original, counted as accessed. Remote images have none.
+    pub hash: Option<String>,
+    pub image_id: String,
+    pub image_props: ImageProps,
+    pub params: HashMap<String, String>,
+    pub response_headers: HeaderMap,
+    pub skip_cache: bool,
+}
+
+/// Respond with the converted image, shared by uploaded and remote images:
+/// hotlink protection, `If-None-Match`, the cache, the render lock,
+/// the processing slot and budget, and caching of the result.
+pub async fn render(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    signed: bool,
+    rendition: Rendition,
+) -> Result<ImageResponse, HttpError> {
+    let Rendition {
+        source,
+        hash,
+        image_id,
+        image_props,
+        params,
+        response_headers,
+        skip_cache,
+    } = rendition;
+    let hash = hash.as_deref();
+
+    // Check hotlink protection, signed URLs are always allowed.
+    if let Some(allowlist) = &state.cfg.hotlink_allowed_hosts {
+        if !signed
+            && !hotlink::is_allowed(allowlist, headers, state.cfg.hotlink_allow_empty_referer)
+        {
+            return Err(HttpError::forbidden("Hotlinking is not allowed"));
+        }
+    }
+
+    let mut redis_con = state.redis.get().await?;
+    if headers.contains_key("If-None-Match") && !skip_cache {
+        debug!("Found if-none-match header: {}", image_id);
+        record_access(&mut redis_con, hash, None).await?;
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            response_headers,
+            boxed(Empty::new()),
+        ));
+    }
+
+    // Check redis cache.
+    // The processed image overwrites the cached one if the cache is skipped.
+    if skip_cache {
+        debug!("Skipping cache for {}", image_id);
+    } else if let Some(image) = cache::read(
+        &mut redis_con,
+        &state.redis,
+        &image_id,
+        state.cache_offload.as_ref(),
+    )
+    .await?
+    {
+        debug!("Using cached image {}", image_id);
+        record_access(&mut redis_con, hash, Some(&image_id)).await?;
         return Ok((StatusCode::OK, response_headers, image));
+    } else {
+        debug!("Image was not found in cache: {}", image_id);
     }
+    check_overlay_svg_file(&state, &image_props).await?;
+    // The connection goes back to the pool while waiting for the lease and rendering.
+    drop(redis_con);
+    // One of concurrent requests renders the image, others wait for it, see `render_lock` module.
+    // The turn is released when dropped, on errors too.
+    let turn = match (state.cfg.render_lock_ms, skip_cache) {
+        (0, _) | (_, true) => None,
+        (lease_ms, false) => {
+            let turn =
+                render_lock::lock(&state.flights, &state.redis, &image_id, lease_ms).await?;
+            if turn.is_none() {
+                let mut redis_con = state.redis.get().await?;
+                if let Some(image) = cache::read(
+                    &mut redis_con,
+                    &state.redis,
+                    &image_id,
+                    state.cache_offload.as_ref(),
+                )
+                .await?
+                {
+                    record_access(&mut redis_con, hash, Some(&image_id)).await?;
+                    return Ok((StatusCode::OK, response_headers, image));
+                }
+            }
+            turn
+        }
+    };
 
-    println!("Image was not found in cache: {}", image_id);
-    let buffer = match process_image(filepath, &image_props, state) {
-        Ok(buffer) => buffer,
-        Err(err) => return Err(HttpError::internal_server_error(&err.to_string())),
+    // Remote images are downloaded before waiting for a processing slot,
+    // so that slow hosts don't hold it.
+    let downloaded = match &source {
+        Source::Remote(_) => Some(source.load(&state).await?),
+        Source::File(_) => None,
+    };
+    // Wait for a processing slot, or reject the request if too many are waiting.
+    // The wait counts towards the processing budget.
+    let mut budget = Budget::new(state.cfg.processing_budget_ms, &image_id);
+    let permit = match budget.wait("queue", state.throttle.acquire()).await {
+        Ok(Ok(permit)) => permit,
+        Ok(Err(_)) => {
+            return Err(HttpError::overloaded(
+                "Server is busy, try again later",
+                throttle::RETRY_AFTER_SECS,
+            ))
+        }
+        Err(err) => return Err(HttpError::service_unavailable(&err.to_string())),
     };
 
+    let data = match downloaded {
+        Some(data) => data,
+        None => source.load(&state).await?,
+    };
+    let format = sniff::mime_type(&data);
+    let process_state = state.clone();
+    let processed = cancel::run_blocking(move |cancel| {
+        // The slot is held until libvips is done, even if the request is gone.
+        let _permit = permit;
+        let buffer = process_buffer(&data, &image_props, &process_state, cancel, &mut budget)?;
+        Ok((buffer, budget))
+    })
+    .await;
+    let (buffer, budget) = match processed {
+        Ok(processed) => processed,
+        Err(err) if err.is::<Cancelled>() || err.is::<OverBudget>() => {
+            return Err(HttpError::service_unavailable(&err.to_string()))
+        }
+        Err(err) if err.is::<PageOutOfRange>() => {
+            return Err(HttpError::bad_request(&err.to_string()))
+        }
+        // Broken images must not affect other requests.
+        Err(err) => {
+            state
+                .metrics
+                .transform_failures
+                .with_label_values(&[metrics::format_label(format)])
+                .inc();
+            return Err(HttpError::unprocessable_image(&err.to_string()));
+        }
+    };
+
+    slow::report(&state, &image_id, &params, &budget, buffer.len());
+
     // Save to redis cache
-    let _: () = redis_con.set(image_id, &buffer).await.unwrap();
+    let mut redis_con = state.redis.get().await?;
+    cache::write(
+        &mut redis_con,
+        &image_id,
+        &buffer,
+        state.settings().cache_ttl_secs,
+        state.cache_offload.as_ref(),
+    )
+    .await?;
+    if let Some(turn) = turn {
+        render_lock::release(&mut redis_con, turn).await?;
+    }
+    record_access(&mut redis_con, hash, Some(&image_id)).await?;
+
+    Ok((StatusCode::OK, response_headers, boxed(Full::new(buffer))))
+}
+
+/// Count the original and the converted image as accessed, see `access` module.
+async fn record_access(
+    redis_con: &mut Connection,
+    hash: Option<&str>,
+    image_id: Option<&str>,
+) -> Result<(), HttpError> {
+    match (hash, image_id) {
+        (Some(hash), image_id) => access::record(redis_con, hash, image_id).await?,
+        (None, Some(image_id)) => access::record_derivative(redis_con, image_id).await?,
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+/// Check that the uploaded watermark can be used and exists.
+/// Watermarks out of `watermark_hashes` and licensees require a signed URL or a bearer token.
+/// The watermark is an image like any other: private, rejected and pending ones
+/// are only used by the clients that could view them.
+pub async fn check_watermark(
+    state: &AppState,
+    redis_con: &mut Connection,
+    image_props: &ImageProps,
+    signed: bool,
+    principal: Option<&Principal>,
+) -> Result<(), HttpError> {
+    let authorized = signed || principal.is_some();
+    // Anyone could put another licensee into the URL.
+    if image_props.licensee.is_some() && !authorized {
+        return Err(HttpError::forbidden(
+            "Licensee can only be set by a signed URL or a bearer token",
+        )
+        .with_field(fingerprint::LICENSEE_PARAM));
+    }
+    let hash = match &image_props.watermark_hash {
+        Some(hash) => hash,
+        None => return Ok(()),
+    };
+    let listed = state
+        .cfg
+        .watermark_hashes
+        .iter()
+        .flatten()
+        .any(|allowed| allowed == hash);
+    if !authorized && !listed {
+        return Err(
+            HttpError::forbidden("This watermark is not allowed").with_field(WATERMARK_HASH_PARAM)
+        );
+    }
+    let not_found = || {
+        HttpError::bad_request(&format!("Watermark {hash} was not found"))
+            .with_field(WATERMARK_HASH_PARAM)
+    };
+    let meta = metadata::get(redis_con, hash).await?;
+    if meta.deleted_at.is_some() {
+        return Err(not_found());
+    }
+    // Listed watermarks are chosen by the operator, like signed URLs.
+    check_access(hash, &meta, signed || listed, principal)
+        .map_err(|err| err.with_field(WATERMARK_HASH_PARAM))?;
+    if !state.get_file_path(hash).exists() {
+        return Err(not_found());
+    }
+    Ok(())
+}
+
+/// Check if the client asks to process the image again instead of using the cache:
+/// 'Cache-Control: no-cache' of signed URLs or clients with a bearer token,
+/// or `skip_cache=1` of signed URLs. Unsigned `skip_cache` is answered with 403.
+pub fn skips_cache(
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    signed: bool,
+    authorized: bool,
+) -> Result<bool, HttpError> {
+    if let Some(value) = params.get(SKIP_CACHE_PARAM) {
+        if !signed {
+            return Err(
+                HttpError::forbidden("Cache can only be skipped by a signed URL")
+                    .with_field(SKIP_CACHE_PARAM),
+            );
+        }
+        if value == "1" || value == "true" {
+            return Ok(true);
+        }
+    }
+    let no_cache = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+    Ok(no_cache && (signed || authorized))
+}
+
+fn not_svg(hash: &str) -> HttpError {
+    HttpError::bad_request(&format!("Overlay {hash} is not an uploaded SVG image"))
+        .with_field(OVERLAY_SVG_PARAM)
+}
+
+/// Check access to the SVG overlay like to any other image:
+/// private ones require a signed URL or a bearer token,
+/// rejected and pending ones are not used for anonymous clients.
+/// The file is checked by `check_overlay_svg_file` after the cache miss.
+pub async fn check_overlay_svg(
+    redis_con: &mut Connection,
+    image_props: &ImageProps,
+    signed: bool,
+    principal: Option<&Principal>,
+) -> Result<(), HttpError> {
+    let hash = match &image_props.overlay_svg {
+        Some(svg) => &svg.hash,
+        None => return Ok(()),
+    };
+    let meta = metadata::get(redis_con, hash).await?;
+    if meta.deleted_at.is_some() {
+        return Err(not_svg(hash));
+    }
+    check_access(hash, &meta, signed, principal)
+        .map_err(|err| err.with_field(OVERLAY_SVG_PARAM))
+}
+
+/// Check that the SVG overlay is an uploaded SVG image.
+pub async fn check_overlay_svg_file(
+    state: &AppState,
+    image_props: &ImageProps,
+) -> Result<(), HttpError> {
+    let hash = match &image_props.overlay_svg {
+        Some(svg) => &svg.hash,
+        None => return Ok(()),
+    };
+    let data = tokio::fs::read(state.get_file_path(hash))
+        .await
+        .map_err(|_| not_svg(hash))?;
+    if sniff::mime_type(&data) != Some("image/svg+xml") {
+        return Err(not_svg(hash));
+    }
+    Ok(())
+}
+
+/// Check if the client can view the image.
+pub fn check_access(
+    hash: &str,
+    meta: &ImageMetadata,
+    signed: bool,
+    principal: Option<&Principal>,
+) -> Result<(), HttpError> {
+    // Private images require a signed URL, a token or the JWT of the uploader.
+    let allowed =
+        signed || principal.is_some_and(|principal| principal.can_view(meta.owner.as_deref()));
+    if meta.is_restricted() && !allowed {
+        return Err(HttpError::forbidden("Access to this image is restricted"));
+    }
+
+    // Images awaiting moderation are served only to authenticated clients.
+    match meta.moderation {
+        Some(Verdict::Rejected) => Err(HttpError::not_found(&format!(
+            "Image {} was not found",
+            hash
+        ))),
+        Some(Verdict::Pending) if principal.is_none() => {
+            Err(HttpError::forbidden("Image is awaiting moderation"))
+        }
+        _ => Ok(()),
+    }
+}
 
-    Ok((StatusCode::OK, response_headers, buffer))
+/// Respond with 400 if the path parameter is not a hash (64 lowercase hex digits).
+/// The hash becomes a file name, so it is checked before touching the disk.
+pub fn check_hash(hash: &str) -> Result<(), HttpError> {
+    match hash::is_valid(hash) {
+        true => Ok(()),
+        false => Err(HttpError::bad_request("Invalid image hash")),
+    }
+}
+
+/// Round gamma and exposure to hundredths.
+fn round_adjustment(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
 }
 
 /// Calculate unique ID for this image.
 /// It takes height, width, quality, format and watermark into account.
 /// Image ID will be used as a key for caching.
-pub fn get_image_id(hash: &str, props: &ImageProps) -> String {
-    format!(
-        "{}-{}-{}-{}-{}-{}-{}",
-        hash,
-        props.width,
-        props.height,
-        props.quality,
-        props.watermark,
-        props.format,
-        props.overlay.clone().unwrap_or("none".to_string())
-    )
+pub fn get_image_id(hash: &str, props: &ImageProps, variant: &Variant) -> String {
+    let quality = match props.auto_quality {
+        true => "auto".to_string(),
+        false => props.quality.to_string(),
+    };
+    let mut image_id = format!(
+        "{}-{}-{}-{}-{}-{}",
+        hash, props.width, props.height, quality, props.watermark, props.format,
+    );
+
+    // Optional properties are added only if set, so that other keys stay the same.
+    if props.lossless {
+        image_id.push_str("-lossless");
+    }
+    if props.enhance {
+        image_id.push_str("-enhance");
+    }
+    if let Some(strength) = props.denoise {
+        image_id.push_str(&format!("-denoise{strength}"));
+    }
+    if let Some(exposure) = props.exposure {
+        image_id.push_str(&format!("-exposure{exposure}"));
+    }
+    if let Some(gamma) = props.gamma {
+        image_id.push_str(&format!("-gamma{gamma}"));
+    }
+    if let Some(vignette) = props.vignette {
+        image_id.push_str(&format!("-vignette{vignette}"));
+    }
+    if let Some(shadow) = &props.shadow {
+        image_id.push_str(&format!("-shadow{shadow}"));
+    }
+    if props.all_pages {
+        image_id.push_str("-all-pages");
+    }
+    if props.page > 0 {
+        image_id.push_str(&format!("-page{}", props.page));
+    }
+    if let Some(pipeline) = &props.pipeline {
+        let pipeline_hash = hash::compute(pipeline.to_string().as_bytes());
+        image_id.push_str(&format!("-pipeline{}", &pipeline_hash[..16]));
+    }
+    if let Some(edge) = props.edge {
+        image_id.push_str(&format!("-{edge}"));
+    }
+    if let Some(scale) = props.scale {
+        image_id.push_str(&format!("-scale{scale}"));
+    }
+    if let (Some(zoom), Fit::Cover, None, None) =
+        (props.zoom, &props.fit, &props.pipeline, props.edge)
+    {
+        image_id.push_str(&format!("-zoom{zoom}"));
+    }
+    if let Some(kernel) = props.kernel {
+        image_id.push_str(&format!("-k{kernel}"));
+    }
+    if let Fit::BlurPad = props.fit {
+        image_id.push_str(&format!("-blurpad-{}", props.gravity));
+    }
+    if let (true, Some(watermark_hash)) = (props.watermark, &props.watermark_hash) {
+        image_id.push_str(&format!("-wm{}", &watermark_hash[..16]));
+    }
+    if props.watermark && props.watermark_mode == WatermarkMode::Tile {
+        image_id.push_str("-wmtile");
+    }
+    if let Some(svg) = &props.overlay_svg {
+        image_id.push_str(&format!(
+            "-svg{}-{}-{}",
+            &svg.hash[..16],
+            svg.gravity,
+            svg.width_percent
+        ));
+    }
+    if let Some(licensee) = &props.licensee {
+        image_id.push_str(&format!("-lic{licensee}"));
+    }
+
+    image_id.push_str(&format!(
+        "-{}{}",
+        props.overlay.clone().unwrap_or("none".to_string()),
+        variant.key()
+    ));
+    image_id
 }
 
 /// Rotate, crop, apply watermark and encode requested image.
 /// Returns encoded image in any of the supported formats.
-fn process_image(
-    filepath: PathBuf,
+/// Encoding is killed once the cancellation flag is raised.
+/// The budget is checked after each stage, see `budget` module.
+pub fn process_buffer(
+    buffer: &[u8],
     image_props: &ImageProps,
-    state: Arc<AppState>,
-) -> anyhow::Result<Vec<u8>> {
-    let image = VipsImage::new_from_file(&filepath.into_os_string().into_string().unwrap())?;
+    state: &AppState,
+    cancel: &CancelFlag,
+    budget: &mut Budget,
+) -> anyhow::Result<Bytes> {
+    // Settings are kept until the end, libvips reads the watermark buffer during encoding.
+    let settings = state.settings();
+    // Uploaded watermarks are read for each request, the configured one is preloaded.
+    let uploaded_watermark = match (image_props.watermark, &image_props.watermark_hash) {
+        (true, Some(hash)) => Some(std::fs::read(state.get_file_path(hash))?),
+        _ => None,
+    };
+    let watermark = uploaded_watermark
+        .as_deref()
+        .or(settings.watermark.as_deref());
+    let svg = match &image_props.overlay_svg {
+        Some(svg) => Some(std::fs::read(state.get_file_path(&svg.hash))?),
+        None => None,
+    };
+
+    // Pages are stacked vertically and transformed one by one.
+    let kernel = image_props.kernel.unwrap_or(state.kernel);
+    let all_pages = image_props.all_pages && matches!(image_props.format, ImageFormat::Tiff);
+    let (image, page_height) = match all_pages {
+        true => {
+            let image = VipsImage::new_from_buffer(buffer, "[n=-1]")?;
+            budget.check("decode")?;
+            let page_height = image.get_page_height();
+            let mut pages = (0..image.get_height() / page_height)
+                .map(|page| {
+                    let page = ops::extract_area(
+                        &image,
+                        0,
+                        page * page_height,
+                        image.get_width(),
+                        page_height,
+                    )?;
+                    transform_image(
+                        page,
+                        image_props,
+                        &settings,
+                        watermark,
+                        svg.as_deref(),
+                        kernel,
+                        budget,
+                    )
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            // All pages have the same size, so they are transformed to the same size too.
+            let page_height = pages[0].get_height();
+            let joined = ops::arrayjoin_with_opts(
+                &mut pages,
+                &ops::ArrayjoinOptions {
+                    across: 1,
+                    ..ops::ArrayjoinOptions::default()
+                },
+            )?;
+            (joined, Some(page_height))
+        }
+        false => {
+            let image = load_page(buffer, image_props.page)?;
+            budget.check("decode")?;
+            (
+                transform_image(
+                    image,
+                    image_props,
+                    &settings,
+                    watermark,
+                    svg.as_deref(),
+                    kernel,
+                    budget,
+                )?,
+                None,
+            )
+        }
+    };
+
+    // The invisible watermark reads all pixels, so the pipeline is evaluated here for marked images.
+    let image = match &image_props.licensee {
+        Some(licensee) => {
+            let strength = state.cfg.invisible_watermark_strength;
+            let image = fingerprint::embed(&image, licensee, strength)?;
+            budget.check("fingerprint")?;
+            image
+        }
+        None => image,
+    };
+
+    // Encode image.
+    // libvips is lazy, so the whole pipeline is evaluated here.
+    let encode = |image: &VipsImage, quality, page_height| {
+        encode_image(
+            image,
+            &image_props.format,
+            quality,
+            image_props.lossless,
+            page_height,
+            &state.encoders,
+        )
+    };
+    let quality = match image_props.auto_quality {
+        // The sample used for comparison is a single page.
+        true => cancel::evaluate(&image, cancel, || {
+            quality::find(&image, state.cfg.auto_quality_target, |image, quality| {
+                encode(image, quality, None)
+            })
+        })?,
+        false => image_props.quality,
+    };
+    if image_props.auto_quality {
+        budget.check("quality")?;
+    }
+    // The encoded buffer is moved into `Bytes` without copying, it is shared
+    // by the cache write and the response body from here on.
+    let buffer = cancel::evaluate(&image, cancel, || encode(&image, quality, page_height))?;
+    budget.check("encode")?;
+    state
+        .metrics
+        .observe_stages(budget.stages(), &image_props.format.to_string());
+    Ok(Bytes::from(buffer))
+}
+
+/// Blur of the background for `fit=blurpad`.
+const BLUR_PAD_SIGMA: f64 = 20.0;
+
+/// The requested page does not exist.
+#[derive(Debug)]
+pub struct PageOutOfRange {
+    pub page: i32,
+    pub pages: i32,
+}
+
+impl fmt::Display for PageOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Page {} is out of range, the image has {} page(s)",
+            self.page, self.pages
+        )
+    }
+}
+
+impl std::error::Error for PageOutOfRange {}
+
+/// Load a single page of the image.
+fn load_page(buffer: &[u8], page: i32) -> anyhow::Result<VipsImage> {
+    let image = VipsImage::new_from_buffer(buffer, "")?;
+    if page == 0 {
+        return Ok(image);
+    }
+
+    let pages = image.get_n_pages();
+    if page >= pages {
+        return Err(PageOutOfRange { page, pages }.into());
+    }
+    Ok(VipsImage::new_from_buffer(
+        buffer,
+        &format!("[page={page}]"),
+    )?)
+}
 
+/// Apply the processing steps to the loaded image.
+/// `watermark` is the buffer of the uploaded or the configured watermark,
+/// `svg` is the buffer of the SVG overlay.
+/// The budget is checked after each step, the steps are the stages of the metrics.
+fn transform_image(
+    image: VipsImage,
+    image_props: &ImageProps,
+    settings: &Settings,
+    watermark: Option<&[u8]>,
+    svg: Option<&[u8]>,
+    kernel: Kernel,
+    budget: &mut Budget,
+) -> anyhow::Result<VipsImage> {
     // Apply rotation from EXIF tag.
     let rotated_image = ops::autorot(&image)?;
+    budget.check("rotate")?;
 
-    // Resize the image so that the smaller side of the image is fully visible
-    let original_width = rotated_image.get_width();
-    let original_height = rotated_image.get_height();
+    let cropped_image = match (&image_props.pipeline, image_props.edge, &image_props.fit) {
+        (Some(pipeline), _, _) => {
+            let image = pipeline.run(rotated_image, kernel)?;
+            budget.check("pipeline")?;
+            image
+        }
+        (None, Some(edge), _) => {
+            // Caps can make the sides differ, the smaller one is the limit.
+            let size = cmp::min(image_props.width, image_props.height);
+            let image = resize_edge(&rotated_image, edge, size, image_props.scale, kernel)?;
+            budget.check("resize")?;
+            image
+        }
+        (None, None, Fit::Cover) => {
+            let (width, height) = (image_props.width, image_props.height);
+            match image_props.zoom {
+                Some(zoom) => {
+                    let image = pipeline::zoom(&rotated_image, width, height, zoom, kernel)?;
+                    budget.check("crop")?;
+                    image
+                }
+                None => {
+                    let resized_image =
+                        pipeline::cover_resize(&rotated_image, width, height, kernel)?;
+                    budget.check("resize")?;
+                    let image = pipeline::cover_crop(&resized_image, width, height)?;
+                    budget.check("crop")?;
+                    image
+                }
+            }
+        }
+        (None, None, Fit::BlurPad) => {
+            let image = blur_pad(&rotated_image, image_props, kernel)?;
+            budget.check("blurpad")?;
+            image
+        }
+    };
 
-    let width_scale_factor: f64 = f64::from(image_props.width) / f64::from(original_width);
-    let height_scale_factor: f64 = f64::from(image_props.height) / f64::from(original_height);
+    // Noise is reduced before the enhancement, which would amplify it.
+    let cropped_image = match image_props.denoise {
+        Some(strength) => {
+            let image = denoise::apply(&cropped_image, strength)?;
+            budget.check("denoise")?;
+            image
+        }
+        None => cropped_image,
+    };
 
-    let min_factor = width_scale_factor.max(height_scale_factor).min(1.0);
-    let resized_image = ops::resize(&rotated_image, min_factor)?;
+    // Manual corrections go before the automatic one.
+    let cropped_image = match image_props.exposure {
+        Some(ev) => pipeline::exposure(&cropped_image, ev)?,
+        None => cropped_image,
+    };
+    let cropped_image = match image_props.gamma {
+        Some(value) => pipeline::gamma(&cropped_image, value)?,
+        None => cropped_image,
+    };
+    if image_props.exposure.is_some() || image_props.gamma.is_some() {
+        budget.check("adjust")?;
+    }
 
-    // Crop big side with smart algorithm
-    let cropped_image = ops::smartcrop(
-        &resized_image,
-        cmp::min(image_props.width.into(), resized_image.get_width()),
-        cmp::min(image_props.height.into(), resized_image.get_height()),
-    )?;
+    // Enhance the output, the statistics of the visible part are what matters.
+    let cropped_image = match image_props.enhance {
+        true => {
+            let image = enhance::apply(&cropped_image)?;
+            budget.check("enhance")?;
+            image
+        }
+        false => cropped_image,
+    };
+
+    let cropped_image = match image_props.vignette {
+        Some(strength) => {
+            let image = effects::vignette(&cropped_image, strength)?;
+            budget.check("vignette")?;
+            image
+        }
+        None => cropped_image,
+    };
 
     // Add watermark if needed.
     let image_with_watermark = match image_props.watermark {
-        true => match &state.watermark {
+        true => match watermark {
             Some(watermark_buffer) => {
                 // I have to load this picture every time again, because it cannot be passed between threads.
                 let watermark = VipsImage::new_from_buffer(watermark_buffer, "")?;
+                // The opacity of the configured watermark is applied when it is preloaded.
+                let watermark = match image_props.watermark_hash.is_some() {
+                    true if settings.watermark_opacity < 1.0 => {
+                        reload::with_opacity(&watermark, settings.watermark_opacity)?
+                    }
+                    _ => watermark,
+                };
+                let watermark = match settings.watermark_width_percent {
+                    Some(percent) => scale_watermark(&watermark, &cropped_image, percent)?,
+                    None => watermark,
+                };
+
+                let watermark = match image_props.watermark_mode {
+                    WatermarkMode::Single => watermark,
+                    WatermarkMode::Tile => tile_watermark(&watermark, &cropped_image, settings)?,
+                };
 
                 // Join images.
-                ops::composite_2(&cropped_image, &watermark, ops::BlendMode::Screen)?
+                let mode = match settings.watermark_blend {
+                    WatermarkBlend::Screen => ops::BlendMode::Screen,
+                    WatermarkBlend::Over => ops::BlendMode::Over,
+                };
+                ops::composite_2(&cropped_image, &watermark, mode)?
             }
             // Watermark image is undefined
             None => cropped_image,
@@ -214,11 +1523,48 @@ fn process_image(
         // Watermark not required
         false => cropped_image,
     };
+    if image_props.watermark {
+        budget.check("watermark")?;
+    }
+
+    // Add SVG overlay.
+    let image_with_watermark = match (&image_props.overlay_svg, svg) {
+        (Some(overlay_svg), Some(svg)) => {
+            let image = add_svg(&image_with_watermark, svg, overlay_svg)?;
+            budget.check("svg")?;
+            image
+        }
+        _ => image_with_watermark,
+    };
 
     // Add overlay.
+    // Pango shapes the text and picks its direction, so that any script is rendered
+    // if the font covers it. The text is escaped markup, see `overlay` module.
+    // It wraps at the width of the image and is cut at its height,
+    // so that long texts don't grow the rendered image.
     let image_with_overlay = match &image_props.overlay {
         Some(overlay) => {
-            let text = ops::text(overlay)?;
+            let width = image_with_watermark.get_width();
+            let height = image_with_watermark.get_height();
+            let text = ops::text_with_opts(
+                overlay,
+                &ops::TextOptions {
+                    font: settings.overlay_font.clone(),
+                    fontfile: settings.overlay_font_file.clone().unwrap_or_default(),
+                    width,
+                    ..ops::TextOptions::default()
+                },
+            )?;
+            let text = match text.get_width() > width || text.get_height() > height {
+                true => ops::extract_area(
+                    &text,
+                    0,
+                    0,
+                    text.get_width().min(width),
+                    text.get_height().min(height),
+                )?,
+                false => text,
+            };
             let white = ops::copy_with_opts(
                 &VipsImage::new_from_image(&text, &[170.0, 170.0, 170.0])?,
                 &ops::CopyOptions {
@@ -231,28 +1577,222 @@ fn process_image(
         }
         None => image_with_watermark,
     };
+    if image_props.overlay.is_some() {
+        budget.check("overlay")?;
+    }
 
-    // Encode image.
-    match image_props.format {
-        ImageFormat::Webp => {
-            let options = get_webp_options(image_props.quality);
-            let buffer = ops::webpsave_buffer_with_opts(&image_with_overlay, &options)?;
-            Ok(buffer)
+    // The shadow goes around everything else.
+    let image_with_shadow = match &image_props.shadow {
+        Some(shadow) => {
+            let opaque = matches!(image_props.format, ImageFormat::Jpeg);
+            let image = effects::shadow(&image_with_overlay, shadow, opaque)?;
+            budget.check("shadow")?;
+            image
         }
+        None => image_with_overlay,
+    };
+
+    Ok(image_with_shadow)
+}
+
+/// Rasterize the SVG at its size on the image and composite it.
+fn add_svg(image: &VipsImage, svg: &[u8], overlay_svg: &SvgOverlay) -> anyhow::Result<VipsImage> {
+    let width = i32::from(overlay_svg.width_percent) * image.get_width() / 100;
+    // SVG is rendered at the target size instead of scaling a raster.
+    let overlay = ops::thumbnail_buffer_with_opts(
+        svg,
+        width.max(1),
+        &ops::ThumbnailBufferOptions {
+            height: image.get_height(),
+            ..ops::ThumbnailBufferOptions::default()
+        },
+    )?;
+    let free_x = image.get_width() - overlay.get_width();
+    let free_y = image.get_height() - overlay.get_height();
+    let (x, y) = overlay_svg.gravity.offset(free_x, free_y);
+    Ok(ops::composite_2_with_opts(
+        image,
+        &overlay,
+        ops::BlendMode::Over,
+        &ops::Composite2Options {
+            x,
+            y,
+            ..ops::Composite2Options::default()
+        },
+    )?)
+}
+
+/// Resize the watermark to `percent` of the image width, keeping its aspect ratio.
+fn scale_watermark(
+    watermark: &VipsImage,
+    image: &VipsImage,
+    percent: u8,
+) -> anyhow::Result<VipsImage> {
+    let width = f64::from(image.get_width()) * f64::from(percent) / 100.0;
+    let scale = width.max(1.0) / f64::from(watermark.get_width());
+    Ok(ops::resize(watermark, scale)?)
+}
+
+/// Repeat the watermark across the whole image with the spacing, angle and opacity of the settings.
+fn tile_watermark(
+    watermark: &VipsImage,
+    image: &VipsImage,
+    settings: &Settings,
+) -> anyhow::Result<VipsImage> {
+    let watermark = reload::with_opacity(watermark, settings.watermark_tile_opacity)?;
+    // Transparent space to the right and below each watermark.
+    let spacing = i32::from(settings.watermark_tile_spacing);
+    let tile = ops::embed(
+        &watermark,
+        0,
+        0,
+        watermark.get_width() + spacing,
+        watermark.get_height() + spacing,
+    )?;
+
+    // The pattern covers the diagonal, so that the image is covered at any angle.
+    let width = image.get_width();
+    let height = image.get_height();
+    let diagonal = f64::from(width).hypot(f64::from(height)).ceil() as i32;
+    let across = diagonal / tile.get_width() + 1;
+    let down = diagonal / tile.get_height() + 1;
+    let pattern = ops::replicate(&tile, across, down)?;
+    let pattern = match settings.watermark_tile_angle {
+        angle if angle % 360.0 == 0.0 => pattern,
+        angle => ops::rotate(&pattern, angle)?,
+    };
+
+    // Take the middle of the pattern.
+    let left = (pattern.get_width() - width) / 2;
+    let top = (pattern.get_height() - height) / 2;
+    Ok(ops::extract_area(&pattern, left, top, width, height)?)
+}
+
+/// Fit the whole image into the requested size and fill the rest
+/// with its enlarged and blurred copy.
+fn blur_pad(
+    image: &VipsImage,
+    image_props: &ImageProps,
+    kernel: Kernel,
+) -> anyhow::Result<VipsImage> {
+    let width = i32::from(image_props.width);
+    let height = i32::from(image_props.height);
+    let width_scale_factor = f64::from(width) / f64::from(image.get_width());
+    let height_scale_factor = f64::from(height) / f64::from(image.get_height());
+
+    // Background covers the whole area, it may be upscaled.
+    let background = pipeline::resize(image, width_scale_factor.max(height_scale_factor), kernel)?;
+    let background = ops::smartcrop_with_opts(
+        &background,
+        cmp::min(width, background.get_width()),
+        cmp::min(height, background.get_height()),
+        &ops::SmartcropOptions {
+            interesting: ops::Interesting::Centre,
+        },
+    )?;
+    let background = ops::gaussblur(&background, BLUR_PAD_SIGMA)?;
+
+    // The image itself is not upscaled.
+    let foreground = pipeline::resize(
+        image,
+        width_scale_factor.min(height_scale_factor).min(1.0),
+        kernel,
+    )?;
+    let free_x = background.get_width() - foreground.get_width();
+    let free_y = background.get_height() - foreground.get_height();
+    let (x, y) = image_props.gravity.offset(free_x, free_y);
+
+    Ok(ops::composite_2_with_opts(
+        &background,
+        &foreground,
+        ops::BlendMode::Over,
+        &ops::Composite2Options {
+            x,
+            y,
+            ..ops::Composite2Options::default()
+        },
+    )?)
+}
+
+/// Resize the image so that the edge is at most `size`, keeping the aspect ratio.
+/// `scale` reduces the image to the percent of its size, within the same limit.
+/// Images are not upscaled.
+fn resize_edge(
+    image: &VipsImage,
+    edge: Edge,
+    size: u16,
+    scale: Option<u8>,
+    kernel: Kernel,
+) -> libvips::Result<VipsImage> {
+    let (width, height) = (image.get_width(), image.get_height());
+    let edge_size = match edge {
+        Edge::Long => cmp::max(width, height),
+        Edge::Short => cmp::min(width, height),
+    };
+    let factor = f64::from(size) / f64::from(edge_size);
+    let factor = match scale {
+        Some(percent) => factor.min(f64::from(percent) / 100.0),
+        None => factor,
+    };
+    pipeline::resize(image, factor.min(1.0), kernel)
+}
+
+/// Encode the image in the given format.
+/// `page_height` is the height of each page of multi-page images.
+pub fn encode_image(
+    image: &VipsImage,
+    format: &ImageFormat,
+    quality: u8,
+    lossless: bool,
+    page_height: Option<i32>,
+    encoders: &EncoderOptions,
+) -> libvips::Result<Vec<u8>> {
+    match format {
+        ImageFormat::Webp => ops::webpsave_buffer_with_opts(
+            image,
+            &get_webp_options(quality, lossless, &encoders.webp),
+        ),
         ImageFormat::Jpeg => {
-            let options = get_jpeg_options(image_props.quality);
-            let buffer = ops::jpegsave_buffer_with_opts(&image_with_overlay, &options)?;
-            Ok(buffer)
+            ops::jpegsave_buffer_with_opts(image, &get_jpeg_options(quality, &encoders.jpeg))
+        }
+        ImageFormat::Avif => {
+            ops::heifsave_buffer_with_opts(image, &get_avif_options(quality, &encoders.avif))
+        }
+        ImageFormat::Png => {
+            ops::pngsave_buffer_with_opts(image, &get_png_options(quality, &encoders.png))
+        }
+        ImageFormat::Gif => ops::gifsave_buffer_with_opts(image, &get_gif_options(&encoders.gif)),
+        ImageFormat::Tiff => ops::tiffsave_buffer_with_opts(
+            image,
+            &get_tiff_options(quality, page_height, &encoders.tiff),
+        ),
+        #[cfg(feature = "jxl")]
+        ImageFormat::Jxl => {
+            ops::jxlsave_buffer_with_opts(image, &get_jxl_options(quality, &encoders.jxl))
         }
     }
 }
 
-fn get_webp_options(quality: u8) -> ops::WebpsaveBufferOptions {
+fn get_webp_options(
+    quality: u8,
+    lossless: bool,
+    defaults: &WebpOptions,
+) -> ops::WebpsaveBufferOptions {
     ops::WebpsaveBufferOptions {
         // Quality
         q: quality.into(),
+        lossless,
         // Preset for lossy compression
-        preset: ops::ForeignWebpPreset::Photo,
+        preset: match defaults.preset {
+            WebpPreset::Default => ops::ForeignWebpPreset::Default,
+            WebpPreset::Picture => ops::ForeignWebpPreset::Picture,
+            WebpPreset::Photo => ops::ForeignWebpPreset::Photo,
+            WebpPreset::Drawing => ops::ForeignWebpPreset::Drawing,
+            WebpPreset::Icon => ops::ForeignWebpPreset::Icon,
+            WebpPreset::Text => ops::ForeignWebpPreset::Text,
+        },
+        effort: defaults.effort.into(),
+        smart_subsample: defaults.smart_subsample,
         // Strip all metadata from image
         strip: true,
         // Default values
@@ -260,10 +1800,13 @@ fn get_webp_options(quality: u8) -> ops::WebpsaveBufferOptions {
     }
 }
 
-fn get_jpeg_options(quality: u8) -> ops::JpegsaveBufferOptions {
+fn get_jpeg_options(quality: u8, defaults: &JpegOptions) -> ops::JpegsaveBufferOptions {
     ops::JpegsaveBufferOptions {
         // Quality
         q: quality.into(),
+        interlace: defaults.progressive,
+        subsample_mode: get_subsample_mode(defaults.subsampling),
+        optimize_coding: defaults.optimize_coding,
         // Strip all metadata from image
         strip: true,
         // Default values
@@ -271,8 +1814,150 @@ fn get_jpeg_options(quality: u8) -> ops::JpegsaveBufferOptions {
     }
 }
 
+fn get_avif_options(quality: u8, defaults: &AvifOptions) -> ops::HeifsaveBufferOptions {
+    ops::HeifsaveBufferOptions {
+        // Quality
+        q: quality.into(),
+        // AV1 compression makes it AVIF rather than HEIC
+        compression: ops::ForeignHeifCompression::Av1,
+        effort: defaults.effort.into(),
+        subsample_mode: get_subsample_mode(defaults.subsampling),
+        // Strip all metadata from image
+        strip: true,
+        // Default values
+        ..ops::HeifsaveBufferOptions::default()
+    }
+}
+
+fn get_png_options(quality: u8, defaults: &PngOptions) -> ops::PngsaveBufferOptions {
+    ops::PngsaveBufferOptions {
+        compression: defaults.compression.into(),
+        // Quality is used only for palette images
+        palette: defaults.palette,
+        q: quality.into(),
+        // Strip all metadata from image
+        strip: true,
+        // Default values
+        ..ops::PngsaveBufferOptions::default()
+    }
+}
+
+fn get_gif_options(defaults: &GifOptions) -> ops::GifsaveBufferOptions {
+    ops::GifsaveBufferOptions {
+        // Palette quantisation
+        dither: defaults.dither,
+        bitdepth: defaults.bitdepth.into(),
+        effort: defaults.effort.into(),
+        // Strip all metadata from image
+        strip: true,
+        // Default values
+        ..ops::GifsaveBufferOptions::default()
+    }
+}
+
+fn get_tiff_options(
+    quality: u8,
+    page_height: Option<i32>,
+    defaults: &TiffOptions,
+) -> ops::TiffsaveBufferOptions {
+    ops::TiffsaveBufferOptions {
+        // Quality is used only for JPEG and WebP compression
+        q: quality.into(),
+        compression: match defaults.compression {
+            TiffCompression::None => ops::ForeignTiffCompression::None,
+            TiffCompression::Jpeg => ops::ForeignTiffCompression::Jpeg,
+            TiffCompression::Deflate => ops::ForeignTiffCompression::Deflate,
+            TiffCompression::Lzw => ops::ForeignTiffCompression::Lzw,
+            TiffCompression::Webp => ops::ForeignTiffCompression::Webp,
+            TiffCompression::Zstd => ops::ForeignTiffCompression::Zstd,
+        },
+        // 0 means a single page
+        page_height: page_height.unwrap_or_default(),
+        // Strip all metadata from image
+        strip: true,
+        // Default values
+        ..ops::TiffsaveBufferOptions::default()
+    }
+}
+
+#[cfg(feature = "jxl")]
+fn get_jxl_options(quality: u8, defaults: &JxlOptions) -> ops::JxlsaveBufferOptions {
+    ops::JxlsaveBufferOptions {
+        // Quality
+        q: quality.into(),
+        effort: defaults.effort.into(),
+        // Strip all metadata from image
+        strip: true,
+        // Default values
+        ..ops::JxlsaveBufferOptions::default()
+    }
+}
+
+fn get_subsample_mode(subsampling: Subsampling) -> ops::ForeignSubsample {
+    match subsampling {
+        Subsampling::Auto => ops::ForeignSubsample::Auto,
+        Subsampling::On => ops::ForeignSubsample::On,
+        Subsampling::Off => ops::ForeignSubsample::Off,
+    }
+}
+
+/// Change the extension of the original filename to the output format.
+fn replace_extension(filename: &str, ext: &str) -> String {
+    let stem = match filename.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => filename,
+    };
+    format!("{stem}.{ext}")
+}
+
+/// Build the 'Content-Disposition' header value (RFC 6266).
+/// Control characters are removed. `filename` gets an ASCII fallback,
+/// `filename*` keeps the full name percent-encoded as UTF-8.
+fn get_content_disposition(filename: &str, download: bool) -> HeaderValue {
+    let filename: String = filename.chars().filter(|c| !c.is_control()).collect();
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            ' '..='~' => c,
+            _ => '_',
+        })
+        .collect();
+    let disposition = match download {
+        true => "attachment",
+        false => "inline",
+    };
+    let mut value = format!("{disposition}; filename=\"{fallback}\"");
+    if fallback != filename {
+        value.push_str("; filename*=UTF-8''");
+        for byte in filename.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => value.push(byte as char),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => value.push(byte as char),
+                _ => value.push_str(&format!("%{byte:02X}")),
+            }
+        }
+    }
+    // Only visible ASCII characters are left.
+    HeaderValue::from_str(&value).unwrap()
+}
+
 // Generate HTTP headers for the image.
-fn get_headers(props: &ImageProps, image_id: &str, image_hash: &str) -> HeaderMap {
+/// Entity tag of the processed image: the image ID contains the overlay text,
+/// which is not a valid header value, so its hash is used.
+fn get_etag(image_id: &str) -> HeaderValue {
+    let etag = format!("\"{}\"", hash::compute(image_id.as_bytes()));
+    HeaderValue::from_str(&etag).unwrap()
+}
+
+pub fn get_headers(
+    props: &ImageProps,
+    variant: &Variant,
+    image_id: &str,
+    image_hash: &str,
+    private: bool,
+) -> HeaderMap {
     let mut headers = HeaderMap::new();
 
     let ext = props.format.to_string();
@@ -287,10 +1972,112 @@ fn get_headers(props: &ImageProps, image_id: &str, image_hash: &str) -> HeaderMa
     );
     headers.insert(
         header::CONTENT_DISPOSITION,
-        format!("inline; filename=\"{filename}\"").parse().unwrap(),
+        get_content_disposition(&filename, props.download),
     );
-    headers.insert(header::ETAG, image_id.parse().unwrap());
-    headers.insert(header::CACHE_CONTROL, "max-age=604800".parse().unwrap());
+    headers.insert(header::ETAG, get_etag(image_id));
+    // Private images must not be stored by shared caches.
+    let cache_control = match private {
+        true => "private, max-age=604800",
+        false => "max-age=604800",
+    };
+    headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+    if let Some(vary) = variant.vary() {
+        headers.insert(header::VARY, vary);
+    }
+    headers.insert(variant::ACCEPT_CH, variant::CLIENT_HINTS.parse().unwrap());
 
     headers
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disposition(filename: &str) -> String {
+        get_content_disposition(filename, false)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn plain_filename_is_quoted() {
+        assert_eq!(disposition("photo.jpg"), "inline; filename=\"photo.jpg\"");
+        assert_eq!(
+            get_content_disposition("photo.jpg", true),
+            "attachment; filename=\"photo.jpg\""
+        );
+    }
+
+    #[test]
+    fn quotes_and_backslashes_are_replaced_in_fallback() {
+        assert_eq!(
+            disposition("a\"b\\c.jpg"),
+            "inline; filename=\"a_b_c.jpg\"; filename*=UTF-8''a%22b%5Cc.jpg"
+        );
+    }
+
+    #[test]
+    fn quote_cannot_add_parameters() {
+        let value = disposition("x.jpg\"; filename=\"evil.html");
+        assert!(value.starts_with("inline; filename=\"x.jpg_; filename=_evil.html\""));
+        assert_eq!(value.matches('"').count(), 2);
+    }
+
+    #[test]
+    fn control_characters_are_removed() {
+        assert_eq!(
+            disposition("photo\r\nSet-Cookie: a=b\0\t\x7f.jpg"),
+            "inline; filename=\"photoSet-Cookie: a=b.jpg\""
+        );
+    }
+
+    #[test]
+    fn non_ascii_filename_is_percent_encoded() {
+        assert_eq!(
+            disposition("фото.jpg"),
+            "inline; filename=\"____.jpg\"; filename*=UTF-8''%D1%84%D0%BE%D1%82%D0%BE.jpg"
+        );
+        assert_eq!(
+            disposition("写真 😀.png"),
+            "inline; filename=\"__ _.png\"; filename*=UTF-8''%E5%86%99%E7%9C%9F%20%F0%9F%98%80.png"
+        );
+    }
+
+    #[test]
+    fn empty_filename() {
+        assert_eq!(disposition(""), "inline; filename=\"\"");
+        assert_eq!(disposition("\r\n"), "inline; filename=\"\"");
+    }
+
+    #[test]
+    fn any_filename_gives_valid_header() {
+        // Every char class: controls, ASCII, Latin-1, BMP, surrogate neighbours and astral.
+        let samples = [
+            '\0', '\t', '\n', '\r', '\x1f', ' ', '"', '\'', ';', '%', '\\', '~', '\x7f', '\u{80}',
+            '\u{9f}', '\u{a0}', 'é', '\u{2028}', '\u{d7ff}', '\u{e000}', '\u{fffd}', '\u{feff}',
+            '😀', '\u{10ffff}',
+        ];
+        for c in samples {
+            for filename in [c.to_string(), format!("a{c}b"), c.to_string().repeat(100)] {
+                for download in [false, true] {
+                    // Doesn't panic on `unwrap`.
+                    let value = get_content_disposition(&filename, download);
+                    assert!(value.to_str().is_ok(), "{filename:?}");
+                }
+            }
+        }
+        let all: String = (0..=0x10ffff).filter_map(char::from_u32).collect();
+        assert!(get_content_disposition(&all, true).to_str().is_ok());
+    }
+
+    #[test]
+    fn etag_is_quoted_hash() {
+        let etag = get_etag("abc-overlay_text=line\nbreak\u{7f}");
+        let etag = etag.to_str().unwrap();
+        assert_eq!(etag.len(), 66);
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(get_etag("abc"), get_etag("abc"));
+        assert_ne!(get_etag("abc"), get_etag("abd"));
+    }
+}
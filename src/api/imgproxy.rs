@@ -0,0 +1,29 @@
+use crate::{
+    api::image::{resolve_image, serve_image, ImageResponse},
+    auth::Principal,
+    imgproxy, AppState, HttpError,
+};
+use axum::{
+    extract::{Extension, Path, State},
+    http::header::HeaderMap,
+};
+use std::sync::Arc;
+
+/// Convert an image requested with an imgproxy URL.
+/// Url: /imgproxy/<signature>/<options>/.../<image> (see `imgproxy` module)
+/// Method: GET
+pub async fn get_imgproxy_image(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    Path(path): Path<String>,
+) -> Result<ImageResponse, HttpError> {
+    let url = match imgproxy::parse(&path, &state.cfg) {
+        Some(url) => url,
+        None => return Err(HttpError::not_found("Invalid imgproxy URL")),
+    };
+
+    let hash = resolve_image(&state, url.image).await?;
+    let principal = principal.map(|Extension(principal)| principal);
+    serve_image(state, &headers, principal, url.signed, hash, &url.params).await
+}
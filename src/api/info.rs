@@ -0,0 +1,128 @@
+use crate::{
+    api::image::{check_access, check_hash},
+    auth::Principal,
+    metadata,
+    params::ImageParams,
+    stats::{self, Stats},
+    throttle, AppState, HttpError,
+};
+use axum::{
+    extract::{Extension, Path, State},
+    response::Json,
+};
+use libvips::{ops, VipsImage};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
+
+#[derive(Serialize)]
+pub struct Response {
+    pub hash: String,
+    /// File size in bytes.
+    pub size: u64,
+    /// Width with orientation applied.
+    pub width: i32,
+    /// Height with orientation applied.
+    pub height: i32,
+    /// Custom metadata given at upload.
+    pub metadata: BTreeMap<String, String>,
+    pub tags: Vec<String>,
+    /// Name of the uploaded file.
+    pub filename: Option<String>,
+    /// MIME type of the uploaded file.
+    pub content_type: Option<String>,
+}
+
+/// Get information about the uploaded image.
+/// Url: /images/:hash/info
+/// Method: GET
+pub async fn get_info(
+    State(state): State<Arc<AppState>>,
+    principal: Option<Extension<Principal>>,
+    Path(hash): Path<String>,
+    ImageParams(params): ImageParams,
+) -> Result<Json<Response>, HttpError> {
+    check_hash(&hash)?;
+    let filepath = state.get_file_path(&hash);
+    let file_metadata = match tokio::fs::metadata(&filepath).await {
+        Ok(file_metadata) => file_metadata,
+        Err(_) => {
+            return Err(HttpError::not_found(&format!(
+                "Image {} was not found",
+                hash
+            )))
+        }
+    };
+
+    let mut redis_con = state.redis.get().await?;
+    let meta = metadata::get(&mut redis_con, &hash).await?;
+    let signed = state.is_signed(&format!("/images/{hash}/info"), &params);
+    let principal = principal.map(|Extension(principal)| principal);
+    check_access(&hash, &meta, signed, principal.as_ref())?;
+
+    // Only the header is read here, pixels are not decoded.
+    let (width, height) = read_dimensions(&filepath.to_string_lossy())?;
+
+    Ok(Json(Response {
+        hash,
+        size: file_metadata.len(),
+        width,
+        height,
+        metadata: meta.custom,
+        tags: meta.tags,
+        filename: meta.filename,
+        content_type: meta.content_type,
+    }))
+}
+
+/// Get brightness and sharpness statistics of the uploaded image, see `stats` module.
+/// Url: /images/:hash/stats
+/// Method: GET
+pub async fn get_stats(
+    State(state): State<Arc<AppState>>,
+    principal: Option<Extension<Principal>>,
+    Path(hash): Path<String>,
+    ImageParams(params): ImageParams,
+) -> Result<Json<Stats>, HttpError> {
+    check_hash(&hash)?;
+    let filepath = state.get_file_path(&hash);
+    if !filepath.exists() {
+        return Err(HttpError::not_found(&format!(
+            "Image {} was not found",
+            hash
+        )));
+    }
+
+    let mut redis_con = state.redis.get().await?;
+    let meta = metadata::get(&mut redis_con, &hash).await?;
+    drop(redis_con);
+    let signed = state.is_signed(&format!("/images/{hash}/stats"), &params);
+    let principal = principal.map(|Extension(principal)| principal);
+    check_access(&hash, &meta, signed, principal.as_ref())?;
+
+    // The sample is decoded like any other transformation.
+    let permit = match state.throttle.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => {
+            return Err(HttpError::overloaded(
+                "Server is busy, try again later",
+                throttle::RETRY_AFTER_SECS,
+            ))
+        }
+    };
+    let data = tokio::fs::read(&filepath).await?;
+    let stats = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        stats::compute(&data)
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+    .map_err(|err| HttpError::unprocessable_image(&err.to_string()))?;
+
+    Ok(Json(stats))
+}
+
+fn read_dimensions(filepath: &str) -> anyhow::Result<(i32, i32)> {
+    let image = VipsImage::new_from_file(filepath)?;
+    let rotated_image = ops::autorot(&image)?;
+    Ok((rotated_image.get_width(), rotated_image.get_height()))
+}
@@ -0,0 +1,23 @@
+use crate::{AppState, HttpError};
+use axum::{
+    extract::State,
+    http::header::{self, HeaderMap},
+};
+use std::sync::Arc;
+
+/// Get server metrics in the Prometheus text format.
+/// Url: /metrics
+/// Method: GET
+pub async fn get_metrics(
+    State(state): State<Arc<AppState>>,
+) -> Result<(HeaderMap, String), HttpError> {
+    let body = state.metrics.encode()?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        "text/plain; version=0.0.4".parse().unwrap(),
+    );
+
+    Ok((headers, body))
+}
@@ -0,0 +1,149 @@
+use crate::{
+    api::image::{
+        check_overlay_svg, check_watermark, get_headers, get_image_id, render, skips_cache,
+        ImageProps, ImageResponse, Rendition, Source,
+    },
+    hash, hotlink,
+    params::ImageParams,
+    preset,
+    variant::Variant,
+    AppState, HttpError,
+};
+use anyhow::anyhow;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header::HeaderMap, status::StatusCode},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+use url::Url;
+
+/// Convert a remote image.
+/// Url: /proxy/:source (base64url-encoded URL of the image)
+/// Method: GET
+/// Possible parameters: see ImageProps.
+pub async fn get_proxied_image(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(source): Path<String>,
+    ImageParams(params): ImageParams,
+) -> Result<ImageResponse, HttpError> {
+    let allowlist = match &state.cfg.proxy_allowed_hosts {
+        Some(allowlist) => allowlist,
+        None => return Err(HttpError::not_found("Proxy mode is disabled")),
+    };
+
+    // Decode and check the source URL.
+    let source_url = match decode_url(&source) {
+        Ok(source_url) => source_url,
+        Err(err) => return Err(HttpError::bad_request(&err.to_string())),
+    };
+    let host = source_url.host_str().unwrap_or_default().to_lowercase();
+    if !allowlist
+        .iter()
+        .any(|allowed| hotlink::host_matches(&host, allowed))
+    {
+        return Err(HttpError::forbidden(&format!(
+            "Host {} is not allowed",
+            host
+        )));
+    }
+
+    let path = format!("/proxy/{source}");
+    let signed = state.is_signed(&path, &params);
+    if !state.cfg.allow_arbitrary_params && preset::has_restricted_params(&params) && !signed {
+        return Err(HttpError::forbidden("Only presets are allowed"));
+    }
+    let skip_cache = skips_cache(&headers, &params, signed, signed)?;
+
+    let source_hash = hash::compute(source_url.as_str().as_bytes());
+    let params = match preset::apply(&state.settings().presets, &params) {
+        Some(params) => params,
+        None => return Err(HttpError::bad_request("Unknown preset")),
+    };
+    let mut image_props = ImageProps::from_params(&params)?;
+    {
+        let mut redis_con = state.redis.get().await?;
+        check_watermark(&state, &mut redis_con, &image_props, signed, None).await?;
+        check_overlay_svg(&mut redis_con, &image_props, signed, None).await?;
+    }
+    let variant = Variant::negotiate(&mut image_props, &params, &headers, &state.cfg);
+    image_props.render_overlay(&source_hash, &BTreeMap::new());
+    let image_id = get_image_id(&format!("proxy-{source_hash}"), &image_props, &variant);
+    let mut response_headers = get_headers(&image_props, &variant, &image_id, &source_hash, false);
+    response_headers.extend(state.response_headers.clone());
+
+    let rendition = Rendition {
+        source: Source::Remote(source_url),
+        hash: None,
+        image_id,
+        image_props,
+        params,
+        response_headers,
+        skip_cache,
+    };
+    render(state, &headers, signed, rendition).await
+}
+
+fn decode_url(source: &str) -> anyhow::Result<Url> {
+    let decoded = URL_SAFE_NO_PAD.decode(source.trim_end_matches('='))?;
+    let url = Url::parse(&String::from_utf8(decoded)?)?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(anyhow!("Unsupported scheme {scheme}")),
+    }
+}
+
+/// The remote image is larger than `proxy_max_size_kb`.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Remote image is too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Download the image for `render`, see `fetch`.
+pub async fn download(state: &AppState, url: &Url) -> Result<Bytes, HttpError> {
+    match fetch(state, url).await {
+        Ok(data) => Ok(data),
+        Err(err) if err.is::<TooLarge>() => Err(HttpError::bad_gateway(&err.to_string())),
+        // Network details and addresses of the remote host are only logged.
+        Err(err) => Err(HttpError::server_error(
+            StatusCode::BAD_GATEWAY,
+            "Remote image could not be fetched",
+            &format!("{url}: {err}"),
+        )),
+    }
+}
+
+/// Download the image, enforcing the size and time limits.
+/// Redirects are not followed, since they could lead to hosts outside the allowlist.
+async fn fetch(state: &AppState, url: &Url) -> anyhow::Result<Bytes> {
+    let max_size = 1024 * state.cfg.proxy_max_size_kb;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(state.cfg.proxy_timeout_secs))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    let mut response = client.get(url.clone()).send().await?.error_for_status()?;
+    if let Some(length) = response.content_length() {
+        if length > max_size as u64 {
+            return Err(TooLarge.into());
+        }
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if data.len() + chunk.len() > max_size {
+            return Err(TooLarge.into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(Bytes::from(data))
+}
@@ -0,0 +1,39 @@
+use crate::{
+    api::image::{resolve_image, serve_image, ImageResponse},
+    auth::Principal,
+    thumbor, AppState, HttpError,
+};
+use axum::{
+    extract::{Extension, State},
+    http::{header::HeaderMap, Method, Uri},
+};
+use std::sync::Arc;
+
+/// Convert an image requested with a Thumbor URL.
+/// Url: /<signature>/.../<image> (see `thumbor` module)
+/// Method: GET
+pub async fn get_thumbor_image(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    uri: Uri,
+) -> Result<ImageResponse, HttpError> {
+    let not_found = || HttpError::not_found(&format!("Path {} was not found", uri.path()));
+    if method != Method::GET {
+        return Err(not_found());
+    }
+
+    let url = match thumbor::parse(
+        uri.path(),
+        state.cfg.thumbor_security_key.as_deref(),
+        state.cfg.thumbor_allow_unsafe,
+    ) {
+        Some(url) => url,
+        None => return Err(not_found()),
+    };
+
+    let hash = resolve_image(&state, url.image).await?;
+    let principal = principal.map(|Extension(principal)| principal);
+    serve_image(state, &headers, principal, url.signed, hash, &url.params).await
+}
@@ -0,0 +1,188 @@
+use crate::{
+    api::image::{serve_image, ImageResponse},
+    auth::Principal,
+    clock::unix_now,
+    hash, metadata,
+    pipeline::{Operation, Pipeline, PIPELINE_PARAM},
+    public_url,
+    signature::{self, EXPIRES_PARAM, SIGNATURE_PARAM},
+    AppState, HttpError,
+};
+use axum::{
+    extract::{Extension, State},
+    http::header::HeaderMap,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+#[derive(Deserialize)]
+pub struct Request {
+    /// Hash of the uploaded image.
+    pub source: String,
+    /// Operations replacing the resize and crop steps, see `pipeline` module.
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    #[serde(default)]
+    pub output: Output,
+}
+
+/// Output options, same as the query parameters of `GET /images/:hash`.
+#[derive(Deserialize, Default)]
+pub struct Output {
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// Quality (1-100) or "auto".
+    pub quality: Option<serde_json::Value>,
+    pub format: Option<String>,
+    #[serde(default)]
+    pub watermark: bool,
+    pub overlay: Option<String>,
+    pub filename: Option<String>,
+    #[serde(default)]
+    pub download: bool,
+    pub page: Option<u16>,
+    /// Respond with the URL of the image instead of the image itself.
+    #[serde(default)]
+    pub url: bool,
+}
+
+#[derive(Serialize)]
+pub struct UrlResponse {
+    /// URL of the image, signed if the signing key is configured.
+    pub url: String,
+    /// Expiration time of the URL, Unix timestamp.
+    pub expires: u64,
+}
+
+/// Transform an image described with JSON.
+/// Url: /transform
+/// Method: POST
+/// Requires authentication, URLs are only given to tokens and the uploader of the image.
+pub async fn transform_image(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    Json(request): Json<Request>,
+) -> Result<Response, HttpError> {
+    let principal = match principal {
+        Some(Extension(principal)) => principal,
+        None => return Err(HttpError::unauthorized("Access token required")),
+    };
+    if !hash::is_valid(&request.source) {
+        return Err(HttpError::bad_request("Invalid source hash"));
+    }
+
+    let mut params = get_params(&request)?;
+    if request.output.url {
+        check_url_access(&state, &principal, &request.source).await?;
+        let expires = unix_now() + state.cfg.transform_url_ttl_secs;
+        params.insert(EXPIRES_PARAM.to_string(), expires.to_string());
+        let url =
+            public_url::base(&state.cfg, &headers) + &get_url(&state, &request.source, params);
+        return Ok(Json(UrlResponse { url, expires }).into_response());
+    }
+
+    // Tokens are trusted like signed URLs, users get what they could request with their JWT.
+    let signed = matches!(principal, Principal::Admin | Principal::AccessToken);
+    let response: ImageResponse = serve_image(
+        state,
+        &headers,
+        Some(principal),
+        signed,
+        request.source,
+        &params,
+    )
+    .await?;
+    Ok(response.into_response())
+}
+
+/// A URL can be shared with anyone, so it is only given to tokens and to the uploader of the image.
+async fn check_url_access(
+    state: &AppState,
+    principal: &Principal,
+    hash: &str,
+) -> Result<(), HttpError> {
+    if let Principal::Admin | Principal::AccessToken = principal {
+        return Ok(());
+    }
+
+    let mut redis_con = state.redis.get().await?;
+    let meta = metadata::get(&mut redis_con, hash).await?;
+    if !principal.can_modify(meta.owner.as_deref()) {
+        return Err(HttpError::forbidden(
+            "Only the uploader or a token can get the URL of this image",
+        ));
+    }
+    Ok(())
+}
+
+/// Convert the request into the query parameters of `GET /images/:hash`.
+fn get_params(request: &Request) -> Result<HashMap<String, String>, HttpError> {
+    let output = &request.output;
+    let mut params = HashMap::new();
+
+    if !request.operations.is_empty() {
+        let pipeline = Pipeline::new(request.operations.clone())
+            .map_err(|err| HttpError::bad_request(&err.to_string()))?;
+        params.insert(PIPELINE_PARAM.to_string(), pipeline.to_string());
+    }
+    if let Some(width) = output.width {
+        params.insert("width".to_string(), width.to_string());
+    }
+    if let Some(height) = output.height {
+        params.insert("height".to_string(), height.to_string());
+    }
+    match &output.quality {
+        Some(serde_json::Value::Number(quality)) => {
+            params.insert("quality".to_string(), quality.to_string());
+        }
+        Some(serde_json::Value::String(quality)) => {
+            params.insert("quality".to_string(), quality.clone());
+        }
+        Some(_) => return Err(HttpError::bad_request("Invalid quality")),
+        None => {}
+    }
+    if let Some(format) = &output.format {
+        params.insert("format".to_string(), format.clone());
+    }
+    if output.watermark {
+        params.insert("watermark".to_string(), "true".to_string());
+    }
+    if let Some(overlay) = &output.overlay {
+        params.insert("overlay".to_string(), overlay.clone());
+    }
+    if let Some(filename) = &output.filename {
+        params.insert("filename".to_string(), filename.clone());
+    }
+    if output.download {
+        params.insert("download".to_string(), "true".to_string());
+    }
+    if let Some(page) = output.page {
+        params.insert("page".to_string(), page.to_string());
+    }
+
+    Ok(params)
+}
+
+/// Build the URL of the image, signed if the signing key is configured.
+pub fn get_url(state: &AppState, hash: &str, mut params: HashMap<String, String>) -> String {
+    let path = format!("/images/{hash}");
+    if let Some(key) = &state.cfg.signing_key {
+        let signature = signature::sign(key, &path, &params);
+        params.insert(SIGNATURE_PARAM.to_string(), signature);
+    }
+    if params.is_empty() {
+        return path;
+    }
+
+    // Sorted, so that the same request gives the same URL.
+    let sorted: BTreeMap<_, _> = params.into_iter().collect();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(sorted)
+        .finish();
+    format!("{path}?{query}")
+}
@@ -1,77 +1,864 @@
-use crate::{AppState, HttpError};
+use crate::{
+    access,
+    api::transform,
+    audit::{self, Actor},
+    auth::{self, Principal},
+    cache,
+    clamav::{self, ScanResult},
+    clock::unix_now,
+    events::{self, EventKind},
+    hash, idempotency, metadata, missing, moderation,
+    preset::PRESET_PARAM,
+    progress::{self, Tracker},
+    public_url, replication, sanitize,
+    signature::SIGNATURE_PARAM,
+    slug, sniff, upload_url, warm, AppState, HttpError,
+};
 use axum::{
     body::Bytes,
-    extract::{Multipart, State},
-    response::{IntoResponse, Json},
+    extract::{multipart::Field, Extension, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::warn;
+use mobc_redis::redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
 };
-use serde::Serialize;
-use sha2::{Digest, Sha256};
-use std::{fs::File, io::Write, sync::Arc};
 
-#[derive(Serialize)]
+/// Size limit of the text fields.
+const TEXT_FIELD_LIMIT: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
 pub struct Response {
     pub hash: String,
+    /// Sensitive metadata tags removed from the original.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_tags: Vec<String>,
+    /// Custom slug of the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    /// URLs of the image with each of the presets, by preset name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variants: BTreeMap<String, String>,
 }
 
 /// Save uploaded image.
 /// Url: /upload
 /// Method: POST
-/// Payload: image - multipart
+/// Payload: image - multipart, slug - optional custom slug,
+/// metadata - optional JSON object with string values, tags - optional list of tags
+/// Parameters: private - require a signed URL or an access token to view the image,
+/// signed upload URLs also have constraints, see `upload_url` module
+/// Headers: Idempotency-Key - retries with the same key get the same response,
+/// Upload-Id - report the progress at /uploads/:id/progress
 pub async fn upload_image(
     State(state): State<Arc<AppState>>,
+    actor: Actor,
+    principal: Option<Extension<Principal>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
     mut multipart: Multipart,
-) -> impl IntoResponse {
-    // Get the first field
-    let field = match multipart.next_field().await {
-        Ok(field) => match field {
-            Some(field) => field,
-            None => return Err(HttpError::bad_request("Missing 'image' field")),
+) -> Result<Json<Response>, HttpError> {
+    // Signed upload URLs limit the upload.
+    let constraints = match params.contains_key(SIGNATURE_PARAM) {
+        true => match &state.cfg.signing_key {
+            Some(key) => match upload_url::verify(key, &params) {
+                Some(constraints) => Some(constraints),
+                None => return Err(HttpError::forbidden("Invalid or expired upload URL")),
+            },
+            None => return Err(HttpError::forbidden("Signed uploads are not enabled")),
         },
-        Err(err) => return Err(HttpError::bad_request(&err.to_string())),
+        false => None,
+    };
+    let size_limit_kb = match constraints.as_ref().and_then(|c| c.max_size_kb) {
+        Some(max_size_kb) => max_size_kb.min(state.cfg.file_size_limit_kb),
+        None => state.cfg.file_size_limit_kb,
+    };
+
+    // Report the progress of uploads with an id.
+    let tracker = match headers.get(progress::HEADER).map(|id| id.to_str()) {
+        Some(Ok(id)) if progress::is_valid_id(id) => {
+            let total = headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            Some(state.progress.start(id, total))
+        }
+        Some(_) => return Err(HttpError::bad_request("Invalid upload id")),
+        None => None,
+    };
+
+    // Read fields
+    let mut image_data = None;
+    let mut filename = None;
+    let mut slug = None;
+    let mut custom = None;
+    let mut tags = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return Err(HttpError::bad_request(&err.to_string())),
+        };
+
+        let name = match field.name() {
+            Some(name) => name.to_string(),
+            None => return Err(HttpError::bad_request("Missing field name")),
+        };
+
+        match name.as_str() {
+            "image" => {
+                check_content_type(&field)?;
+                filename = field.file_name().and_then(metadata::clean_filename);
+                let limit = 1024 * size_limit_kb;
+                let data = read_field(field, &name, limit, tracker.as_ref()).await?;
+                match sniff::mime_type(&data) {
+                    Some(mime_type)
+                        if constraints.as_ref().is_some_and(|c| !c.allows(mime_type)) =>
+                    {
+                        return Err(HttpError::unsupported_media_type(&format!(
+                            "File type {mime_type} is not allowed by the upload URL"
+                        ))
+                        .with_field(&name));
+                    }
+                    Some(_) => {}
+                    None => {
+                        return Err(HttpError::unsupported_media_type("Unsupported file type")
+                            .with_field(&name));
+                    }
+                }
+                image_data = Some(data);
+            }
+            "slug" => match read_text(field, &name, tracker.as_ref()).await {
+                Ok(value) if slug::is_valid(&value) => slug = Some(value),
+                Ok(_) => return Err(HttpError::bad_request("Invalid slug")),
+                Err(err) => return Err(err),
+            },
+            "metadata" => match read_text(field, &name, tracker.as_ref()).await {
+                Ok(value) => match serde_json::from_str::<BTreeMap<String, String>>(&value) {
+                    Ok(value) if metadata::is_valid_custom(&value) => custom = Some(value),
+                    Ok(_) => return Err(HttpError::bad_request("Metadata is too large")),
+                    Err(err) => return Err(HttpError::bad_request(&err.to_string())),
+                },
+                Err(err) => return Err(err),
+            },
+            "tags" => match read_text(field, &name, tracker.as_ref()).await {
+                Ok(value) => {
+                    for tag in value.split(|c: char| c == ',' || c.is_whitespace()) {
+                        if tag.is_empty() {
+                            continue;
+                        }
+                        if !metadata::is_valid_tag(tag) {
+                            return Err(HttpError::bad_request(&format!("Invalid tag {}", tag)));
+                        }
+                        tags.push(tag.to_string());
+                    }
+                }
+                Err(err) => return Err(err),
+            },
+            _ => {
+                return Err(HttpError::bad_request(&format!(
+                    "Unexpected field {} (expected 'image', 'slug', 'metadata' or 'tags')",
+                    name
+                )))
+            }
+        }
+    }
+
+    let data = match image_data {
+        Some(data) => data,
+        None => return Err(HttpError::bad_request("Missing 'image' field")),
+    };
+    if let Some(tracker) = &tracker {
+        tracker.received();
+    }
+
+    let upload = Upload {
+        data,
+        filename,
+        base_url: public_url::base(&state.cfg, &headers),
+        private: params.contains_key("private"),
+        slug,
+        custom,
+        tags,
+        owner: get_owner(principal),
     };
 
-    // Get the name of the first field
-    let name = match field.name() {
-        Some(name) => name.to_string(),
-        None => return Err(HttpError::bad_request("Missing field name")),
+    // Connect only after the body was read, slow clients don't hold connections.
+    let mut redis_con = state.redis.get().await?;
+
+    // Return the stored response for retried requests.
+    let idempotency_key = get_idempotency_key(&headers);
+    let fingerprint = upload.fingerprint();
+    if let Some(stored) =
+        claim_idempotency_key(&mut redis_con, idempotency_key, &fingerprint).await?
+    {
+        return Ok(Json(stored));
+    }
+
+    // Signed upload URLs are single-use.
+    let result = match claim_upload_url(&mut redis_con, constraints.as_ref()).await {
+        Ok(()) => save_upload(&state, &mut redis_con, &actor, upload).await,
+        Err(err) => Err(err),
+    };
+    let response =
+        finish_idempotency_key(&mut redis_con, idempotency_key, &fingerprint, result).await?;
+
+    if let Some(tracker) = tracker {
+        tracker.finish();
+    }
+
+    // Return file hash
+    Ok(Json(response))
+}
+
+#[derive(Deserialize)]
+pub struct Base64Request {
+    /// Base64-encoded image, a data URL is also accepted.
+    pub data: String,
+    /// Original name of the file.
+    pub filename: Option<String>,
+    /// Custom slug of the image.
+    pub slug: Option<String>,
+    /// Custom metadata.
+    pub metadata: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Save image sent as base64 in a JSON body.
+/// Url: /images/base64
+/// Method: POST
+/// Payload: JSON with data - base64-encoded image and optional filename, slug, metadata and tags
+/// Parameters: private - require a signed URL or an access token to view the image
+/// Headers: Idempotency-Key - retries with the same key get the same response
+pub async fn upload_base64(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    principal: Option<Extension<Principal>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<Base64Request>,
+) -> Result<Json<Response>, HttpError> {
+    // Strip the 'data:image/png;base64,' prefix of data URLs.
+    let encoded = match request.data.split_once(";base64,") {
+        Some((prefix, encoded)) if prefix.starts_with("data:") => encoded,
+        _ => request.data.as_str(),
+    };
+
+    // Check the size before decoding.
+    let limit = 1024 * state.cfg.file_size_limit_kb;
+    if encoded.len() / 4 * 3 > limit {
+        return Err(HttpError::payload_too_large(&format!(
+            "Field data is larger than {limit} bytes"
+        ))
+        .with_field("data"));
+    }
+    let data = match STANDARD.decode(encoded) {
+        Ok(data) => Bytes::from(data),
+        Err(err) => return Err(HttpError::bad_request(&err.to_string()).with_field("data")),
     };
+    if sniff::mime_type(&data).is_none() {
+        return Err(HttpError::unsupported_media_type("Unsupported file type").with_field("data"));
+    }
 
-    // Check the name
-    if name != "image" {
-        return Err(HttpError::bad_request(&format!(
-            "Unexpected field {} (expected 'image')",
-            name
+    if let Some(slug) = &request.slug {
+        if !slug::is_valid(slug) {
+            return Err(HttpError::bad_request("Invalid slug").with_field("slug"));
+        }
+    }
+    if let Some(custom) = &request.metadata {
+        if !metadata::is_valid_custom(custom) {
+            return Err(HttpError::bad_request("Metadata is too large").with_field("metadata"));
+        }
+    }
+    if let Some(tag) = request.tags.iter().find(|tag| !metadata::is_valid_tag(tag)) {
+        return Err(HttpError::bad_request(&format!("Invalid tag {}", tag)).with_field("tags"));
+    }
+
+    let upload = Upload {
+        data,
+        filename: request
+            .filename
+            .as_deref()
+            .and_then(metadata::clean_filename),
+        base_url: public_url::base(&state.cfg, &headers),
+        private: params.contains_key("private"),
+        slug: request.slug,
+        custom: request.metadata,
+        tags: request.tags,
+        owner: get_owner(principal),
+    };
+
+    let mut redis_con = state.redis.get().await?;
+
+    // Return the stored response for retried requests.
+    let idempotency_key = get_idempotency_key(&headers);
+    let fingerprint = upload.fingerprint();
+    if let Some(stored) =
+        claim_idempotency_key(&mut redis_con, idempotency_key, &fingerprint).await?
+    {
+        return Ok(Json(stored));
+    }
+
+    let result = save_upload(&state, &mut redis_con, &actor, upload).await;
+    let response =
+        finish_idempotency_key(&mut redis_con, idempotency_key, &fingerprint, result).await?;
+
+    Ok(Json(response))
+}
+
+/// Replace the image behind the slug, so that its URL shows the new content.
+/// Metadata, tags and privacy of the previous image carry over to the new one,
+/// cached versions of the previous image are removed. The previous original is kept.
+/// Url: /images/:slug
+/// Method: PUT
+/// Payload: the image file
+pub async fn replace_image(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    principal: Option<Extension<Principal>>,
+    headers: HeaderMap,
+    Path(slug): Path<String>,
+    data: Bytes,
+) -> Result<Json<Response>, HttpError> {
+    if principal.is_none() {
+        return Err(HttpError::unauthorized("Authentication required"));
+    }
+    if !slug::is_valid(&slug) {
+        return Err(HttpError::bad_request("Invalid slug"));
+    }
+    if sniff::mime_type(&data).is_none() {
+        return Err(HttpError::unsupported_media_type("Unsupported file type"));
+    }
+
+    // Concurrent replacements fail here instead of after saving their uploads.
+    let mut redis_con = state.redis.get().await?;
+    let lock = match slug::lock(&mut redis_con, &slug, state.cfg.upload_timeout_secs).await? {
+        Some(lock) => lock,
+        None => {
+            return Err(HttpError::conflict(&format!(
+                "Slug {} is being replaced by another request",
+                slug
+            )))
+        }
+    };
+    let replaced = replace_locked(
+        &state,
+        &mut redis_con,
+        &actor,
+        principal,
+        &headers,
+        &slug,
+        data,
+    )
+    .await;
+    slug::unlock(&mut redis_con, lock).await?;
+    let mut response = replaced?;
+
+    response.slug = Some(slug);
+    Ok(Json(response))
+}
+
+/// Replace the image behind the locked slug.
+async fn replace_locked(
+    state: &Arc<AppState>,
+    redis_con: &mut Connection,
+    actor: &Actor,
+    principal: Option<Extension<Principal>>,
+    headers: &HeaderMap,
+    slug: &str,
+    data: Bytes,
+) -> Result<Response, HttpError> {
+    let previous = slug::resolve(redis_con, slug).await?;
+    let meta = match &previous {
+        Some(previous) => {
+            let meta = metadata::get(redis_con, previous).await?;
+            // Same rule as for deleting the previous image.
+            auth::check_modify(
+                principal.as_ref().map(|Extension(principal)| principal),
+                &meta,
+            )?;
+            meta
+        }
+        None => Default::default(),
+    };
+
+    let upload = Upload {
+        data,
+        filename: None,
+        base_url: public_url::base(&state.cfg, headers),
+        private: meta.private,
+        slug: None,
+        custom: Some(meta.custom).filter(|custom| !custom.is_empty()),
+        tags: meta.tags,
+        owner: get_owner(principal),
+    };
+    let response = save_upload(state, redis_con, actor, upload).await?;
+
+    audit::record(
+        redis_con,
+        state.cfg.audit_max_entries,
+        actor,
+        "replace",
+        &response.hash,
+    )
+    .await?;
+    // Still checked, in case the lock expired or a new upload claimed a free slug.
+    if !slug::replace(redis_con, slug, previous.as_deref(), &response.hash).await? {
+        return Err(HttpError::conflict(&format!(
+            "Slug {} was changed by another request",
+            slug
         )));
     }
 
-    // Get field data
-    let data = match field.bytes().await {
-        Ok(data) => data,
-        Err(err) => return Err(HttpError::bad_request(&err.to_string())),
+    // The previous image is no longer reachable by the slug.
+    if let Some(previous) = previous.filter(|previous| *previous != response.hash) {
+        cache::purge(redis_con, &previous, state.cache_offload.as_ref()).await?;
+        events::publish(&state.events, EventKind::CachePurge, &previous);
+    }
+
+    Ok(response)
+}
+
+/// Validated upload.
+struct Upload {
+    data: Bytes,
+    /// Cleaned up name of the uploaded file.
+    filename: Option<String>,
+    /// Base of the URLs in the response, see `public_url` module.
+    base_url: String,
+    private: bool,
+    slug: Option<String>,
+    custom: Option<BTreeMap<String, String>>,
+    tags: Vec<String>,
+    /// User id of the uploader, see `Principal::can_modify`.
+    owner: Option<String>,
+}
+
+impl Upload {
+    /// Hash of the file and the fields, retries with the same idempotency key must match it.
+    fn fingerprint(&self) -> String {
+        let fields = serde_json::json!([
+            hash::compute(&self.data),
+            self.filename,
+            self.private,
+            self.slug,
+            self.custom,
+            self.tags,
+        ]);
+        hash::compute(fields.to_string().as_bytes())
+    }
+}
+
+/// User id of the uploader authenticated with a JWT.
+fn get_owner(principal: Option<Extension<Principal>>) -> Option<String> {
+    principal.and_then(|Extension(principal)| principal.subject().map(|sub| sub.to_string()))
+}
+
+/// Value of the 'Idempotency-Key' header.
+fn get_idempotency_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(idempotency::HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Claim the idempotency key before the upload is processed.
+/// Returns the stored response if the request was already processed.
+async fn claim_idempotency_key(
+    redis_con: &mut Connection,
+    idempotency_key: Option<&str>,
+    fingerprint: &str,
+) -> Result<Option<Response>, HttpError> {
+    let key = match idempotency_key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    match idempotency::claim(redis_con, key, fingerprint).await? {
+        idempotency::Claim::New => Ok(None),
+        idempotency::Claim::Done(stored) => Ok(Some(serde_json::from_str(&stored)?)),
+        idempotency::Claim::InProgress => Err(HttpError::conflict(
+            "Request with this Idempotency-Key is being processed",
+        )),
+        idempotency::Claim::Mismatch => Err(HttpError::unprocessable_entity(
+            "Idempotency-Key was used with another request",
+        )),
+    }
+}
+
+/// Store the response for retries, or release the key if the upload failed.
+async fn finish_idempotency_key(
+    redis_con: &mut Connection,
+    idempotency_key: Option<&str>,
+    fingerprint: &str,
+    result: Result<Response, HttpError>,
+) -> Result<Response, HttpError> {
+    let key = match idempotency_key {
+        Some(key) => key,
+        None => return result,
+    };
+    match &result {
+        Ok(response) => {
+            let stored = serde_json::to_string(response)?;
+            idempotency::save(redis_con, key, fingerprint, &stored).await?;
+        }
+        Err(_) => idempotency::release(redis_con, key).await?,
+    }
+    result
+}
+
+/// Signed upload URLs are single-use.
+async fn claim_upload_url(
+    redis_con: &mut Connection,
+    constraints: Option<&upload_url::Constraints>,
+) -> Result<(), HttpError> {
+    match constraints {
+        Some(constraints) if !upload_url::claim(redis_con, constraints).await? => {
+            Err(HttpError::conflict("Upload URL was already used"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Scan, clean up and save the uploaded image along with its metadata.
+async fn save_upload(
+    state: &Arc<AppState>,
+    redis_con: &mut Connection,
+    actor: &Actor,
+    upload: Upload,
+) -> Result<Response, HttpError> {
+    let hash = hash::compute(&upload.data);
+
+    // Claim the slug before any side effects, so that a taken slug leaves nothing behind.
+    let claim = match &upload.slug {
+        Some(slug) => Some(slug::claim(redis_con, slug, &hash).await?),
+        None => None,
     };
+    if let (Some(slug), Some(slug::Claim::Taken)) = (&upload.slug, claim) {
+        return Err(HttpError::conflict(&format!(
+            "Slug {} is already taken",
+            slug
+        )));
+    }
+
+    let slug = upload.slug.clone();
+    let result = store_upload(state, redis_con, actor, upload, hash.clone()).await;
+    // Free the slug claimed by a failed upload.
+    if let (Err(_), Some(slug), Some(slug::Claim::New)) = (&result, &slug, claim) {
+        slug::release(redis_con, slug, &hash).await?;
+    }
+    result
+}
+
+async fn store_upload(
+    state: &Arc<AppState>,
+    redis_con: &mut Connection,
+    actor: &Actor,
+    upload: Upload,
+    hash: String,
+) -> Result<Response, HttpError> {
+    let mut data = upload.data;
+    // The upload as received, `data` could be re-encoded below.
+    let raw = data.clone();
+    let content_type = sniff::mime_type(&data);
 
-    // Calculate file path
-    let hash = get_file_hash(&data);
     let filepath = state.get_file_path(&hash);
 
+    // Scan new files for viruses before saving.
+    let is_new = !filepath.exists();
+    if let (true, Some(address)) = (is_new, &state.cfg.clamav_address) {
+        match clamav::scan(address, &data).await {
+            Ok(ScanResult::Clean) => {}
+            Ok(ScanResult::Infected(signature)) => {
+                warn!("Rejected upload {hash}: {signature}");
+                return Err(HttpError::unprocessable_entity(&format!(
+                    "File is infected: {signature}"
+                )));
+            }
+            Err(err) if state.cfg.clamav_fail_open => {
+                warn!("Virus scan failed, accepting {hash} anyway: {err}");
+            }
+            Err(err) => {
+                warn!("Virus scan failed: {err}");
+                return Err(HttpError::service_unavailable("Virus scan is unavailable"));
+            }
+        }
+    }
+
+    // Remove location and serial numbers from new originals.
+    let mut removed_tags = Vec::new();
+    if is_new && state.cfg.strip_sensitive_metadata {
+        let tags = sanitize::sensitive_tags(&data);
+        if !tags.is_empty() {
+            let input = data.clone();
+            let stripped = tokio::task::spawn_blocking(move || sanitize::strip_metadata(&input))
+                .await
+                .map_err(anyhow::Error::from)?
+                .map_err(|err| {
+                    HttpError::server_error(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "Cannot remove sensitive metadata from the image",
+                        &format!("{hash}: {err}"),
+                    )
+                })?;
+            data = Bytes::from(stripped);
+            removed_tags = tags;
+        }
+    }
+
+    // Re-encode new originals into the canonical format.
+    if let (true, Some(format)) = (is_new, state.cfg.canonical_format) {
+        let input = data.clone();
+        data = tokio::task::spawn_blocking(move || sanitize::canonicalize(&input, format))
+            .await
+            .map_err(anyhow::Error::from)?
+            .map(Bytes::from)
+            .map_err(|err| HttpError::unprocessable_image(&format!("{hash}: {err}")))?;
+
+        // Kept only for accepted uploads, with the bytes the hash was computed from.
+        if state.cfg.keep_raw_originals {
+            tokio::fs::write(state.get_raw_file_path(&hash), &raw).await?;
+        }
+    }
+
+    // The upload is not saved if it can't be audited.
+    audit::record(
+        redis_con,
+        state.cfg.audit_max_entries,
+        actor,
+        "upload",
+        &hash,
+    )
+    .await?;
+
+    // Hold new images back until moderated, before the file can be served.
+    if is_new && state.cfg.moderation_url.is_some() {
+        moderation::enqueue(redis_con, &hash).await?;
+    }
+
     // Save file
-    if !filepath.exists() {
-        let mut f = match File::create(filepath) {
-            Ok(f) => f,
-            Err(err) => return Err(HttpError::internal_server_error(&err.to_string())),
-        };
+    if is_new {
+        // Stripped or re-encoded files don't match their hash, the origin fallback
+        // checks them against the checksum instead.
+        let checksum = hash::compute(&data);
+        if checksum != hash {
+            metadata::set_checksum(redis_con, &hash, &checksum).await?;
+        }
+        tokio::fs::write(&filepath, &data).await?;
+    }
+
+    if is_new {
+        replication::enqueue(state, &hash);
+        access::record_upload(redis_con, &hash).await?;
+        missing::clear(redis_con, &hash).await?;
+
+        // The image could have been deleted earlier.
+        metadata::set_deleted_at(redis_con, &hash, None).await?;
+    }
 
-        if let Err(err) = f.write_all(&data) {
-            return Err(HttpError::internal_server_error(&err.to_string()));
+    // Always written, images without the visibility are treated as private.
+    // An image is never made public again by a subsequent upload.
+    metadata::set_visibility(redis_con, &hash, upload.private).await?;
+
+    // Generate standard renditions in the background.
+    if is_new && state.cfg.warm_presets.is_some() {
+        tokio::spawn(warm::generate(state.clone(), hash.clone(), data.clone()));
+    }
+
+    // Send new images to moderation.
+    if let (true, Some(url)) = (is_new, &state.cfg.moderation_url) {
+        tokio::spawn(moderation::moderate(
+            state.clone(),
+            url.clone(),
+            hash.clone(),
+            data,
+        ));
+    }
+
+    // Remember the original name and type, the file itself could be re-encoded.
+    let content_type = content_type.unwrap_or("application/octet-stream");
+    metadata::set_original(redis_con, &hash, upload.filename.as_deref(), content_type).await?;
+    if let Some(owner) = &upload.owner {
+        metadata::set_owner(redis_con, &hash, owner).await?;
+    }
+
+    // Save custom metadata and tags.
+    if let Some(custom) = &upload.custom {
+        metadata::set_custom(redis_con, &hash, custom).await?;
+    }
+    if !upload.tags.is_empty() {
+        metadata::add_tags(redis_con, &hash, &upload.tags).await?;
+    }
+
+    events::publish(&state.events, EventKind::Upload, &hash);
+
+    let variants = get_variant_urls(state, &hash, &upload.base_url);
+    Ok(Response {
+        hash,
+        removed_tags,
+        slug: upload.slug,
+        variants,
+    })
+}
+
+/// Build URLs of the image with each of the presets.
+/// They are signed if the signing key is set, so that they work for private images too.
+fn get_variant_urls(state: &AppState, hash: &str, base_url: &str) -> BTreeMap<String, String> {
+    state
+        .settings()
+        .presets
+        .keys()
+        .map(|name| {
+            let params = HashMap::from([(PRESET_PARAM.to_string(), name.clone())]);
+            let url = transform::get_url(state, hash, params);
+            (name.clone(), format!("{base_url}{url}"))
+        })
+        .collect()
+}
+
+/// Get the progress of the upload.
+/// Url: /uploads/:id/progress
+/// Method: GET
+pub async fn get_upload_progress(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<progress::Entry>, HttpError> {
+    match state.progress.get(&id) {
+        Some(entry) => Ok(Json(entry)),
+        None => Err(HttpError::not_found("Upload not found")),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SignRequest {
+    /// Size limit of the image in kilobytes.
+    pub max_size_kb: Option<usize>,
+    /// Allowed MIME types of the image.
+    pub types: Option<Vec<String>>,
+    /// Mark the uploaded image as private.
+    #[serde(default)]
+    pub private: bool,
+    /// Lifetime of the URL in seconds.
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SignResponse {
+    pub url: String,
+    /// Unix timestamp.
+    pub expires: u64,
+}
+
+/// Create a single-use URL for uploads without an access token.
+/// Url: /uploads/sign
+/// Method: POST
+/// Requires authentication.
+pub async fn sign_upload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    Json(request): Json<SignRequest>,
+) -> Result<Json<SignResponse>, HttpError> {
+    if principal.is_none() {
+        return Err(HttpError::unauthorized("Access token required"));
+    }
+    let key = match &state.cfg.signing_key {
+        Some(key) => key,
+        None => return Err(HttpError::not_found("Signed uploads are not enabled")),
+    };
+    if let Some(types) = &request.types {
+        if let Some(unknown) = types.iter().find(|t| !upload_url::is_known_type(t)) {
+            return Err(HttpError::bad_request(&format!(
+                "Unsupported type {unknown}"
+            )));
         }
     }
 
-    // Return file hash
-    Ok(Json(Response { hash }))
+    let ttl = match request.expires_in {
+        Some(expires_in) => expires_in.min(state.cfg.upload_url_ttl_secs),
+        None => state.cfg.upload_url_ttl_secs,
+    };
+    let expires = unix_now() + ttl;
+    let params = upload_url::params(
+        key,
+        expires,
+        request.max_size_kb,
+        request.types.as_deref(),
+        request.private,
+    );
+
+    // Sorted, so that the URL is easy to read.
+    let sorted: BTreeMap<_, _> = params.into_iter().collect();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(sorted)
+        .finish();
+    Ok(Json(SignResponse {
+        url: format!(
+            "{}{}?{}",
+            public_url::base(&state.cfg, &headers),
+            upload_url::PATH,
+            query
+        ),
+        expires,
+    }))
+}
+
+/// Check the declared content type of the image field.
+fn check_content_type(field: &Field<'_>) -> Result<(), HttpError> {
+    match field.content_type() {
+        None => Ok(()),
+        Some(content_type)
+            if content_type.starts_with("image/")
+                || content_type == "application/pdf"
+                || content_type == "application/octet-stream" =>
+        {
+            Ok(())
+        }
+        Some(content_type) => Err(HttpError::unsupported_media_type(&format!(
+            "Unsupported content type {content_type}"
+        ))
+        .with_field("image")),
+    }
+}
+
+/// Read the field in chunks, stopping as soon as it exceeds the limit.
+async fn read_field(
+    mut field: Field<'_>,
+    name: &str,
+    limit: usize,
+    tracker: Option<&Tracker<'_>>,
+) -> Result<Bytes, HttpError> {
+    let mut data = Vec::new();
+    loop {
+        match field.chunk().await {
+            Ok(Some(chunk)) => {
+                if data.len() + chunk.len() > limit {
+                    return Err(HttpError::payload_too_large(&format!(
+                        "Field {name} is larger than {limit} bytes"
+                    ))
+                    .with_field(name));
+                }
+                data.extend_from_slice(&chunk);
+                if let Some(tracker) = tracker {
+                    tracker.add(chunk.len());
+                }
+            }
+            Ok(None) => return Ok(Bytes::from(data)),
+            Err(err) => return Err(HttpError::bad_request(&err.to_string()).with_field(name)),
+        }
+    }
 }
 
-fn get_file_hash(data: &Bytes) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    format!("{:x}", hasher.finalize())
+async fn read_text(
+    field: Field<'_>,
+    name: &str,
+    tracker: Option<&Tracker<'_>>,
+) -> Result<String, HttpError> {
+    let data = read_field(field, name, TEXT_FIELD_LIMIT, tracker).await?;
+    String::from_utf8(data.to_vec())
+        .map_err(|_| HttpError::bad_request("Field is not valid UTF-8").with_field(name))
 }
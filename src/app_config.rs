@@ -1,18 +1,152 @@
+use crate::{encoder::EncoderOptions, pipeline::Kernel, policy::Policies, preset::Presets};
 use config::Config;
+use std::{collections::BTreeMap, fmt};
+
+/// Format in which uploaded originals are stored.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CanonicalFormat {
+    /// Lossless WebP.
+    Webp,
+    /// JPEG, quality 95.
+    Jpeg,
+}
+
+/// Handling of repeated query parameters of image requests.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateParams {
+    /// Respond with 400.
+    Reject,
+    /// Use the first value.
+    First,
+    /// Use the last value.
+    Last,
+}
+
+/// Header with the client address set by trusted proxies.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// 'X-Forwarded-For: <client>, <proxy>'.
+    XForwardedFor,
+    /// 'Forwarded: for=<client>, for=<proxy>' (RFC 7239).
+    Forwarded,
+}
+
+/// How the watermark is composited over the image.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatermarkBlend {
+    /// Lightens the image, dark parts of the watermark are invisible.
+    Screen,
+    /// Places the watermark on top, respecting its alpha channel.
+    Over,
+}
+
+/// Tradeoff between the output quality and the CPU time.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Speed {
+    /// Configured encoder options and the Lanczos kernel.
+    Balanced,
+    /// Lower encoder efforts and the linear kernel, see `EncoderOptions::fast`.
+    Fast,
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Balanced => write!(f, "balanced"),
+            Speed::Fast => write!(f, "fast"),
+        }
+    }
+}
+
+impl Speed {
+    /// Encoder options and the resize kernel of the profile.
+    /// The configured kernel replaces the one of the profile.
+    pub fn profile(self, cfg: &AppConfig) -> (EncoderOptions, Kernel) {
+        let (encoders, kernel) = match self {
+            Speed::Balanced => (cfg.encoders.clone(), Kernel::Lanczos3),
+            Speed::Fast => (cfg.encoders.fast(), Kernel::Linear),
+        };
+        (encoders, cfg.resize_kernel.unwrap_or(kernel))
+    }
+}
 
 /// Server configuration.
-#[derive(Debug, Clone, serde::Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, serde::Deserialize, PartialEq)]
 pub struct AppConfig {
     // Directory where uploaded files will be saved (default: 'uploads')
     pub upload_dir: String,
     /// File size limit in kilobytes (default: 4096)
+    /// Applies to the request body of uploads.
     pub file_size_limit_kb: usize,
+    /// Request body limit of other endpoints in kilobytes (default: 64)
+    pub body_limit_kb: usize,
     /// Server port (default: 3000)
     pub port: u16,
+    /// Accept HTTP/2 without TLS (h2c with prior knowledge)? (default: true)
+    pub http2_enabled: bool,
+    /// Maximum number of concurrent requests on one HTTP/2 connection (default: 200)
+    pub http2_max_concurrent_streams: u32,
+    /// Interval of HTTP/2 keep-alive pings in seconds (default: no pings)
+    pub http2_keep_alive_interval_secs: Option<u64>,
+    /// How long to wait for the answer to a keep-alive ping before closing
+    /// the HTTP/2 connection, in seconds (default: 20)
+    pub http2_keep_alive_timeout_secs: u64,
+    /// Keep HTTP/1.1 connections open between requests? (default: true)
+    pub keep_alive: bool,
+    /// Time limit for reading request headers of HTTP/1.1 in seconds (default: 30)
+    pub header_read_timeout_secs: u64,
+    /// Size limit of request headers in kilobytes, at least 8 (default: hyper's limits)
+    pub max_header_size_kb: Option<usize>,
+    /// Interval of TCP keep-alive probes in seconds (default: disabled)
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Public URL of the server, used as the base of URLs in responses (example: "https://img.example.com").
+    pub public_base_url: Option<String>,
+    /// Take the base of URLs from 'X-Forwarded-Proto' and 'X-Forwarded-Host' headers
+    /// if the public base URL is not set? (default: false)
+    /// Enable only behind a reverse proxy that sets them.
+    pub trust_forwarded_host: bool,
+    /// Addresses or networks of reverse proxies, separate values with spaces (example: "10.0.0.0/8 ::1").
+    /// The client address of their requests is taken from `forwarded_header`.
+    pub trusted_proxies: Option<Vec<String>>,
+    /// Header with the client address set by trusted proxies: "x-forwarded-for" or "forwarded"
+    /// (default: "x-forwarded-for"). Only this header is read, the proxies must set or clear it.
+    pub forwarded_header: ForwardedHeader,
     /// Redis URL (default: "redis://127.0.0.1/")
     pub redis_url: String,
     /// Watermark file path (example: '/app/watermark.png')
     pub watermark_file_path: Option<String>,
+    /// Skip the watermark and the overlay if the width or height of the output
+    /// is smaller than this (example: 200).
+    pub watermark_min_size: Option<u16>,
+    /// Hashes of uploaded images that can be used as watermarks without a signed URL
+    /// or a bearer token (`watermark_hash` parameter), separate values with spaces.
+    pub watermark_hashes: Option<Vec<String>>,
+    /// Width of the watermark in percent of the output width (example: 20).
+    /// If not set, the watermark keeps its size.
+    pub watermark_width_percent: Option<u8>,
+    /// How the watermark is composited: 'screen' or 'over' (default: 'screen')
+    pub watermark_blend: WatermarkBlend,
+    /// Opacity of the watermark, multiplies its alpha channel (from 0 to 1, default: 1)
+    pub watermark_opacity: f64,
+    /// Space between repeated watermarks of `watermark_mode=tile` in pixels (default: 100)
+    pub watermark_tile_spacing: u16,
+    /// Angle of repeated watermarks in degrees, clockwise (default: -30)
+    pub watermark_tile_angle: f64,
+    /// Opacity of repeated watermarks, applied on top of `watermark_opacity`
+    /// (from 0 to 1, default: 0.3)
+    pub watermark_tile_opacity: f64,
+    /// Step of the block brightness carrying the invisible watermark with the `licensee`,
+    /// higher values survive stronger compression but are more visible (from 2 to 32, default: 8)
+    pub invisible_watermark_strength: f64,
+    /// Pango font description of the overlay text (default: 'sans 12')
+    pub overlay_font: String,
+    /// Font file to be loaded for the overlay (example: '/app/NotoSans-Regular.ttf')
+    pub overlay_font_file: Option<String>,
     /// List of addresses to be specified in the 'Access-Control-Allow-Origin' header.
     /// Separate addresses with spaces.
     /// 
@@ -20,20 +154,278 @@ pub struct AppConfig {
     ///
     /// If no addresses are given, the header value will be "*".
     pub allowed_origins: Option<Vec<String>>,
+    /// List of HTTP methods allowed by CORS (default: "GET POST DELETE OPTIONS")
+    pub cors_allowed_methods: Vec<String>,
+    /// List of request headers allowed by CORS.
+    /// If no headers are given, all headers are allowed.
+    pub cors_allowed_headers: Option<Vec<String>>,
+    /// List of response headers exposed to scripts.
+    pub cors_exposed_headers: Option<Vec<String>>,
+    /// Allow requests with credentials (default: false).
+    /// Requires allowed origins and allowed headers to be set.
+    pub cors_allow_credentials: bool,
+    /// How long the preflight response can be cached, in seconds (default: 600)
+    pub cors_max_age_secs: u64,
     /// Print debug information about requests?
     /// Adds 'TraceLayer' to the application.
-    pub enable_tracing: bool
+    pub enable_tracing: bool,
+    /// Secret key used to sign URLs.
+    /// Signed URLs bypass hotlink protection.
+    pub signing_key: Option<String>,
+    /// List of hosts allowed to embed images (hotlink protection).
+    /// Separate hosts with spaces, wildcards are supported.
+    ///
+    /// Example: "example.com *.example.com"
+    ///
+    /// If no hosts are given, hotlink protection is disabled.
+    pub hotlink_allowed_hosts: Option<Vec<String>>,
+    /// Allow requests without 'Origin' and 'Referer' headers
+    /// when hotlink protection is enabled (default: true)
+    pub hotlink_allow_empty_referer: bool,
+    /// List of bearer tokens granting access to private images.
+    /// Separate tokens with spaces.
+    pub access_tokens: Option<Vec<String>>,
+    /// List of bearer tokens granting access to admin endpoints.
+    /// Admin tokens also grant access to private images.
+    /// Separate tokens with spaces.
+    pub admin_tokens: Option<Vec<String>>,
+    /// URL of the JSON Web Key Set used to validate JWTs.
+    /// If not set, only access tokens are accepted.
+    pub jwks_url: Option<String>,
+    /// Expected 'iss' claim of JWTs.
+    pub jwt_issuer: Option<String>,
+    /// Expected 'aud' claim of JWTs.
+    pub jwt_audience: Option<String>,
+    /// How long to cache the key set, in seconds (default: 3600)
+    pub jwks_cache_secs: u64,
+    /// URL of the moderation endpoint.
+    /// New uploads are sent there and stay pending until the verdict is received.
+    pub moderation_url: Option<String>,
+    /// Send a downscaled copy of the image to the moderation endpoint
+    /// (size of the bounding square in pixels).
+    pub moderation_image_size: Option<u16>,
+    /// Address of clamd (example: '127.0.0.1:3310').
+    /// If set, all uploads are scanned for viruses before saving.
+    pub clamav_address: Option<String>,
+    /// Accept uploads if clamd is unavailable? (default: false)
+    pub clamav_fail_open: bool,
+    /// Remove GPS and serial number tags from uploaded originals? (default: false)
+    /// Such images are re-encoded with orientation applied.
+    pub strip_sensitive_metadata: bool,
+    /// Re-encode uploaded originals into this format ('webp' or 'jpeg').
+    /// Orientation is applied and metadata is removed.
+    pub canonical_format: Option<CanonicalFormat>,
+    /// Keep the raw upload in the 'raw' subdirectory when canonicalizing? (default: false)
+    pub keep_raw_originals: bool,
+    /// How long deleted images can be restored, in hours (default: 168)
+    pub trash_retention_hours: u64,
+    /// Approximate maximum number of audit log entries (not trimmed by default)
+    pub audit_max_entries: Option<usize>,
+    /// URL of the secondary storage for originals
+    /// (example: 's3://bucket/prefix?region=eu-central-1' or 'file:///mnt/replica').
+    /// New uploads are copied there in the background.
+    pub replica_url: Option<String>,
+    /// Copy originals missing in the replica on startup? (default: false)
+    pub replica_backfill: bool,
+    /// URL of the storage with originals missing on the local disk
+    /// (example: 's3://bucket/prefix' or 'https://images.example.com/originals').
+    /// Fetched originals are saved locally.
+    pub origin_url: Option<String>,
+    /// URL of the storage for processed images shared by all instances
+    /// (example: 's3://bucket/cache?region=eu-central-1').
+    /// Redis keeps only pointers to them and the render locks.
+    pub cache_storage_url: Option<String>,
+    /// List of hosts allowed in the proxy mode ('/proxy/<base64url-encoded URL>').
+    /// Separate hosts with spaces, wildcards are supported.
+    ///
+    /// If no hosts are given, the proxy mode is disabled.
+    pub proxy_allowed_hosts: Option<Vec<String>>,
+    /// Size limit for remote images in kilobytes (default: 10240)
+    pub proxy_max_size_kb: usize,
+    /// Time limit for downloading remote images in seconds (default: 10)
+    pub proxy_timeout_secs: u64,
+    /// Time limit for uploads in seconds (default: 60)
+    pub upload_timeout_secs: u64,
+    /// Lifetime of signed upload URLs in seconds (default: 600)
+    pub upload_url_ttl_secs: u64,
+    /// Lifetime of URLs returned by `POST /transform` in seconds (default: 3600)
+    pub transform_url_ttl_secs: u64,
+    /// Time limit for image requests (including processing) in seconds (default: 30)
+    pub transform_timeout_secs: u64,
+    /// Maximum number of images processed at once (default: number of CPUs)
+    pub max_concurrent_transforms: Option<usize>,
+    /// Processing budget of image requests in milliseconds, including the wait
+    /// for a processing slot (example: 5000).
+    /// Requests over the budget are aborted with 503 between the processing stages.
+    pub processing_budget_ms: Option<u64>,
+    /// Log and count transformations slower than this, in milliseconds (example: 2000)
+    pub slow_transform_ms: Option<u64>,
+    /// Log and count transformations with output larger than this, in kilobytes (example: 1024)
+    pub large_output_kb: Option<usize>,
+    /// Maximum number of requests waiting for processing (default: 100).
+    /// Other requests are rejected with 503.
+    pub transform_queue_size: usize,
+    /// Return details of server-side errors to clients (default: false).
+    /// Useful for development, details may contain file paths and other internals.
+    pub verbose_errors: bool,
+    /// How long to remember that an image is missing, in seconds (default: 60).
+    /// Set to 0 to disable.
+    pub missing_cache_secs: u64,
+    /// How long concurrent requests for the same missing image wait for the one rendering it,
+    /// in milliseconds (default: 10000). Set to 0 to disable.
+    pub render_lock_ms: u64,
+    /// Lifetime of processed images in the cache in seconds (default: no limit)
+    pub cache_ttl_secs: Option<u64>,
+    /// Maximum number of processed images in the cache (default: no limit).
+    /// The least recently used ones are evicted every minute.
+    pub cache_max_entries: Option<usize>,
+    /// Directory for processed images larger than `disk_cache_threshold_kb` (default: none).
+    /// Redis keeps only pointers to them.
+    pub disk_cache_dir: Option<String>,
+    /// Size from which processed images are kept on the disk, in kilobytes (default: 1024)
+    pub disk_cache_threshold_kb: usize,
+    /// Size limit of the disk cache in megabytes (default: 1024).
+    /// The least recently used images over the limit are evicted every minute.
+    pub disk_cache_max_size_mb: u64,
+    /// Quality of images requested with 'Save-Data: on' (default: 50).
+    /// Lower explicit quality is kept.
+    pub save_data_quality: u8,
+    /// Maximum width and height of images requested with 'Save-Data: on' (default: 640)
+    pub save_data_max_size: u16,
+    /// Serve JPEG instead of WebP and AVIF to clients that can't show them? (default: false)
+    /// Requested and default formats are replaced, see `variant` module.
+    pub format_fallback: bool,
+    /// Parts of 'User-Agent' headers of clients without WebP and AVIF support, used with
+    /// `format_fallback`, separate values with spaces (example: "Outlook Thunderbird").
+    pub format_fallback_user_agents: Option<Vec<String>>,
+    /// Round requested width and height up to a multiple of this value (example: 100).
+    /// Limits the number of cached variants of each image.
+    pub size_step: Option<u16>,
+    /// Maximum width and height of images (example: 2560).
+    /// Larger sizes are reduced to this value.
+    pub max_size: Option<u16>,
+    /// List of allowed quality values, separate values with spaces (example: "50 75 90").
+    /// Requested quality is rounded up to the nearest allowed value.
+    pub quality_steps: Option<Vec<u8>>,
+    /// Speed profile: 'balanced' or 'fast' (default: 'balanced').
+    pub speed: Speed,
+    /// Default interpolation kernel of resizing: 'nearest', 'linear', 'cubic' or 'lanczos3'
+    /// (default: 'lanczos3', 'linear' with the fast speed profile).
+    pub resize_kernel: Option<Kernel>,
+    /// Allow width, height, ar, quality, overlay, pipeline, watermark_hash and overlay_svg parameters
+    /// in unsigned URLs? (default: true)
+    /// If disabled, unsigned requests can use only presets.
+    pub allow_arbitrary_params: bool,
+    /// Handling of repeated query parameters of image requests: 'reject', 'first' or 'last' (default: 'reject')
+    pub duplicate_params: DuplicateParams,
+    /// Maximum mean colour difference (CIEDE2000) for `quality=auto` (default: 1.5).
+    /// Lower values give better quality and bigger files.
+    pub auto_quality_target: f64,
+    /// Security key of Thumbor URLs ('/<signature>/300x200/smart/<image>').
+    /// Enables the Thumbor-compatible URLs.
+    pub thumbor_security_key: Option<String>,
+    /// Allow unsigned Thumbor URLs ('/unsafe/300x200/smart/<image>')? (default: false)
+    /// Enables the Thumbor-compatible URLs.
+    pub thumbor_allow_unsafe: bool,
+    /// Hex-encoded key of imgproxy URLs ('/imgproxy/<signature>/rs:fill:300:200/plain/<image>').
+    /// Enables the imgproxy-compatible URLs, requires the salt.
+    pub imgproxy_key: Option<String>,
+    /// Hex-encoded salt of imgproxy URLs.
+    pub imgproxy_salt: Option<String>,
+    /// Allow unsigned imgproxy URLs ('/imgproxy/insecure/...')? (default: false)
+    /// Enables the imgproxy-compatible URLs.
+    pub imgproxy_allow_unsafe: bool,
+    /// Formats that libvips must support for the server to be ready, separate values with spaces
+    /// (default: output formats of the build). Known formats: jpeg, webp, png, gif, tiff, heif,
+    /// avif, jxl, pdf, svg.
+    pub required_formats: Option<Vec<String>>,
+    /// Presets to generate right after upload, separate values with spaces (example: "thumbnail card").
+    pub warm_presets: Option<Vec<String>>,
+    /// Named sets of image parameters, see `preset` module.
+    /// Can be defined only in the config file.
+    #[serde(default)]
+    pub presets: Presets,
+    /// Defaults and limits of images by tag, see `policy` module.
+    /// Can be defined only in the config file.
+    #[serde(default)]
+    pub policies: Policies,
+    /// Default options of the image encoders, see `encoder` module.
+    /// Can be defined only in the config file.
+    #[serde(default)]
+    pub encoders: EncoderOptions,
+    /// Extra headers of image responses, see `response_headers` module.
+    /// Can be defined only in the config file.
+    #[serde(default)]
+    pub response_headers: BTreeMap<String, String>,
 }
 
-pub fn get_config() -> anyhow::Result<AppConfig> {
+/// Read the configuration.
+/// Settings are read from the config file (`path`, `CANVAS_CONFIG` or optional `canvas.toml`/`canvas.yaml`
+/// in the working directory) and overridden by environment variables.
+pub fn get_config(path: Option<&str>) -> anyhow::Result<AppConfig> {
     let _ = dotenvy::dotenv();
 
+    let path = path
+        .map(|path| path.to_string())
+        .or_else(|| std::env::var("CANVAS_CONFIG").ok());
+    let file = match &path {
+        Some(path) => config::File::with_name(path).required(true),
+        None => config::File::with_name("canvas").required(false),
+    };
+
     let config = Config::builder()
         .set_default("upload_dir", "uploads")?
         .set_default("file_size_limit_kb", 4096)?
+        .set_default("body_limit_kb", 64)?
         .set_default("port", 3000)?
+        .set_default("http2_enabled", true)?
+        .set_default("http2_max_concurrent_streams", 200)?
+        .set_default("http2_keep_alive_timeout_secs", 20)?
+        .set_default("keep_alive", true)?
+        .set_default("header_read_timeout_secs", 30)?
+        .set_default("trust_forwarded_host", false)?
+        .set_default("forwarded_header", "x-forwarded-for")?
         .set_default("redis_url", "redis://127.0.0.1/")?
+        .set_default("watermark_blend", "screen")?
+        .set_default("watermark_opacity", 1.0)?
+        .set_default("watermark_tile_spacing", 100)?
+        .set_default("watermark_tile_angle", -30.0)?
+        .set_default("watermark_tile_opacity", 0.3)?
+        .set_default("invisible_watermark_strength", 8.0)?
+        .set_default("overlay_font", "sans 12")?
         .set_default("enable_tracing", true)?
+        .set_default("cors_allowed_methods", vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])?
+        .set_default("cors_allow_credentials", false)?
+        .set_default("cors_max_age_secs", 600)?
+        .set_default("hotlink_allow_empty_referer", true)?
+        .set_default("jwks_cache_secs", 3600)?
+        .set_default("clamav_fail_open", false)?
+        .set_default("strip_sensitive_metadata", false)?
+        .set_default("keep_raw_originals", false)?
+        .set_default("trash_retention_hours", 168)?
+        .set_default("replica_backfill", false)?
+        .set_default("proxy_max_size_kb", 10240)?
+        .set_default("proxy_timeout_secs", 10)?
+        .set_default("upload_timeout_secs", 60)?
+        .set_default("upload_url_ttl_secs", 600)?
+        .set_default("transform_url_ttl_secs", 3600)?
+        .set_default("transform_timeout_secs", 30)?
+        .set_default("transform_queue_size", 100)?
+        .set_default("verbose_errors", false)?
+        .set_default("missing_cache_secs", 60)?
+        .set_default("render_lock_ms", 10000)?
+        .set_default("disk_cache_threshold_kb", 1024)?
+        .set_default("disk_cache_max_size_mb", 1024)?
+        .set_default("save_data_quality", 50)?
+        .set_default("save_data_max_size", 640)?
+        .set_default("format_fallback", false)?
+        .set_default("speed", "balanced")?
+        .set_default("allow_arbitrary_params", true)?
+        .set_default("duplicate_params", "reject")?
+        .set_default("auto_quality_target", 1.5)?
+        .set_default("thumbor_allow_unsafe", false)?
+        .set_default("imgproxy_allow_unsafe", false)?
+        .add_source(file)
         .add_source(
             config::Environment::with_prefix("CANVAS")
                 .try_parsing(true)
@@ -0,0 +1,187 @@
+//! Audit log.
+//!
+//! Mutating operations and admin actions are appended to the `audit` Redis stream.
+//! Entries are written before the action, and the action fails if its entry can't be
+//! written. The stream is not trimmed unless `audit_max_entries` is set.
+use crate::{
+    auth::{self, Principal},
+    client_ip::ClientIp,
+    clock::unix_now,
+    hash, AppState, HttpError,
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, State},
+    http::{request::Parts, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use mobc_redis::redis::{
+    aio::Connection,
+    streams::{StreamMaxlen, StreamRangeReply},
+    AsyncCommands, RedisResult,
+};
+use serde::Serialize;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+/// Redis stream with audit entries.
+pub const STREAM: &str = "audit";
+
+/// Who performed the action.
+#[derive(Debug, Clone)]
+pub struct Actor {
+    /// User id, 'admin', 'access_token', 'anonymous' or 'system'.
+    pub name: String,
+    /// Fingerprint of the bearer token (first 12 hex digits of its SHA-256).
+    pub key: Option<String>,
+    /// Client IP address.
+    pub ip: Option<String>,
+}
+
+impl Actor {
+    /// Actor for background tasks.
+    pub fn system() -> Actor {
+        Actor {
+            name: "system".to_string(),
+            key: None,
+            ip: None,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Actor
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let name = match parts.extensions.get::<Principal>() {
+            Some(Principal::Admin) => "admin".to_string(),
+            Some(Principal::AccessToken) => "access_token".to_string(),
+            Some(Principal::User(claims)) => claims.sub.clone(),
+            None => "anonymous".to_string(),
+        };
+        let key = auth::bearer_token(&parts.headers)
+            .map(|token| hash::compute(token.as_bytes())[..12].to_string());
+        // The address behind trusted proxies, see `client_ip` module.
+        let ip = match parts.extensions.get::<ClientIp>() {
+            Some(ClientIp(ip)) => Some(ip.to_string()),
+            None => parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        };
+
+        Ok(Actor { name, key, ip })
+    }
+}
+
+/// Audit log entry.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    /// Stream entry id.
+    pub id: String,
+    /// Unix timestamp.
+    pub time: u64,
+    pub action: String,
+    /// Image hash or request path.
+    pub target: String,
+    pub actor: String,
+    pub key: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Append the entry to the audit log, trimming it to about `max_entries`.
+/// Failures are logged, the action should not be performed then.
+pub async fn record(
+    con: &mut Connection,
+    max_entries: Option<usize>,
+    actor: &Actor,
+    action: &str,
+    target: &str,
+) -> RedisResult<()> {
+    let time = unix_now().to_string();
+    let mut fields = vec![
+        ("time", time.as_str()),
+        ("action", action),
+        ("target", target),
+        ("actor", actor.name.as_str()),
+    ];
+    if let Some(key) = &actor.key {
+        fields.push(("key", key.as_str()));
+    }
+    if let Some(ip) = &actor.ip {
+        fields.push(("ip", ip.as_str()));
+    }
+
+    let result: RedisResult<String> = match max_entries {
+        Some(max_entries) => {
+            con.xadd_maxlen(STREAM, StreamMaxlen::Approx(max_entries), "*", &fields[..])
+                .await
+        }
+        None => con.xadd(STREAM, "*", &fields[..]).await,
+    };
+    if let Err(err) = &result {
+        warn!("Failed to write audit entry ({action} {target}): {err}");
+    }
+    result.map(|_| ())
+}
+
+/// Read entries between `start` and `end` ids, newest first.
+pub async fn read(
+    con: &mut Connection,
+    end: &str,
+    start: &str,
+    count: usize,
+) -> RedisResult<Vec<Entry>> {
+    let reply: StreamRangeReply = con.xrevrange_count(STREAM, end, start, count).await?;
+
+    Ok(reply
+        .ids
+        .into_iter()
+        .map(|entry| Entry {
+            time: entry
+                .get::<String>("time")
+                .and_then(|time| time.parse().ok())
+                .unwrap_or(0),
+            action: entry.get("action").unwrap_or_default(),
+            target: entry.get("target").unwrap_or_default(),
+            actor: entry.get("actor").unwrap_or_default(),
+            key: entry.get("key"),
+            ip: entry.get("ip"),
+            id: entry.id,
+        })
+        .collect())
+}
+
+/// Middleware recording every admin request.
+pub async fn record_admin_action<B>(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let action = format!("admin.{}", request.method().as_str().to_lowercase());
+    let target = request.uri().path().to_string();
+
+    // Admin actions are not performed without an entry.
+    let recorded = match state.redis.get().await {
+        Ok(mut redis_con) => record(
+            &mut redis_con,
+            state.cfg.audit_max_entries,
+            &actor,
+            &action,
+            &target,
+        )
+        .await
+        .map_err(HttpError::from),
+        Err(err) => Err(HttpError::from(err)),
+    };
+    match recorded {
+        Ok(()) => next.run(request).await,
+        Err(err) => err.into_response(),
+    }
+}
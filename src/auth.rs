@@ -0,0 +1,123 @@
+//! Request authentication.
+//!
+//! The `authenticate` middleware checks the 'Authorization: Bearer <token>' header
+//! and adds the `Principal` to request extensions.
+//! Requests without the header are passed through unchanged.
+use crate::{jwks::Claims, metadata::ImageMetadata, AppState, HttpError};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::debug;
+use std::sync::Arc;
+
+/// Authenticated client.
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// One of the configured admin tokens.
+    Admin,
+    /// One of the configured access tokens.
+    AccessToken,
+    /// Valid JWT.
+    User(Claims),
+}
+
+impl Principal {
+    /// User id, if the client was authenticated with a JWT.
+    pub fn subject(&self) -> Option<&str> {
+        match self {
+            Principal::Admin | Principal::AccessToken => None,
+            Principal::User(claims) => Some(&claims.sub),
+        }
+    }
+
+    /// Can the client view the private image uploaded by `owner`?
+    /// Tokens can view any image, users only the images they uploaded.
+    pub fn can_view(&self, owner: Option<&str>) -> bool {
+        self.can_modify(owner)
+    }
+
+    /// Can the client change or delete the image uploaded by `owner`?
+    /// Tokens can change any image, users only the images they uploaded.
+    pub fn can_modify(&self, owner: Option<&str>) -> bool {
+        match self {
+            Principal::Admin | Principal::AccessToken => true,
+            Principal::User(claims) => owner == Some(claims.sub.as_str()),
+        }
+    }
+}
+
+/// Respond with 401 without authentication and with 403 if the client can't change the image.
+pub fn check_modify(principal: Option<&Principal>, meta: &ImageMetadata) -> Result<(), HttpError> {
+    match principal {
+        None => Err(HttpError::unauthorized("Authentication required")),
+        Some(principal) if !principal.can_modify(meta.owner.as_deref()) => Err(
+            HttpError::forbidden("Only the uploader or a token can change this image"),
+        ),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Get the token from the 'Authorization: Bearer <token>' header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+}
+
+/// Authentication middleware.
+/// Responds with 401 if the token is invalid.
+pub async fn authenticate<B>(
+    State(state): State<Arc<AppState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = match bearer_token(request.headers()) {
+        Some(token) => token.to_string(),
+        None => return next.run(request).await,
+    };
+
+    match authenticate_token(&state, &token).await {
+        Ok(principal) => {
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Err(err) => {
+            debug!("Authentication failed: {err}");
+            HttpError::unauthorized("Invalid access token").into_response()
+        }
+    }
+}
+
+/// Allow only requests authenticated with an admin token.
+/// Must be applied inside the `authenticate` middleware.
+pub async fn require_admin<B>(request: Request<B>, next: Next<B>) -> Response {
+    match request.extensions().get::<Principal>() {
+        Some(Principal::Admin) => next.run(request).await,
+        Some(_) => HttpError::forbidden("Admin token required").into_response(),
+        None => HttpError::unauthorized("Admin token required").into_response(),
+    }
+}
+
+async fn authenticate_token(state: &AppState, token: &str) -> anyhow::Result<Principal> {
+    if let Some(tokens) = &state.cfg.admin_tokens {
+        if tokens.iter().any(|allowed| allowed == token) {
+            return Ok(Principal::Admin);
+        }
+    }
+
+    if let Some(tokens) = &state.cfg.access_tokens {
+        if tokens.iter().any(|allowed| allowed == token) {
+            return Ok(Principal::AccessToken);
+        }
+    }
+
+    match &state.jwks {
+        Some(jwks) => Ok(Principal::User(jwks.validate(token).await?)),
+        None => Err(anyhow::anyhow!("Unknown access token")),
+    }
+}
@@ -0,0 +1,229 @@
+//! Benchmark of the processing stages on the current host (`canvas bench`).
+//!
+//! Each sample is decoded, resized to the cover size and encoded to every output
+//! format available in libvips, with both speed profiles. The stages are evaluated
+//! one by one (libvips is lazy otherwise), and their throughput is reported in
+//! megapixels per second: of the source for decoding and resizing, of the output
+//! for encoding. Comparing the reports of instance types shows which of them gain
+//! from `speed = "fast"` or lower encoder efforts.
+//!
+//! The SIMD extensions of the CPU are listed as well, libvips uses them for
+//! resizing and colour conversion if it was built with vector support
+//! (the bindings don't tell if it was).
+//! Without samples, a noisy 3000x2000 JPEG is generated.
+use crate::{
+    api::image::{encode_image, ImageFormat},
+    app_config::{AppConfig, Speed},
+    build_info, capabilities, pipeline,
+};
+use libvips::{ops, VipsImage};
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+/// Size of the generated sample.
+const SAMPLE_WIDTH: i32 = 3000;
+const SAMPLE_HEIGHT: i32 = 2000;
+/// Output size, like the default `width` and `height` parameters.
+const OUTPUT_SIZE: u16 = 1024;
+/// Quality of the encoding, like the default `quality` parameter.
+const QUALITY: u8 = 80;
+
+/// Source image of the benchmark.
+struct Sample {
+    name: String,
+    data: Vec<u8>,
+}
+
+/// Total time and processed megapixels of a stage.
+#[derive(Default)]
+struct Throughput {
+    time: Duration,
+    megapixels: f64,
+}
+
+impl Throughput {
+    fn add(&mut self, time: Duration, width: i32, height: i32) {
+        self.time += time;
+        self.megapixels += f64::from(width) * f64::from(height) / 1_000_000.0;
+    }
+
+    fn report(&self, stage: &str) {
+        let seconds = self.time.as_secs_f64();
+        let rate = match seconds > 0.0 {
+            true => self.megapixels / seconds,
+            false => 0.0,
+        };
+        println!(
+            "  {stage:<8} {rate:>9.1} MP/s {:>10.1} ms",
+            seconds * 1000.0
+        );
+    }
+}
+
+/// Run the benchmark over the files, `iterations` times each.
+pub fn run(cfg: &AppConfig, files: &[String], iterations: u32) -> anyhow::Result<()> {
+    report_host();
+    let samples = match files.is_empty() {
+        true => vec![generate_sample()?],
+        false => files
+            .iter()
+            .map(|file| {
+                Ok(Sample {
+                    name: file.clone(),
+                    data: fs::read(file)?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+    };
+    for sample in &samples {
+        println!("Sample: {}", sample.name);
+    }
+
+    let capabilities = capabilities::detect();
+    let formats: Vec<ImageFormat> = build_info::output_formats()
+        .into_iter()
+        .filter(|format| {
+            capabilities
+                .get(format)
+                .is_some_and(|support| support.is_available())
+        })
+        .filter_map(ImageFormat::parse)
+        .collect();
+
+    for speed in [Speed::Balanced, Speed::Fast] {
+        let (encoders, kernel) = speed.profile(cfg);
+        println!();
+        println!("Profile {speed} (kernel {kernel}), {iterations} iteration(s):");
+        let mut decode = Throughput::default();
+        let mut resize = Throughput::default();
+        let mut encode: Vec<Throughput> = formats.iter().map(|_| Throughput::default()).collect();
+
+        for sample in &samples {
+            for _ in 0..iterations {
+                let start = Instant::now();
+                let image = VipsImage::new_from_buffer(&sample.data, "")?;
+                let image = materialize(&ops::autorot(&image)?)?;
+                let (width, height) = (image.get_width(), image.get_height());
+                decode.add(start.elapsed(), width, height);
+
+                let start = Instant::now();
+                let resized = pipeline::cover_resize(&image, OUTPUT_SIZE, OUTPUT_SIZE, kernel)?;
+                let cropped = pipeline::cover_crop(&resized, OUTPUT_SIZE, OUTPUT_SIZE)?;
+                let output = materialize(&cropped)?;
+                resize.add(start.elapsed(), width, height);
+
+                for (format, throughput) in formats.iter().zip(encode.iter_mut()) {
+                    let start = Instant::now();
+                    encode_image(&output, format, QUALITY, false, None, &encoders)?;
+                    throughput.add(start.elapsed(), output.get_width(), output.get_height());
+                }
+            }
+        }
+
+        decode.report("decode");
+        resize.report("resize");
+        for (format, throughput) in formats.iter().zip(&encode) {
+            throughput.report(&format.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Print the architecture, the CPU count and the SIMD extensions.
+fn report_host() {
+    println!("Host: {}, {} CPUs", std::env::consts::ARCH, num_cpus::get());
+    let extensions = simd_extensions();
+    match extensions.is_empty() {
+        true => println!("SIMD: none detected"),
+        false => println!("SIMD: {}", extensions.join(" ")),
+    }
+}
+
+/// SIMD extensions of the CPU that matter for image processing.
+fn simd_extensions() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut extensions = Vec::new();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        for (name, detected) in [
+            ("sse4.1", is_x86_feature_detected!("sse4.1")),
+            ("avx2", is_x86_feature_detected!("avx2")),
+            ("avx512f", is_x86_feature_detected!("avx512f")),
+        ] {
+            if detected {
+                extensions.push(name);
+            }
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        for (name, detected) in [
+            ("neon", std::arch::is_aarch64_feature_detected!("neon")),
+            ("sve", std::arch::is_aarch64_feature_detected!("sve")),
+        ] {
+            if detected {
+                extensions.push(name);
+            }
+        }
+    }
+    extensions
+}
+
+/// Evaluate the image into memory, so that the following stage doesn't repeat its work.
+fn materialize(image: &VipsImage) -> anyhow::Result<VipsImage> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    let pixels = image.image_write_to_memory();
+    let image = VipsImage::new_from_memory(
+        &pixels,
+        image.get_width(),
+        image.get_height(),
+        image.get_bands(),
+        ops::BandFormat::Uchar,
+    )?;
+    Ok(ops::copy_with_opts(
+        &image,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?)
+}
+
+/// Photo-like sample: a colour gradient with noise, which is hard to compress.
+fn generate_sample() -> anyhow::Result<Sample> {
+    let coordinates = ops::xyz(SAMPLE_WIDTH, SAMPLE_HEIGHT)?;
+    let gradient = ops::linear(
+        &coordinates,
+        &mut [
+            255.0 / f64::from(SAMPLE_WIDTH),
+            255.0 / f64::from(SAMPLE_HEIGHT),
+        ],
+        &mut [0.0, 0.0],
+    )?;
+    let blue = ops::bandmean(&gradient)?;
+    let colour = ops::bandjoin(&mut [gradient, blue])?;
+    let noise = ops::gaussnoise_with_opts(
+        SAMPLE_WIDTH,
+        SAMPLE_HEIGHT,
+        &ops::GaussnoiseOptions {
+            mean: 0.0,
+            sigma: 20.0,
+            ..ops::GaussnoiseOptions::default()
+        },
+    )?;
+    let image = ops::cast(&ops::add(&colour, &noise)?, ops::BandFormat::Uchar)?;
+    let image = ops::copy_with_opts(
+        &image,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?;
+    Ok(Sample {
+        name: format!("generated {SAMPLE_WIDTH}x{SAMPLE_HEIGHT} JPEG"),
+        data: ops::jpegsave_buffer(&image)?,
+    })
+}
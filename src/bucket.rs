@@ -0,0 +1,56 @@
+//! Bucketing of image parameters.
+//!
+//! Requested sizes and qualities are snapped to a small set of values,
+//! so that slightly different requests share one cached image.
+use crate::{api::image::ImageProps, AppConfig};
+use anyhow::bail;
+use std::cmp;
+
+/// Snap the size and quality of the image to the configured buckets.
+///
+/// Width and height are rounded up to a multiple of `size_step` and capped at `max_size`,
+/// quality is rounded up to the nearest of `quality_steps` (or down to the highest one).
+pub fn apply(props: &mut ImageProps, cfg: &AppConfig) {
+    if let Some(step) = cfg.size_step.filter(|step| *step > 0) {
+        props.width = round_up(props.width, step);
+        props.height = round_up(props.height, step);
+    }
+
+    if let Some(max_size) = cfg.max_size {
+        props.width = cmp::min(props.width, max_size);
+        props.height = cmp::min(props.height, max_size);
+    }
+
+    if let (Some(steps), false) = (&cfg.quality_steps, props.auto_quality) {
+        let quality = steps
+            .iter()
+            .filter(|step| **step >= props.quality)
+            .min()
+            .or_else(|| steps.iter().max());
+        if let Some(quality) = quality {
+            props.quality = *quality;
+        }
+    }
+}
+
+/// Check the bucketing options.
+pub fn check(cfg: &AppConfig) -> anyhow::Result<()> {
+    if cfg.size_step == Some(0) {
+        bail!("Size step must be greater than 0");
+    }
+    if cfg.max_size == Some(0) {
+        bail!("Maximum size must be greater than 0");
+    }
+    if let Some(steps) = &cfg.quality_steps {
+        if steps.is_empty() || steps.iter().any(|step| !(1..=100).contains(step)) {
+            bail!("Quality steps must be between 1 and 100");
+        }
+    }
+    Ok(())
+}
+
+/// Round the size up to a multiple of the step, staying within `u16`.
+fn round_up(size: u16, step: u16) -> u16 {
+    let rounded = u32::from(size).div_ceil(u32::from(step)) * u32::from(step);
+    u16::try_from(rounded).unwrap_or(u16::MAX / step * step)
+}
@@ -0,0 +1,120 @@
+//! Processing budget of image requests.
+//!
+//! With `processing_budget_ms`, the time spent on a request from the moment it starts
+//! waiting for a processing slot is checked between the stages: queue, decode, rotate,
+//! resize and crop (or pipeline, blurpad), watermark, svg, overlay, fingerprint (for
+//! `licensee`), quality (for `quality=auto`) and encode. A request over the budget is aborted with 503 and the stage that
+//! exceeded it is logged, so that a starved instance fails fast instead of queuing
+//! requests until the transform timeout.
+//!
+//! The time of each stage is kept for the slow request log (see `slow` module)
+//! and the stage metrics.
+use log::warn;
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Time limit of the processing.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    start: Instant,
+    limit: Option<Duration>,
+    /// What is processed, for the log.
+    target: String,
+    /// End of the last stage.
+    last: Instant,
+    /// Finished stages with their time.
+    stages: Vec<(&'static str, Duration)>,
+}
+
+/// The budget was exceeded, the stage is logged.
+#[derive(Debug)]
+pub struct OverBudget;
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Processing took too long, try again later")
+    }
+}
+
+impl std::error::Error for OverBudget {}
+
+impl Budget {
+    /// Start counting the time of processing `target` (like the cache key of the image).
+    pub fn new(limit_ms: Option<u64>, target: &str) -> Budget {
+        let now = Instant::now();
+        Budget {
+            start: now,
+            limit: limit_ms.map(Duration::from_millis),
+            target: target.to_string(),
+            last: now,
+            stages: Vec::new(),
+        }
+    }
+
+    /// Budget without a limit, for background work.
+    pub fn unlimited(target: &str) -> Budget {
+        Budget::new(None, target)
+    }
+
+    /// Time since the start.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Finished stages with their time, in order.
+    pub fn stages(&self) -> &[(&'static str, Duration)] {
+        &self.stages
+    }
+
+    /// Checkpoint after the stage.
+    pub fn check(&mut self, stage: &'static str) -> Result<(), OverBudget> {
+        self.finish_stage(stage);
+        let elapsed = self.start.elapsed();
+        match self.limit {
+            Some(limit) if elapsed > limit => Err(self.exceeded(stage)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for the future as long as the budget allows.
+    pub async fn wait<F: Future>(
+        &mut self,
+        stage: &'static str,
+        future: F,
+    ) -> Result<F::Output, OverBudget> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                let output = future.await;
+                self.finish_stage(stage);
+                return Ok(output);
+            }
+        };
+        let remaining = limit.saturating_sub(self.start.elapsed());
+        let output = tokio::time::timeout(remaining, future).await;
+        self.finish_stage(stage);
+        match output {
+            Ok(output) => Ok(output),
+            Err(_) => Err(self.exceeded(stage)),
+        }
+    }
+
+    fn finish_stage(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.stages.push((stage, now - self.last));
+        self.last = now;
+    }
+
+    fn exceeded(&self, stage: &'static str) -> OverBudget {
+        let elapsed = self.start.elapsed();
+        warn!(
+            "Processing of {} exceeded the budget at the {stage} stage after {} ms",
+            self.target,
+            elapsed.as_millis()
+        );
+        OverBudget
+    }
+}
@@ -0,0 +1,49 @@
+//! Build information of the running server.
+//!
+//! Lets operators check what is actually deployed: the crate version, the git
+//! commit (embedded by the build script), the version of the linked libvips and
+//! the output formats enabled by build features.
+use serde::Serialize;
+
+/// Version of the crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Git commit of the build, 'unknown' if it was built without git.
+pub const GIT_SHA: &str = env!("CANVAS_GIT_SHA");
+
+/// Versions and features of the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub libvips_version: String,
+    /// Output formats, including the ones enabled by build features.
+    pub formats: Vec<&'static str>,
+    /// Enabled build features.
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn new(libvips_version: &str) -> BuildInfo {
+        let mut features = Vec::new();
+        if cfg!(feature = "jxl") {
+            features.push("jxl");
+        }
+
+        BuildInfo {
+            version: VERSION,
+            git_sha: GIT_SHA,
+            libvips_version: libvips_version.to_string(),
+            formats: output_formats(),
+            features,
+        }
+    }
+}
+
+/// Output formats of the build.
+pub fn output_formats() -> Vec<&'static str> {
+    let mut formats = vec!["jpeg", "webp", "avif", "png", "gif", "tiff"];
+    if cfg!(feature = "jxl") {
+        formats.push("jxl");
+    }
+    formats
+}
@@ -0,0 +1,239 @@
+//! Cache of processed images.
+//!
+//! Processed images are stored in Redis, keys start with the image hash
+//! (see `get_image_id`).
+//!
+//! Large images are read and written in chunks, so that a request never
+//! holds more than one full copy of the image in memory. Images over the threshold
+//! of the disk cache are kept in files and, with the remote cache, all images are kept
+//! in the shared storage. Redis has pointers to them then (see `disk_cache` and
+//! `remote_cache` modules).
+use crate::{
+    access,
+    disk_cache::{self, DiskCache},
+    remote_cache::{self, RemoteCache},
+    AppConfig,
+};
+use axum::body::{boxed, BoxBody, Bytes, Full, StreamBody};
+use futures::StreamExt;
+use log::warn;
+use mobc::Pool;
+use mobc_redis::{
+    redis::{self, aio::Connection, AsyncCommands, ErrorKind, RedisError},
+    RedisConnectionManager,
+};
+use std::cmp;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+/// Size of chunks for reading and writing large images.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+/// Lifetime of the temporary key of an image written in chunks, in seconds.
+const PART_TTL_SECS: usize = 60;
+
+/// Storage of processed images outside of Redis.
+pub enum Offload {
+    Disk(DiskCache),
+    Remote(RemoteCache),
+}
+
+impl Offload {
+    /// Storage of the configuration, `None` if images are kept in Redis.
+    pub fn new(cfg: &AppConfig) -> anyhow::Result<Option<Offload>> {
+        if let Some(remote) = RemoteCache::new(cfg)? {
+            return Ok(Some(Offload::Remote(remote)));
+        }
+        Ok(DiskCache::new(cfg).map(Offload::Disk))
+    }
+}
+
+/// Read the cached image.
+/// The size and the first chunk are read in one round trip on the caller's connection,
+/// so small images take a single request. Larger images are streamed with GETRANGE,
+/// each chunk on a connection taken from `pool` and returned right after, so that slow
+/// clients don't hold connections. The stream fails if the image changes while reading.
+/// Buffers read from Redis are moved into the body as `Bytes`, without copying.
+/// Pointers are followed to `offload`, missing files and objects are treated as not cached.
+/// Returns `None` if the image is not cached.
+pub async fn read(
+    con: &mut Connection,
+    pool: &Pool<RedisConnectionManager>,
+    key: &str,
+    offload: Option<&Offload>,
+) -> Result<Option<BoxBody>, mobc::Error<RedisError>> {
+    // STRLEN is 0 and GETRANGE is empty for missing keys.
+    let (len, head): (usize, Vec<u8>) = redis::pipe()
+        .atomic()
+        .strlen(key)
+        .getrange(key, 0, CHUNK_SIZE as isize - 1)
+        .query_async(con)
+        .await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if let Some(size) = remote_cache::parse_pointer(&head) {
+        // Pointers left from a disabled remote cache are misses.
+        let remote = match offload {
+            Some(Offload::Remote(remote)) => remote,
+            _ => return Ok(None),
+        };
+        return match remote.read(key, size).await {
+            Ok(data) => Ok(data.map(|data| boxed(Full::new(Bytes::from(data))))),
+            Err(err) => {
+                warn!("Cannot read cached image {key} from the storage: {err}");
+                Ok(None)
+            }
+        };
+    }
+    if let Some(size) = disk_cache::parse_pointer(&head) {
+        // Pointers left from a disabled disk cache are misses.
+        let disk = match offload {
+            Some(Offload::Disk(disk)) => disk,
+            _ => return Ok(None),
+        };
+        return match read_file(disk, key, size).await {
+            Ok(Some(body)) => {
+                disk.touch(con, key).await?;
+                Ok(Some(body))
+            }
+            Ok(None) => Ok(None),
+            Err(err) => {
+                warn!("Cannot read cached image {key} from the disk: {err}");
+                Ok(None)
+            }
+        };
+    }
+    if head.len() >= len {
+        return Ok(Some(boxed(Full::new(Bytes::from(head)))));
+    }
+
+    let key = key.to_string();
+    let pool = pool.clone();
+    let offset = head.len();
+    let rest = futures::stream::try_unfold(offset, move |offset| {
+        let key = key.clone();
+        let pool = pool.clone();
+        async move {
+            if offset >= len {
+                return Ok(None);
+            }
+            let end = cmp::min(offset + CHUNK_SIZE, len) - 1;
+            let mut con = pool.get().await?;
+            let (current_len, chunk): (usize, Vec<u8>) = redis::pipe()
+                .atomic()
+                .strlen(&key)
+                .getrange(&key, offset as isize, end as isize)
+                .query_async(&mut *con)
+                .await?;
+            if current_len != len || chunk.is_empty() {
+                return Err(RedisError::from((
+                    ErrorKind::ResponseError,
+                    "Cached image was changed while reading",
+                ))
+                .into());
+            }
+            let offset = offset + chunk.len();
+            Ok::<_, mobc::Error<RedisError>>(Some((Bytes::from(chunk), offset)))
+        }
+    });
+    let chunks = futures::stream::once(async { Ok(Bytes::from(head)) }).chain(rest);
+
+    Ok(Some(boxed(StreamBody::new(chunks))))
+}
+
+/// Stream the file of the disk cache, `None` if it is missing or has another size.
+async fn read_file(disk: &DiskCache, key: &str, size: u64) -> std::io::Result<Option<BoxBody>> {
+    let file = match tokio::fs::File::open(disk.path(key)).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if file.metadata().await?.len() != size {
+        return Ok(None);
+    }
+    let chunks = futures::stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        chunk.truncate(read);
+        Ok(Some((Bytes::from(chunk), file)))
+    });
+    Ok(Some(boxed(StreamBody::new(chunks))))
+}
+
+/// Save the processed image, with the expiration time if `ttl_secs` is given.
+/// Images over the threshold of the disk cache and all images of the remote cache are
+/// written to `offload`, Redis gets the pointer then. Other large images are appended
+/// in chunks to a temporary key, which is then renamed, so that readers never see
+/// a partial image.
+pub async fn write(
+    con: &mut Connection,
+    key: &str,
+    data: &[u8],
+    ttl_secs: Option<u64>,
+    offload: Option<&Offload>,
+) -> anyhow::Result<()> {
+    let pointer = match offload {
+        Some(Offload::Disk(disk)) if disk.stores(data.len()) => {
+            Some(disk.write(con, key, data).await?)
+        }
+        Some(Offload::Remote(remote)) => Some(remote.write(key, data).await?),
+        _ => None,
+    };
+    let data = pointer.as_deref().unwrap_or(data);
+    if data.len() <= CHUNK_SIZE {
+        let _: () = match ttl_secs {
+            Some(ttl) => con.set_ex(key, data, ttl as usize).await?,
+            None => con.set(key, data).await?,
+        };
+        return Ok(());
+    }
+
+    // Each write has its own temporary key, so that concurrent writers of the same image
+    // don't interleave their chunks. Parts of dead writers expire.
+    let part_key = format!("{key}.part-{}", Uuid::new_v4());
+    for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        let _: () = con.append(&part_key, chunk).await?;
+        if index == 0 {
+            let _: () = con.expire(&part_key, PART_TTL_SECS).await?;
+        }
+    }
+    // The expiration is set in the same transaction as the rename,
+    // which keeps the one of the temporary key.
+    let mut pipe = redis::pipe();
+    pipe.atomic().rename(part_key.as_str(), key).ignore();
+    match ttl_secs {
+        Some(ttl) => pipe.expire(key, ttl as usize).ignore(),
+        None => pipe.persist(key).ignore(),
+    };
+    let _: () = pipe.query_async(con).await?;
+    Ok(())
+}
+
+/// Delete all cached derivatives of the image, with their objects in the remote cache.
+/// Files of the disk cache are deleted by its eviction.
+/// Returns the number of deleted entries.
+pub async fn purge(
+    con: &mut Connection,
+    hash: &str,
+    offload: Option<&Offload>,
+) -> anyhow::Result<usize> {
+    let mut keys: Vec<String> = Vec::new();
+    {
+        let mut iter = con.scan_match::<_, String>(format!("{hash}-*")).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+
+    if keys.is_empty() {
+        return Ok(0);
+    }
+    if let Some(Offload::Remote(remote)) = offload {
+        remote.delete(&keys).await?;
+    }
+    let _: () = con.zrem(access::DERIVATIVES, &keys).await?;
+    Ok(con.del(keys).await?)
+}
@@ -0,0 +1,146 @@
+//! Cancellation of image processing.
+//!
+//! libvips work runs on the blocking thread pool. When the handler future is dropped
+//! (the client disconnected or the request timed out), the cancellation flag is raised
+//! and the running libvips pipeline is killed.
+use libvips::VipsImage;
+use log::error;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How often the cancellation flag is checked during evaluation.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Flag raised when the result of the processing is no longer needed.
+#[derive(Debug, Clone, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The processing was cancelled before it was finished.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Processing was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Raises the flag when dropped.
+struct CancelOnDrop(CancelFlag);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Run the processing on the blocking thread pool.
+/// The processing is cancelled if the returned future is dropped.
+/// A panic during the processing is returned as an error.
+pub async fn run_blocking<T, F>(process: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&CancelFlag) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let flag = CancelFlag::default();
+    let _guard = CancelOnDrop(flag.clone());
+
+    let task = tokio::task::spawn_blocking(move || {
+        // The request may be gone while the task was waiting for a thread.
+        if flag.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        process(&flag)
+    });
+
+    match task.await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => {
+            let panic = err.into_panic();
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str()))
+                .unwrap_or("unknown panic");
+            error!("Image processing panicked: {message}");
+            Err(anyhow::anyhow!("Image processing failed"))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Evaluate the image (for example, encode it).
+/// The evaluation is killed as soon as the flag is raised.
+pub fn evaluate<T, E, F>(image: &VipsImage, flag: &CancelFlag, run: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> Result<T, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let done = AtomicBool::new(false);
+    let image = SharedImage(image);
+
+    let result = thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                if flag.is_cancelled() {
+                    image.kill();
+                    return;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        // Stop the watcher even if the evaluation panics, otherwise the scope never ends.
+        let _done = SetOnDrop(&done);
+        run()
+    });
+
+    match result {
+        Ok(value) => Ok(value),
+        Err(_) if flag.is_cancelled() => Err(Cancelled.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Image shared with the watcher thread.
+/// Setting the kill flag is the only operation done from that thread,
+/// libvips allows it while the image is being evaluated.
+struct SharedImage<'a>(&'a VipsImage);
+
+unsafe impl Sync for SharedImage<'_> {}
+
+impl SharedImage<'_> {
+    /// Stop the evaluation of the image.
+    // A method, so that the closure captures the whole `SharedImage`, not its `!Sync` field.
+    fn kill(&self) {
+        self.0.image_set_kill(true);
+    }
+}
+
+/// Sets the flag when dropped.
+struct SetOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for SetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
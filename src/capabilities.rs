@@ -0,0 +1,101 @@
+//! Formats supported by the linked libvips.
+//!
+//! libvips can be built without some loaders and savers, for example without libheif
+//! there is no AVIF. They are detected at startup by looking up the libvips operations.
+//! `GET /ready` reports them and fails while any of `required_formats` is missing,
+//! so that a bad build doesn't receive traffic.
+use crate::build_info;
+use libvips::bindings::vips_type_find;
+use log::warn;
+use serde::Serialize;
+use std::{collections::BTreeMap, ffi::CString};
+
+/// Known formats with their loader and saver operations.
+const FORMATS: &[(&str, &str, Option<&str>)] = &[
+    ("jpeg", "jpegload_buffer", Some("jpegsave_buffer")),
+    ("webp", "webpload_buffer", Some("webpsave_buffer")),
+    ("png", "pngload_buffer", Some("pngsave_buffer")),
+    ("gif", "gifload_buffer", Some("gifsave_buffer")),
+    ("tiff", "tiffload_buffer", Some("tiffsave_buffer")),
+    // AVIF is saved by the HEIF saver with the AV1 compression.
+    ("heif", "heifload_buffer", Some("heifsave_buffer")),
+    ("avif", "heifload_buffer", Some("heifsave_buffer")),
+    ("jxl", "jxlload_buffer", Some("jxlsave_buffer")),
+    ("pdf", "pdfload_buffer", None),
+    ("svg", "svgload_buffer", None),
+];
+
+/// Support of the format.
+#[derive(Debug, Clone, Serialize)]
+pub struct Support {
+    pub load: bool,
+    /// `None` if libvips can't save the format at all.
+    pub save: Option<bool>,
+}
+
+impl Support {
+    /// Can the format be loaded and, if it has a saver, saved?
+    pub fn is_available(&self) -> bool {
+        self.load && self.save.unwrap_or(true)
+    }
+}
+
+/// Support of the known formats.
+pub type Capabilities = BTreeMap<&'static str, Support>;
+
+/// Check if the format is known.
+pub fn is_known(format: &str) -> bool {
+    FORMATS.iter().any(|(name, _, _)| *name == format)
+}
+
+/// Detect the supported formats, libvips must be initialized.
+pub fn detect() -> Capabilities {
+    FORMATS
+        .iter()
+        .map(|(name, loader, saver)| {
+            let support = Support {
+                load: has_operation(loader),
+                save: saver.map(has_operation),
+            };
+            if !support.is_available() {
+                warn!("Format {name} is not fully supported by libvips: {support:?}");
+            }
+            (*name, support)
+        })
+        .collect()
+}
+
+/// Formats required for the readiness: `required_formats` or the output formats of the build.
+pub fn required(configured: Option<&[String]>) -> Vec<String> {
+    match configured {
+        Some(formats) => formats.to_vec(),
+        None => build_info::output_formats()
+            .into_iter()
+            .map(String::from)
+            .collect(),
+    }
+}
+
+/// Get the required formats that are not available.
+pub fn missing(capabilities: &Capabilities, required: &[String]) -> Vec<String> {
+    required
+        .iter()
+        .filter(|format| {
+            !capabilities
+                .get(format.as_str())
+                .is_some_and(|support| support.is_available())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Check if libvips has the operation, like 'heifsave_buffer'.
+fn has_operation(name: &str) -> bool {
+    let base = CString::new("VipsOperation").expect("no NUL in the name");
+    let nickname = match CString::new(name) {
+        Ok(nickname) => nickname,
+        Err(_) => return false,
+    };
+    // Both strings outlive the call, the lookup only reads them.
+    unsafe { vips_type_find(base.as_ptr(), nickname.as_ptr()) != 0 }
+}
@@ -0,0 +1,59 @@
+//! ClamAV integration.
+//!
+//! Files are streamed to clamd with the INSTREAM command:
+//! the data is sent in chunks prefixed with their length (4 bytes, big endian),
+//! a zero-length chunk marks the end of the stream.
+use anyhow::anyhow;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Size of the chunks sent to clamd.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Limit for the whole scan, including the connection.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Result of the scan.
+#[derive(Debug)]
+pub enum ScanResult {
+    Clean,
+    /// Signature name reported by clamd.
+    Infected(String),
+}
+
+/// Scan the data with clamd listening on `address` (for example: "127.0.0.1:3310").
+pub async fn scan(address: &str, data: &[u8]) -> anyhow::Result<ScanResult> {
+    timeout(SCAN_TIMEOUT, instream(address, data))
+        .await
+        .map_err(|_| anyhow!("clamd did not respond in time"))?
+}
+
+async fn instream(address: &str, data: &[u8]) -> anyhow::Result<ScanResult> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    for chunk in data.chunks(CHUNK_SIZE) {
+        let length = u32::try_from(chunk.len())?;
+        stream.write_all(&length.to_be_bytes()).await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let response = response.trim_end_matches('\0').trim();
+
+    // Possible responses: "stream: OK", "stream: <signature> FOUND", "<message> ERROR"
+    match response.strip_prefix("stream: ") {
+        Some("OK") => Ok(ScanResult::Clean),
+        Some(result) if result.ends_with(" FOUND") => Ok(ScanResult::Infected(
+            result.trim_end_matches(" FOUND").to_string(),
+        )),
+        _ => Err(anyhow!("Unexpected clamd response: {response}")),
+    }
+}
@@ -0,0 +1,103 @@
+//! Command line interface.
+use crate::{fingerprint, AppConfig};
+use clap::{Parser, Subcommand};
+use libvips::VipsImage;
+use log::LevelFilter;
+use mobc_redis::redis;
+
+/// Canvas - image processing server.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Path to the config file (TOML or YAML).
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    /// Server port, overrides the configuration.
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Log level (off, error, warn, info, debug or trace), overrides RUST_LOG.
+    #[arg(long, global = true)]
+    pub log_level: Option<LevelFilter>,
+    /// Check the configuration and the connection to Redis, then exit.
+    #[arg(long, global = true)]
+    pub check_config: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the server (default).
+    Serve,
+    /// Read the invisible watermark of an image.
+    Watermark {
+        #[command(subcommand)]
+        command: WatermarkCommand,
+    },
+    /// Measure the throughput of decoding, resizing and encoding on this host.
+    Bench {
+        /// Sample images, a generated photo-like image is used if none are given.
+        files: Vec<String>,
+        /// Number of runs of each sample.
+        #[arg(long, default_value_t = 3)]
+        iterations: u32,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WatermarkCommand {
+    /// Print the licensee id embedded into the image.
+    Extract {
+        /// Image file.
+        file: String,
+    },
+    /// Check if the image carries the licensee id, tolerating some damage.
+    Verify {
+        /// Image file.
+        file: String,
+        /// Expected licensee id.
+        licensee: String,
+    },
+}
+
+/// Run the watermark command.
+/// Returns `false` if the watermark was not found or does not match.
+pub fn watermark(cfg: &AppConfig, command: &WatermarkCommand) -> anyhow::Result<bool> {
+    let strength = cfg.invisible_watermark_strength;
+    match command {
+        WatermarkCommand::Extract { file } => {
+            let image = VipsImage::new_from_file(file)?;
+            match fingerprint::extract(&image, strength)? {
+                Some(licensee) => {
+                    println!("{licensee}");
+                    Ok(true)
+                }
+                None => {
+                    println!("No watermark was found");
+                    Ok(false)
+                }
+            }
+        }
+        WatermarkCommand::Verify { file, licensee } => {
+            let image = VipsImage::new_from_file(file)?;
+            let (matches, share) = fingerprint::verify(&image, licensee, strength)?;
+            let percent = share * 100.0;
+            match matches {
+                true => println!("Licensee {licensee} matches ({percent:.0}% of bits)"),
+                false => println!("Licensee {licensee} does not match ({percent:.0}% of bits)"),
+            }
+            Ok(matches)
+        }
+    }
+}
+
+/// Check the connection to Redis.
+pub async fn check_redis(cfg: &AppConfig) -> anyhow::Result<()> {
+    let client = redis::Client::open(cfg.redis_url.clone())?;
+    let mut con = client.get_async_connection().await?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut con)
+        .await?;
+
+    Ok(())
+}
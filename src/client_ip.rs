@@ -0,0 +1,172 @@
+//! Client IP address behind reverse proxies.
+//!
+//! Requests from `trusted_proxies` carry the client address in the configured
+//! `forwarded_header`, 'X-Forwarded-For' or 'Forwarded'. The other header is
+//! ignored, since a client could set it. The header is read from right to left,
+//! skipping trusted proxies: the first untrusted address is the client. An entry
+//! that isn't an address (`unknown`, an obfuscated identifier or garbage) stops
+//! the walk, and the last trusted proxy is taken as the client, since nothing left
+//! of it can be trusted. Headers of other peers are ignored, since anyone can set them.
+//!
+//! The `resolve` middleware adds `ClientIp` to request extensions.
+use crate::{app_config::ForwardedHeader, AppState};
+use anyhow::anyhow;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const FORWARDED: &str = "forwarded";
+
+/// Address of the client.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// IP network, like `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    pub fn parse(value: &str) -> anyhow::Result<Network> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("Invalid trusted proxy '{value}'"))?;
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix.map(|prefix| prefix.parse::<u32>()) {
+            None => max_prefix,
+            Some(Ok(prefix)) if prefix <= max_prefix => prefix,
+            Some(_) => return Err(anyhow!("Invalid prefix length in trusted proxy '{value}'")),
+        };
+        Ok(Network { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of dual-stack sockets are IPv4-mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse the list of trusted proxies.
+pub fn parse_networks(values: &[String]) -> anyhow::Result<Vec<Network>> {
+    values.iter().map(|value| Network::parse(value)).collect()
+}
+
+/// Get the client address for the request from `peer`.
+pub fn get(
+    trusted: &[Network],
+    header: ForwardedHeader,
+    peer: IpAddr,
+    headers: &HeaderMap,
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|network| network.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let chain = match header {
+        ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+        ForwardedHeader::Forwarded => forwarded_for(headers),
+    };
+    let mut client = peer;
+    for ip in chain.into_iter().rev() {
+        match ip {
+            Some(ip) => client = ip,
+            None => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Entries of all 'X-Forwarded-For' headers, in order, `None` if the entry isn't an address.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("unknown").split(','))
+        .map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+/// `for` parameters of the elements of all 'Forwarded' headers (RFC 7239), in order,
+/// `None` if it is missing, `unknown` or an obfuscated identifier.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("unknown").split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                if !name.eq_ignore_ascii_case("for") {
+                    return None;
+                }
+                parse_node(value.trim_matches('"'))
+            })
+        })
+        .collect()
+}
+
+/// Parse `192.0.2.1`, `192.0.2.1:8080`, `[2001:db8::1]` or `[2001:db8::1]:8080`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    let addr = node.split(':').next()?;
+    addr.parse().ok()
+}
+
+/// Add `ClientIp` to request extensions.
+pub async fn resolve<B>(
+    State(state): State<Arc<AppState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer {
+        let ip = get(
+            &state.trusted_proxies,
+            state.cfg.forwarded_header,
+            peer,
+            request.headers(),
+        );
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
@@ -0,0 +1,36 @@
+//! Time helpers.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix timestamp in seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Current UTC date as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let (year, month, day) = civil_date(unix_now() / 86400);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Date of the day number since 1970-01-01 (proleptic Gregorian calendar).
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Days since 0000-03-01, years start in March so that the leap day is the last one.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
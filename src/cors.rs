@@ -0,0 +1,76 @@
+//! CORS configuration.
+use crate::{AppConfig, AppState};
+use anyhow::{anyhow, bail};
+use axum::http::{HeaderName, Method};
+use log::warn;
+use std::{sync::Arc, time::Duration};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Check the methods and headers.
+/// Origins are checked when reloadable settings are loaded.
+pub fn check(cfg: &AppConfig) -> anyhow::Result<()> {
+    parse_methods(&cfg.cors_allowed_methods)?;
+    if let Some(headers) = &cfg.cors_allowed_headers {
+        parse_headers(headers)?;
+    }
+    if let Some(headers) = &cfg.cors_exposed_headers {
+        parse_headers(headers)?;
+    }
+    if cfg.cors_allow_credentials && cfg.cors_allowed_headers.is_none() {
+        bail!("CORS credentials cannot be allowed for all headers, set allowed headers");
+    }
+    Ok(())
+}
+
+/// Build the CORS layer from the configuration.
+/// Returns an error if any of the methods or headers is invalid.
+/// Allowed origins are read from the reloadable settings on every request.
+pub fn layer(cfg: &AppConfig, state: Arc<AppState>) -> anyhow::Result<CorsLayer> {
+    check(cfg)?;
+
+    let mut cors = CorsLayer::new()
+        .allow_methods(parse_methods(&cfg.cors_allowed_methods)?)
+        .allow_credentials(cfg.cors_allow_credentials)
+        .max_age(Duration::from_secs(cfg.cors_max_age_secs));
+
+    if cfg.allowed_origins.is_none() {
+        warn!("CORS: all origins are allowed");
+    }
+    cors = cors.allow_origin(AllowOrigin::predicate(move |origin, _| {
+        state.settings().is_origin_allowed(origin)
+    }));
+
+    cors = match &cfg.cors_allowed_headers {
+        Some(headers) => cors.allow_headers(parse_headers(headers)?),
+        None => cors.allow_headers(Any),
+    };
+
+    if let Some(headers) = &cfg.cors_exposed_headers {
+        cors = cors.expose_headers(parse_headers(headers)?);
+    }
+
+    Ok(cors)
+}
+
+fn parse_methods(methods: &[String]) -> anyhow::Result<Vec<Method>> {
+    methods
+        .iter()
+        .map(|method| {
+            method
+                .to_uppercase()
+                .parse::<Method>()
+                .map_err(|_| anyhow!("Invalid CORS method '{method}'"))
+        })
+        .collect()
+}
+
+fn parse_headers(headers: &[String]) -> anyhow::Result<Vec<HeaderName>> {
+    headers
+        .iter()
+        .map(|header| {
+            header
+                .parse::<HeaderName>()
+                .map_err(|_| anyhow!("Invalid CORS header '{header}'"))
+        })
+        .collect()
+}
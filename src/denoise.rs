@@ -0,0 +1,46 @@
+//! Noise reduction (`denoise`).
+//!
+//! An edge-preserving blur: each pixel moves towards the Gaussian blur of its
+//! neighbourhood, the less the more it differs from it, so that grain is smoothed
+//! while edges and texture stay. Strong settings add a 3x3 median pass for speckles.
+//! Smoother images need fewer bits, especially with AVIF.
+use libvips::{ops, VipsImage};
+
+/// Maximum strength.
+pub const MAX_STRENGTH: u8 = 10;
+/// Strength from which the median pass is added.
+const MEDIAN_STRENGTH: u8 = 6;
+
+/// Reduce the noise with the strength from 1 to `MAX_STRENGTH`.
+pub fn apply(image: &VipsImage, strength: u8) -> anyhow::Result<VipsImage> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    let image = match strength >= MEDIAN_STRENGTH {
+        true => ops::rank(&image, 3, 3, 4)?,
+        false => image,
+    };
+
+    let strength = f64::from(strength);
+    let blurred = ops::gaussblur(&image, 0.5 + 0.25 * strength)?;
+    let delta = ops::subtract(&blurred, &image)?;
+    // Differences above the threshold are treated as edges and kept.
+    let threshold = 4.0 * strength;
+    let difference = ops::bandmean(&ops::abs(&delta)?)?;
+    let weight = ops::linear(&difference, &mut [-1.0 / threshold], &mut [1.0])?;
+    // Negative weights are set to 0: (w + |w|) / 2.
+    let weight = ops::linear(
+        &ops::add(&weight, &ops::abs(&weight)?)?,
+        &mut [0.5],
+        &mut [0.0],
+    )?;
+
+    let denoised = ops::add(&image, &ops::multiply(&delta, &weight)?)?;
+    let denoised = ops::cast(&denoised, ops::BandFormat::Uchar)?;
+    Ok(ops::copy_with_opts(
+        &denoised,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?)
+}
@@ -0,0 +1,172 @@
+//! Comparison of two images.
+//!
+//! `dssim` is the structural dissimilarity `1 / SSIM - 1` of the brightness: 0 for
+//! identical images, growing with visible changes. `ae` (absolute error) is the number
+//! of pixels that differ in any band. Both images are flattened and compared
+//! in 8-bit sRGB, with orientation applied, and must have the same size.
+use libvips::{ops, VipsImage};
+use std::{fmt, str::FromStr};
+
+/// Standard deviation of the SSIM window.
+const SSIM_SIGMA: f64 = 1.5;
+/// SSIM stabilizers for the 0-255 range.
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+/// Brightness of the first image in the visual diff.
+const DIFF_BACKGROUND: f64 = 0.3;
+
+/// Measure of the difference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Dssim,
+    Ae,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Metric::Dssim => write!(f, "dssim"),
+            Metric::Ae => write!(f, "ae"),
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Metric, Self::Err> {
+        match value {
+            "dssim" => Ok(Metric::Dssim),
+            "ae" => Ok(Metric::Ae),
+            _ => Err(anyhow::anyhow!("Unknown metric {value}")),
+        }
+    }
+}
+
+/// Result of the comparison.
+pub struct Comparison {
+    pub score: f64,
+    /// PNG with the differing pixels in red over the dimmed first image.
+    pub image: Option<Vec<u8>>,
+}
+
+/// Error of images with different sizes, the message is safe to show to clients.
+#[derive(Debug)]
+pub struct DifferentSizes((i32, i32), (i32, i32));
+
+impl fmt::Display for DifferentSizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let DifferentSizes(a, b) = self;
+        write!(
+            f,
+            "Images have different sizes: {}x{} and {}x{}",
+            a.0, a.1, b.0, b.1
+        )
+    }
+}
+
+impl std::error::Error for DifferentSizes {}
+
+/// Compare the images, optionally rendering the visual diff.
+pub fn compare(a: &[u8], b: &[u8], metric: Metric, render: bool) -> anyhow::Result<Comparison> {
+    let a = prepare(&VipsImage::new_from_buffer(a, "")?)?;
+    let b = prepare(&VipsImage::new_from_buffer(b, "")?)?;
+    let (size_a, size_b) = (
+        (a.get_width(), a.get_height()),
+        (b.get_width(), b.get_height()),
+    );
+    if size_a != size_b {
+        return Err(DifferentSizes(size_a, size_b).into());
+    }
+
+    let score = match metric {
+        Metric::Dssim => dssim(&a, &b)?,
+        Metric::Ae => {
+            let mask = difference_mask(&a, &b)?;
+            (ops::avg(&mask)? / 255.0 * f64::from(size_a.0) * f64::from(size_a.1)).round()
+        }
+    };
+    let image = match render {
+        true => Some(render_diff(&a, &b)?),
+        false => None,
+    };
+    Ok(Comparison { score, image })
+}
+
+/// Oriented 8-bit sRGB image without alpha.
+fn prepare(image: &VipsImage) -> anyhow::Result<VipsImage> {
+    let image = ops::autorot(image)?;
+    let image = match image.image_hasalpha() {
+        true => ops::flatten(&image)?,
+        false => image,
+    };
+    let image = ops::colourspace(&image, ops::Interpretation::Srgb)?;
+    Ok(ops::cast(&image, ops::BandFormat::Uchar)?)
+}
+
+/// Structural dissimilarity of the brightness.
+fn dssim(a: &VipsImage, b: &VipsImage) -> anyhow::Result<f64> {
+    let brightness = |image: &VipsImage| -> anyhow::Result<VipsImage> {
+        let grey = ops::colourspace(image, ops::Interpretation::BW)?;
+        Ok(ops::cast(&grey, ops::BandFormat::Double)?)
+    };
+    let (a, b) = (brightness(a)?, brightness(b)?);
+    let blur = |image: &VipsImage| ops::gaussblur(image, SSIM_SIGMA);
+
+    let mean_a = blur(&a)?;
+    let mean_b = blur(&b)?;
+    let mean_aa = ops::multiply(&mean_a, &mean_a)?;
+    let mean_bb = ops::multiply(&mean_b, &mean_b)?;
+    let mean_ab = ops::multiply(&mean_a, &mean_b)?;
+    let var_a = ops::subtract(&blur(&ops::multiply(&a, &a)?)?, &mean_aa)?;
+    let var_b = ops::subtract(&blur(&ops::multiply(&b, &b)?)?, &mean_bb)?;
+    let covar = ops::subtract(&blur(&ops::multiply(&a, &b)?)?, &mean_ab)?;
+
+    let numerator = ops::multiply(
+        &ops::linear(&mean_ab, &mut [2.0], &mut [C1])?,
+        &ops::linear(&covar, &mut [2.0], &mut [C2])?,
+    )?;
+    let denominator = ops::multiply(
+        &ops::linear(&ops::add(&mean_aa, &mean_bb)?, &mut [1.0], &mut [C1])?,
+        &ops::linear(&ops::add(&var_a, &var_b)?, &mut [1.0], &mut [C2])?,
+    )?;
+    let ssim = ops::avg(&ops::divide(&numerator, &denominator)?)?;
+
+    Ok(match ssim > 0.0 {
+        true => (1.0 / ssim - 1.0).max(0.0),
+        false => f64::INFINITY,
+    })
+}
+
+/// One band image, 255 where the pixels differ and 0 elsewhere.
+fn difference_mask(a: &VipsImage, b: &VipsImage) -> anyhow::Result<VipsImage> {
+    let difference = ops::abs(&ops::subtract(a, b)?)?;
+    let difference = ops::cast(&difference, ops::BandFormat::Uchar)?;
+    let any = ops::bandbool(&difference, ops::OperationBoolean::Or)?;
+    Ok(ops::relational_const(
+        &any,
+        ops::OperationRelational::More,
+        &mut [0.0],
+    )?)
+}
+
+/// Differing pixels in red over the dimmed brightness of the first image, as PNG.
+fn render_diff(a: &VipsImage, b: &VipsImage) -> anyhow::Result<Vec<u8>> {
+    let mask = difference_mask(a, b)?;
+    let grey = ops::colourspace(a, ops::Interpretation::BW)?;
+    let background = ops::linear(&grey, &mut [DIFF_BACKGROUND], &mut [0.0])?;
+    let background = ops::colourspace(
+        &ops::cast(&background, ops::BandFormat::Uchar)?,
+        ops::Interpretation::Srgb,
+    )?;
+    let red = ops::black_with_opts(
+        a.get_width(),
+        a.get_height(),
+        &ops::BlackOptions { bands: 3 },
+    )?;
+    let red = ops::linear(&red, &mut [1.0, 1.0, 1.0], &mut [255.0, 0.0, 0.0])?;
+    let red = ops::cast(&red, ops::BandFormat::Uchar)?;
+
+    let diff = ops::ifthenelse(&mask, &red, &background)?;
+    Ok(ops::pngsave_buffer(&diff)?)
+}
@@ -0,0 +1,179 @@
+//! Cache of large processed images on the local disk.
+//!
+//! With `disk_cache_dir`, images larger than `disk_cache_threshold_kb` are written
+//! to the directory instead of Redis, named by the SHA-256 of the cache key. Redis keeps
+//! a short pointer with the size under the cache key (see `cache` module), so expiration,
+//! purging and the derivative eviction work as for other entries.
+//!
+//! Files are indexed in the `cache:disk` sorted set by the last access time.
+//! Every minute, files of removed pointers are deleted, then the least recently used
+//! ones while the directory is over `disk_cache_max_size_mb`.
+use crate::{access, clock::unix_now, hash, AppConfig, AppState};
+use log::{info, warn};
+use mobc_redis::redis::{self, aio::Connection, AsyncCommands};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Prefix of pointers to files, followed by the size in bytes.
+/// Images never start with it.
+pub const POINTER_PREFIX: &[u8] = b"canvas-disk:";
+/// Sorted set with cache keys of files, scored by the last access time.
+const INDEX: &str = "cache:disk";
+/// How often to evict files.
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Directory of large cached images.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    /// Images larger than this are stored on the disk, in bytes.
+    threshold: usize,
+    /// Size limit of the directory in bytes.
+    max_size: u64,
+}
+
+impl DiskCache {
+    /// Disk cache of the configuration, `None` if disabled.
+    pub fn new(cfg: &AppConfig) -> Option<DiskCache> {
+        cfg.disk_cache_dir.as_ref().map(|dir| DiskCache {
+            dir: PathBuf::from(dir),
+            threshold: cfg.disk_cache_threshold_kb * 1024,
+            max_size: cfg.disk_cache_max_size_mb * 1024 * 1024,
+        })
+    }
+
+    /// Should the image be stored on the disk?
+    pub fn stores(&self, len: usize) -> bool {
+        len > self.threshold
+    }
+
+    /// Path of the file with the cached image.
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(hash::compute(key.as_bytes()))
+    }
+
+    /// Write the image and return the pointer to be stored in Redis.
+    /// The file is renamed into place, so that readers never see a partial image.
+    pub async fn write(
+        &self,
+        con: &mut Connection,
+        key: &str,
+        data: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let path = self.path(key);
+        let part_path = path.with_extension("part");
+        tokio::fs::write(&part_path, data).await?;
+        tokio::fs::rename(&part_path, &path).await?;
+        let _: () = con.zadd(INDEX, key, unix_now()).await?;
+        Ok(pointer(data.len()))
+    }
+
+    /// Record that the file was served.
+    pub async fn touch(&self, con: &mut Connection, key: &str) -> redis::RedisResult<()> {
+        con.zadd(INDEX, key, unix_now()).await
+    }
+}
+
+/// Pointer to a file of `len` bytes.
+fn pointer(len: usize) -> Vec<u8> {
+    let mut pointer = POINTER_PREFIX.to_vec();
+    pointer.extend_from_slice(len.to_string().as_bytes());
+    pointer
+}
+
+/// Size of the file if the value is a pointer.
+pub fn parse_pointer(value: &[u8]) -> Option<u64> {
+    let len = value.strip_prefix(POINTER_PREFIX)?;
+    std::str::from_utf8(len).ok()?.parse().ok()
+}
+
+/// Create the directory and check that files can be written there.
+pub fn prepare(dir: &str) -> io::Result<()> {
+    let dir = Path::new(dir);
+    std::fs::create_dir_all(dir)?;
+    let test_file = dir.join(".write-test");
+    std::fs::write(&test_file, b"")?;
+    std::fs::remove_file(&test_file)
+}
+
+/// Periodically remove files of removed pointers and the least recently used ones over the limit.
+pub async fn evict_loop(state: Arc<AppState>, disk: DiskCache) {
+    let mut interval = tokio::time::interval(EVICT_INTERVAL);
+    loop {
+        interval.tick().await;
+        match evict(&state, &disk).await {
+            Ok(0) => {}
+            Ok(evicted) => info!("Evicted {evicted} images from the disk cache"),
+            Err(err) => warn!("Failed to evict images from the disk cache: {err}"),
+        }
+    }
+}
+
+async fn evict(state: &AppState, disk: &DiskCache) -> anyhow::Result<usize> {
+    let mut redis_con = state.redis.get().await?;
+    // Least recently used first.
+    let keys: Vec<String> = redis_con.zrange(INDEX, 0, -1).await?;
+    if keys.is_empty() {
+        return Ok(0);
+    }
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.exists(key);
+    }
+    let exists: Vec<bool> = pipe.query_async(&mut *redis_con).await?;
+
+    let mut evicted = 0;
+    let mut kept = Vec::new();
+    let mut total_size = 0;
+    for (key, exists) in keys.into_iter().zip(exists) {
+        let size = match tokio::fs::metadata(disk.path(&key)).await {
+            Ok(metadata) => Some(metadata.len()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        match (exists, size) {
+            (true, Some(size)) => {
+                total_size += size;
+                kept.push((key, size));
+            }
+            // Expired, purged or evicted pointer, or a lost file.
+            _ => {
+                remove(&mut redis_con, disk, &key).await?;
+                evicted += 1;
+            }
+        }
+    }
+
+    for (key, size) in kept {
+        if total_size <= disk.max_size {
+            break;
+        }
+        remove(&mut redis_con, disk, &key).await?;
+        total_size -= size;
+        evicted += 1;
+    }
+    Ok(evicted)
+}
+
+/// Remove the file with its pointer.
+async fn remove(con: &mut Connection, disk: &DiskCache, key: &str) -> anyhow::Result<()> {
+    match tokio::fs::remove_file(disk.path(key)).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let _: () = redis::pipe()
+        .atomic()
+        .del(key)
+        .ignore()
+        .zrem(INDEX, key)
+        .ignore()
+        .zrem(access::DERIVATIVES, key)
+        .ignore()
+        .query_async(con)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Styling effects: vignette (`vignette`) and drop shadow (`shadow`).
+//!
+//! The shadow follows the shape of the image, so images with transparent rounded
+//! corners get rounded shadows. It is rendered onto a transparent canvas expanded
+//! by the blur and the offset, so the output is larger than the requested size.
+use libvips::{ops, VipsImage};
+use std::fmt;
+
+/// Maximum blur of the shadow.
+const MAX_SHADOW_BLUR: u8 = 50;
+/// Maximum offset of the shadow in pixels.
+const MAX_SHADOW_OFFSET: u8 = 50;
+/// Expected format of the `shadow` parameter.
+pub const SHADOW_FORMAT: &str = "<blur 0-50>,<offset 0-50>,<colour like 000000 or 00000080>";
+
+/// Darken the corners by `strength` percent, fading towards the centre.
+pub fn vignette(image: &VipsImage, strength: u8) -> anyhow::Result<VipsImage> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    let (width, height) = (image.get_width(), image.get_height());
+    let (centre_x, centre_y) = (f64::from(width) / 2.0, f64::from(height) / 2.0);
+
+    // Squared distance from the centre, 1 at the corners.
+    let coordinates = ops::xyz(width, height)?;
+    let relative = ops::linear(
+        &coordinates,
+        &mut [1.0 / centre_x, 1.0 / centre_y],
+        &mut [-1.0, -1.0],
+    )?;
+    let distance = ops::bandmean(&ops::multiply(&relative, &relative)?)?;
+    let distance = ops::linear(&distance, &mut [2.0], &mut [0.0])?;
+    let factor = ops::linear(&distance, &mut [-f64::from(strength) / 200.0], &mut [1.0])?;
+
+    // Alpha stays as is.
+    let factor = match image.image_hasalpha() {
+        true => ops::bandjoin_const(&factor, &mut [1.0])?,
+        false => factor,
+    };
+    let darkened = ops::multiply(&image, &factor)?;
+    let darkened = ops::cast(&darkened, ops::BandFormat::Uchar)?;
+    Ok(ops::copy_with_opts(
+        &darkened,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?)
+}
+
+/// Drop shadow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shadow {
+    /// Sigma of the Gaussian blur.
+    pub blur: u8,
+    /// Shift to the right and down in pixels.
+    pub offset: u8,
+    /// RGBA colour.
+    pub colour: [u8; 4],
+}
+
+impl fmt::Display for Shadow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b, a] = self.colour;
+        write!(
+            f,
+            "{},{},{r:02x}{g:02x}{b:02x}{a:02x}",
+            self.blur, self.offset
+        )
+    }
+}
+
+impl Shadow {
+    /// Parse the value of the `shadow` parameter, see `SHADOW_FORMAT`.
+    /// The colour is given as hex RGB or RGBA, without '#'.
+    pub fn parse(value: &str) -> Option<Shadow> {
+        let mut parts = value.split(',');
+        let blur = parts.next()?.trim().parse().ok()?;
+        let offset = parts.next()?.trim().parse().ok()?;
+        let colour = parts.next()?.trim();
+        if parts.next().is_some() || blur > MAX_SHADOW_BLUR || offset > MAX_SHADOW_OFFSET {
+            return None;
+        }
+        if !matches!(colour.len(), 6 | 8) || !colour.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let channel = |index: usize| {
+            colour
+                .get(index * 2..index * 2 + 2)
+                .map_or(Some(255), |hex| u8::from_str_radix(hex, 16).ok())
+        };
+        Some(Shadow {
+            blur,
+            offset,
+            colour: [channel(0)?, channel(1)?, channel(2)?, channel(3)?],
+        })
+    }
+}
+
+/// Put the image onto an expanded canvas with the shadow.
+/// `opaque` flattens the result onto white, for formats without transparency.
+pub fn shadow(image: &VipsImage, shadow: &Shadow, opaque: bool) -> anyhow::Result<VipsImage> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    let image = match image.image_hasalpha() {
+        true => image,
+        false => ops::bandjoin_const(&image, &mut [255.0])?,
+    };
+    let (width, height) = (image.get_width(), image.get_height());
+    // Room for the blur (3 sigma) and the offset.
+    let margin = 3 * i32::from(shadow.blur) + i32::from(shadow.offset);
+    let (canvas_width, canvas_height) = (width + 2 * margin, height + 2 * margin);
+
+    // The shape of the shadow is the alpha of the image.
+    let alpha = ops::extract_band(&image, 3)?;
+    let offset = margin + i32::from(shadow.offset);
+    let shape = ops::embed(&alpha, offset, offset, canvas_width, canvas_height)?;
+    let shape = match shadow.blur {
+        0 => shape,
+        blur => ops::gaussblur(&shape, f64::from(blur))?,
+    };
+    let [r, g, b, a] = shadow.colour.map(f64::from);
+    let shape = ops::linear(&shape, &mut [a / 255.0], &mut [0.0])?;
+    let colour = ops::linear(
+        &ops::black_with_opts(canvas_width, canvas_height, &ops::BlackOptions { bands: 3 })?,
+        &mut [1.0, 1.0, 1.0],
+        &mut [r, g, b],
+    )?;
+    let shadow_image = ops::cast(
+        &ops::bandjoin(&mut [colour, shape])?,
+        ops::BandFormat::Uchar,
+    )?;
+    let shadow_image = ops::copy_with_opts(
+        &shadow_image,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?;
+
+    let result = ops::composite_2_with_opts(
+        &shadow_image,
+        &image,
+        ops::BlendMode::Over,
+        &ops::Composite2Options {
+            x: margin,
+            y: margin,
+            ..ops::Composite2Options::default()
+        },
+    )?;
+    let result = ops::cast(&result, ops::BandFormat::Uchar)?;
+    match opaque {
+        true => Ok(ops::flatten_with_opts(
+            &result,
+            &ops::FlattenOptions {
+                background: vec![255.0, 255.0, 255.0],
+                ..ops::FlattenOptions::default()
+            },
+        )?),
+        false => Ok(result),
+    }
+}
@@ -0,0 +1,223 @@
+//! Default options of the image encoders.
+//!
+//! Options are defined in the config file, for example:
+//!
+//! ```toml
+//! [encoders.webp]
+//! effort = 6
+//!
+//! [encoders.jpeg]
+//! progressive = true
+//! subsampling = "off"
+//! ```
+use anyhow::bail;
+use std::cmp;
+
+/// Options of all encoders.
+#[derive(Debug, Clone, Default, serde::Deserialize, PartialEq)]
+#[serde(default)]
+pub struct EncoderOptions {
+    pub webp: WebpOptions,
+    pub jpeg: JpegOptions,
+    pub avif: AvifOptions,
+    pub png: PngOptions,
+    pub gif: GifOptions,
+    pub tiff: TiffOptions,
+    #[cfg(feature = "jxl")]
+    pub jxl: JxlOptions,
+}
+
+impl EncoderOptions {
+    /// Options of the `fast` speed profile: efforts are capped and slow extras are off.
+    pub fn fast(&self) -> EncoderOptions {
+        let mut options = self.clone();
+        options.webp.effort = cmp::min(options.webp.effort, 2);
+        options.webp.smart_subsample = false;
+        options.jpeg.optimize_coding = false;
+        options.avif.effort = cmp::min(options.avif.effort, 1);
+        options.png.compression = cmp::min(options.png.compression, 3);
+        options.gif.effort = cmp::min(options.gif.effort, 3);
+        #[cfg(feature = "jxl")]
+        {
+            options.jxl.effort = cmp::min(options.jxl.effort, 3);
+        }
+        options
+    }
+}
+
+/// Chroma subsampling.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Subsampling {
+    /// Subsample unless the quality is high.
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+/// Kind of images the WebP encoder is tuned for.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebpPreset {
+    Default,
+    Picture,
+    #[default]
+    Photo,
+    Drawing,
+    Icon,
+    Text,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WebpOptions {
+    /// Encoder tuning (default: photo).
+    pub preset: WebpPreset,
+    /// CPU effort, 0-6 (default: 4).
+    pub effort: u8,
+    /// Use high quality chroma subsampling (default: false).
+    pub smart_subsample: bool,
+}
+
+impl Default for WebpOptions {
+    fn default() -> WebpOptions {
+        WebpOptions {
+            preset: WebpPreset::Photo,
+            effort: 4,
+            smart_subsample: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct JpegOptions {
+    /// Progressive (interlaced) JPEG (default: false).
+    pub progressive: bool,
+    /// Chroma subsampling (default: auto).
+    pub subsampling: Subsampling,
+    /// Compute optimal Huffman tables (default: false).
+    pub optimize_coding: bool,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AvifOptions {
+    /// CPU effort, 0-9, higher is slower and smaller (default: 4).
+    pub effort: u8,
+    /// Chroma subsampling (default: auto).
+    pub subsampling: Subsampling,
+}
+
+impl Default for AvifOptions {
+    fn default() -> AvifOptions {
+        AvifOptions {
+            effort: 4,
+            subsampling: Subsampling::Auto,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PngOptions {
+    /// Compression level, 0-9 (default: 6).
+    pub compression: u8,
+    /// Quantise to an 8-bit palette, uses the requested quality (default: false).
+    pub palette: bool,
+}
+
+impl Default for PngOptions {
+    fn default() -> PngOptions {
+        PngOptions {
+            compression: 6,
+            palette: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, PartialEq)]
+#[serde(default)]
+pub struct GifOptions {
+    /// Amount of dithering, 0-1 (default: 1).
+    pub dither: f64,
+    /// Bits per pixel of the palette, 1-8 (default: 8).
+    pub bitdepth: u8,
+    /// CPU effort of the quantisation, 1-10 (default: 7).
+    pub effort: u8,
+}
+
+impl Default for GifOptions {
+    fn default() -> GifOptions {
+        GifOptions {
+            dither: 1.0,
+            bitdepth: 8,
+            effort: 7,
+        }
+    }
+}
+
+/// Compression of TIFF images.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TiffCompression {
+    None,
+    /// Lossy, uses the requested quality.
+    Jpeg,
+    #[default]
+    Deflate,
+    Lzw,
+    /// Lossy, uses the requested quality.
+    Webp,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TiffOptions {
+    /// Compression (default: deflate).
+    pub compression: TiffCompression,
+}
+
+#[cfg(feature = "jxl")]
+#[derive(Debug, Clone, serde::Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct JxlOptions {
+    /// CPU effort, 1-9 (default: 7).
+    pub effort: u8,
+}
+
+#[cfg(feature = "jxl")]
+impl Default for JxlOptions {
+    fn default() -> JxlOptions {
+        JxlOptions { effort: 7 }
+    }
+}
+
+/// Check the ranges of the options.
+pub fn check(options: &EncoderOptions) -> anyhow::Result<()> {
+    if options.webp.effort > 6 {
+        bail!("WebP effort must be between 0 and 6");
+    }
+    if options.avif.effort > 9 {
+        bail!("AVIF effort must be between 0 and 9");
+    }
+    if options.png.compression > 9 {
+        bail!("PNG compression must be between 0 and 9");
+    }
+    if !(0.0..=1.0).contains(&options.gif.dither) {
+        bail!("GIF dither must be between 0 and 1");
+    }
+    if !(1..=8).contains(&options.gif.bitdepth) {
+        bail!("GIF bit depth must be between 1 and 8");
+    }
+    if !(1..=10).contains(&options.gif.effort) {
+        bail!("GIF effort must be between 1 and 10");
+    }
+    #[cfg(feature = "jxl")]
+    if !(1..=9).contains(&options.jxl.effort) {
+        bail!("JPEG XL effort must be between 1 and 9");
+    }
+    Ok(())
+}
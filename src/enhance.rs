@@ -0,0 +1,79 @@
+//! Automatic enhancement (`enhance`).
+//!
+//! Dim and tinted phone photos get a contrast stretch and a white balance correction,
+//! both limited, so that well exposed images stay almost unchanged:
+//! - the darkest and brightest `CLIP_PERCENT` of the brightness are clipped and the rest
+//!   is stretched to the full range, with the gain at most `MAX_GAIN` (the black point
+//!   is kept then, so that dark scenes don't turn grey);
+//! - colour channels are scaled towards equal means (grey world),
+//!   by at most `MAX_BALANCE` in either direction.
+use libvips::{ops, VipsImage};
+
+/// Share of the darkest and of the brightest pixels clipped by the stretch, in percent.
+const CLIP_PERCENT: f64 = 0.5;
+/// Maximum contrast gain.
+const MAX_GAIN: f64 = 2.0;
+/// Maximum white balance correction of each channel (1.25 is +25% or -20%).
+const MAX_BALANCE: f64 = 1.25;
+
+/// Enhance the image.
+pub fn apply(image: &VipsImage) -> anyhow::Result<VipsImage> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    // Alpha is kept as is.
+    let (colour, alpha) = match image.image_hasalpha() {
+        true => (
+            ops::extract_band_with_opts(
+                &image,
+                0,
+                &ops::ExtractBandOptions {
+                    n: image.get_bands() - 1,
+                },
+            )?,
+            Some(ops::extract_band(&image, image.get_bands() - 1)?),
+        ),
+        false => (image, None),
+    };
+    let bands = colour.get_bands() as usize;
+
+    // Contrast stretch by the brightness, so that the colours don't shift.
+    let brightness = ops::colourspace(&colour, ops::Interpretation::BW)?;
+    let low = f64::from(ops::percent(&brightness, CLIP_PERCENT)?);
+    let high = f64::from(ops::percent(&brightness, 100.0 - CLIP_PERCENT)?);
+    let (gain, offset) = match high > low {
+        true => {
+            let gain = (255.0 / (high - low)).min(MAX_GAIN);
+            (gain, -gain * low)
+        }
+        // Single colour images are left as they are.
+        false => (1.0, 0.0),
+    };
+
+    // Grey world white balance, grey images have equal means already.
+    let mut means = Vec::with_capacity(bands);
+    for band in 0..bands {
+        means.push(ops::avg(&ops::extract_band(&colour, band as i32)?)?);
+    }
+    let grey = means.iter().sum::<f64>() / bands as f64;
+    let balance = means.iter().map(|mean| match *mean > 0.0 {
+        true => (grey / mean).clamp(1.0 / MAX_BALANCE, MAX_BALANCE),
+        false => 1.0,
+    });
+
+    let mut multipliers: Vec<f64> = balance.map(|factor| factor * gain).collect();
+    let mut offsets = vec![offset; bands];
+    let enhanced = ops::linear(&colour, &mut multipliers, &mut offsets)?;
+    // Values outside the range are clipped.
+    let enhanced = ops::cast(&enhanced, ops::BandFormat::Uchar)?;
+    let enhanced = match alpha {
+        Some(alpha) => ops::bandjoin(&mut [enhanced, alpha])?,
+        None => enhanced,
+    };
+    Ok(ops::copy_with_opts(
+        &enhanced,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?)
+}
@@ -1,15 +1,35 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Json, Response},
 };
+use log::error;
+use mobc_redis::redis::RedisError;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
-use std::fmt;
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use uuid::Uuid;
+
+/// Return details of server-side errors to clients?
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable details of server-side errors in responses.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
 
 /// Custom error class.
 #[derive(Debug, Clone)]
 pub struct HttpError {
     pub status_code: StatusCode,
     pub message: String,
+    /// Value of the 'Retry-After' header in seconds.
+    pub retry_after: Option<u64>,
+    /// ID of the server-side error, the details are logged under this ID.
+    pub error_id: Option<String>,
+    /// Name of the request field that caused the error.
+    pub field: Option<String>,
 }
 
 impl HttpError {
@@ -17,6 +37,29 @@ impl HttpError {
         HttpError {
             status_code: StatusCode::BAD_REQUEST,
             message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    pub fn unauthorized(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::UNAUTHORIZED,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    pub fn forbidden(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::FORBIDDEN,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
         }
     }
 
@@ -24,25 +67,194 @@ impl HttpError {
         HttpError {
             status_code: StatusCode::NOT_FOUND,
             message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    pub fn conflict(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::CONFLICT,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    pub fn unprocessable_entity(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::UNPROCESSABLE_ENTITY,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    /// 422 for images that can't be processed.
+    /// `detail` (usually a libvips error) is logged, clients get only the error ID.
+    pub fn unprocessable_image(detail: &str) -> HttpError {
+        HttpError::server_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Image could not be processed",
+            detail,
+        )
+    }
+
+    pub fn payload_too_large(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::PAYLOAD_TOO_LARGE,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    pub fn unsupported_media_type(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    /// `detail` is logged, clients get only the error ID (see `server_error`).
+    pub fn internal_server_error(detail: &str) -> HttpError {
+        HttpError::server_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error",
+            detail,
+        )
+    }
+
+    pub fn bad_gateway(message: &str) -> HttpError {
+        HttpError {
+            status_code: StatusCode::BAD_GATEWAY,
+            message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+
+    /// 503 asking the client to retry the request later.
+    pub fn overloaded(message: &str, retry_after: u64) -> HttpError {
+        HttpError {
+            status_code: StatusCode::SERVICE_UNAVAILABLE,
+            message: message.to_string(),
+            retry_after: Some(retry_after),
+            error_id: None,
+            field: None,
         }
     }
 
-    pub fn internal_server_error(message: &str) -> HttpError {
+    pub fn service_unavailable(message: &str) -> HttpError {
         HttpError {
-            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            status_code: StatusCode::SERVICE_UNAVAILABLE,
             message: message.to_string(),
+            retry_after: None,
+            error_id: None,
+            field: None,
+        }
+    }
+}
+
+impl HttpError {
+    /// Name the request field that caused the error.
+    pub fn with_field(mut self, field: &str) -> HttpError {
+        self.field = Some(field.to_string());
+        self
+    }
+
+    /// Server-side error.
+    /// The detail is logged with a new error ID and replaced with the generic message,
+    /// unless verbose errors are enabled.
+    pub fn server_error(status_code: StatusCode, message: &str, detail: &str) -> HttpError {
+        let error_id = Uuid::new_v4().simple().to_string();
+        error!("Error {error_id}: {detail}");
+
+        let message = match VERBOSE.load(Ordering::Relaxed) {
+            true => format!("{message}: {detail}"),
+            false => message.to_string(),
+        };
+        HttpError {
+            status_code,
+            message,
+            retry_after: None,
+            error_id: Some(error_id),
+            field: None,
         }
     }
 }
 
+/// Redis pool errors mean that the cache is down.
+impl From<mobc::Error<RedisError>> for HttpError {
+    fn from(err: mobc::Error<RedisError>) -> HttpError {
+        HttpError::server_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Cache is unavailable",
+            &err.to_string(),
+        )
+    }
+}
+
+impl From<RedisError> for HttpError {
+    fn from(err: RedisError) -> HttpError {
+        if err.is_io_error()
+            || err.is_connection_refusal()
+            || err.is_connection_dropped()
+            || err.is_timeout()
+        {
+            HttpError::server_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Cache is unavailable",
+                &err.to_string(),
+            )
+        } else {
+            HttpError::internal_server_error(&err.to_string())
+        }
+    }
+}
+
+impl From<std::io::Error> for HttpError {
+    fn from(err: std::io::Error) -> HttpError {
+        HttpError::internal_server_error(&err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for HttpError {
+    fn from(err: serde_json::Error) -> HttpError {
+        HttpError::internal_server_error(&err.to_string())
+    }
+}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(err: anyhow::Error) -> HttpError {
+        HttpError::internal_server_error(&err.to_string())
+    }
+}
+
 impl Serialize for HttpError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("HttpError", 2)?;
+        let mut state = serializer.serialize_struct("HttpError", 4)?;
         state.serialize_field("status_code", &self.status_code.as_u16())?;
         state.serialize_field("message", &self.message)?;
+        match &self.error_id {
+            Some(error_id) => state.serialize_field("error_id", error_id)?,
+            None => state.skip_field("error_id")?,
+        }
+        match &self.field {
+            Some(field) => state.serialize_field("field", field)?,
+            None => state.skip_field("field")?,
+        }
         state.end()
     }
 }
@@ -55,6 +267,14 @@ impl fmt::Display for HttpError {
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
-        (self.status_code, Json(self)).into_response()
+        match self.retry_after {
+            Some(retry_after) => (
+                self.status_code,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(self),
+            )
+                .into_response(),
+            None => (self.status_code, Json(self)).into_response(),
+        }
     }
 }
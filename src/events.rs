@@ -0,0 +1,39 @@
+//! Image events (uploads, deletions, purges).
+//!
+//! Events are broadcast to subscribers of the `/admin/events` stream of this node.
+use crate::clock::unix_now;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of events kept for slow subscribers.
+pub const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Upload,
+    Delete,
+    Restore,
+    /// Deleted image was permanently removed.
+    Purge,
+    /// Cached versions of the image were removed.
+    CachePurge,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageEvent {
+    pub kind: EventKind,
+    pub hash: String,
+    /// Unix timestamp.
+    pub time: u64,
+}
+
+/// Notify subscribers about the event.
+pub fn publish(sender: &broadcast::Sender<ImageEvent>, kind: EventKind, hash: &str) {
+    // Sending fails only if there are no subscribers.
+    let _ = sender.send(ImageEvent {
+        kind,
+        hash: hash.to_string(),
+        time: unix_now(),
+    });
+}
@@ -0,0 +1,184 @@
+//! Invisible watermark with the licensee id.
+//!
+//! The `licensee` parameter is embedded into the output before encoding, so that
+//! leaked images can be traced with `canvas watermark extract <file>`.
+//!
+//! The image is split into 8x8 blocks, and the mean brightness of each block is moved
+//! to the nearest multiple of `invisible_watermark_strength` (bit 0) or to the middle
+//! between two multiples (bit 1). Block means survive re-encoding with lossy formats
+//! at usual qualities; resizing, cropping or palette formats (GIF) destroy the mark.
+//! Bits of the payload repeat over the blocks and are read by majority vote.
+use anyhow::anyhow;
+use libvips::{ops, VipsImage};
+
+/// Query parameter with the licensee id.
+pub const LICENSEE_PARAM: &str = "licensee";
+/// Maximum length of licensee ids, the payload has a fixed size.
+pub const MAX_LICENSEE_LENGTH: usize = 16;
+/// Bits of the payload.
+const PAYLOAD_BITS: usize = MAX_LICENSEE_LENGTH * 8;
+/// Side of the blocks in pixels.
+const BLOCK: usize = 8;
+/// Share of matching bits for `verify`.
+const MATCH_THRESHOLD: f64 = 0.9;
+
+/// Check if the licensee id is valid: 1-16 latin letters, digits, '-' or '_'.
+pub fn is_valid_licensee(licensee: &str) -> bool {
+    !licensee.is_empty()
+        && licensee.len() <= MAX_LICENSEE_LENGTH
+        && licensee
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// Check the strength of the watermark.
+pub fn check(strength: f64) -> anyhow::Result<()> {
+    if !(2.0..=32.0).contains(&strength) {
+        return Err(anyhow!("Invisible watermark strength must be from 2 to 32"));
+    }
+    Ok(())
+}
+
+/// Embed the licensee id into the image.
+/// Images with fewer blocks than the payload bits are returned unchanged.
+pub fn embed(image: &VipsImage, licensee: &str, strength: f64) -> anyhow::Result<VipsImage> {
+    let (mut pixels, width, height, bands) = read_pixels(image)?;
+    if (width / BLOCK) * (height / BLOCK) < PAYLOAD_BITS {
+        return Ok(ops::copy(image)?);
+    }
+    let payload = payload_bits(licensee);
+
+    for (index, (x, y)) in blocks(width, height).enumerate() {
+        let bit = payload[index % PAYLOAD_BITS];
+        let mean = block_mean(&pixels, width, bands, x, y);
+        let shift = quantize(mean, bit, strength) - mean;
+        for row in y..y + BLOCK {
+            for column in x..x + BLOCK {
+                let pixel = (row * width + column) * bands;
+                // Equal shift of the colour bands shifts the brightness by the same amount.
+                for value in &mut pixels[pixel..pixel + 3] {
+                    *value = (f64::from(*value) + shift).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+
+    let marked = VipsImage::new_from_memory(
+        &pixels,
+        width as i32,
+        height as i32,
+        bands as i32,
+        ops::BandFormat::Uchar,
+    )?;
+    Ok(ops::copy_with_opts(
+        &marked,
+        &ops::CopyOptions {
+            interpretation: ops::Interpretation::Srgb,
+            ..ops::CopyOptions::default()
+        },
+    )?)
+}
+
+/// Read the licensee id from the image.
+/// Returns `None` if the image has no readable watermark.
+pub fn extract(image: &VipsImage, strength: f64) -> anyhow::Result<Option<String>> {
+    let bits = match read_bits(image, strength)? {
+        Some(bits) => bits,
+        None => return Ok(None),
+    };
+    let bytes: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| {
+            byte.iter()
+                .fold(0, |value, bit| (value << 1) | u8::from(*bit))
+        })
+        .take_while(|byte| *byte != 0)
+        .collect();
+    Ok(String::from_utf8(bytes)
+        .ok()
+        .filter(|licensee| is_valid_licensee(licensee)))
+}
+
+/// Share of the payload bits of the licensee found in the image, from 0 to 1.
+/// Tolerates some damage that makes `extract` fail.
+pub fn verify(image: &VipsImage, licensee: &str, strength: f64) -> anyhow::Result<(bool, f64)> {
+    let bits = match read_bits(image, strength)? {
+        Some(bits) => bits,
+        None => return Ok((false, 0.0)),
+    };
+    let expected = payload_bits(licensee);
+    let matching = bits.iter().zip(&expected).filter(|(a, b)| a == b).count();
+    let share = matching as f64 / PAYLOAD_BITS as f64;
+    Ok((share >= MATCH_THRESHOLD, share))
+}
+
+/// Vote for each payload bit with all blocks carrying it.
+fn read_bits(image: &VipsImage, strength: f64) -> anyhow::Result<Option<Vec<bool>>> {
+    let (pixels, width, height, bands) = read_pixels(image)?;
+    if (width / BLOCK) * (height / BLOCK) < PAYLOAD_BITS {
+        return Ok(None);
+    }
+    let mut votes = vec![0i64; PAYLOAD_BITS];
+    for (index, (x, y)) in blocks(width, height).enumerate() {
+        let mean = block_mean(&pixels, width, bands, x, y);
+        let remainder = mean.rem_euclid(strength);
+        let to_zero = remainder.min(strength - remainder);
+        let to_one = (remainder - strength / 2.0).abs();
+        votes[index % PAYLOAD_BITS] += if to_one < to_zero { 1 } else { -1 };
+    }
+    Ok(Some(votes.into_iter().map(|vote| vote > 0).collect()))
+}
+
+/// Pixels of the image as 8-bit sRGB, with the width, height and number of bands.
+fn read_pixels(image: &VipsImage) -> anyhow::Result<(Vec<u8>, usize, usize, usize)> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    let pixels = image.image_write_to_memory();
+    Ok((
+        pixels,
+        image.get_width() as usize,
+        image.get_height() as usize,
+        image.get_bands() as usize,
+    ))
+}
+
+/// Top left corners of the whole blocks, row by row.
+fn blocks(width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..height / BLOCK)
+        .flat_map(move |row| (0..width / BLOCK).map(move |column| (column * BLOCK, row * BLOCK)))
+}
+
+/// Mean brightness of the block.
+fn block_mean(pixels: &[u8], width: usize, bands: usize, x: usize, y: usize) -> f64 {
+    let mut sum = 0.0;
+    for row in y..y + BLOCK {
+        for column in x..x + BLOCK {
+            let pixel = (row * width + column) * bands;
+            let [r, g, b] = [pixels[pixel], pixels[pixel + 1], pixels[pixel + 2]].map(f64::from);
+            sum += 0.299 * r + 0.587 * g + 0.114 * b;
+        }
+    }
+    sum / (BLOCK * BLOCK) as f64
+}
+
+/// Nearest value carrying the bit.
+fn quantize(mean: f64, bit: bool, strength: f64) -> f64 {
+    let offset = if bit { strength / 2.0 } else { 0.0 };
+    let target = ((mean - offset) / strength).round() * strength + offset;
+    // Blocks close to black or white cannot move outside the range.
+    match target {
+        target if target < 0.0 => target + strength,
+        target if target > 255.0 => target - strength,
+        target => target,
+    }
+}
+
+/// The licensee id padded with zero bytes, most significant bits first.
+fn payload_bits(licensee: &str) -> Vec<bool> {
+    let mut bytes = licensee.as_bytes().to_vec();
+    bytes.resize(MAX_LICENSEE_LENGTH, 0);
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| (byte >> bit) & 1 == 1))
+        .collect()
+}
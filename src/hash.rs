@@ -0,0 +1,14 @@
+//! Image hashes (ids).
+use sha2::{Digest, Sha256};
+
+/// Calculate the hash of the file (hex-encoded SHA-256).
+pub fn compute(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Check if the value looks like a hash: 64 lowercase hex digits.
+pub fn is_valid(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
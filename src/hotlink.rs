@@ -0,0 +1,40 @@
+//! Hotlink protection.
+//!
+//! Checks the `Origin` (or `Referer`) header of the request against the list of allowed hosts.
+use axum::http::{header, HeaderMap, Uri};
+
+/// Check if the request comes from an allowed site.
+///
+/// Entries of the allowlist are host names (`example.com`) or wildcards (`*.example.com`).
+/// Requests without `Origin` and `Referer` headers (direct navigation, some apps)
+/// are allowed only if `allow_empty` is set.
+pub fn is_allowed(allowlist: &[String], headers: &HeaderMap, allow_empty: bool) -> bool {
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|value| value.to_str().ok());
+
+    let source = match source {
+        Some(source) => source,
+        None => return allow_empty,
+    };
+
+    let host = match source.parse::<Uri>() {
+        Ok(uri) => match uri.host() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+
+    allowlist.iter().any(|allowed| host_matches(&host, allowed))
+}
+
+/// Check if the host matches the allowlist entry (`example.com` or `*.example.com`).
+pub fn host_matches(host: &str, allowed: &str) -> bool {
+    let allowed = allowed.to_lowercase();
+    match allowed.strip_prefix("*.") {
+        Some(domain) => host == domain || host.ends_with(&format!(".{domain}")),
+        None => host == allowed,
+    }
+}
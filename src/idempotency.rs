@@ -0,0 +1,101 @@
+//! Idempotency keys.
+//!
+//! Responses of requests with the 'Idempotency-Key' header are stored in Redis
+//! under `idempotency:<key>` keys, retries get the stored response.
+//!
+//! The key is claimed with `SET NX` before the request is processed, so that concurrent
+//! retries don't repeat its side effects, and it is bound to the fingerprint of the request:
+//! the same key with another request is rejected instead of getting a wrong response.
+use mobc_redis::redis::{self, aio::Connection, AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+
+/// Header with the key.
+pub const HEADER: &str = "Idempotency-Key";
+/// How long to keep responses, in seconds.
+const TTL_SECS: usize = 24 * 60 * 60;
+/// How long a claim of a request that never finished blocks retries, in seconds.
+const CLAIM_TTL_SECS: usize = 10 * 60;
+
+/// Stored state of the request.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Fingerprint of the request, see `claim`.
+    fingerprint: String,
+    /// Response as JSON, `None` while the request is processed.
+    response: Option<String>,
+}
+
+/// Result of claiming the key.
+pub enum Claim {
+    /// The request should be processed.
+    New,
+    /// The request with this key is being processed.
+    InProgress,
+    /// The request was processed, the stored response should be returned.
+    Done(String),
+    /// The key was used with another request.
+    Mismatch,
+}
+
+fn key(idempotency_key: &str) -> String {
+    format!("idempotency:{idempotency_key}")
+}
+
+fn entry(fingerprint: &str, response: Option<&str>) -> String {
+    let entry = Entry {
+        fingerprint: fingerprint.to_string(),
+        response: response.map(|response| response.to_string()),
+    };
+    serde_json::to_string(&entry).unwrap_or_default()
+}
+
+/// Claim the key for the request with the fingerprint (a hash of its body).
+pub async fn claim(
+    con: &mut Connection,
+    idempotency_key: &str,
+    fingerprint: &str,
+) -> RedisResult<Claim> {
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(key(idempotency_key))
+        .arg(entry(fingerprint, None))
+        .arg("NX")
+        .arg("EX")
+        .arg(CLAIM_TTL_SECS)
+        .query_async(con)
+        .await?;
+    if claimed.is_some() {
+        return Ok(Claim::New);
+    }
+
+    let stored: Option<String> = con.get(key(idempotency_key)).await?;
+    let entry: Option<Entry> = stored.and_then(|stored| serde_json::from_str(&stored).ok());
+    Ok(match entry {
+        Some(entry) if entry.fingerprint != fingerprint => Claim::Mismatch,
+        Some(Entry {
+            response: Some(response),
+            ..
+        }) => Claim::Done(response),
+        // Expired just now, the client retries.
+        _ => Claim::InProgress,
+    })
+}
+
+/// Store the response of the processed request.
+pub async fn save(
+    con: &mut Connection,
+    idempotency_key: &str,
+    fingerprint: &str,
+    response: &str,
+) -> RedisResult<()> {
+    con.set_ex(
+        key(idempotency_key),
+        entry(fingerprint, Some(response)),
+        TTL_SECS,
+    )
+    .await
+}
+
+/// Release the key of a failed request, so that a retry processes it again.
+pub async fn release(con: &mut Connection, idempotency_key: &str) -> RedisResult<()> {
+    con.del(key(idempotency_key)).await
+}
@@ -0,0 +1,241 @@
+//! imgproxy-compatible URLs.
+//!
+//! URLs in the imgproxy format are translated into Canvas parameters:
+//! `/imgproxy/<signature>/<option>:<args>/.../plain/<image>[@<extension>]`
+//! or `/imgproxy/<signature>/<option>:<args>/.../<base64url-encoded image>[.<extension>]`.
+//! The signature is a base64url-encoded HMAC-SHA256 of the salt and the rest of the path,
+//! the image is a hash or a slug.
+use crate::{
+    pipeline::{Operation, PIPELINE_PARAM},
+    preset::PRESET_PARAM,
+    AppConfig,
+};
+use anyhow::bail;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signatures of unsigned URLs.
+const UNSAFE: [&str; 2] = ["insecure", "_"];
+
+/// Parsed imgproxy URL.
+#[derive(Debug)]
+pub struct ImgproxyUrl {
+    /// The URL has a valid signature.
+    pub signed: bool,
+    /// Image hash or slug, without the extension.
+    pub image: String,
+    /// Equivalent query parameters of `GET /images/:hash`.
+    pub params: HashMap<String, String>,
+}
+
+/// Check if imgproxy-compatible URLs are enabled.
+pub fn is_enabled(cfg: &AppConfig) -> bool {
+    cfg.imgproxy_key.is_some() || cfg.imgproxy_allow_unsafe
+}
+
+/// Check the key and the salt.
+pub fn check(cfg: &AppConfig) -> anyhow::Result<()> {
+    match (&cfg.imgproxy_key, &cfg.imgproxy_salt) {
+        (Some(key), Some(salt)) => {
+            if hex::decode(key).is_err() || hex::decode(salt).is_err() {
+                bail!("imgproxy key and salt must be hex-encoded");
+            }
+        }
+        (None, None) => {}
+        _ => bail!("imgproxy key and salt must be set together"),
+    }
+    Ok(())
+}
+
+/// Parse the path of an imgproxy URL (without the '/imgproxy' prefix).
+/// Returns `None` if the path is invalid or its signature is not accepted.
+pub fn parse(path: &str, cfg: &AppConfig) -> Option<ImgproxyUrl> {
+    let (signature, rest) = path.trim_start_matches('/').split_once('/')?;
+    let signed = match (&cfg.imgproxy_key, &cfg.imgproxy_salt) {
+        (Some(key), Some(salt)) if verify(key, salt, rest, signature) => true,
+        _ if cfg.imgproxy_allow_unsafe && UNSAFE.contains(&signature) => false,
+        _ => return None,
+    };
+
+    let mut options = Options::default();
+    let mut segments = rest.split('/');
+    let image = loop {
+        let segment = segments.next()?;
+        if segment == "plain" {
+            let source: Vec<&str> = segments.collect();
+            let source = source.join("/");
+            break match source.rsplit_once('@') {
+                Some((image, extension)) => {
+                    options.format = Some(extension.to_string());
+                    image.to_string()
+                }
+                None => source,
+            };
+        }
+        match segment.split_once(':') {
+            Some((name, args)) => options.apply(name, &args.split(':').collect::<Vec<_>>()),
+            // Encoded source, the rest of the path is a part of it.
+            None => {
+                let (encoded, extension) = match segment.split_once('.') {
+                    Some((encoded, extension)) => (encoded, Some(extension)),
+                    None => (segment, None),
+                };
+                if let Some(extension) = extension {
+                    options.format = Some(extension.to_string());
+                }
+                let decoded = URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')).ok()?;
+                break String::from_utf8(decoded).ok()?;
+            }
+        }
+    };
+    if image.is_empty() {
+        return None;
+    }
+
+    Some(ImgproxyUrl {
+        signed,
+        image,
+        params: options.into_params(),
+    })
+}
+
+/// Check the signature of the URL (HMAC-SHA256 of the salt and the path after the signature).
+fn verify(key: &str, salt: &str, path: &str, signature: &str) -> bool {
+    let (key, salt) = match (hex::decode(key), hex::decode(salt)) {
+        (Ok(key), Ok(salt)) => (key, salt),
+        _ => return false,
+    };
+    let signature = match URL_SAFE_NO_PAD.decode(signature.trim_end_matches('=')) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any size");
+    mac.update(&salt);
+    mac.update(b"/");
+    mac.update(path.as_bytes());
+    // Signatures can be truncated.
+    !signature.is_empty() && mac.verify_truncated_left(&signature).is_ok()
+}
+
+/// Processing options collected from the path.
+#[derive(Default)]
+struct Options {
+    resizing_type: Option<String>,
+    width: u16,
+    height: u16,
+    dpr: Option<f64>,
+    quality: Option<String>,
+    format: Option<String>,
+    watermark: bool,
+    preset: Option<String>,
+    page: Option<String>,
+    /// Operations applied after resizing.
+    operations: Vec<Operation>,
+}
+
+impl Options {
+    /// Apply the option (`<name>:<arg1>:<arg2>...`), unsupported options are ignored.
+    fn apply(&mut self, name: &str, args: &[&str]) {
+        let arg = |index: usize| args.get(index).copied().filter(|arg| !arg.is_empty());
+        let number = |index: usize| arg(index).and_then(|arg| arg.parse::<u16>().ok());
+        let float = |index: usize| arg(index).and_then(|arg| arg.parse::<f64>().ok());
+
+        match name {
+            "resize" | "rs" => {
+                self.resizing_type = arg(0).map(str::to_string);
+                self.width = number(1).unwrap_or(self.width);
+                self.height = number(2).unwrap_or(self.height);
+            }
+            "size" | "s" => {
+                self.width = number(0).unwrap_or(self.width);
+                self.height = number(1).unwrap_or(self.height);
+            }
+            "resizing_type" | "rt" => self.resizing_type = arg(0).map(str::to_string),
+            "width" | "w" => self.width = number(0).unwrap_or(self.width),
+            "height" | "h" => self.height = number(0).unwrap_or(self.height),
+            "dpr" => self.dpr = float(0),
+            "quality" | "q" => self.quality = arg(0).map(str::to_string),
+            "format" | "f" | "ext" => self.format = arg(0).map(str::to_string),
+            "watermark" | "wm" => self.watermark = float(0).is_some_and(|opacity| opacity > 0.0),
+            "preset" | "pr" => self.preset = arg(0).map(str::to_string),
+            "page" | "pg" => self.page = arg(0).map(str::to_string),
+            "blur" | "bl" => {
+                if let Some(sigma) = float(0).filter(|sigma| *sigma > 0.0) {
+                    self.operations.push(Operation::Blur { sigma });
+                }
+            }
+            "sharpen" | "sh" => {
+                if let Some(sigma) = float(0).filter(|sigma| *sigma > 0.0) {
+                    self.operations.push(Operation::Sharpen { sigma });
+                }
+            }
+            "rotate" | "rot" => {
+                if let Some(angle) = number(0).filter(|angle| *angle > 0) {
+                    self.operations.push(Operation::Rotate { angle });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Convert the options into query parameters.
+    fn into_params(self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+
+        let scale = |side: u16| match self.dpr {
+            Some(dpr) if dpr > 0.0 => {
+                (f64::from(side) * dpr).round().min(f64::from(u16::MAX)) as u16
+            }
+            _ => side,
+        };
+        let (width, height) = (scale(self.width), scale(self.height));
+
+        // 0 means the side is calculated from the other one.
+        let mut operations = Vec::new();
+        match (width, height, self.resizing_type.as_deref()) {
+            (0, 0, _) => {}
+            (width, height, Some("fit")) | (width, height @ 0, _) | (width @ 0, height, _) => {
+                operations.push(Operation::Resize {
+                    width: if width == 0 { u16::MAX } else { width },
+                    height: if height == 0 { None } else { Some(height) },
+                })
+            }
+            (width, height, _) => operations.push(Operation::Cover { width, height }),
+        }
+        operations.extend(self.operations);
+
+        match operations.is_empty() {
+            // Presets give their own size.
+            true if self.preset.is_some() => {}
+            // Without the size, imgproxy keeps the original size.
+            true => {
+                params.insert("width".to_string(), u16::MAX.to_string());
+                params.insert("height".to_string(), u16::MAX.to_string());
+            }
+            false => {
+                let pipeline: Vec<String> = operations.iter().map(|op| op.to_string()).collect();
+                params.insert(PIPELINE_PARAM.to_string(), pipeline.join("|"));
+            }
+        }
+        if let Some(quality) = self.quality {
+            params.insert("quality".to_string(), quality);
+        }
+        if let Some(format) = self.format {
+            params.insert("format".to_string(), format);
+        }
+        if self.watermark {
+            params.insert("watermark".to_string(), "true".to_string());
+        }
+        if let Some(preset) = self.preset {
+            params.insert(PRESET_PARAM.to_string(), preset);
+        }
+        if let Some(page) = self.page {
+            params.insert("page".to_string(), page);
+        }
+        params
+    }
+}
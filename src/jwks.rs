@@ -0,0 +1,231 @@
+//! JWT validation against a JSON Web Key Set.
+//!
+//! Keys are cached and refreshed when they expire or when a token
+//! is signed with an unknown key (key rotation). Concurrent requests wait for
+//! one refresh, and cached keys keep being used if the key set can't be fetched.
+//! After a failed refresh, the next one waits for `RETRY_INTERVAL`.
+//!
+//! Tokens are accepted only with the algorithms of their key: the `alg` of the key
+//! or the algorithms of its type, never the one the token claims on its own.
+use anyhow::anyhow;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use log::warn;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
+
+/// Keys are not refetched more often than this, even if an unknown key id is seen.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// The key set is not fetched again sooner than this after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Time limits of fetching the key set.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Validated token claims.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    /// Subject (user id).
+    pub sub: String,
+    /// Expiration time.
+    pub exp: u64,
+}
+
+/// Key with the algorithms it can verify.
+#[derive(Clone)]
+struct Key {
+    decoding: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+#[derive(Default)]
+struct CachedKeys {
+    keys: HashMap<String, Key>,
+    fetched_at: Option<Instant>,
+    failed_at: Option<Instant>,
+}
+
+impl CachedKeys {
+    fn recently_failed(&self) -> bool {
+        matches!(self.failed_at, Some(failed_at) if failed_at.elapsed() < RETRY_INTERVAL)
+    }
+}
+
+/// JWKS client with key cache.
+pub struct Jwks {
+    url: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    ttl: Duration,
+    client: reqwest::Client,
+    cache: RwLock<CachedKeys>,
+    /// Held while the key set is fetched.
+    refreshing: Mutex<()>,
+}
+
+impl Jwks {
+    pub fn new(
+        url: String,
+        issuer: Option<String>,
+        audience: Option<String>,
+        ttl: Duration,
+    ) -> Jwks {
+        Jwks {
+            url,
+            issuer,
+            audience,
+            ttl,
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .unwrap(),
+            cache: RwLock::new(CachedKeys::default()),
+            refreshing: Mutex::new(()),
+        }
+    }
+
+    /// Validate the token signature, issuer, audience and expiration time.
+    pub async fn validate(&self, token: &str) -> anyhow::Result<Claims> {
+        let header = decode_header(token)?;
+        let kid = header.kid.ok_or_else(|| anyhow!("Token has no key id"))?;
+        let key = self.get_key(&kid).await?;
+        if !key.algorithms.contains(&header.alg) {
+            return Err(anyhow!(
+                "Algorithm {:?} is not allowed for key {kid}",
+                header.alg
+            ));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = key.algorithms;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        Ok(decode::<Claims>(token, &key.decoding, &validation)?.claims)
+    }
+
+    /// Get the key by id, refreshing the cache if needed.
+    async fn get_key(&self, kid: &str) -> anyhow::Result<Key> {
+        let cache = self.cache.read().await;
+        let age = cache.fetched_at.map(|fetched_at| fetched_at.elapsed());
+        let fresh = matches!(age, Some(age) if age < self.ttl);
+        if fresh {
+            if let Some(key) = cache.keys.get(kid) {
+                return Ok(key.clone());
+            }
+        }
+        let recently_fetched = matches!(age, Some(age) if age < MIN_REFRESH_INTERVAL);
+        let recently_failed = cache.recently_failed();
+        let cache_fetched_at = cache.fetched_at;
+        drop(cache);
+
+        if (!fresh || !recently_fetched) && !recently_failed {
+            self.refresh(cache_fetched_at).await;
+        }
+
+        self.cache
+            .read()
+            .await
+            .keys
+            .get(kid)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown key id {kid}"))
+    }
+
+    /// Refresh the key set, unless another request did it after `fetched_at`
+    /// or has just failed to.
+    /// On failure the cached keys are kept, even if they are expired.
+    async fn refresh(&self, fetched_at: Option<Instant>) {
+        let _refreshing = self.refreshing.lock().await;
+        {
+            let cache = self.cache.read().await;
+            if cache.fetched_at != fetched_at || cache.recently_failed() {
+                return;
+            }
+        }
+        if let Err(err) = self.fetch().await {
+            warn!("Failed to refresh JWKS from {}: {err}", self.url);
+            self.cache.write().await.failed_at = Some(Instant::now());
+        }
+    }
+
+    /// Download the key set.
+    async fn fetch(&self) -> anyhow::Result<()> {
+        let jwks: JwkSet = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut keys = HashMap::new();
+        for jwk in &jwks.keys {
+            if let Some(kid) = &jwk.common.key_id {
+                let algorithms = algorithms(jwk);
+                if algorithms.is_empty() {
+                    warn!("Skipping JWKS key {kid}: unsupported key type");
+                    continue;
+                }
+                let decoding = DecodingKey::from_jwk(jwk)?;
+                keys.insert(
+                    kid.clone(),
+                    Key {
+                        decoding,
+                        algorithms,
+                    },
+                );
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        cache.keys = keys;
+        cache.fetched_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Algorithms the key can verify: its `alg`, or all asymmetric ones of its type.
+/// Symmetric keys are never used, their secret would be public.
+fn algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    let by_type = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => vec![],
+        },
+        AlgorithmParameters::OctetKeyPair(params) => match params.curve {
+            EllipticCurve::Ed25519 => vec![Algorithm::EdDSA],
+            _ => vec![],
+        },
+        AlgorithmParameters::OctetKey(_) => vec![],
+    };
+    match jwk.common.algorithm {
+        Some(alg) => by_type
+            .into_iter()
+            .filter(|&by_type| by_type == alg)
+            .collect(),
+        None => by_type,
+    }
+}
@@ -5,19 +5,24 @@
 //! HTTP API is powered by Axum.
 use axum::{
     extract::DefaultBodyLimit,
-    http::Method,
-    routing::{get, post},
-    Router, Server,
+    handler::Handler,
+    middleware,
+    routing::{get, post, put},
+    Router, Server, ServiceExt,
 };
+use clap::Parser;
 use libvips::VipsApp;
 use mobc::Pool;
 use mobc_redis::RedisConnectionManager;
-use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tower_http::cors::{Any, CorsLayer};
+use storage::Storage;
+use tokio::sync::mpsc;
+use tower::{Layer, ServiceBuilder};
+use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
-use hyper::http::HeaderValue;
-use log::{info, warn};
+use log::{error, info};
 
 // Re-exports
 pub use app_config::AppConfig;
@@ -25,72 +30,360 @@ pub use error::HttpError;
 pub use state::AppState;
 
 // Modules
+mod access;
 mod api;
 mod app_config;
+mod audit;
+mod auth;
+mod bench;
+mod bucket;
+mod budget;
+mod build_info;
+mod cache;
+mod cancel;
+mod capabilities;
+mod clamav;
+mod client_ip;
+mod cli;
+mod clock;
+mod cors;
+mod denoise;
+mod diff;
+mod disk_cache;
+mod effects;
+mod encoder;
+mod enhance;
 mod error;
+mod events;
+mod fingerprint;
+mod hash;
+mod hotlink;
+mod idempotency;
+mod imgproxy;
+mod jwks;
+mod metadata;
+mod metrics;
+mod missing;
+mod moderation;
+mod normalize;
+mod origin;
+mod overlay;
+mod params;
+mod pipeline;
+mod policy;
+mod preset;
+mod progress;
+mod public_url;
+mod quality;
+mod reload;
+mod remote_cache;
+mod render_lock;
+mod replication;
+mod response_headers;
+mod sanitize;
+mod server;
+mod signature;
+mod slow;
+mod smart;
+mod slug;
+mod sniff;
+mod startup;
+mod stats;
 mod state;
+mod storage;
+mod thumbor;
+mod throttle;
+mod trash;
+mod upload_url;
+mod variant;
+mod warm;
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let cli = cli::Cli::parse();
 
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    match cli.command {
+        Some(cli::Command::Serve) | None => serve(cli).await,
+        Some(cli::Command::Watermark { ref command }) => read_watermark(&cli, command),
+        Some(cli::Command::Bench {
+            ref files,
+            iterations,
+        }) => bench(&cli, files, iterations),
+    }
+}
+
+/// Run the benchmark of the processing stages.
+fn bench(cli: &cli::Cli, files: &[String], iterations: u32) {
+    let libvipsapp = VipsApp::new("Canvas", false).unwrap();
+    libvipsapp.concurrency_set(num_cpus::get().try_into().unwrap());
+    let cfg = match app_config::get_config(cli.config.as_deref()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("Cannot read configuration: {err}");
+            std::process::exit(2);
+        }
+    };
+    if let Err(err) = bench::run(&cfg, files, iterations) {
+        error!("Benchmark failed: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// Read the invisible watermark of an image file.
+/// Exits with 1 if the watermark was not found or does not match.
+fn read_watermark(cli: &cli::Cli, command: &cli::WatermarkCommand) {
+    let _libvipsapp = VipsApp::new("Canvas", false).unwrap();
+    let cfg = match app_config::get_config(cli.config.as_deref()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("Cannot read configuration: {err}");
+            std::process::exit(2);
+        }
+    };
+    match cli::watermark(&cfg, command) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            error!("Cannot read the watermark: {err}");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Start the server.
+async fn serve(cli: cli::Cli) {
     // Initialize libvips.
     let libvipsapp = VipsApp::new("Test Libvips", false).unwrap();
     let cpu_num: i32 = num_cpus::get().try_into().unwrap();
     info!("Starting {cpu_num} workers");
     libvipsapp.concurrency_set(cpu_num);
+    let build = build_info::BuildInfo::new(libvipsapp.version_string().unwrap_or("unknown"));
+    info!(
+        "Canvas {} ({}), libvips {}",
+        build.version, build.git_sha, build.libvips_version
+    );
 
     // Read configuration.
-    let cfg = app_config::get_config().unwrap();
-    fs::create_dir_all(cfg.upload_dir.clone()).unwrap();
+    let mut cfg = match app_config::get_config(cli.config.as_deref()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("Cannot read configuration: {err}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(port) = cli.port {
+        cfg.port = port;
+    }
+    error::set_verbose(cfg.verbose_errors);
+
+    // Report all problems at once.
+    let problems = startup::validate(&cfg);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("{problem}");
+        }
+        std::process::exit(1);
+    }
+
+    if cli.check_config {
+        match cli::check_redis(&cfg).await {
+            Ok(()) => {
+                println!("Configuration is valid");
+                return;
+            }
+            Err(err) => {
+                error!("Cannot connect to Redis: {err}");
+                std::process::exit(1);
+            }
+        }
+    }
 
     // Connect to redis.
+    // The configuration was validated above, so the unwraps below cannot fail.
     let redis_client = mobc_redis::redis::Client::open(cfg.redis_url.clone()).unwrap();
     let redis_manager = RedisConnectionManager::new(redis_client);
     let redis_pool = Pool::builder()
         .max_open(cpu_num.try_into().unwrap())
         .build(redis_manager);
 
+    // Connect to the replica.
+    let replica = cfg
+        .replica_url
+        .as_ref()
+        .map(|url| Arc::new(Storage::from_url(url).unwrap()));
+    let (replication_sender, replication_receiver) = mpsc::channel(replication::QUEUE_SIZE);
+
     // Create shared state.
-    let state = AppState::new(cfg.clone(), redis_pool);
+    let state = AppState::new(
+        cfg.clone(),
+        cli.config,
+        redis_pool,
+        replica.as_ref().map(|_| replication_sender),
+        build,
+    );
+
+    // Copy new originals to the replica.
+    if let Some(replica) = replica {
+        tokio::spawn(replication::worker(
+            state.clone(),
+            replica.clone(),
+            replication_receiver,
+        ));
+        if cfg.replica_backfill {
+            tokio::spawn(replication::backfill(state.clone(), replica));
+        }
+    }
+
+    // Index originals uploaded before the admin listing index existed.
+    tokio::spawn(access::index_backfill(state.clone()));
+    // Write the visibility of originals uploaded before it existed.
+    tokio::spawn(metadata::visibility_backfill(state.clone()));
+
+    // Permanently remove deleted images after the retention period.
+    tokio::spawn(trash::purge_loop(state.clone()));
+
+    // Evict the least recently used processed images over the limit.
+    if let Some(max_entries) = cfg.cache_max_entries {
+        tokio::spawn(access::evict_loop(state.clone(), max_entries));
+    }
+    if let Some(cache::Offload::Disk(disk_cache)) = &state.cache_offload {
+        tokio::spawn(disk_cache::evict_loop(state.clone(), disk_cache.clone()));
+    }
+
+    // Retry moderation of images left without a verdict.
+    if let Some(url) = &cfg.moderation_url {
+        tokio::spawn(moderation::retry_loop(state.clone(), url.clone()));
+    }
+
+    // Reload the configuration on SIGHUP.
+    tokio::spawn(reload::handle_sighup(state.clone()));
 
     // Initialize axum.
 
     // Configure CORS layer.
-    let mut cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(Any)
-        .max_age(Duration::from_secs(60) * 10);
-
-    match cfg.allowed_origins {
-        Some(raw_list) => {
-            let mut origins: Vec<HeaderValue> = Vec::new();
-            for origin in raw_list.iter() {
-                origins.push(origin.parse().unwrap());
-            }
-            cors = cors.allow_origin(origins);
-        },
-        None => {
-            warn!("CORS: all origins are allowed");
-            cors = cors.allow_origin(Any);
-        },
+    let cors = match cors::layer(&cfg, state.clone()) {
+        Ok(cors) => cors,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
     };
 
-    let mut axumapp = Router::new()
+    // Admin endpoints require an admin token.
+    let admin = Router::new()
+        .route("/images", get(api::admin::list_images))
+        .route("/audit", get(api::admin::get_audit_log))
+        .route("/events", get(api::events::get_events))
+        .route("/reload", post(api::admin::reload_config))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit::record_admin_action,
+        ))
+        .route_layer(middleware::from_fn(auth::require_admin));
+
+    // Requests running past the deadline are dropped with 408,
+    // image processing is cancelled along with them.
+    let upload_timeout = TimeoutLayer::new(Duration::from_secs(cfg.upload_timeout_secs));
+    let transform_timeout = TimeoutLayer::new(Duration::from_secs(cfg.transform_timeout_secs));
+
+    // Only uploads may have large bodies, the route limit overrides the default one.
+    let upload_body_limit = DefaultBodyLimit::max(1024 * cfg.file_size_limit_kb);
+    // Base64 is a third larger than the file, plus room for the other fields.
+    let base64_body_limit =
+        DefaultBodyLimit::max(1024 * cfg.file_size_limit_kb / 3 * 4 + 1024 * cfg.body_limit_kb);
+
+    let mut routes = Router::new()
         .route("/health", get(api::health::get_health))
-        .route("/images", post(api::upload::upload_image))
-        .route("/images/:hash", get(api::image::get_image))
-        .layer(DefaultBodyLimit::max(1024 * cfg.file_size_limit_kb))
+        .route("/version", get(api::health::get_version))
+        .route("/ready", get(api::health::get_ready))
+        .route("/metrics", get(api::metrics::get_metrics))
+        .route(
+            "/images",
+            post(api::upload::upload_image).layer(
+                ServiceBuilder::new()
+                    .layer(upload_body_limit.clone())
+                    .layer(upload_timeout),
+            ),
+        )
+        .route(
+            "/images/base64",
+            post(api::upload::upload_base64)
+                .layer(ServiceBuilder::new().layer(base64_body_limit).layer(upload_timeout)),
+        )
+        .route("/uploads/sign", post(api::upload::sign_upload))
+        .route("/uploads/:id/progress", get(api::upload::get_upload_progress))
+        .route(
+            "/images/:hash",
+            get(api::image::get_image)
+                .layer(transform_timeout)
+                .delete(api::delete::delete_image)
+                .merge(
+                    // The parameter is the slug of the image.
+                    put(api::upload::replace_image)
+                        .layer(ServiceBuilder::new().layer(upload_body_limit).layer(upload_timeout)),
+                ),
+        )
+        .route("/images/:hash/restore", post(api::delete::restore_image))
+        .route("/images/:hash/info", get(api::info::get_info))
+        .route(
+            "/images/:hash/stats",
+            get(api::info::get_stats).layer(transform_timeout),
+        )
+        .route(
+            "/images/by-slug/:slug",
+            get(api::image::get_image_by_slug).layer(transform_timeout),
+        )
+        .route(
+            "/proxy/:source",
+            get(api::proxy::get_proxied_image).layer(transform_timeout),
+        )
+        .route(
+            "/transform",
+            post(api::transform::transform_image).layer(transform_timeout),
+        )
+        .route("/diff", get(api::diff::get_diff).layer(transform_timeout))
+        .nest("/admin", admin);
+
+    if imgproxy::is_enabled(&cfg) {
+        routes = routes.route(
+            "/imgproxy/*path",
+            get(api::imgproxy::get_imgproxy_image).layer(transform_timeout),
+        );
+    }
+
+    // Thumbor URLs don't have a common prefix, so they are handled by the fallback.
+    if cfg.thumbor_security_key.is_some() || cfg.thumbor_allow_unsafe {
+        routes = routes.fallback(api::thumbor::get_thumbor_image.layer(transform_timeout));
+    }
+
+    let mut axumapp = routes
+        .layer(DefaultBodyLimit::max(1024 * cfg.body_limit_kb))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            client_ip::resolve,
+        ))
         .layer(cors)
         .with_state(state);
 
     if cfg.enable_tracing {
         axumapp = axumapp.layer(TraceLayer::new_for_http());
     }
+    // Layers of the router run after routing, so the normalization wraps it.
+    let axumapp = middleware::from_fn(normalize::normalize_uri).layer(axumapp);
 
-    Server::bind(&format!("0.0.0.0:{}", cfg.port).parse().unwrap())
-        .serve(axumapp.into_make_service())
+    let server = Server::bind(&format!("0.0.0.0:{}", cfg.port).parse().unwrap());
+    server::configure(server, &cfg)
+        .serve(axumapp.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
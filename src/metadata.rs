@@ -0,0 +1,318 @@
+//! Image metadata stored in Redis.
+//!
+//! Metadata of each image is kept in a Redis hash under the `meta:<hash>` key.
+//! Hashes of tagged images are also kept in Redis sets under `tag:<tag>` keys.
+//!
+//! Every upload writes the visibility of the image. Images without it, because their
+//! metadata was lost or never written, are treated as private. Images uploaded before
+//! the field existed get it once from `visibility_backfill`.
+use crate::{hash, moderation::Verdict, smart::ContentClass, AppState};
+use log::{error, info};
+use mobc_redis::redis::{aio::Connection, AsyncCommands, RedisResult, Script};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+};
+
+/// Maximum number of custom metadata entries.
+pub const MAX_CUSTOM_ENTRIES: usize = 32;
+/// Maximum length of custom metadata keys and tags.
+pub const MAX_KEY_LENGTH: usize = 64;
+/// Maximum length of custom metadata values.
+pub const MAX_VALUE_LENGTH: usize = 1024;
+/// Maximum length of original filenames.
+pub const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Visibility of the image: "public" or "private".
+pub const VISIBILITY: &str = "visibility";
+/// Is the image private? ("1" or missing), written by earlier versions.
+pub const PRIVATE: &str = "private";
+/// Moderation verdict (see `Verdict`).
+pub const MODERATION: &str = "moderation";
+/// Time the image was moved to the trash (unix timestamp).
+pub const DELETED_AT: &str = "deleted_at";
+/// Custom key/value metadata (JSON object).
+pub const CUSTOM: &str = "custom";
+/// Tags (JSON array).
+pub const TAGS: &str = "tags";
+/// Name of the uploaded file.
+pub const FILENAME: &str = "filename";
+/// MIME type of the uploaded file detected by its content.
+pub const CONTENT_TYPE: &str = "content_type";
+/// Kind of the content for `format=smart` (see `ContentClass`).
+pub const CONTENT_CLASS: &str = "content_class";
+/// User id (JWT subject) of the first uploader.
+pub const OWNER: &str = "owner";
+/// Hash of the stored file, if it was rewritten and doesn't match the image hash.
+pub const CHECKSUM: &str = "checksum";
+
+/// Metadata of an uploaded image.
+#[derive(Debug, Clone, Default)]
+pub struct ImageMetadata {
+    /// Image requires a signed URL or an access token.
+    pub private: bool,
+    /// The visibility was not found, it was lost or never written.
+    /// Such images are treated as private, see `is_restricted`.
+    pub missing: bool,
+    /// Moderation verdict (if moderation is enabled).
+    pub moderation: Option<Verdict>,
+    /// Time the image was moved to the trash (unix timestamp).
+    pub deleted_at: Option<u64>,
+    /// Custom key/value metadata given at upload.
+    pub custom: BTreeMap<String, String>,
+    /// Tags given at upload.
+    pub tags: Vec<String>,
+    /// Name of the uploaded file (if the client sent it).
+    pub filename: Option<String>,
+    /// MIME type of the uploaded file.
+    pub content_type: Option<String>,
+    /// Kind of the content, once classified.
+    pub content_class: Option<ContentClass>,
+    /// User id of the first uploader, `None` if uploaded with a token.
+    pub owner: Option<String>,
+    /// Hash of the stored file, `None` if it is the image hash.
+    pub checksum: Option<String>,
+}
+
+impl ImageMetadata {
+    fn from_fields(fields: &HashMap<String, String>) -> ImageMetadata {
+        ImageMetadata {
+            private: fields.get(VISIBILITY).map(String::as_str) == Some("private")
+                || fields.contains_key(PRIVATE),
+            missing: !fields.contains_key(VISIBILITY),
+            moderation: fields.get(MODERATION).and_then(|value| value.parse().ok()),
+            deleted_at: fields.get(DELETED_AT).and_then(|value| value.parse().ok()),
+            custom: fields
+                .get(CUSTOM)
+                .and_then(|value| serde_json::from_str(value).ok())
+                .unwrap_or_default(),
+            tags: fields
+                .get(TAGS)
+                .and_then(|value| serde_json::from_str(value).ok())
+                .unwrap_or_default(),
+            filename: fields.get(FILENAME).cloned(),
+            content_type: fields.get(CONTENT_TYPE).cloned(),
+            content_class: fields
+                .get(CONTENT_CLASS)
+                .and_then(|value| value.parse().ok()),
+            owner: fields.get(OWNER).cloned(),
+            checksum: fields.get(CHECKSUM).cloned(),
+        }
+    }
+
+    /// Does the image require a signed URL or an access token?
+    /// Uploads always write the visibility, so a missing one means it was evicted or lost,
+    /// and the image can't be trusted to be public.
+    pub fn is_restricted(&self) -> bool {
+        self.private || self.missing
+    }
+}
+
+/// Check if the tag is valid: 1-64 latin letters, digits, '-', '_', '.' or ':'.
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_KEY_LENGTH
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Check the size of custom metadata.
+pub fn is_valid_custom(custom: &BTreeMap<String, String>) -> bool {
+    custom.len() <= MAX_CUSTOM_ENTRIES
+        && custom.iter().all(|(key, value)| {
+            !key.is_empty() && key.len() <= MAX_KEY_LENGTH && value.len() <= MAX_VALUE_LENGTH
+        })
+}
+
+/// Clean up the name of the uploaded file: drop the directories and control characters.
+/// Returns `None` if nothing is left.
+pub fn clean_filename(filename: &str) -> Option<String> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
+/// Redis key with metadata of the image.
+pub fn key(hash: &str) -> String {
+    format!("meta:{hash}")
+}
+
+/// Redis key with hashes of images having the tag.
+pub fn tag_key(tag: &str) -> String {
+    format!("tag:{tag}")
+}
+
+/// Get metadata of the image.
+pub async fn get(con: &mut Connection, hash: &str) -> RedisResult<ImageMetadata> {
+    let fields: HashMap<String, String> = con.hgetall(key(hash)).await?;
+    Ok(ImageMetadata::from_fields(&fields))
+}
+
+/// Mark the image as private, or as public unless it is private already.
+pub async fn set_visibility(con: &mut Connection, hash: &str, private: bool) -> RedisResult<()> {
+    if private {
+        con.hset(key(hash), VISIBILITY, "private").await
+    } else {
+        con.hset_nx(key(hash), VISIBILITY, "public").await
+    }
+}
+
+/// Replace custom metadata of the image.
+pub async fn set_custom(
+    con: &mut Connection,
+    hash: &str,
+    custom: &BTreeMap<String, String>,
+) -> RedisResult<()> {
+    let value = serde_json::to_string(custom).unwrap_or_default();
+    con.hset(key(hash), CUSTOM, value).await
+}
+
+/// Save the name and the type of the uploaded file.
+/// The name is kept from earlier uploads if it is not given.
+pub async fn set_original(
+    con: &mut Connection,
+    hash: &str,
+    filename: Option<&str>,
+    content_type: &str,
+) -> RedisResult<()> {
+    if let Some(filename) = filename {
+        let _: () = con.hset(key(hash), FILENAME, filename).await?;
+    }
+    con.hset(key(hash), CONTENT_TYPE, content_type).await
+}
+
+/// Save the hash of the stored file, see `CHECKSUM`.
+pub async fn set_checksum(con: &mut Connection, hash: &str, checksum: &str) -> RedisResult<()> {
+    con.hset(key(hash), CHECKSUM, checksum).await
+}
+
+/// Remember the user who uploaded the image first.
+pub async fn set_owner(con: &mut Connection, hash: &str, owner: &str) -> RedisResult<()> {
+    con.hset_nx(key(hash), OWNER, owner).await
+}
+
+/// Save the kind of the content.
+pub async fn set_content_class(
+    con: &mut Connection,
+    hash: &str,
+    class: ContentClass,
+) -> RedisResult<()> {
+    con.hset(key(hash), CONTENT_CLASS, class.to_string()).await
+}
+
+/// Add tags to the image.
+pub async fn add_tags(con: &mut Connection, hash: &str, tags: &[String]) -> RedisResult<()> {
+    let mut all_tags = get(con, hash).await?.tags;
+    all_tags.extend_from_slice(tags);
+    all_tags.sort();
+    all_tags.dedup();
+
+    let value = serde_json::to_string(&all_tags).unwrap_or_default();
+    let _: () = con.hset(key(hash), TAGS, value).await?;
+    for tag in tags {
+        let _: () = con.sadd(tag_key(tag), hash).await?;
+    }
+    Ok(())
+}
+
+/// Get hashes of images having the tag.
+pub async fn tagged(con: &mut Connection, tag: &str) -> RedisResult<Vec<String>> {
+    con.smembers(tag_key(tag)).await
+}
+
+/// Set or clear the deletion time of the image.
+pub async fn set_deleted_at(
+    con: &mut Connection,
+    hash: &str,
+    deleted_at: Option<u64>,
+) -> RedisResult<()> {
+    match deleted_at {
+        Some(deleted_at) => con.hset(key(hash), DELETED_AT, deleted_at).await,
+        None => con.hdel(key(hash), DELETED_AT).await,
+    }
+}
+
+/// Remove all metadata of the image, including tags.
+pub async fn remove(con: &mut Connection, hash: &str) -> RedisResult<()> {
+    let tags = get(con, hash).await?.tags;
+    for tag in tags {
+        let _: () = con.srem(tag_key(&tag), hash).await?;
+    }
+    con.del(key(hash)).await
+}
+
+/// Name of the file in the upload directory marking that the backfill is done.
+const BACKFILL_MARKER: &str = ".visibility-backfilled";
+
+/// Write the visibility of the originals uploaded before it existed, once.
+/// Their metadata has the old private flag, or nothing for public images.
+/// The marker is kept in the upload directory rather than in Redis, so that lost
+/// metadata never makes the images public again.
+pub async fn visibility_backfill(state: Arc<AppState>) {
+    let marker = Path::new(&state.cfg.upload_dir).join(BACKFILL_MARKER);
+    if marker.exists() {
+        return;
+    }
+    let filled = async {
+        let mut filled = 0;
+        for dir in [
+            Path::new(&state.cfg.upload_dir).to_path_buf(),
+            state.get_trash_dir(),
+        ] {
+            filled += fill_visibility(&state, &dir).await?;
+        }
+        tokio::fs::write(&marker, b"").await?;
+        anyhow::Ok(filled)
+    };
+    match filled.await {
+        Ok(filled) => info!("Wrote the visibility of {filled} images"),
+        Err(err) => error!("Failed to write the visibility of images: {err}"),
+    }
+}
+
+async fn fill_visibility(state: &AppState, dir: &Path) -> anyhow::Result<usize> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    // Public unless the old private flag is set, images written by this version are skipped.
+    let script = Script::new(
+        r"
+        if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 1 then
+            return 0
+        end
+        local visibility = 'public'
+        if redis.call('HEXISTS', KEYS[1], ARGV[2]) == 1 then
+            visibility = 'private'
+        end
+        redis.call('HSET', KEYS[1], ARGV[1], visibility)
+        return 1
+        ",
+    );
+    let mut redis_con = state.redis.get().await?;
+    let mut filled = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !hash::is_valid(&name) {
+            continue;
+        }
+        let written: i32 = script
+            .key(key(&name))
+            .arg(VISIBILITY)
+            .arg(PRIVATE)
+            .invoke_async(&mut *redis_con)
+            .await?;
+        filled += written as usize;
+    }
+    Ok(filled)
+}
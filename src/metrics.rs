@@ -0,0 +1,103 @@
+//! Prometheus metrics.
+//!
+//! Metrics are served at '/metrics' in the text exposition format.
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Server metrics.
+pub struct Metrics {
+    registry: Registry,
+    /// Failed transformations by the format of the source image.
+    pub transform_failures: IntCounterVec,
+    /// Transformations slower than `slow_transform_ms`.
+    pub slow_transforms: IntCounter,
+    /// Transformations with output larger than `large_output_kb`.
+    pub large_outputs: IntCounter,
+    /// Time of processing stages by the stage and the output format.
+    pub stage_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        let registry =
+            Registry::new_custom(Some("canvas".to_string()), None).expect("metric prefix is valid");
+
+        let transform_failures = IntCounterVec::new(
+            Opts::new(
+                "transform_failures_total",
+                "Failed transformations by the format of the source image",
+            ),
+            &["format"],
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(transform_failures.clone()))
+            .expect("metric is registered once");
+
+        let slow_transforms = IntCounter::new(
+            "slow_transforms_total",
+            "Transformations slower than the slow transform threshold",
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(slow_transforms.clone()))
+            .expect("metric is registered once");
+
+        let large_outputs = IntCounter::new(
+            "large_outputs_total",
+            "Transformations with output larger than the large output threshold",
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(large_outputs.clone()))
+            .expect("metric is registered once");
+
+        let stage_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "stage_duration_seconds",
+                "Time of processing stages by the stage and the output format",
+            ),
+            &["stage", "format"],
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(stage_duration.clone()))
+            .expect("metric is registered once");
+
+        Metrics {
+            registry,
+            transform_failures,
+            slow_transforms,
+            large_outputs,
+            stage_duration,
+        }
+    }
+}
+
+impl Metrics {
+    /// Encode all metrics in the text exposition format.
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// Record the time of the processing stages, see `Budget::stages`.
+    pub fn observe_stages(&self, stages: &[(&'static str, Duration)], format: &str) {
+        for (stage, time) in stages {
+            self.stage_duration
+                .with_label_values(&[stage, format])
+                .observe(time.as_secs_f64());
+        }
+    }
+}
+
+/// Metric label for the MIME type of the image ('jpeg', 'png', 'unknown', ...).
+pub fn format_label(mime_type: Option<&str>) -> &str {
+    match mime_type {
+        Some(mime_type) => mime_type.rsplit('/').next().unwrap_or(mime_type),
+        None => "unknown",
+    }
+}
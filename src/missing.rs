@@ -0,0 +1,26 @@
+//! Negative cache of missing images.
+//!
+//! Hashes that were not found are remembered in Redis under `missing:<hash>` keys
+//! for a short time, so that repeated requests don't hit the disk and the origin.
+//! The entry is removed when the image is uploaded or restored.
+use mobc_redis::redis::{aio::Connection, AsyncCommands, RedisResult};
+
+/// Redis key marking the image as missing.
+pub fn key(hash: &str) -> String {
+    format!("missing:{hash}")
+}
+
+/// Check if the image was recently found missing.
+pub async fn is_missing(con: &mut Connection, hash: &str) -> RedisResult<bool> {
+    con.exists(key(hash)).await
+}
+
+/// Remember that the image is missing for `ttl_secs` seconds.
+pub async fn set_missing(con: &mut Connection, hash: &str, ttl_secs: u64) -> RedisResult<()> {
+    con.set_ex(key(hash), 1, ttl_secs as usize).await
+}
+
+/// Forget that the image was missing.
+pub async fn clear(con: &mut Connection, hash: &str) -> RedisResult<()> {
+    con.del(key(hash)).await
+}
@@ -0,0 +1,238 @@
+//! Upload moderation.
+//!
+//! After upload, the image (or its downscaled copy) is sent to an external classifier.
+//! Images stay pending until the classifier responds.
+//!
+//! New images are marked as pending and queued in the `moderation:queue` sorted set
+//! before their file is saved, scored by the time of the next attempt. Requests are
+//! retried a few times right away, then every few minutes from the queue, so that
+//! an unavailable classifier or a restart never leaves an image without a verdict.
+use crate::{clock::unix_now, metadata, AppState};
+use axum::body::Bytes;
+use libvips::{ops, VipsImage};
+use log::{info, warn};
+use mobc_redis::redis::{self, aio::Connection, AsyncCommands, RedisResult};
+use serde::Deserialize;
+use std::{fmt, io, str::FromStr, sync::Arc, time::Duration};
+
+/// Sorted set with hashes of images waiting for a verdict, scored by the next attempt time.
+const QUEUE: &str = "moderation:queue";
+/// Number of attempts right after the upload.
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled after each attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Delay between attempts of queued images.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+/// How often to check the queue.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Time limits of requests to the classifier, failed ones are retried.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP client of the requests to the classifier.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap()
+}
+
+/// Moderation verdict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// Image can be served to everyone.
+    Approved,
+    /// Image is served only to authenticated clients.
+    Pending,
+    /// Image was deleted.
+    Rejected,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Verdict::Approved => "approved",
+                Verdict::Pending => "pending",
+                Verdict::Rejected => "rejected",
+            }
+        )
+    }
+}
+
+impl FromStr for Verdict {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Verdict, Self::Err> {
+        match value {
+            "approved" => Ok(Verdict::Approved),
+            "pending" => Ok(Verdict::Pending),
+            "rejected" => Ok(Verdict::Rejected),
+            _ => Err(anyhow::anyhow!("Unknown verdict {value}")),
+        }
+    }
+}
+
+/// Response of the moderation endpoint.
+#[derive(Deserialize)]
+struct ModerationResponse {
+    verdict: Verdict,
+}
+
+/// Mark the new image as pending and queue it for moderation.
+/// Called before the file is saved, so that it is never served unmoderated.
+pub async fn enqueue(con: &mut Connection, hash: &str) -> RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .hset(
+            metadata::key(hash),
+            metadata::MODERATION,
+            Verdict::Pending.to_string(),
+        )
+        .ignore()
+        .zadd(QUEUE, hash, unix_now() + RETRY_INTERVAL.as_secs())
+        .ignore()
+        .query_async(con)
+        .await
+}
+
+/// Send the image to the moderation endpoint and save the verdict.
+/// Rejected images are deleted. If all attempts fail, the queue retries it later.
+pub async fn moderate(state: Arc<AppState>, url: String, hash: String, data: Bytes) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match request_verdict(&state, &url, &hash, data.clone()).await {
+            Ok(verdict) => return save_verdict(&state, &hash, verdict).await,
+            Err(err) if attempt < MAX_ATTEMPTS => {
+                warn!("Moderation of {hash} failed (attempt {attempt}): {err}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => warn!("Moderation of {hash} failed, the image stays pending: {err}"),
+        }
+    }
+}
+
+/// Periodically retry moderation of queued images.
+pub async fn retry_loop(state: Arc<AppState>, url: String) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = retry_queued(&state, &url).await {
+            warn!("Failed to retry moderation: {err}");
+        }
+    }
+}
+
+async fn retry_queued(state: &AppState, url: &str) -> anyhow::Result<()> {
+    // Postpone the next attempt first, so that other instances skip these images.
+    // The connection is not held during the requests.
+    let hashes = {
+        let mut redis_con = state.redis.get().await?;
+        let now = unix_now();
+        let hashes: Vec<String> = redis_con.zrangebyscore(QUEUE, 0, now).await?;
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for hash in &hashes {
+            pipe.zadd(QUEUE, hash, now + RETRY_INTERVAL.as_secs())
+                .ignore();
+        }
+        let _: () = pipe.query_async(&mut *redis_con).await?;
+        hashes
+    };
+
+    for hash in hashes {
+        let data = match tokio::fs::read(state.get_file_path(&hash)).await {
+            Ok(data) => Bytes::from(data),
+            // Deleted in the meantime.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut redis_con = state.redis.get().await?;
+                let _: () = redis_con.zrem(QUEUE, &hash).await?;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match request_verdict(state, url, &hash, data).await {
+            Ok(verdict) => save_verdict(state, &hash, verdict).await,
+            Err(err) => warn!("Moderation of {hash} failed, the image stays pending: {err}"),
+        }
+    }
+    Ok(())
+}
+
+/// Save the verdict and remove the image from the queue, delete rejected images.
+async fn save_verdict(state: &AppState, hash: &str, verdict: Verdict) {
+    info!("Moderation verdict for {hash}: {verdict}");
+
+    if verdict == Verdict::Rejected {
+        if let Err(err) = tokio::fs::remove_file(state.get_file_path(hash)).await {
+            warn!("Failed to delete rejected image {hash}: {err}");
+        }
+    }
+
+    let saved = match state.redis.get().await {
+        Ok(mut redis_con) => redis::pipe()
+            .atomic()
+            .hset(
+                metadata::key(hash),
+                metadata::MODERATION,
+                verdict.to_string(),
+            )
+            .ignore()
+            .zrem(QUEUE, hash)
+            .ignore()
+            .query_async::<_, ()>(&mut *redis_con)
+            .await
+            .map_err(anyhow::Error::from),
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = saved {
+        warn!("Failed to save moderation verdict for {hash}: {err}");
+    }
+}
+
+async fn request_verdict(
+    state: &AppState,
+    url: &str,
+    hash: &str,
+    data: Bytes,
+) -> anyhow::Result<Verdict> {
+    let body = match state.cfg.moderation_image_size {
+        Some(size) => tokio::task::spawn_blocking(move || downscale(&data, size)).await??,
+        None => data.to_vec(),
+    };
+
+    let response: ModerationResponse = state
+        .http_client
+        .post(url)
+        .header("X-Canvas-Hash", hash)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response.verdict)
+}
+
+/// Shrink the image to fit into a square with the given side and encode it as JPEG.
+fn downscale(data: &[u8], size: u16) -> anyhow::Result<Vec<u8>> {
+    let image = VipsImage::new_from_buffer(data, "")?;
+    let thumbnail = ops::thumbnail_image_with_opts(
+        &image,
+        size.into(),
+        &ops::ThumbnailImageOptions {
+            height: size.into(),
+            size: ops::Size::Down,
+            ..ops::ThumbnailImageOptions::default()
+        },
+    )?;
+    Ok(ops::jpegsave_buffer(&thumbnail)?)
+}
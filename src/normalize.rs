@@ -0,0 +1,98 @@
+//! Request URI normalization.
+//!
+//! Equivalent URIs are rewritten to one form before routing, so that routes,
+//! validation, signatures and the cache see the same request however it was
+//! encoded by the client or a proxy in front of the server:
+//! - percent-encoded unreserved characters are decoded (`%77idth` is `width`),
+//!   other percent-encodings use upper case hex digits;
+//! - the query is re-encoded pair by pair, empty pairs are dropped.
+//!
+//! Paths with malformed percent-encodings, encoded control characters or `.`/`..`
+//! segments are rejected with 400.
+use crate::HttpError;
+use axum::{
+    http::{uri::PathAndQuery, Request, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Rewrite the request URI to the normal form.
+/// Must wrap the router, layers of the router run after routing.
+pub async fn normalize_uri<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let uri = request.uri();
+    let path = match normalize_path(uri.path()) {
+        Some(path) => path,
+        None => return HttpError::bad_request("Invalid request path").into_response(),
+    };
+    let path_and_query = match uri.query().map(normalize_query) {
+        Some(query) if !query.is_empty() => format!("{path}?{query}"),
+        _ => path,
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = match path_and_query.parse::<PathAndQuery>() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return HttpError::bad_request("Invalid request path").into_response(),
+    };
+    match Uri::from_parts(parts) {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(_) => return HttpError::bad_request("Invalid request path").into_response(),
+    }
+    next.run(request).await
+}
+
+/// Normalize percent-encodings of the path.
+/// Returns `None` if the path is invalid.
+fn normalize_path(path: &str) -> Option<String> {
+    let mut normalized = String::with_capacity(path.len());
+    for (i, segment) in path.split('/').enumerate() {
+        if i > 0 {
+            normalized.push('/');
+        }
+        let segment = normalize_segment(segment)?;
+        if segment == "." || segment == ".." {
+            return None;
+        }
+        normalized.push_str(&segment);
+    }
+    Some(normalized)
+}
+
+/// Decode unreserved characters (RFC 3986, section 2.3) and keep the rest encoded.
+fn normalize_segment(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut normalized = String::with_capacity(segment.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            normalized.push(bytes[i] as char);
+            i += 1;
+            continue;
+        }
+
+        let hex = segment.get(i + 1..i + 3)?;
+        let byte = u8::from_str_radix(hex, 16).ok()?;
+        if byte.is_ascii_control() {
+            return None;
+        }
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            normalized.push(byte as char);
+        } else {
+            normalized.push_str(&format!("%{byte:02X}"));
+        }
+        i += 3;
+    }
+    Some(normalized)
+}
+
+/// Decode the query and encode it again.
+/// Parameters keep their order, so that repeated ones are handled the same way.
+fn normalize_query(query: &str) -> String {
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        if !name.is_empty() {
+            serializer.append_pair(&name, &value);
+        }
+    }
+    serializer.finish()
+}
@@ -0,0 +1,44 @@
+//! Read-through fallback for missing originals.
+//!
+//! Originals missing on the local disk are fetched from the origin storage
+//! and saved locally. The content is checked against the hash, or the checksum
+//! of originals rewritten at upload (see `metadata::CHECKSUM`), so that a wrong
+//! or corrupted object is never stored under it.
+use crate::{hash, AppState};
+use log::{info, warn};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Fetch the original from the origin storage.
+/// `checksum` is the hash of the stored file if it differs from the image hash.
+/// Returns false if the origin is not configured or doesn't have the image.
+pub async fn fetch(state: &AppState, hash: &str, checksum: Option<&str>) -> anyhow::Result<bool> {
+    let origin = match &state.origin {
+        Some(origin) => origin,
+        None => return Ok(false),
+    };
+    if !hash::is_valid(hash) {
+        return Ok(false);
+    }
+
+    let data = match origin.get(hash).await? {
+        Some(data) => data,
+        None => return Ok(false),
+    };
+    if hash::compute(&data) != checksum.unwrap_or(hash) {
+        warn!("Origin returned another image for {hash}, ignoring it");
+        return Ok(false);
+    }
+
+    // Write to a temporary file first, so that concurrent requests never see a partial file.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    let filepath = state.get_file_path(hash);
+    let tmp_path = filepath.with_extension(format!("{nanos}.part"));
+    tokio::fs::write(&tmp_path, &data).await?;
+    tokio::fs::rename(&tmp_path, &filepath).await?;
+
+    info!("Fetched {hash} from the origin");
+    Ok(true)
+}
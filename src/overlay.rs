@@ -0,0 +1,173 @@
+//! Overlay text templates.
+//!
+//! The `overlay` parameter can contain placeholders replaced before the cache key
+//! is computed, so that each rendered text is cached on its own:
+//! - `{hash}` - hash of the original;
+//! - `{date}` - current UTC date, `YYYY-MM-DD`;
+//! - `{width}`, `{height}` - size of the output;
+//! - `{meta.KEY}` - value of the custom metadata key given at upload (empty if missing).
+//!
+//! Unknown placeholders are kept as is. The whole text, not only the substituted
+//! values, is escaped, since the overlay is rendered as Pango markup: tags of the
+//! client could change the size of the text or break the rendering. The text is
+//! cut to `MAX_LENGTH` characters before escaping, so that no entity is cut.
+use crate::clock;
+use std::collections::BTreeMap;
+
+/// Maximum length of the rendered text.
+const MAX_LENGTH: usize = 1024;
+
+/// Values of the placeholders.
+pub struct Fields<'a> {
+    pub hash: &'a str,
+    pub width: u16,
+    pub height: u16,
+    pub custom: &'a BTreeMap<String, String>,
+}
+
+/// Replace the placeholders in the template and escape the result.
+pub fn render(template: &str, fields: &Fields) -> String {
+    let text: String = substitute(template, fields)
+        .chars()
+        .take(MAX_LENGTH)
+        .collect();
+    escape(&text)
+}
+
+fn substitute(template: &str, fields: &Fields) -> String {
+    if !template.contains('{') {
+        return template.to_string();
+    }
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let end = match placeholder.find('}') {
+            Some(end) => end,
+            None => {
+                rest = placeholder;
+                break;
+            }
+        };
+        let name = &placeholder[1..end];
+        match value(name, fields) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&placeholder[..=end]),
+        }
+        rest = &placeholder[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn value(name: &str, fields: &Fields) -> Option<String> {
+    match name {
+        "hash" => Some(fields.hash.to_string()),
+        "date" => Some(clock::today()),
+        "width" => Some(fields.width.to_string()),
+        "height" => Some(fields.height.to_string()),
+        _ => {
+            let key = name.strip_prefix("meta.")?;
+            Some(fields.custom.get(key).cloned().unwrap_or_default())
+        }
+    }
+}
+
+/// Escape the special characters of Pango markup.
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_with(template: &str, custom: &[(&str, &str)]) -> String {
+        let custom: BTreeMap<String, String> = custom
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let fields = Fields {
+            hash: "abc",
+            width: 300,
+            height: 200,
+            custom: &custom,
+        };
+        render(template, &fields)
+    }
+
+    #[test]
+    fn template_text_is_escaped() {
+        assert_eq!(
+            render_with("<span size=\"500000\">big</span> & co", &[]),
+            "&lt;span size=&quot;500000&quot;&gt;big&lt;/span&gt; &amp; co"
+        );
+    }
+
+    #[test]
+    fn substituted_values_are_escaped() {
+        assert_eq!(
+            render_with(
+                "© {meta.author}, {width}x{height}",
+                &[("author", "<b>Tom's</b>")]
+            ),
+            "© &lt;b&gt;Tom&apos;s&lt;/b&gt;, 300x200"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        assert_eq!(render_with("{name} {hash}", &[]), "{name} abc");
+        assert_eq!(render_with("{meta.missing}|", &[]), "|");
+        assert_eq!(render_with("open {brace", &[]), "open {brace");
+    }
+
+    #[test]
+    fn arabic_text_is_kept() {
+        let text = "حقوق النشر محفوظة";
+        assert_eq!(render_with(text, &[]), text);
+        assert_eq!(
+            render_with("{meta.author} ©", &[("author", "مصور")]),
+            "مصور ©"
+        );
+    }
+
+    #[test]
+    fn hebrew_text_is_kept() {
+        let text = "כל הזכויות שמורות";
+        assert_eq!(render_with(text, &[]), text);
+        // Mixed directions and escaping.
+        assert_eq!(
+            render_with("{meta.author} <{width}>", &[("author", "צלם & co")]),
+            "צלם &amp; co &lt;300&gt;"
+        );
+    }
+
+    #[test]
+    fn cjk_text_is_kept() {
+        let text = "版权所有 著作権 저작권";
+        assert_eq!(render_with(text, &[]), text);
+        assert_eq!(
+            render_with("{meta.author}", &[("author", "写真家")]),
+            "写真家"
+        );
+    }
+
+    #[test]
+    fn length_is_limited_in_characters() {
+        let text = "字".repeat(MAX_LENGTH + 10);
+        assert_eq!(render_with(&text, &[]).chars().count(), MAX_LENGTH);
+    }
+
+    #[test]
+    fn entities_are_not_cut() {
+        let text = "&".repeat(MAX_LENGTH + 10);
+        assert_eq!(render_with(&text, &[]), "&amp;".repeat(MAX_LENGTH));
+    }
+}
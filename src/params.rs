@@ -0,0 +1,107 @@
+//! Query parameters of image requests.
+//!
+//! Repeated parameters (`?width=100&width=2000`) are handled by the `duplicate_params`
+//! policy instead of the order of the map: they are rejected with 400, or the first
+//! or the last value is used. Signatures and image properties see the same values.
+//!
+//! Values are parsed by `ImageProps::from_params` with the helpers below, invalid
+//! ones are answered with 400 naming the parameter and the expected values.
+use crate::{app_config::DuplicateParams, AppState, HttpError};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Query parameters with repeated ones resolved.
+pub struct ImageParams(pub HashMap<String, String>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ImageParams {
+    type Rejection = HttpError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let pairs = url::form_urlencoded::parse(query.as_bytes());
+        collect(pairs, state.cfg.duplicate_params).map(ImageParams)
+    }
+}
+
+/// Collect the parameters, resolving repeated ones by the policy.
+pub fn collect<'a>(
+    pairs: impl Iterator<Item = (Cow<'a, str>, Cow<'a, str>)>,
+    policy: DuplicateParams,
+) -> Result<HashMap<String, String>, HttpError> {
+    let mut params = HashMap::new();
+    for (name, value) in pairs {
+        match params.entry(name.into_owned()) {
+            Entry::Vacant(entry) => {
+                entry.insert(value.into_owned());
+            }
+            Entry::Occupied(mut entry) => match policy {
+                DuplicateParams::Reject => {
+                    let name = entry.key();
+                    return Err(HttpError::bad_request(&format!(
+                        "Parameter '{name}' is given more than once, repeated parameters are not allowed"
+                    ))
+                    .with_field(name));
+                }
+                DuplicateParams::First => {}
+                DuplicateParams::Last => {
+                    entry.insert(value.into_owned());
+                }
+            },
+        }
+    }
+    Ok(params)
+}
+
+/// Get the parameter converted by `parse`.
+/// `expected` describes valid values in the error.
+pub fn get<T>(
+    params: &HashMap<String, String>,
+    name: &str,
+    expected: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, HttpError> {
+    match params.get(name) {
+        Some(value) => match parse(value) {
+            Some(parsed) => Ok(Some(parsed)),
+            None => Err(invalid(name, value, expected)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Get the number parameter from `min` to `max`.
+pub fn number<T>(
+    params: &HashMap<String, String>,
+    name: &str,
+    min: T,
+    max: T,
+) -> Result<Option<T>, HttpError>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let expected = format!("a number from {min} to {max}");
+    get(params, name, &expected, |value| {
+        value
+            .parse()
+            .ok()
+            .filter(|number| *number >= min && *number <= max)
+    })
+}
+
+/// Error of the invalid parameter.
+pub fn invalid(name: &str, value: &str, expected: &str) -> HttpError {
+    HttpError::bad_request(&format!(
+        "Invalid value '{value}' of parameter '{name}', expected {expected}"
+    ))
+    .with_field(name)
+}
@@ -0,0 +1,417 @@
+//! Explicit processing pipelines.
+//!
+//! The `pipeline` parameter replaces the resize and crop steps with operations
+//! executed in the given order, for example:
+//! `pipeline=crop:0,0,800,600|resize:400|grayscale|sharpen:1.5|gamma:1.2`.
+//! The watermark and the overlay are still added afterwards.
+//!
+//! Operations can also be given as JSON objects (see `POST /transform`),
+//! for example `{"op": "resize", "width": 400}`.
+use anyhow::{anyhow, bail};
+use libvips::{ops, VipsImage};
+use std::{cmp, fmt};
+
+/// Query parameter with the pipeline.
+pub const PIPELINE_PARAM: &str = "pipeline";
+/// Maximum number of operations in a pipeline.
+const MAX_OPERATIONS: usize = 16;
+/// Maximum sigma of the blur and sharpen operations.
+const MAX_SIGMA: f64 = 50.0;
+/// Range of the gamma correction.
+pub const MIN_GAMMA: f64 = 0.1;
+pub const MAX_GAMMA: f64 = 10.0;
+/// Range of the exposure correction in stops.
+pub const MIN_EXPOSURE: f64 = -5.0;
+pub const MAX_EXPOSURE: f64 = 5.0;
+
+/// Interpolation kernel of the resize steps, from the fastest to the sharpest.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kernel {
+    Nearest,
+    Linear,
+    Cubic,
+    Lanczos3,
+}
+
+/// Valid values of the `kernel` parameter.
+pub const KERNELS: &str = "nearest, linear, cubic or lanczos3";
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kernel::Nearest => write!(f, "nearest"),
+            Kernel::Linear => write!(f, "linear"),
+            Kernel::Cubic => write!(f, "cubic"),
+            Kernel::Lanczos3 => write!(f, "lanczos3"),
+        }
+    }
+}
+
+impl Kernel {
+    /// Parse the value of the `kernel` parameter.
+    pub fn parse(value: &str) -> Option<Kernel> {
+        match value {
+            "nearest" => Some(Kernel::Nearest),
+            "linear" => Some(Kernel::Linear),
+            "cubic" => Some(Kernel::Cubic),
+            "lanczos3" => Some(Kernel::Lanczos3),
+            _ => None,
+        }
+    }
+}
+
+/// Resize the image with the kernel.
+pub fn resize(image: &VipsImage, factor: f64, kernel: Kernel) -> libvips::Result<VipsImage> {
+    ops::resize_with_opts(
+        image,
+        factor,
+        &ops::ResizeOptions {
+            kernel: match kernel {
+                Kernel::Nearest => ops::Kernel::Nearest,
+                Kernel::Linear => ops::Kernel::Linear,
+                Kernel::Cubic => ops::Kernel::Cubic,
+                Kernel::Lanczos3 => ops::Kernel::Lanczos3,
+            },
+            ..ops::ResizeOptions::default()
+        },
+    )
+}
+
+/// Single processing step.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    /// Cut out the area (`crop:<x>,<y>,<width>,<height>`), clipped to the image.
+    Crop {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    /// Shrink the image to fit the width (and the height) (`resize:<width>[,<height>]`).
+    /// Images are not upscaled.
+    Resize {
+        width: u16,
+        #[serde(default)]
+        height: Option<u16>,
+    },
+    /// Resize so that the smaller side is fully visible and crop the big side
+    /// with the smart algorithm (`cover:<width>,<height>`), as without a pipeline.
+    Cover { width: u16, height: u16 },
+    /// Convert to grayscale (`grayscale`).
+    Grayscale,
+    /// Sharpen (`sharpen:<sigma>`).
+    Sharpen { sigma: f64 },
+    /// Gaussian blur (`blur:<sigma>`).
+    Blur { sigma: f64 },
+    /// Rotate clockwise by 90, 180 or 270 degrees (`rotate:<angle>`).
+    Rotate { angle: u16 },
+    /// Mirror horizontally (`flip:h`) or vertically (`flip:v`).
+    Flip { horizontal: bool },
+    /// Gamma correction (`gamma:<value>`), values above 1 brighten the midtones.
+    Gamma { value: f64 },
+    /// Exposure correction in stops (`exposure:<ev>`), +1 doubles the light.
+    Exposure { ev: f64 },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "crop:{x},{y},{width},{height}"),
+            Operation::Resize {
+                width,
+                height: Some(height),
+            } => write!(f, "resize:{width},{height}"),
+            Operation::Resize {
+                width,
+                height: None,
+            } => write!(f, "resize:{width}"),
+            Operation::Cover { width, height } => write!(f, "cover:{width},{height}"),
+            Operation::Grayscale => write!(f, "grayscale"),
+            Operation::Sharpen { sigma } => write!(f, "sharpen:{sigma}"),
+            Operation::Blur { sigma } => write!(f, "blur:{sigma}"),
+            Operation::Rotate { angle } => write!(f, "rotate:{angle}"),
+            Operation::Flip { horizontal: true } => write!(f, "flip:h"),
+            Operation::Flip { horizontal: false } => write!(f, "flip:v"),
+            Operation::Gamma { value } => write!(f, "gamma:{value}"),
+            Operation::Exposure { ev } => write!(f, "exposure:{ev}"),
+        }
+    }
+}
+
+impl Operation {
+    /// Parse a single operation (`<name>[:<arguments>]`).
+    fn parse(value: &str) -> anyhow::Result<Operation> {
+        let (name, args) = match value.split_once(':') {
+            Some((name, args)) => (name, args.split(',').map(str::trim).collect()),
+            None => (value, Vec::new()),
+        };
+        let invalid = || anyhow!("Invalid arguments of '{name}'");
+        let number = |index: usize| -> anyhow::Result<u16> {
+            args.get(index)
+                .and_then(|arg| arg.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let float = || -> anyhow::Result<f64> {
+            args.first()
+                .and_then(|arg| arg.parse().ok())
+                .ok_or_else(invalid)
+        };
+
+        let operation = match (name.trim(), args.len()) {
+            ("crop", 4) => Operation::Crop {
+                x: number(0)?,
+                y: number(1)?,
+                width: number(2)?,
+                height: number(3)?,
+            },
+            ("resize", 1) => Operation::Resize {
+                width: number(0)?,
+                height: None,
+            },
+            ("resize", 2) => Operation::Resize {
+                width: number(0)?,
+                height: Some(number(1)?),
+            },
+            ("cover", 2) => Operation::Cover {
+                width: number(0)?,
+                height: number(1)?,
+            },
+            ("grayscale", 0) => Operation::Grayscale,
+            ("sharpen", 1) => Operation::Sharpen { sigma: float()? },
+            ("blur", 1) => Operation::Blur { sigma: float()? },
+            ("rotate", 1) => Operation::Rotate { angle: number(0)? },
+            ("flip", 1) => match args[0] {
+                "h" => Operation::Flip { horizontal: true },
+                "v" => Operation::Flip { horizontal: false },
+                _ => return Err(invalid()),
+            },
+            ("gamma", 1) => Operation::Gamma { value: float()? },
+            ("exposure", 1) => Operation::Exposure { ev: float()? },
+            (
+                "crop" | "resize" | "cover" | "grayscale" | "sharpen" | "blur" | "rotate" | "flip"
+                | "gamma" | "exposure",
+                _,
+            ) => return Err(invalid()),
+            _ => bail!("Unknown operation '{name}'"),
+        };
+
+        // Arguments are checked by `Pipeline::new`.
+        Ok(operation)
+    }
+
+    /// Check the arguments of the operation.
+    fn validate(&self) -> anyhow::Result<()> {
+        let valid = match self {
+            Operation::Crop { width, height, .. }
+            | Operation::Cover { width, height }
+            | Operation::Resize {
+                width,
+                height: Some(height),
+            } => *width > 0 && *height > 0,
+            Operation::Resize { width, .. } => *width > 0,
+            Operation::Sharpen { sigma } | Operation::Blur { sigma } => {
+                *sigma > 0.0 && *sigma <= MAX_SIGMA
+            }
+            Operation::Rotate { angle } => matches!(angle, 90 | 180 | 270),
+            Operation::Gamma { value } => (MIN_GAMMA..=MAX_GAMMA).contains(value),
+            Operation::Exposure { ev } => (MIN_EXPOSURE..=MAX_EXPOSURE).contains(ev),
+            Operation::Grayscale | Operation::Flip { .. } => true,
+        };
+        match valid {
+            true => Ok(()),
+            false => Err(anyhow!("Invalid arguments of '{self}'")),
+        }
+    }
+
+    /// Apply the operation to the image.
+    fn run(&self, image: &VipsImage, kernel: Kernel) -> libvips::Result<VipsImage> {
+        match self {
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let x = cmp::min(i32::from(*x), image.get_width() - 1);
+                let y = cmp::min(i32::from(*y), image.get_height() - 1);
+                let width = cmp::min(i32::from(*width), image.get_width() - x);
+                let height = cmp::min(i32::from(*height), image.get_height() - y);
+                ops::extract_area(image, x, y, width, height)
+            }
+            Operation::Resize { width, height } => {
+                let width_factor = f64::from(*width) / f64::from(image.get_width());
+                let factor = match height {
+                    Some(height) => {
+                        width_factor.min(f64::from(*height) / f64::from(image.get_height()))
+                    }
+                    None => width_factor,
+                };
+                resize(image, factor.min(1.0), kernel)
+            }
+            Operation::Cover { width, height } => cover(image, *width, *height, kernel),
+            Operation::Grayscale => ops::colourspace(image, ops::Interpretation::BW),
+            Operation::Sharpen { sigma } => ops::sharpen_with_opts(
+                image,
+                &ops::SharpenOptions {
+                    sigma: *sigma,
+                    ..ops::SharpenOptions::default()
+                },
+            ),
+            Operation::Blur { sigma } => ops::gaussblur(image, *sigma),
+            Operation::Rotate { angle } => {
+                let angle = match angle {
+                    90 => ops::Angle::D90,
+                    180 => ops::Angle::D180,
+                    _ => ops::Angle::D270,
+                };
+                ops::rot(image, angle)
+            }
+            Operation::Flip { horizontal } => {
+                let direction = match horizontal {
+                    true => ops::Direction::Horizontal,
+                    false => ops::Direction::Vertical,
+                };
+                ops::flip(image, direction)
+            }
+            Operation::Gamma { value } => gamma(image, *value),
+            Operation::Exposure { ev } => exposure(image, *ev),
+        }
+    }
+}
+
+/// Ordered list of operations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline(Vec<Operation>);
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operations: Vec<String> = self.0.iter().map(|op| op.to_string()).collect();
+        write!(f, "{}", operations.join("|"))
+    }
+}
+
+impl Pipeline {
+    /// Parse the pipeline, operations are separated with '|'.
+    pub fn parse(value: &str) -> anyhow::Result<Pipeline> {
+        let operations = value
+            .split('|')
+            .map(Operation::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Pipeline::new(operations)
+    }
+
+    /// Check the operations and make a pipeline of them.
+    pub fn new(operations: Vec<Operation>) -> anyhow::Result<Pipeline> {
+        if operations.is_empty() {
+            bail!("Pipeline has no operations");
+        }
+        if operations.len() > MAX_OPERATIONS {
+            bail!("Pipeline is limited to {MAX_OPERATIONS} operations");
+        }
+        for operation in &operations {
+            operation.validate()?;
+        }
+        Ok(Pipeline(operations))
+    }
+
+    /// Apply all operations in order, resizing with the kernel.
+    pub fn run(&self, image: VipsImage, kernel: Kernel) -> libvips::Result<VipsImage> {
+        self.0
+            .iter()
+            .try_fold(image, |image, op| op.run(&image, kernel))
+    }
+}
+
+/// Gamma correction of the colour bands, alpha is kept.
+pub fn gamma(image: &VipsImage, value: f64) -> libvips::Result<VipsImage> {
+    let options = ops::GammaOptions { exponent: value };
+    if !image.image_hasalpha() {
+        return ops::gamma_with_opts(image, &options);
+    }
+    let colour_bands = image.get_bands() - 1;
+    let colour =
+        ops::extract_band_with_opts(image, 0, &ops::ExtractBandOptions { n: colour_bands })?;
+    let alpha = ops::extract_band(image, colour_bands)?;
+    ops::bandjoin(&mut [ops::gamma_with_opts(&colour, &options)?, alpha])
+}
+
+/// Exposure correction in stops, applied in linear light.
+pub fn exposure(image: &VipsImage, ev: f64) -> libvips::Result<VipsImage> {
+    let linear = ops::colourspace(image, ops::Interpretation::Scrgb)?;
+    let bands = linear.get_bands() as usize;
+    let mut multipliers = vec![2f64.powf(ev); bands];
+    // Alpha stays as is.
+    if linear.image_hasalpha() {
+        multipliers[bands - 1] = 1.0;
+    }
+    let exposed = ops::linear(&linear, &mut multipliers, &mut vec![0.0; bands])?;
+    ops::colourspace(&exposed, ops::Interpretation::Srgb)
+}
+
+/// Resize the image so that the smaller side is fully visible and crop the big side.
+/// Images are not upscaled.
+pub fn cover(
+    image: &VipsImage,
+    width: u16,
+    height: u16,
+    kernel: Kernel,
+) -> libvips::Result<VipsImage> {
+    let resized_image = cover_resize(image, width, height, kernel)?;
+    cover_crop(&resized_image, width, height)
+}
+
+/// Resize step of `cover`: the smaller side of the image becomes fully visible.
+pub fn cover_resize(
+    image: &VipsImage,
+    width: u16,
+    height: u16,
+    kernel: Kernel,
+) -> libvips::Result<VipsImage> {
+    let width_scale_factor = f64::from(width) / f64::from(image.get_width());
+    let height_scale_factor = f64::from(height) / f64::from(image.get_height());
+
+    let min_factor = width_scale_factor.max(height_scale_factor).min(1.0);
+    resize(image, min_factor, kernel)
+}
+
+/// `cover` zoomed in by the factor around the area found by the smart crop.
+/// The original is used as far as its resolution allows, the rest is upscaled.
+pub fn zoom(
+    image: &VipsImage,
+    width: u16,
+    height: u16,
+    zoom: f64,
+    kernel: Kernel,
+) -> libvips::Result<VipsImage> {
+    let width_scale_factor = f64::from(width) / f64::from(image.get_width());
+    let height_scale_factor = f64::from(height) / f64::from(image.get_height());
+    let cover_factor = width_scale_factor.max(height_scale_factor).min(1.0);
+
+    let resize_factor = (cover_factor * zoom).min(1.0);
+    let upscale = cover_factor * zoom / resize_factor;
+    let resized_image = resize(image, resize_factor, kernel)?;
+    // The region is enlarged to the requested size afterwards.
+    let region_width = (f64::from(width) / upscale).round().max(1.0) as u16;
+    let region_height = (f64::from(height) / upscale).round().max(1.0) as u16;
+    let region = cover_crop(&resized_image, region_width, region_height)?;
+    match upscale > 1.0 {
+        true => resize(&region, upscale, kernel),
+        false => Ok(region),
+    }
+}
+
+/// Crop step of `cover`: the big side is cropped with the smart algorithm.
+pub fn cover_crop(image: &VipsImage, width: u16, height: u16) -> libvips::Result<VipsImage> {
+    ops::smartcrop(
+        image,
+        cmp::min(width.into(), image.get_width()),
+        cmp::min(height.into(), image.get_height()),
+    )
+}
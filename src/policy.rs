@@ -0,0 +1,210 @@
+//! Transformation policies of image groups.
+//!
+//! Images are grouped by tags: a policy applies to the images having the tag
+//! of the same name. Policies are defined in the config file, for example:
+//!
+//! ```toml
+//! [policies.press-kit]
+//! watermark = "always"
+//! max_width = 2000
+//! max_height = 2000
+//! formats = ["jpeg", "webp"]
+//! max_quality = 85
+//!
+//! [policies.press-kit.defaults]
+//! width = 1200
+//! ```
+//!
+//! `defaults` are used for parameters missing in the request (after presets),
+//! the other options are hard limits applied after the negotiation. If several
+//! policies apply, their defaults are merged in name order and the strictest limits win.
+use crate::{
+    api::image::{ImageFormat, ImageProps},
+    params, HttpError,
+};
+use anyhow::bail;
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap},
+};
+
+/// Whether the watermark is forced.
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatermarkPolicy {
+    /// Always add the watermark.
+    Always,
+    /// Never add a watermark, even if requested.
+    Never,
+}
+
+/// Defaults and limits of the image group.
+#[derive(Debug, Clone, Default, serde::Deserialize, PartialEq)]
+pub struct Policy {
+    /// Image parameters used if the request doesn't give them.
+    #[serde(default)]
+    pub defaults: HashMap<String, String>,
+    pub max_width: Option<u16>,
+    pub max_height: Option<u16>,
+    pub watermark: Option<WatermarkPolicy>,
+    /// Allowed output formats, all formats are allowed if not set.
+    pub formats: Option<Vec<String>>,
+    pub max_quality: Option<u8>,
+}
+
+/// Policies by tag, sorted by name so that they are merged in a stable order.
+pub type Policies = BTreeMap<String, Policy>;
+
+/// Check the policies.
+pub fn check(policies: &Policies) -> anyhow::Result<()> {
+    for (name, policy) in policies {
+        if policy.max_width == Some(0) || policy.max_height == Some(0) {
+            bail!("Maximum size of policy '{name}' must be greater than 0");
+        }
+        if let Some(quality) = policy.max_quality {
+            if quality == 0 || quality > 100 {
+                bail!("Maximum quality of policy '{name}' must be from 1 to 100");
+            }
+        }
+        match &policy.formats {
+            Some(formats) if formats.is_empty() => {
+                bail!("Policy '{name}' must allow at least one format")
+            }
+            Some(formats) => {
+                for format in formats {
+                    if ImageFormat::parse(format).is_none() {
+                        bail!("Unknown format '{format}' in policy '{name}'");
+                    }
+                }
+            }
+            None => {}
+        }
+        if let Err(err) = ImageProps::from_params(&policy.defaults) {
+            bail!("Invalid defaults of policy '{name}': {}", err.message);
+        }
+    }
+    Ok(())
+}
+
+/// Merge the policies of the tags.
+/// Returns `None` if no policy applies.
+pub fn resolve(policies: &Policies, tags: &[String]) -> Option<Policy> {
+    let mut matching = policies
+        .iter()
+        .filter(|(name, _)| tags.contains(name))
+        .map(|(_, policy)| policy);
+    let mut merged = matching.next()?.clone();
+    for policy in matching {
+        for (name, value) in &policy.defaults {
+            merged.defaults.entry(name.clone()).or_insert(value.clone());
+        }
+        merged.max_width = min(merged.max_width, policy.max_width);
+        merged.max_height = min(merged.max_height, policy.max_height);
+        merged.max_quality = min(merged.max_quality, policy.max_quality);
+        // Forced watermarks win over forbidden ones.
+        merged.watermark = match (merged.watermark, policy.watermark) {
+            (Some(WatermarkPolicy::Always), _) | (_, Some(WatermarkPolicy::Always)) => {
+                Some(WatermarkPolicy::Always)
+            }
+            (watermark, None) | (None, watermark) => watermark,
+            (Some(WatermarkPolicy::Never), Some(WatermarkPolicy::Never)) => {
+                Some(WatermarkPolicy::Never)
+            }
+        };
+        merged.formats = match (merged.formats, &policy.formats) {
+            (Some(formats), Some(allowed)) => Some(
+                formats
+                    .into_iter()
+                    .filter(|format| allows(allowed, format))
+                    .collect(),
+            ),
+            (formats, None) => formats,
+            (None, allowed) => allowed.clone(),
+        };
+    }
+    Some(merged)
+}
+
+impl Policy {
+    /// Add the defaults to the parameters.
+    pub fn apply_defaults(&self, params: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut resolved = self.defaults.clone();
+        resolved.extend(
+            params
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        resolved
+    }
+
+    /// Apply the limits to the negotiated properties.
+    /// Explicitly requested formats that are not allowed are answered with 400,
+    /// negotiated and default ones are replaced by the first allowed format.
+    pub fn apply_limits(
+        &self,
+        props: &mut ImageProps,
+        params: &HashMap<String, String>,
+    ) -> Result<(), HttpError> {
+        if let Some(formats) = &self.formats {
+            if !allows(formats, &props.format.to_string()) {
+                match props.auto_format || props.smart_format || !params.contains_key("format") {
+                    true => match formats
+                        .first()
+                        .and_then(|format| ImageFormat::parse(format))
+                    {
+                        Some(format) => props.format = format,
+                        None => return Err(HttpError::forbidden("No format is allowed")),
+                    },
+                    false => {
+                        return Err(params::invalid(
+                            "format",
+                            &props.format.to_string(),
+                            &formats.join(", "),
+                        ))
+                    }
+                }
+            }
+        }
+
+        if let Some(max_width) = self.max_width {
+            props.width = cmp::min(props.width, max_width);
+        }
+        if let Some(max_height) = self.max_height {
+            props.height = cmp::min(props.height, max_height);
+        }
+        props.fit_aspect_ratio();
+
+        if let Some(max_quality) = self.max_quality {
+            // The ceiling replaces `quality=auto`, which could go above it.
+            props.auto_quality = false;
+            props.quality = cmp::min(props.quality, max_quality);
+        }
+
+        match self.watermark {
+            Some(WatermarkPolicy::Always) => props.watermark = true,
+            Some(WatermarkPolicy::Never) => {
+                props.watermark = false;
+                props.watermark_hash = None;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Check if the format is in the list, `jpg` and `jpeg` are the same.
+fn allows(formats: &[String], format: &str) -> bool {
+    let format = ImageFormat::parse(format).map(|format| format.to_string());
+    formats
+        .iter()
+        .any(|allowed| ImageFormat::parse(allowed).map(|allowed| allowed.to_string()) == format)
+}
+
+/// Smaller of the limits, `None` is no limit.
+fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
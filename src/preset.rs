@@ -0,0 +1,53 @@
+//! Named sets of image parameters.
+//!
+//! Presets are defined in the config file, for example:
+//!
+//! ```toml
+//! [presets.thumbnail]
+//! width = 200
+//! height = 200
+//! format = "jpeg"
+//! ```
+//!
+//! and requested with the `preset` parameter: `/images/<hash>?preset=thumbnail`.
+use std::collections::HashMap;
+
+/// Query parameter with the preset name.
+pub const PRESET_PARAM: &str = "preset";
+
+/// Parameters allowed in unsigned URLs if arbitrary parameters are not allowed.
+/// Any other parameter creates another variant, so it can only be set by a preset
+/// or a signed URL.
+pub const UNSIGNED_PARAMS: [&str; 3] = [PRESET_PARAM, "filename", "download"];
+
+/// Parameters of all presets by name.
+pub type Presets = HashMap<String, HashMap<String, String>>;
+
+/// Expand the requested preset into image parameters.
+/// Parameters given explicitly override the ones of the preset.
+/// Returns `None` if the preset does not exist.
+pub fn apply(
+    presets: &Presets,
+    params: &HashMap<String, String>,
+) -> Option<HashMap<String, String>> {
+    let name = match params.get(PRESET_PARAM) {
+        Some(name) => name,
+        None => return Some(params.clone()),
+    };
+
+    let mut resolved = presets.get(name)?.clone();
+    resolved.extend(
+        params
+            .iter()
+            .filter(|(key, _)| key.as_str() != PRESET_PARAM)
+            .map(|(key, value)| (key.clone(), value.clone())),
+    );
+    Some(resolved)
+}
+
+/// Check if the request sets image parameters directly instead of using a preset.
+pub fn has_restricted_params(params: &HashMap<String, String>) -> bool {
+    params
+        .keys()
+        .any(|param| !UNSIGNED_PARAMS.contains(&param.as_str()))
+}
@@ -0,0 +1,132 @@
+//! Upload progress.
+//!
+//! Uploads with the 'Upload-Id' header report the number of received bytes here,
+//! `GET /uploads/:id/progress` reads it. Progress is kept in memory of the instance
+//! handling the upload and is forgotten a minute after the upload ends.
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Header with the client-generated upload id.
+pub const HEADER: &str = "Upload-Id";
+/// How long to keep the progress of finished uploads.
+const RETAIN: Duration = Duration::from_secs(60);
+/// Maximum length of the upload id.
+const MAX_ID_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The body is being received.
+    Receiving,
+    /// The body is received, the image is being checked and saved.
+    Processing,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    /// Received bytes of the request body.
+    pub received: u64,
+    /// Size of the request body (if the client sent 'Content-Length').
+    pub total: Option<u64>,
+    pub status: Status,
+    #[serde(skip)]
+    updated: Instant,
+}
+
+/// Progress of the running and recently finished uploads.
+#[derive(Default)]
+pub struct Progress {
+    uploads: Mutex<HashMap<String, Entry>>,
+}
+
+/// Reports the progress of one upload.
+/// The upload is marked as failed if the tracker is dropped before `finish`.
+pub struct Tracker<'a> {
+    progress: &'a Progress,
+    id: String,
+    finished: bool,
+}
+
+/// Upload ids are up to 64 latin letters, digits, `-` and `_`.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Progress {
+    /// Start tracking the upload.
+    pub fn start(&self, id: &str, total: Option<u64>) -> Tracker<'_> {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, entry| {
+            entry.status == Status::Receiving
+                || entry.status == Status::Processing
+                || entry.updated.elapsed() < RETAIN
+        });
+        uploads.insert(
+            id.to_string(),
+            Entry {
+                received: 0,
+                total,
+                status: Status::Receiving,
+                updated: Instant::now(),
+            },
+        );
+
+        Tracker {
+            progress: self,
+            id: id.to_string(),
+            finished: false,
+        }
+    }
+
+    /// Get the progress of the upload.
+    pub fn get(&self, id: &str) -> Option<Entry> {
+        self.uploads.lock().unwrap().get(id).cloned()
+    }
+
+    fn update(&self, id: &str, update: impl FnOnce(&mut Entry)) {
+        if let Some(entry) = self.uploads.lock().unwrap().get_mut(id) {
+            update(entry);
+            entry.updated = Instant::now();
+        }
+    }
+}
+
+impl Tracker<'_> {
+    /// Count received bytes.
+    pub fn add(&self, bytes: usize) {
+        self.progress
+            .update(&self.id, |entry| entry.received += bytes as u64);
+    }
+
+    /// The whole body is received.
+    pub fn received(&self) {
+        self.progress
+            .update(&self.id, |entry| entry.status = Status::Processing);
+    }
+
+    /// The upload is saved.
+    pub fn finish(mut self) {
+        self.finished = true;
+        self.progress
+            .update(&self.id, |entry| entry.status = Status::Done);
+    }
+}
+
+impl Drop for Tracker<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.progress
+                .update(&self.id, |entry| entry.status = Status::Failed);
+        }
+    }
+}
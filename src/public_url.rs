@@ -0,0 +1,48 @@
+//! Absolute URLs in responses.
+//!
+//! The base is `public_base_url` if it is set. Otherwise, with `trust_forwarded_host`,
+//! it is taken from the 'X-Forwarded-Proto' and 'X-Forwarded-Host' headers set by
+//! the reverse proxy, falling back to 'Host'. Without both, URLs stay relative.
+use crate::AppConfig;
+use axum::http::{header, HeaderMap};
+
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Get the base of absolute URLs, without the trailing slash.
+/// Returns an empty string if it is unknown.
+pub fn base(cfg: &AppConfig, headers: &HeaderMap) -> String {
+    if let Some(base) = &cfg.public_base_url {
+        return base.trim_end_matches('/').to_string();
+    }
+    if !cfg.trust_forwarded_host {
+        return String::new();
+    }
+
+    // Proxies append their values, the first one is set by the outermost proxy.
+    let first = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|value| value.trim().to_string())
+            .filter(|value| is_valid_host(value))
+    };
+    let host = match first(X_FORWARDED_HOST).or_else(|| first(header::HOST.as_str())) {
+        Some(host) => host,
+        None => return String::new(),
+    };
+    let proto = match first(X_FORWARDED_PROTO).as_deref() {
+        Some("http") => "http",
+        _ => "https",
+    };
+    format!("{proto}://{host}")
+}
+
+/// Hosts are limited to the characters of domain names, IP addresses and ports.
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+}
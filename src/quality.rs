@@ -0,0 +1,57 @@
+//! Automatic quality (`quality=auto`).
+//!
+//! The lowest quality is searched for, at which a downscaled copy of the image
+//! stays within the target mean colour difference (CIEDE2000) from the original.
+//!
+//! CIEDE2000 is used instead of DSSIM or butteraugli: libvips computes it in one
+//! operation (`dE00`), without another dependency, and it is cheap enough to run
+//! for every step of the search. It is less sensitive to blocking and ringing than
+//! structural metrics, so the default target is kept conservative.
+use libvips::{ops, VipsImage};
+
+/// Lowest quality considered.
+const MIN_QUALITY: u8 = 30;
+/// Highest quality considered.
+const MAX_QUALITY: u8 = 95;
+/// Size of the copy used for comparison.
+const SAMPLE_SIZE: i32 = 256;
+
+/// Find the lowest quality meeting the target difference.
+/// `encode` encodes the image in the requested format with the given quality.
+pub fn find<F>(image: &VipsImage, target: f64, encode: F) -> libvips::Result<u8>
+where
+    F: Fn(&VipsImage, u8) -> libvips::Result<Vec<u8>>,
+{
+    let sample = ops::thumbnail_image_with_opts(
+        image,
+        SAMPLE_SIZE,
+        &ops::ThumbnailImageOptions {
+            height: SAMPLE_SIZE,
+            size: ops::Size::Down,
+            ..ops::ThumbnailImageOptions::default()
+        },
+    )?;
+    // Colour difference is defined for images without alpha.
+    let sample = match sample.image_hasalpha() {
+        true => ops::flatten(&sample)?,
+        false => sample,
+    };
+    let reference = ops::colourspace(&sample, ops::Interpretation::Lab)?;
+
+    // Binary search, the difference decreases with quality.
+    let (mut low, mut high) = (MIN_QUALITY, MAX_QUALITY);
+    while low < high {
+        let quality = low + (high - low) / 2;
+        let encoded = encode(&sample, quality)?;
+        let decoded = VipsImage::new_from_buffer(&encoded, "")?;
+        let decoded = ops::colourspace(&decoded, ops::Interpretation::Lab)?;
+        let difference = ops::avg(&ops::d_e00(&reference, &decoded)?)?;
+
+        match difference <= target {
+            true => high = quality,
+            false => low = quality + 1,
+        }
+    }
+
+    Ok(high)
+}
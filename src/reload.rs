@@ -0,0 +1,167 @@
+//! Hot configuration reload.
+//!
+//! On SIGHUP or `POST /admin/reload` the configuration is read again and
+//! the reloadable settings are swapped in `AppState`. Other settings
+//! are applied only after a restart.
+use crate::{app_config::WatermarkBlend, preset::Presets, AppConfig, AppState};
+use anyhow::bail;
+use axum::http::HeaderValue;
+use libvips::{ops, VipsImage};
+use log::{error, info, warn};
+use std::{path::Path, sync::Arc};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Settings that can be changed without a restart.
+pub struct Settings {
+    /// Named sets of image parameters.
+    pub presets: Presets,
+    /// Buffer with watermark, the opacity is already applied.
+    /// (VipsImage cannot be passed between threads)
+    pub watermark: Option<Vec<u8>>,
+    /// How the watermark is composited.
+    pub watermark_blend: WatermarkBlend,
+    /// Opacity of uploaded watermarks.
+    pub watermark_opacity: f64,
+    /// Width of the watermark in percent of the output width, native size if not set.
+    pub watermark_width_percent: Option<u8>,
+    /// Space between repeated watermarks in pixels.
+    pub watermark_tile_spacing: u16,
+    /// Angle of repeated watermarks in degrees.
+    pub watermark_tile_angle: f64,
+    /// Opacity of repeated watermarks.
+    pub watermark_tile_opacity: f64,
+    /// Pango font description of the overlay.
+    pub overlay_font: String,
+    /// Font file loaded for the overlay.
+    pub overlay_font_file: Option<String>,
+    /// Origins allowed by CORS, all origins are allowed if not set.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Lifetime of processed images in the cache, in seconds.
+    /// Applies to images cached after the reload.
+    pub cache_ttl_secs: Option<u64>,
+    /// How long to remember missing images, in seconds (0 disables).
+    pub missing_cache_secs: u64,
+}
+
+impl Settings {
+    /// Take the reloadable settings from the configuration.
+    pub fn load(cfg: &AppConfig) -> anyhow::Result<Settings> {
+        if !(0.0..=1.0).contains(&cfg.watermark_opacity) {
+            bail!("Watermark opacity must be from 0 to 1");
+        }
+        if !(0.0..=1.0).contains(&cfg.watermark_tile_opacity) {
+            bail!("Opacity of repeated watermarks must be from 0 to 1");
+        }
+        if !cfg.watermark_tile_angle.is_finite() {
+            bail!("Angle of repeated watermarks must be a number");
+        }
+        // Preload watermark
+        let watermark = match &cfg.watermark_file_path {
+            Some(path) => {
+                let image = VipsImage::new_from_file(path)
+                    .map_err(|err| anyhow::anyhow!("Cannot load watermark '{path}': {err}"))?;
+                let image = match cfg.watermark_opacity < 1.0 {
+                    true => with_opacity(&image, cfg.watermark_opacity)?,
+                    false => image,
+                };
+                Some(image.image_write_to_buffer(".png")?)
+            }
+            None => None,
+        };
+        if let Some(percent) = cfg.watermark_width_percent {
+            if percent == 0 || percent > 100 {
+                bail!("Watermark width must be from 1 to 100 percent");
+            }
+        }
+
+        if let Some(path) = &cfg.overlay_font_file {
+            if !Path::new(path).is_file() {
+                bail!("Overlay font file '{path}' was not found");
+            }
+        }
+
+        if let Some(origins) = &cfg.allowed_origins {
+            for origin in origins {
+                if origin.parse::<HeaderValue>().is_err() {
+                    bail!("Invalid CORS origin '{origin}'");
+                }
+            }
+        }
+        if cfg.cors_allow_credentials && cfg.allowed_origins.is_none() {
+            bail!("CORS credentials cannot be allowed for all origins, set allowed origins");
+        }
+
+        Ok(Settings {
+            presets: cfg.presets.clone(),
+            watermark,
+            watermark_blend: cfg.watermark_blend,
+            watermark_opacity: cfg.watermark_opacity,
+            watermark_width_percent: cfg.watermark_width_percent,
+            watermark_tile_spacing: cfg.watermark_tile_spacing,
+            watermark_tile_angle: cfg.watermark_tile_angle,
+            watermark_tile_opacity: cfg.watermark_tile_opacity,
+            overlay_font: cfg.overlay_font.clone(),
+            overlay_font_file: cfg.overlay_font_file.clone(),
+            allowed_origins: cfg.allowed_origins.clone(),
+            cache_ttl_secs: cfg.cache_ttl_secs,
+            missing_cache_secs: cfg.missing_cache_secs,
+        })
+    }
+
+    /// Check the 'Origin' header against the allowed origins.
+    pub fn is_origin_allowed(&self, origin: &HeaderValue) -> bool {
+        match &self.allowed_origins {
+            Some(origins) => origins
+                .iter()
+                .any(|allowed| allowed.as_bytes() == origin.as_bytes()),
+            None => true,
+        }
+    }
+}
+
+/// Multiply the alpha channel of the image by the opacity, adding the channel if it is missing.
+pub fn with_opacity(image: &VipsImage, opacity: f64) -> anyhow::Result<VipsImage> {
+    // Grayscale and RGB images have an alpha channel with 2 and 4 bands.
+    let image = match image.get_bands() {
+        1 | 3 => ops::bandjoin_const(image, &mut [255.0])?,
+        _ => ops::copy(image)?,
+    };
+    let bands = image.get_bands() as usize;
+    let mut multipliers = vec![1.0; bands];
+    multipliers[bands - 1] = opacity;
+    let image = ops::linear(&image, &mut multipliers, &mut vec![0.0; bands])?;
+    Ok(ops::cast(&image, ops::BandFormat::Uchar)?)
+}
+
+/// Read the configuration again and swap the reloadable settings.
+pub async fn reload(state: Arc<AppState>) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let cfg = crate::app_config::get_config(state.config_path.as_deref())?;
+        // Credentials cannot be enabled without a restart, check the current value.
+        let cfg = AppConfig {
+            cors_allow_credentials: state.cfg.cors_allow_credentials,
+            ..cfg
+        };
+        state.set_settings(Settings::load(&cfg)?);
+        Ok(())
+    })
+    .await?
+}
+
+/// Reload the configuration on SIGHUP.
+pub async fn handle_sighup(state: Arc<AppState>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!("Cannot listen for SIGHUP: {err}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match reload(state.clone()).await {
+            Ok(()) => info!("Configuration reloaded"),
+            Err(err) => error!("Cannot reload configuration: {err}"),
+        }
+    }
+}
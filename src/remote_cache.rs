@@ -0,0 +1,84 @@
+//! Cache of processed images in a shared storage.
+//!
+//! With `cache_storage_url`, processed images are written to the storage (usually
+//! an S3 bucket shared by all instances) under the hash of their cache key, and Redis keeps only
+//! a short pointer with the size (see `cache` module). Any instance serves an image
+//! rendered by another one, and the render lock (see `render_lock` module) keeps
+//! them from rendering it at the same time.
+//!
+//! Objects of removed pointers stay in the storage, a lifecycle rule of the bucket
+//! should expire them. Purged images are deleted from the storage too.
+use crate::{hash, storage::Storage, AppConfig};
+
+/// Prefix of pointers to objects, followed by the size in bytes.
+/// Images never start with it.
+pub const POINTER_PREFIX: &[u8] = b"canvas-remote:";
+
+/// Shared storage of processed images.
+pub struct RemoteCache {
+    storage: Storage,
+}
+
+impl RemoteCache {
+    /// Remote cache of the configuration, `None` if disabled.
+    pub fn new(cfg: &AppConfig) -> anyhow::Result<Option<RemoteCache>> {
+        cfg.cache_storage_url
+            .as_ref()
+            .map(|url| {
+                Ok(RemoteCache {
+                    storage: Storage::from_url(url)?,
+                })
+            })
+            .transpose()
+    }
+
+    /// Write the image and return the pointer to be stored in Redis.
+    pub async fn write(&self, key: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.storage.put(&object_key(key), data).await?;
+        let mut pointer = POINTER_PREFIX.to_vec();
+        pointer.extend_from_slice(data.len().to_string().as_bytes());
+        Ok(pointer)
+    }
+
+    /// Read the image, `None` if it is missing or has another size.
+    pub async fn read(&self, key: &str, size: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self
+            .storage
+            .get(&object_key(key))
+            .await?
+            .filter(|data| data.len() as u64 == size))
+    }
+
+    /// Delete the images.
+    pub async fn delete(&self, keys: &[String]) -> anyhow::Result<()> {
+        for key in keys {
+            self.storage.delete(&object_key(key)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the object. Cache keys contain the overlay text, so they can be too long
+/// for the storage or contain slashes.
+fn object_key(key: &str) -> String {
+    hash::compute(key.as_bytes())
+}
+
+/// Check that the storage can be used for the cache.
+pub fn check(cfg: &AppConfig) -> anyhow::Result<()> {
+    if let Some(url) = &cfg.cache_storage_url {
+        if let Storage::Http { .. } = Storage::from_url(url)? {
+            anyhow::bail!("Cache storage '{url}' is read only");
+        }
+        if cfg.disk_cache_dir.is_some() {
+            anyhow::bail!("Settings 'cache_storage_url' and 'disk_cache_dir' can't be combined");
+        }
+    }
+    Ok(())
+}
+
+/// Size of the object if the value is a pointer.
+pub fn parse_pointer(value: &[u8]) -> Option<u64> {
+    let len = value.strip_prefix(POINTER_PREFIX)?;
+    std::str::from_utf8(len).ok()?.parse().ok()
+}
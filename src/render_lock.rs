@@ -0,0 +1,234 @@
+//! Render locks of processed images.
+//!
+//! Requests missing the cache for the same key at once, on one instance or on several
+//! sharing Redis, would render the same image each. The first one takes a lease
+//! (`render:<key>`, set with NX and PX) and renders the image. Others poll the cache
+//! until the image appears, or until the lease is gone or `render_lock_ms` passed,
+//! then try to take the lease again, so that only one of them renders it.
+//!
+//! The lease expires by itself if the holder dies, and only the holder can release it.
+//! A lease dropped without `release`, when rendering fails or the request is cancelled,
+//! is released in the background, so that waiting requests don't wait for it to expire.
+//! No pooled connection is held while waiting.
+//!
+//! Requests on the same instance first wait for each other in memory (`Flights`,
+//! an in-process singleflight keyed by the cache key), so only one of them polls Redis.
+use log::warn;
+use mobc::Pool;
+use mobc_redis::{
+    redis::{self, aio::Connection, AsyncCommands, RedisError, RedisResult, Script},
+    RedisConnectionManager,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+/// How often waiting requests check the cache.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lease of the render lock, released when dropped.
+pub struct Lease {
+    key: String,
+    token: String,
+    /// Pool to release the lease with if it is dropped, `None` once released.
+    pool: Option<Pool<RedisConnectionManager>>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let pool = match self.pool.take() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let key = std::mem::take(&mut self.key);
+        let token = std::mem::take(&mut self.token);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let released = match pool.get().await {
+                    Ok(mut con) => delete(&mut con, &key, &token)
+                        .await
+                        .map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                if let Err(err) = released {
+                    warn!("Cannot release the render lock {key}: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// Requests of this instance rendering each image, they take turns.
+#[derive(Default)]
+pub struct Flights(Mutex<HashMap<String, Arc<AsyncMutex<()>>>>);
+
+/// Place of the request in the flight of the image, the next one goes when dropped.
+struct Flight<'a> {
+    flights: &'a Flights,
+    image_id: String,
+    mutex: Arc<AsyncMutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Flights {
+    /// Wait for the requests of this instance ahead of this one.
+    /// Returns the place and whether there were any.
+    async fn join(&self, image_id: &str) -> (Flight<'_>, bool) {
+        let mutex = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(image_id.to_string())
+            .or_default()
+            .clone();
+        // The place is cleaned up by its drop, on cancellation too.
+        let mut flight = Flight {
+            flights: self,
+            image_id: image_id.to_string(),
+            mutex,
+            guard: None,
+        };
+        let waited = match flight.mutex.clone().try_lock_owned() {
+            Ok(guard) => {
+                flight.guard = Some(guard);
+                false
+            }
+            Err(_) => {
+                flight.guard = Some(flight.mutex.clone().lock_owned().await);
+                true
+            }
+        };
+        (flight, waited)
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        self.guard = None;
+        let mut flights = self.flights.0.lock().unwrap();
+        // Held only by the map and this place, nobody else is waiting.
+        let last = flights
+            .get(&self.image_id)
+            .is_some_and(|mutex| Arc::strong_count(mutex) == 2);
+        if last {
+            flights.remove(&self.image_id);
+        }
+    }
+}
+
+/// Turn of the request to render the image, see `lock`.
+/// The lease is dropped before the place in the flight.
+pub struct Turn<'a> {
+    lease: Lease,
+    _flight: Flight<'a>,
+}
+
+fn key(image_id: &str) -> String {
+    format!("render:{image_id}")
+}
+
+/// Wait for the turn to render the image, `None` if another request cached it meanwhile.
+/// Only the first request of this instance goes to Redis, the others wait for it.
+pub async fn lock<'a>(
+    flights: &'a Flights,
+    pool: &Pool<RedisConnectionManager>,
+    image_id: &str,
+    lease_ms: u64,
+) -> Result<Option<Turn<'a>>, mobc::Error<RedisError>> {
+    let (flight, waited) = flights.join(image_id).await;
+    if waited {
+        let mut con = pool.get().await?;
+        if con.exists(image_id).await? {
+            return Ok(None);
+        }
+    }
+    loop {
+        if let Some(lease) = acquire(pool, image_id, lease_ms).await? {
+            return Ok(Some(Turn {
+                lease,
+                _flight: flight,
+            }));
+        }
+        if wait(pool, image_id, lease_ms).await? {
+            return Ok(None);
+        }
+    }
+}
+
+/// Take the lease for `lease_ms`, `None` if another request holds it.
+async fn acquire(
+    pool: &Pool<RedisConnectionManager>,
+    image_id: &str,
+    lease_ms: u64,
+) -> Result<Option<Lease>, mobc::Error<RedisError>> {
+    let mut con = pool.get().await?;
+    let key = key(image_id);
+    let token = Uuid::new_v4().to_string();
+    let acquired: Option<String> = redis::cmd("SET")
+        .arg(&key)
+        .arg(&token)
+        .arg("NX")
+        .arg("PX")
+        .arg(lease_ms)
+        .query_async(&mut *con)
+        .await?;
+    Ok(acquired.map(|_| Lease {
+        key,
+        token,
+        pool: Some(pool.clone()),
+    }))
+}
+
+/// Release the turn after the image was cached.
+/// A lease that expired and was taken by another request is left alone.
+pub async fn release(con: &mut Connection, turn: Turn<'_>) -> RedisResult<()> {
+    let mut lease = turn.lease;
+    lease.pool = None;
+    delete(con, &lease.key, &lease.token).await
+}
+
+/// Delete the lease if it still has the token, in one step.
+async fn delete(con: &mut Connection, key: &str, token: &str) -> RedisResult<()> {
+    let script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    );
+    let _: i32 = script.key(key).arg(token).invoke_async(con).await?;
+    Ok(())
+}
+
+/// Wait for the image rendered by the holder of the lease, for at most `lease_ms`.
+/// Returns `false` if it wasn't cached, then the lease should be taken again.
+/// A connection is taken from the pool only for each check.
+async fn wait(
+    pool: &Pool<RedisConnectionManager>,
+    image_id: &str,
+    lease_ms: u64,
+) -> Result<bool, mobc::Error<RedisError>> {
+    let lock_key = key(image_id);
+    let deadline = Instant::now() + Duration::from_millis(lease_ms);
+    while Instant::now() < deadline {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let mut con = pool.get().await?;
+        let (cached, locked): (bool, bool) = redis::pipe()
+            .exists(image_id)
+            .exists(&lock_key)
+            .query_async(&mut *con)
+            .await?;
+        if cached {
+            return Ok(true);
+        }
+        if !locked {
+            return Ok(false);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,99 @@
+//! Replication of originals to the secondary storage.
+//!
+//! New uploads are queued and copied in the background.
+//! Failed copies are retried a few times, the backfill on startup catches the rest.
+use crate::{hash, storage::Storage, AppState};
+use log::{error, info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+/// Maximum number of queued images.
+pub const QUEUE_SIZE: usize = 10000;
+/// Number of attempts to copy an image.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled after each attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Queue the image for replication.
+pub fn enqueue(state: &AppState, hash: &str) {
+    if let Some(queue) = &state.replication_queue {
+        if let Err(err) = queue.try_send(hash.to_string()) {
+            warn!("Failed to queue {hash} for replication: {err}");
+        }
+    }
+}
+
+/// Copy queued images to the replica.
+pub async fn worker(
+    state: Arc<AppState>,
+    replica: Arc<Storage>,
+    mut queue: mpsc::Receiver<String>,
+) {
+    while let Some(hash) = queue.recv().await {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match replicate(&state, &replica, &hash).await {
+                Ok(()) => break,
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    warn!("Replication of {hash} failed (attempt {attempt}): {err}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => error!("Replication of {hash} failed: {err}"),
+            }
+        }
+    }
+}
+
+async fn replicate(state: &AppState, replica: &Storage, hash: &str) -> anyhow::Result<()> {
+    // The image could have been deleted in the meantime.
+    let data = match tokio::fs::read(state.get_file_path(hash)).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    replica.put(hash, &data).await
+}
+
+/// Queue all local originals missing in the replica.
+pub async fn backfill(state: Arc<AppState>, replica: Arc<Storage>) {
+    let mut entries = match tokio::fs::read_dir(&state.cfg.upload_dir).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Replication backfill failed: {err}");
+            return;
+        }
+    };
+
+    let mut queued = 0;
+    loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(err) => {
+                error!("Replication backfill failed: {err}");
+                return;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !hash::is_valid(&name) {
+            continue;
+        }
+
+        match replica.exists(&name).await {
+            Ok(true) => {}
+            Ok(false) => {
+                if let Some(queue) = &state.replication_queue {
+                    // Wait for free space instead of dropping images.
+                    if queue.send(name).await.is_err() {
+                        return;
+                    }
+                    queued += 1;
+                }
+            }
+            Err(err) => warn!("Failed to check {name} in the replica: {err}"),
+        }
+    }
+
+    info!("Replication backfill queued {queued} images");
+}
@@ -0,0 +1,20 @@
+//! Extra headers of image responses.
+//!
+//! Static headers from the `response_headers` section of the config file are added
+//! to every image response, replacing the headers set by the server.
+use anyhow::anyhow;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::BTreeMap;
+
+/// Parse the configured headers.
+pub fn parse(headers: &BTreeMap<String, String>) -> anyhow::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|_| anyhow!("Invalid response header name '{name}'"))?;
+        let value = HeaderValue::try_from(value.as_str())
+            .map_err(|_| anyhow!("Invalid value of response header '{name}'"))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
@@ -0,0 +1,72 @@
+//! Sanitization of uploaded originals.
+//!
+//! Images containing location or serial number tags are re-encoded
+//! with the orientation applied and all metadata stripped.
+//! Optionally, every upload is re-encoded into a canonical format.
+use crate::{app_config::CanonicalFormat, sniff};
+use exif::{Context, Tag};
+use libvips::{ops, VipsImage};
+use std::io::Cursor;
+
+/// Tags that identify the owner or the device.
+const SENSITIVE_TAGS: [Tag; 4] = [
+    Tag::BodySerialNumber,
+    Tag::LensSerialNumber,
+    Tag::CameraOwnerName,
+    Tag::ImageUniqueID,
+];
+
+/// List sensitive EXIF tags of the image (GPS and serial numbers).
+pub fn sensitive_tags(data: &[u8]) -> Vec<String> {
+    let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(exif) => exif,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut tags: Vec<String> = exif
+        .fields()
+        .filter(|field| field.tag.context() == Context::Gps || SENSITIVE_TAGS.contains(&field.tag))
+        .map(|field| field.tag.to_string())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Re-encode the image in its original format without metadata.
+/// Fails if the format can't be written, the image must not be stored with the tags.
+pub fn strip_metadata(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let suffix = match sniff::mime_type(data) {
+        Some("image/jpeg") => ".jpg[Q=95,strip]",
+        Some("image/png") => ".png[strip]",
+        Some("image/webp") => ".webp[Q=95,strip]",
+        Some("image/tiff") => ".tif[strip]",
+        Some("image/heif") => ".heic[Q=95,strip]",
+        Some("image/avif") => ".avif[Q=95,strip]",
+        Some("image/gif") => ".gif[strip]",
+        mime_type => anyhow::bail!(
+            "Cannot remove metadata from {}",
+            mime_type.unwrap_or("unknown format")
+        ),
+    };
+
+    let image = VipsImage::new_from_buffer(data, "")?;
+    // Orientation tag is removed too, so apply it first.
+    let rotated_image = ops::autorot(&image)?;
+
+    Ok(rotated_image.image_write_to_buffer(suffix)?)
+}
+
+/// Re-encode the image into the canonical storage format.
+/// Orientation is applied and all metadata is removed.
+pub fn canonicalize(data: &[u8], format: CanonicalFormat) -> anyhow::Result<Vec<u8>> {
+    let suffix = match format {
+        CanonicalFormat::Webp => ".webp[lossless,strip]",
+        CanonicalFormat::Jpeg => ".jpg[Q=95,strip]",
+    };
+
+    let image = VipsImage::new_from_buffer(data, "")?;
+    let rotated_image = ops::autorot(&image)?;
+
+    Ok(rotated_image.image_write_to_buffer(suffix)?)
+}
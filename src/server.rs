@@ -0,0 +1,45 @@
+//! HTTP server settings.
+//!
+//! Both HTTP/1.1 and HTTP/2 are served on the same port. Without TLS, HTTP/2 is
+//! spoken with prior knowledge (h2c): load balancers can multiplex many requests
+//! over one connection.
+use crate::AppConfig;
+use anyhow::anyhow;
+use hyper::server::{conn::AddrIncoming, Builder};
+use std::time::Duration;
+
+/// Smallest header size limit accepted by hyper.
+const MIN_HEADER_SIZE_KB: usize = 8;
+
+/// Check the server settings.
+pub fn check(cfg: &AppConfig) -> anyhow::Result<()> {
+    if let Some(size) = cfg.max_header_size_kb {
+        if size < MIN_HEADER_SIZE_KB {
+            return Err(anyhow!(
+                "Header size limit must be at least {MIN_HEADER_SIZE_KB} KB"
+            ));
+        }
+    }
+    if cfg.http2_max_concurrent_streams == 0 {
+        return Err(anyhow!("HTTP/2 concurrent stream limit must be positive"));
+    }
+    Ok(())
+}
+
+/// Apply the settings to the server.
+pub fn configure(builder: Builder<AddrIncoming>, cfg: &AppConfig) -> Builder<AddrIncoming> {
+    let mut builder = builder
+        .http1_only(!cfg.http2_enabled)
+        .http1_keepalive(cfg.keep_alive)
+        .http1_header_read_timeout(Duration::from_secs(cfg.header_read_timeout_secs))
+        .http2_max_concurrent_streams(cfg.http2_max_concurrent_streams)
+        .http2_keep_alive_interval(cfg.http2_keep_alive_interval_secs.map(Duration::from_secs))
+        .http2_keep_alive_timeout(Duration::from_secs(cfg.http2_keep_alive_timeout_secs))
+        .tcp_keepalive(cfg.tcp_keepalive_secs.map(Duration::from_secs));
+    if let Some(size) = cfg.max_header_size_kb {
+        builder = builder
+            .http1_max_buf_size(1024 * size)
+            .http2_max_header_list_size((1024 * size) as u32);
+    }
+    builder
+}
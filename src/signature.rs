@@ -0,0 +1,155 @@
+//! Signed URLs.
+//!
+//! A signature is a hex-encoded HMAC-SHA256 of the request path and its sorted
+//! query parameters (excluding the signature itself), for example:
+//! `/images/<hash>?expires=1700000000&width=300`.
+//!
+//! Keys and values are percent-encoded in the signed string, except for the unreserved
+//! characters (`A-Z a-z 0-9 - . _ ~`). Otherwise a key containing `=` or `&`, like
+//! `expires=1700000000&width`, would sign the same string as separate parameters,
+//! and a signature for them would verify without the expiration time.
+use crate::clock::unix_now;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Query parameter containing the signature.
+pub const SIGNATURE_PARAM: &str = "sig";
+/// Optional query parameter with the expiration time (unix timestamp, seconds).
+pub const EXPIRES_PARAM: &str = "expires";
+
+/// Calculate the signature for the given path and query parameters.
+pub fn sign(key: &str, path: &str, params: &HashMap<String, String>) -> String {
+    let mut mac = new_mac(key);
+    mac.update(canonical_string(path, params).as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Check the signature and expiration time of the request.
+pub fn verify(key: &str, path: &str, params: &HashMap<String, String>) -> bool {
+    let signature = match params.get(SIGNATURE_PARAM).map(hex::decode) {
+        Some(Ok(signature)) => signature,
+        _ => return false,
+    };
+
+    if let Some(expires) = params.get(EXPIRES_PARAM) {
+        match expires.parse::<u64>() {
+            Ok(expires) if expires >= unix_now() => {}
+            _ => return false,
+        }
+    }
+
+    let mut mac = new_mac(key);
+    mac.update(canonical_string(path, params).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn new_mac(key: &str) -> HmacSha256 {
+    HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size")
+}
+
+/// Build the string to be signed: path and sorted parameters without the signature.
+fn canonical_string(path: &str, params: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<String> = params
+        .iter()
+        .filter(|(key, _)| key.as_str() != SIGNATURE_PARAM)
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect();
+    pairs.sort();
+
+    format!("{}?{}", path, pairs.join("&"))
+}
+
+/// Percent-encode everything except the unreserved characters.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "secret";
+    const PATH: &str = "/images/hash";
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn merged_parameters_have_another_canonical_string() {
+        let separate = params(&[("expires", "1700000000"), ("width", "300")]);
+        let merged = params(&[("expires=1700000000&width", "300")]);
+        assert_ne!(
+            canonical_string(PATH, &separate),
+            canonical_string(PATH, &merged)
+        );
+    }
+
+    #[test]
+    fn signature_of_separate_parameters_does_not_verify_merged_ones() {
+        let separate = params(&[("expires", "1700000000"), ("width", "300")]);
+        let sig = sign(KEY, PATH, &separate);
+        let mut merged = params(&[("expires=1700000000&width", "300")]);
+        merged.insert(SIGNATURE_PARAM.to_string(), sig);
+        assert!(!verify(KEY, PATH, &merged));
+    }
+
+    #[test]
+    fn value_with_separators_does_not_collide() {
+        let separate = params(&[("private", "1"), ("types", "image/png")]);
+        let merged = params(&[("private", "1&types=image/png")]);
+        assert_ne!(
+            canonical_string(PATH, &separate),
+            canonical_string(PATH, &merged)
+        );
+    }
+
+    #[test]
+    fn unreserved_characters_are_kept() {
+        let params = params(&[("width", "300"), ("format", "webp")]);
+        assert_eq!(
+            canonical_string(PATH, &params),
+            "/images/hash?format=webp&width=300"
+        );
+    }
+
+    #[test]
+    fn other_characters_are_encoded() {
+        let params = params(&[("ar", "16:9"), ("overlay", "a b&c")]);
+        assert_eq!(
+            canonical_string(PATH, &params),
+            "/images/hash?ar=16%3A9&overlay=a%20b%26c"
+        );
+    }
+
+    #[test]
+    fn valid_signature_verifies() {
+        let mut params = params(&[("width", "300"), ("ar", "16:9")]);
+        let sig = sign(KEY, PATH, &params);
+        params.insert(SIGNATURE_PARAM.to_string(), sig);
+        assert!(verify(KEY, PATH, &params));
+    }
+
+    #[test]
+    fn expired_signature_does_not_verify() {
+        let mut params = params(&[("expires", "1")]);
+        let sig = sign(KEY, PATH, &params);
+        params.insert(SIGNATURE_PARAM.to_string(), sig);
+        assert!(!verify(KEY, PATH, &params));
+    }
+}
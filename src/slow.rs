@@ -0,0 +1,55 @@
+//! Slow and large transformations.
+//!
+//! Transformations taking longer than `slow_transform_ms` or producing images larger
+//! than `large_output_kb` are logged with the cache key, the parameters and the time
+//! of each stage, and counted in metrics. The log helps to find pathological source
+//! images and abusive combinations of parameters.
+use crate::{budget::Budget, AppState};
+use log::warn;
+use std::{collections::HashMap, time::Duration};
+
+/// Report the finished transformation if it is slow or large.
+pub fn report(
+    state: &AppState,
+    image_id: &str,
+    params: &HashMap<String, String>,
+    budget: &Budget,
+    output_size: usize,
+) {
+    let elapsed = budget.elapsed();
+    let slow = match state.cfg.slow_transform_ms {
+        Some(ms) => elapsed > Duration::from_millis(ms),
+        None => false,
+    };
+    let large = match state.cfg.large_output_kb {
+        Some(kb) => output_size > 1024 * kb,
+        None => false,
+    };
+    if slow {
+        state.metrics.slow_transforms.inc();
+    }
+    if large {
+        state.metrics.large_outputs.inc();
+    }
+    if !slow && !large {
+        return;
+    }
+
+    let stages: Vec<String> = budget
+        .stages()
+        .iter()
+        .map(|(stage, time)| format!("{stage} {} ms", time.as_millis()))
+        .collect();
+    let mut params: Vec<String> = params
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    params.sort();
+    warn!(
+        "{} transformation of {image_id}: {} ms ({}), {output_size} bytes, params: {}",
+        if slow { "Slow" } else { "Large" },
+        elapsed.as_millis(),
+        stages.join(", "),
+        params.join("&")
+    );
+}
@@ -0,0 +1,137 @@
+//! Custom slugs (human-readable aliases of image hashes).
+//!
+//! The slug -> hash mapping is stored in Redis under `slug:<slug>` keys.
+//! Replacements of the image behind the slug are serialized with `lock`
+//! (`slug-lock:<slug>`, set with NX and EX), so that a replacement losing
+//! the race fails before its upload is saved.
+use mobc_redis::redis::{self, aio::Connection, AsyncCommands, RedisResult, Script};
+use uuid::Uuid;
+
+/// Maximum length of the slug.
+const MAX_LENGTH: usize = 128;
+
+/// Check if the slug is valid: 1-128 latin letters, digits, '-', '_' or '.'.
+pub fn is_valid(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_LENGTH
+        && slug != "."
+        && slug != ".."
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Redis key with the hash of the slug.
+pub fn key(slug: &str) -> String {
+    format!("slug:{slug}")
+}
+
+/// Get the hash of the image behind the slug.
+pub async fn resolve(con: &mut Connection, slug: &str) -> RedisResult<Option<String>> {
+    con.get(key(slug)).await
+}
+
+/// Lock of the slug being replaced.
+pub struct Lock {
+    key: String,
+    token: String,
+}
+
+/// Lock the slug for `ttl_secs`, `None` if another replacement holds it.
+pub async fn lock(con: &mut Connection, slug: &str, ttl_secs: u64) -> RedisResult<Option<Lock>> {
+    let lock = Lock {
+        key: format!("slug-lock:{slug}"),
+        token: Uuid::new_v4().to_string(),
+    };
+    let locked: Option<String> = redis::cmd("SET")
+        .arg(&lock.key)
+        .arg(&lock.token)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl_secs)
+        .query_async(con)
+        .await?;
+    Ok(locked.map(|_| lock))
+}
+
+/// Release the lock, unless it expired and was taken by another replacement.
+pub async fn unlock(con: &mut Connection, lock: Lock) -> RedisResult<()> {
+    let script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    );
+    let _: i32 = script
+        .key(lock.key)
+        .arg(lock.token)
+        .invoke_async(con)
+        .await?;
+    Ok(())
+}
+
+/// Result of claiming a slug.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    /// The slug was free and now points to the image.
+    New,
+    /// The slug already pointed to the image.
+    Existing,
+    /// The slug points to another image.
+    Taken,
+}
+
+/// Assign the slug to the image.
+pub async fn claim(con: &mut Connection, slug: &str, hash: &str) -> RedisResult<Claim> {
+    if con.set_nx(key(slug), hash).await? {
+        return Ok(Claim::New);
+    }
+    Ok(match resolve(con, slug).await?.as_deref() == Some(hash) {
+        true => Claim::Existing,
+        false => Claim::Taken,
+    })
+}
+
+/// Remove the slug if it still points to the image (after a failed upload).
+pub async fn release(con: &mut Connection, slug: &str, hash: &str) -> RedisResult<()> {
+    let script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    );
+    let _: i32 = script.key(key(slug)).arg(hash).invoke_async(con).await?;
+    Ok(())
+}
+
+/// Move the slug to another image if it still points to `previous`
+/// (`None` if the slug is not assigned).
+/// Returns false if the slug was changed in the meantime.
+pub async fn replace(
+    con: &mut Connection,
+    slug: &str,
+    previous: Option<&str>,
+    hash: &str,
+) -> RedisResult<bool> {
+    // Compare and set in one step, so that concurrent replacements can't overwrite each other.
+    let script = Script::new(
+        r"
+        if (redis.call('GET', KEYS[1]) or '') ~= ARGV[1] then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[2])
+        return 1
+        ",
+    );
+    let replaced: i32 = script
+        .key(key(slug))
+        .arg(previous.unwrap_or(""))
+        .arg(hash)
+        .invoke_async(con)
+        .await?;
+    Ok(replaced == 1)
+}
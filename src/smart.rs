@@ -0,0 +1,129 @@
+//! Output format chosen by the image content (`format=smart`).
+//!
+//! Graphics (screenshots, diagrams, logos) compress better and stay sharp with
+//! lossless encoding, photos with lossy one. The original is classified once
+//! by a small sample: few colours or large flat areas mean graphics. The class
+//! is kept in the image metadata, see `metadata::CONTENT_CLASS`.
+use crate::{
+    metadata::{self, ImageMetadata},
+    AppState,
+};
+use axum::body::Bytes;
+use libvips::{ops, VipsImage};
+use mobc_redis::redis::aio::Connection;
+use std::{collections::HashSet, fmt, str::FromStr};
+
+/// Longest side of the sample in pixels.
+const SAMPLE_SIZE: f64 = 256.0;
+/// Images with at most this number of colours are graphics.
+const MAX_GRAPHICS_COLOURS: usize = 256;
+/// Images with at least this share of pixels equal to their left neighbour are graphics.
+const MIN_GRAPHICS_FLATNESS: f64 = 0.6;
+
+/// Kind of the image content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentClass {
+    /// Photo without transparency, any lossy format.
+    Photo,
+    /// Photo with transparency, lossy formats with an alpha channel.
+    TransparentPhoto,
+    /// Screenshots, diagrams and logos, lossless formats.
+    Graphics,
+}
+
+impl fmt::Display for ContentClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ContentClass::Photo => "photo",
+                ContentClass::TransparentPhoto => "transparent-photo",
+                ContentClass::Graphics => "graphics",
+            }
+        )
+    }
+}
+
+impl FromStr for ContentClass {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<ContentClass, Self::Err> {
+        match value {
+            "photo" => Ok(ContentClass::Photo),
+            "transparent-photo" => Ok(ContentClass::TransparentPhoto),
+            "graphics" => Ok(ContentClass::Graphics),
+            _ => Err(anyhow::anyhow!("Unknown content class {value}")),
+        }
+    }
+}
+
+/// Get the content class of the uploaded image, classifying it on the first use.
+/// `data` is the original if it is already read.
+pub async fn resolve(
+    state: &AppState,
+    con: &mut Connection,
+    hash: &str,
+    meta: &ImageMetadata,
+    data: Option<Bytes>,
+) -> anyhow::Result<ContentClass> {
+    if let Some(class) = meta.content_class {
+        return Ok(class);
+    }
+    let data = match data {
+        Some(data) => data,
+        None => Bytes::from(tokio::fs::read(state.get_file_path(hash)).await?),
+    };
+    let class = tokio::task::spawn_blocking(move || classify(&data)).await??;
+    metadata::set_content_class(con, hash, class).await?;
+    Ok(class)
+}
+
+/// Classify the image by a sample.
+pub fn classify(data: &[u8]) -> anyhow::Result<ContentClass> {
+    let image = VipsImage::new_from_buffer(data, "")?;
+    // Nearest neighbour keeps the colours of the original.
+    let scale = SAMPLE_SIZE / f64::from(image.get_width().max(image.get_height()));
+    let sample = ops::resize_with_opts(
+        &image,
+        scale.min(1.0),
+        &ops::ResizeOptions {
+            kernel: ops::Kernel::Nearest,
+            ..ops::ResizeOptions::default()
+        },
+    )?;
+    let sample = ops::colourspace(&sample, ops::Interpretation::Srgb)?;
+    let sample = ops::cast(&sample, ops::BandFormat::Uchar)?;
+    let width = sample.get_width() as usize;
+    let bands = sample.get_bands() as usize;
+    let pixels = sample.image_write_to_memory();
+
+    let mut colours = HashSet::new();
+    let mut flat = 0;
+    let mut total = 0;
+    for (index, pixel) in pixels.chunks_exact(bands).enumerate() {
+        if colours.len() <= MAX_GRAPHICS_COLOURS {
+            colours.insert(pixel.to_vec());
+        }
+        if index % width > 0 {
+            let left = &pixels[(index - 1) * bands..index * bands];
+            total += 1;
+            if left == pixel {
+                flat += 1;
+            }
+        }
+    }
+    let flatness = match total {
+        0 => 0.0,
+        total => f64::from(flat) / f64::from(total),
+    };
+
+    if colours.len() <= MAX_GRAPHICS_COLOURS || flatness >= MIN_GRAPHICS_FLATNESS {
+        return Ok(ContentClass::Graphics);
+    }
+    // Grayscale and RGB images have an alpha channel with 2 and 4 bands.
+    match bands {
+        2 | 4 => Ok(ContentClass::TransparentPhoto),
+        _ => Ok(ContentClass::Photo),
+    }
+}
@@ -0,0 +1,41 @@
+//! File type detection by magic bytes.
+
+/// MIME types detected by `mime_type`.
+pub const MIME_TYPES: [&str; 9] = [
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/tiff",
+    "image/avif",
+    "image/heif",
+    "application/pdf",
+    "image/svg+xml",
+];
+
+/// Detect MIME type of the image by the first bytes of the file.
+pub fn mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some("image/png")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        Some("image/tiff")
+    } else if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        match &data[8..12] {
+            b"avif" | b"avis" => Some("image/avif"),
+            b"heic" | b"heix" | b"mif1" | b"msf1" => Some("image/heif"),
+            _ => None,
+        }
+    } else if data.starts_with(b"%PDF") {
+        Some("application/pdf")
+    } else if data.starts_with(b"<svg") || data.starts_with(b"<?xml") {
+        Some("image/svg+xml")
+    } else {
+        None
+    }
+}
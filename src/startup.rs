@@ -0,0 +1,99 @@
+//! Startup validation.
+//!
+//! All problems of the configuration are collected and reported together,
+//! so that they can be fixed at once.
+use crate::{
+    bucket, capabilities, client_ip, cors, disk_cache, encoder, fingerprint, imgproxy, policy,
+    reload::Settings, remote_cache, response_headers, server, storage::Storage, warm, AppConfig,
+};
+use mobc_redis::redis;
+use std::{fs, path::Path};
+
+/// Check the configuration and prepare the upload directory.
+/// Returns the list of problems, empty if the server can start.
+pub fn validate(cfg: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Err(err) = prepare_upload_dir(cfg) {
+        problems.push(format!(
+            "Upload directory '{}' is not writable: {err}",
+            cfg.upload_dir
+        ));
+    }
+    if let Some(dir) = &cfg.disk_cache_dir {
+        if let Err(err) = disk_cache::prepare(dir) {
+            problems.push(format!(
+                "Disk cache directory '{dir}' is not writable: {err}"
+            ));
+        }
+    }
+    // Watermark and allowed origins.
+    if let Err(err) = Settings::load(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = cors::check(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = bucket::check(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = encoder::check(&cfg.encoders) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = imgproxy::check(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = response_headers::parse(&cfg.response_headers) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = remote_cache::check(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = warm::check(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = server::check(cfg) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = fingerprint::check(cfg.invisible_watermark_strength) {
+        problems.push(err.to_string());
+    }
+    if let Err(err) = policy::check(&cfg.policies) {
+        problems.push(err.to_string());
+    }
+    for format in cfg.required_formats.iter().flatten() {
+        if !capabilities::is_known(format) {
+            problems.push(format!("Unknown required format '{format}'"));
+        }
+    }
+    if let Some(proxies) = &cfg.trusted_proxies {
+        if let Err(err) = client_ip::parse_networks(proxies) {
+            problems.push(err.to_string());
+        }
+    }
+    if let Err(err) = redis::Client::open(cfg.redis_url.clone()) {
+        problems.push(format!("Invalid Redis URL '{}': {err}", cfg.redis_url));
+    }
+    for (name, url) in [("replica", &cfg.replica_url), ("origin", &cfg.origin_url)] {
+        if let Some(url) = url {
+            if let Err(err) = Storage::from_url(url) {
+                problems.push(format!("Invalid {name} URL '{url}': {err}"));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Create the upload directory with its subdirectories and check that files can be written there.
+fn prepare_upload_dir(cfg: &AppConfig) -> std::io::Result<()> {
+    let upload_dir = Path::new(&cfg.upload_dir);
+    fs::create_dir_all(upload_dir.join("trash"))?;
+    if cfg.keep_raw_originals {
+        fs::create_dir_all(upload_dir.join("raw"))?;
+    }
+
+    let test_file = upload_dir.join(".write-test");
+    fs::write(&test_file, b"")?;
+    fs::remove_file(&test_file)
+}
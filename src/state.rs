@@ -1,11 +1,32 @@
-use crate::app_config::AppConfig;
-use libvips::VipsImage;
+use crate::{
+    app_config::AppConfig,
+    build_info::BuildInfo,
+    cache::Offload,
+    capabilities::{self, Capabilities},
+    client_ip::{self, Network},
+    encoder::EncoderOptions,
+    events::{self, ImageEvent},
+    jwks::Jwks,
+    metrics::Metrics,
+    moderation,
+    pipeline::Kernel,
+    progress::Progress,
+    reload::Settings,
+    render_lock::Flights,
+    response_headers, signature,
+    storage::Storage,
+    throttle::Throttle,
+};
+use axum::http::HeaderMap;
 use mobc::Pool;
 use mobc_redis::RedisConnectionManager;
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
+use tokio::sync::{broadcast, mpsc};
 
 /// Shared application state.
 pub struct AppState {
@@ -13,32 +34,151 @@ pub struct AppState {
     pub cfg: AppConfig,
     /// Redis connection pool.
     pub redis: Pool<RedisConnectionManager>,
-    /// Buffer with watermark.
-    /// (VipsImage cannot be passed between threads)
-    pub watermark: Option<Vec<u8>>,
+    /// Settings that can be reloaded without a restart.
+    settings: RwLock<Arc<Settings>>,
+    /// Path of the config file given on the command line.
+    pub config_path: Option<String>,
+    /// JWT validator (if JWKS URL is configured).
+    pub jwks: Option<Jwks>,
+    /// Image events for the '/admin/events' stream.
+    pub events: broadcast::Sender<ImageEvent>,
+    /// Queue of images to be copied to the replica (if configured).
+    pub replication_queue: Option<mpsc::Sender<String>>,
+    /// Storage with originals missing on the local disk (if configured).
+    pub origin: Option<Storage>,
+    /// Limits the number of concurrent transformations.
+    pub throttle: Throttle,
+    /// Prometheus metrics.
+    pub metrics: Metrics,
+    /// Progress of the uploads with the 'Upload-Id' header.
+    pub progress: Progress,
+    /// Extra headers of image responses.
+    pub response_headers: HeaderMap,
+    /// Reverse proxies allowed to set the client address.
+    pub trusted_proxies: Vec<Network>,
+    /// Versions and features of the server.
+    pub build: BuildInfo,
+    /// Formats supported by libvips.
+    pub capabilities: Capabilities,
+    /// Formats required for the readiness.
+    pub required_formats: Vec<String>,
+    /// Encoder options with the speed profile applied.
+    pub encoders: EncoderOptions,
+    /// Interpolation kernel of requests without the `kernel` parameter.
+    pub kernel: Kernel,
+    /// Storage of processed images outside of Redis,
+    /// if `disk_cache_dir` or `cache_storage_url` is set.
+    pub cache_offload: Option<Offload>,
+    /// HTTP client of the moderation requests, shares connections between them.
+    pub http_client: reqwest::Client,
+    /// Renderings in progress on this instance, see `render_lock` module.
+    pub flights: Flights,
 }
 
 impl AppState {
     /// Create new instance of application state.
-    pub fn new(cfg: AppConfig, redis: Pool<RedisConnectionManager>) -> Arc<AppState> {
-        // Preload watermark
-        let watermark = match &cfg.watermark_file_path {
-            Some(path) => {
-                let image = VipsImage::new_from_file(path).unwrap();
-                Some(image.image_write_to_buffer(".png").unwrap())
-            }
-            None => None,
+    pub fn new(
+        cfg: AppConfig,
+        config_path: Option<String>,
+        redis: Pool<RedisConnectionManager>,
+        replication_queue: Option<mpsc::Sender<String>>,
+        build: BuildInfo,
+    ) -> Arc<AppState> {
+        let settings = RwLock::new(Arc::new(Settings::load(&cfg).unwrap()));
+
+        let jwks = cfg.jwks_url.as_ref().map(|url| {
+            Jwks::new(
+                url.clone(),
+                cfg.jwt_issuer.clone(),
+                cfg.jwt_audience.clone(),
+                Duration::from_secs(cfg.jwks_cache_secs),
+            )
+        });
+
+        let (events, _) = broadcast::channel(events::CAPACITY);
+
+        let origin = cfg
+            .origin_url
+            .as_ref()
+            .map(|url| Storage::from_url(url).unwrap());
+
+        let throttle = Throttle::new(
+            cfg.max_concurrent_transforms.unwrap_or_else(num_cpus::get),
+            cfg.transform_queue_size,
+        );
+
+        let response_headers = response_headers::parse(&cfg.response_headers).unwrap();
+        let trusted_proxies = match &cfg.trusted_proxies {
+            Some(proxies) => client_ip::parse_networks(proxies).unwrap(),
+            None => Vec::new(),
         };
 
+        let capabilities = capabilities::detect();
+        let required_formats = capabilities::required(cfg.required_formats.as_deref());
+
+        let (encoders, kernel) = cfg.speed.profile(&cfg);
+        let cache_offload = Offload::new(&cfg).unwrap();
+
         Arc::new(AppState {
             cfg,
             redis,
-            watermark,
+            settings,
+            config_path,
+            jwks,
+            events,
+            replication_queue,
+            origin,
+            throttle,
+            metrics: Metrics::default(),
+            progress: Progress::default(),
+            response_headers,
+            trusted_proxies,
+            build,
+            capabilities,
+            required_formats,
+            encoders,
+            kernel,
+            cache_offload,
+            http_client: moderation::http_client(),
+            flights: Flights::default(),
         })
     }
 
+    /// Current reloadable settings.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Replace reloadable settings.
+    pub fn set_settings(&self, settings: Settings) {
+        *self.settings.write().unwrap() = Arc::new(settings);
+    }
+
     /// Get path to uploaded file by hash (id).
     pub fn get_file_path(&self, hash: &str) -> PathBuf {
         Path::new(&self.cfg.upload_dir).join(hash)
     }
+
+    /// Get path to the raw upload kept before canonicalization.
+    pub fn get_raw_file_path(&self, hash: &str) -> PathBuf {
+        Path::new(&self.cfg.upload_dir).join("raw").join(hash)
+    }
+
+    /// Get directory with deleted originals.
+    pub fn get_trash_dir(&self) -> PathBuf {
+        Path::new(&self.cfg.upload_dir).join("trash")
+    }
+
+    /// Get path to the deleted original.
+    pub fn get_trash_file_path(&self, hash: &str) -> PathBuf {
+        self.get_trash_dir().join(hash)
+    }
+
+    /// Check if the request to `path` has a valid signature.
+    pub fn is_signed(&self, path: &str, params: &HashMap<String, String>) -> bool {
+        match &self.cfg.signing_key {
+            Some(key) => signature::verify(key, path, params),
+            None => false,
+        }
+    }
 }
@@ -0,0 +1,98 @@
+//! Brightness and sharpness statistics of uploaded images.
+//!
+//! Statistics are computed on a sample of the image, so that they don't depend
+//! much on its size: blurry photos have a low sharpness (variance of the Laplacian
+//! of the brightness), black or blank ones a low mean and entropy.
+use libvips::ops;
+use serde::Serialize;
+
+/// Longest side of the sample in pixels.
+const SAMPLE_SIZE: i32 = 512;
+/// Number of histogram buckets.
+const BUCKETS: usize = 16;
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    /// Mean brightness from 0 to 255.
+    pub mean_brightness: f64,
+    /// Share of pixels in each of the equal brightness ranges, from dark to bright.
+    pub histogram: Vec<f64>,
+    /// Variance of the Laplacian of the brightness, lower for blurry images.
+    pub sharpness: f64,
+    /// Shannon entropy of the brightness in bits, from 0 (single colour) to 8.
+    pub entropy: f64,
+}
+
+/// Compute the statistics of the image.
+pub fn compute(data: &[u8]) -> anyhow::Result<Stats> {
+    let sample = ops::thumbnail_buffer_with_opts(
+        data,
+        SAMPLE_SIZE,
+        &ops::ThumbnailBufferOptions {
+            height: SAMPLE_SIZE,
+            size: ops::Size::Down,
+            ..ops::ThumbnailBufferOptions::default()
+        },
+    )?;
+    let sample = match sample.image_hasalpha() {
+        true => ops::flatten(&sample)?,
+        false => sample,
+    };
+    let grey = ops::colourspace(&sample, ops::Interpretation::BW)?;
+    let grey = ops::cast(&grey, ops::BandFormat::Uchar)?;
+    let width = grey.get_width() as usize;
+    let height = grey.get_height() as usize;
+    let pixels = grey.image_write_to_memory();
+
+    let mut counts = [0u64; 256];
+    for value in &pixels {
+        counts[usize::from(*value)] += 1;
+    }
+    let total = pixels.len().max(1) as f64;
+
+    let mean_brightness = counts
+        .iter()
+        .enumerate()
+        .map(|(value, count)| value as f64 * *count as f64)
+        .sum::<f64>()
+        / total;
+    let histogram = counts
+        .chunks(256 / BUCKETS)
+        .map(|bucket| bucket.iter().sum::<u64>() as f64 / total)
+        .collect();
+    let entropy = counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let share = *count as f64 / total;
+            -share * share.log2()
+        })
+        .sum();
+
+    Ok(Stats {
+        mean_brightness,
+        histogram,
+        sharpness: laplacian_variance(&pixels, width, height),
+        entropy,
+    })
+}
+
+/// Variance of the 4-neighbour Laplacian over the inner pixels.
+fn laplacian_variance(pixels: &[u8], width: usize, height: usize) -> f64 {
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let at = |x: usize, y: usize| f64::from(pixels[y * width + x]);
+    let (mut sum, mut sum_squares, mut count) = (0.0, 0.0, 0.0);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian =
+                at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+            sum += laplacian;
+            sum_squares += laplacian * laplacian;
+            count += 1.0;
+        }
+    }
+    let mean = sum / count;
+    sum_squares / count - mean * mean
+}
@@ -0,0 +1,157 @@
+//! Storage backends for originals and the shared cache of processed images.
+//!
+//! Backends are configured with URLs:
+//! - `file:///mnt/images` - local directory
+//! - `s3://bucket/prefix?region=us-east-1&endpoint=https://s3.example.com` - S3 bucket,
+//!   credentials are read from the standard AWS environment variables
+//! - `https://example.com/images` - HTTP server (read only)
+use anyhow::anyhow;
+use s3::{creds::Credentials, Bucket, Region};
+use std::path::PathBuf;
+use url::Url;
+
+pub enum Storage {
+    /// Local directory.
+    Local(PathBuf),
+    /// S3 bucket, keys are prefixed with `prefix`.
+    S3 { bucket: Box<Bucket>, prefix: String },
+    /// HTTP server, files are requested at `<base_url>/<key>`.
+    Http {
+        base_url: String,
+        client: reqwest::Client,
+    },
+}
+
+impl Storage {
+    /// Create the backend from its URL.
+    pub fn from_url(value: &str) -> anyhow::Result<Storage> {
+        let url = Url::parse(value)?;
+        match url.scheme() {
+            "file" => Ok(Storage::Local(PathBuf::from(url.path()))),
+            "s3" => {
+                let name = url
+                    .host_str()
+                    .ok_or_else(|| anyhow!("Missing bucket name in {value}"))?;
+                let param = |key: &str| {
+                    url.query_pairs()
+                        .find(|(name, _)| name == key)
+                        .map(|(_, value)| value.to_string())
+                };
+                let region_name = param("region").unwrap_or_else(|| "us-east-1".to_string());
+                let region = match param("endpoint") {
+                    Some(endpoint) => Region::Custom {
+                        region: region_name,
+                        endpoint,
+                    },
+                    None => region_name.parse()?,
+                };
+
+                let mut bucket = Bucket::new(name, region, Credentials::default()?)?;
+                if param("path_style").is_some() {
+                    bucket = bucket.with_path_style();
+                }
+
+                Ok(Storage::S3 {
+                    bucket: Box::new(bucket),
+                    prefix: url.path().trim_matches('/').to_string(),
+                })
+            }
+            "http" | "https" => Ok(Storage::Http {
+                base_url: value.trim_end_matches('/').to_string(),
+                client: reqwest::Client::new(),
+            }),
+            scheme => Err(anyhow!("Unsupported storage scheme {scheme}")),
+        }
+    }
+
+    /// Read the file, returns `None` if it doesn't exist.
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            Storage::Local(dir) => match tokio::fs::read(dir.join(key)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            Storage::S3 { bucket, prefix } => {
+                let response = bucket.get_object(object_path(prefix, key)).await?;
+                match response.status_code() {
+                    200 => Ok(Some(response.bytes().to_vec())),
+                    404 => Ok(None),
+                    code => Err(anyhow!("S3 responded with {code}")),
+                }
+            }
+            Storage::Http { base_url, client } => {
+                let response = client.get(format!("{base_url}/{key}")).send().await?;
+                match response.status() {
+                    reqwest::StatusCode::NOT_FOUND => Ok(None),
+                    _ => Ok(Some(response.error_for_status()?.bytes().await?.to_vec())),
+                }
+            }
+        }
+    }
+
+    /// Write the file.
+    pub async fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Storage::Local(dir) => Ok(tokio::fs::write(dir.join(key), data).await?),
+            Storage::S3 { bucket, prefix } => {
+                let response = bucket.put_object(object_path(prefix, key), data).await?;
+                match response.status_code() {
+                    200..=299 => Ok(()),
+                    code => Err(anyhow!("S3 responded with {code}")),
+                }
+            }
+            Storage::Http { .. } => Err(anyhow!("HTTP storage is read only")),
+        }
+    }
+
+    /// Delete the file, missing files are ignored.
+    pub async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match self {
+            Storage::Local(dir) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            },
+            Storage::S3 { bucket, prefix } => {
+                let response = bucket.delete_object(object_path(prefix, key)).await?;
+                match response.status_code() {
+                    200..=299 | 404 => Ok(()),
+                    code => Err(anyhow!("S3 responded with {code}")),
+                }
+            }
+            Storage::Http { .. } => Err(anyhow!("HTTP storage is read only")),
+        }
+    }
+
+    /// Check if the file exists.
+    pub async fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        match self {
+            Storage::Local(dir) => Ok(tokio::fs::try_exists(dir.join(key)).await?),
+            Storage::S3 { bucket, prefix } => {
+                let (_, code) = bucket.head_object(object_path(prefix, key)).await?;
+                match code {
+                    200 => Ok(true),
+                    404 => Ok(false),
+                    code => Err(anyhow!("S3 responded with {code}")),
+                }
+            }
+            Storage::Http { base_url, client } => {
+                let response = client.head(format!("{base_url}/{key}")).send().await?;
+                match response.status() {
+                    reqwest::StatusCode::NOT_FOUND => Ok(false),
+                    _ => {
+                        response.error_for_status()?;
+                        Ok(true)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn object_path(prefix: &str, key: &str) -> String {
+    match prefix.is_empty() {
+        true => format!("/{key}"),
+        false => format!("/{prefix}/{key}"),
+    }
+}
@@ -0,0 +1,64 @@
+//! Backpressure for image processing.
+//!
+//! At most `max_concurrent_transforms` images are processed at once and
+//! at most `transform_queue_size` requests wait for their turn.
+//! Other requests are rejected with 503 right away.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Value of the 'Retry-After' header for rejected requests (seconds).
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+/// Limits the number of running and waiting transformations.
+pub struct Throttle {
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    queue_size: usize,
+}
+
+/// The processing queue is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// Decrements the number of waiting requests when dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Throttle {
+    pub fn new(concurrency: usize, queue_size: usize) -> Throttle {
+        Throttle {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            waiting: AtomicUsize::new(0),
+            queue_size,
+        }
+    }
+
+    /// Wait for a free processing slot.
+    /// The slot is released when the permit is dropped.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, QueueFull> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.queue_size {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return Err(QueueFull);
+        }
+        let _waiting = Waiting(&self.waiting);
+
+        // The semaphore is never closed.
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| QueueFull)
+    }
+}
@@ -0,0 +1,232 @@
+//! Thumbor-compatible URLs.
+//!
+//! URLs in the Thumbor format are translated into Canvas parameters:
+//! `/<signature>/[AxB:CxD/][fit-in/][-]WxH/[halign/][valign/][smart/][filters:.../]<image>`,
+//! where the signature is `unsafe` or a base64url-encoded HMAC-SHA1 of the rest of the path.
+//! The image is a hash (the extension is ignored) or a slug.
+use crate::pipeline::{Operation, PIPELINE_PARAM};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::collections::HashMap;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Signature of unsigned URLs.
+const UNSAFE: &str = "unsafe";
+
+/// Parsed Thumbor URL.
+#[derive(Debug)]
+pub struct ThumborUrl {
+    /// The URL has a valid signature.
+    pub signed: bool,
+    /// Image hash or slug, without the extension.
+    pub image: String,
+    /// Equivalent query parameters of `GET /images/:hash`.
+    pub params: HashMap<String, String>,
+}
+
+/// Parse the path of a Thumbor URL.
+/// Returns `None` if the path is not a Thumbor URL or its signature is not accepted.
+pub fn parse(path: &str, key: Option<&str>, allow_unsafe: bool) -> Option<ThumborUrl> {
+    let (signature, rest) = path.trim_start_matches('/').split_once('/')?;
+    let signed = match (signature, key) {
+        (UNSAFE, _) if allow_unsafe => false,
+        (UNSAFE, _) | (_, None) => return None,
+        (signature, Some(key)) if verify(key, rest, signature) => true,
+        _ => return None,
+    };
+
+    let mut params = HashMap::new();
+    let mut operations = Vec::new();
+    let mut fit_in = false;
+    let mut size = None;
+    let mut segments = rest.split('/').peekable();
+
+    while let Some(segment) = segments.peek() {
+        if segment.starts_with("trim") || is_alignment(segment) || *segment == "smart" {
+            // Trimming and alignment are not supported, smart cropping is the default.
+        } else if let Some(crop) = parse_crop(segment) {
+            operations.push(crop);
+        } else if matches!(*segment, "fit-in" | "adaptive-fit-in" | "full-fit-in") {
+            fit_in = true;
+        } else if let Some(parsed) = parse_size(segment) {
+            size = Some(parsed);
+        } else if let Some(filters) = segment.strip_prefix("filters:") {
+            parse_filters(filters, &mut params, &mut operations);
+        } else {
+            break;
+        }
+        segments.next();
+    }
+
+    let image: Vec<&str> = segments.collect();
+    let image = image.join("/");
+    let image = match image.rsplit_once('.') {
+        Some((name, _extension)) => name.to_string(),
+        None => image,
+    };
+    if image.is_empty() {
+        return None;
+    }
+
+    // Resizing goes before the filters, but after the manual crop.
+    let position = operations
+        .iter()
+        .position(|op| !matches!(op, Operation::Crop { .. }))
+        .unwrap_or(operations.len());
+    let mut resize = Vec::new();
+    if let Some(Size {
+        width,
+        height,
+        flip_horizontal,
+        flip_vertical,
+    }) = size
+    {
+        // 0 means the side is calculated from the other one.
+        let (width, height) = (width.unwrap_or(0), height.unwrap_or(0));
+        match (width, height, fit_in) {
+            (0, 0, _) => {}
+            (width, height, true) | (width, height @ 0, false) | (width @ 0, height, false) => {
+                resize.push(Operation::Resize {
+                    width: if width == 0 { u16::MAX } else { width },
+                    height: if height == 0 { None } else { Some(height) },
+                })
+            }
+            (width, height, false) => resize.push(Operation::Cover { width, height }),
+        }
+        if flip_horizontal {
+            resize.push(Operation::Flip { horizontal: true });
+        }
+        if flip_vertical {
+            resize.push(Operation::Flip { horizontal: false });
+        }
+    }
+    operations.splice(position..position, resize);
+
+    match operations.is_empty() {
+        // Thumbor serves the original size by default.
+        true => {
+            params.insert("width".to_string(), u16::MAX.to_string());
+            params.insert("height".to_string(), u16::MAX.to_string());
+        }
+        false => {
+            let pipeline: Vec<String> = operations.iter().map(|op| op.to_string()).collect();
+            params.insert(PIPELINE_PARAM.to_string(), pipeline.join("|"));
+        }
+    }
+
+    Some(ThumborUrl {
+        signed,
+        image,
+        params,
+    })
+}
+
+/// Check the signature of the URL (HMAC-SHA1 of the path after the signature).
+fn verify(key: &str, path: &str, signature: &str) -> bool {
+    let signature = match URL_SAFE.decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = HmacSha1::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(path.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn is_alignment(segment: &str) -> bool {
+    matches!(
+        segment,
+        "left" | "center" | "right" | "top" | "middle" | "bottom"
+    )
+}
+
+/// Parse the manual crop (`AxB:CxD`, top left and bottom right corners).
+fn parse_crop(segment: &str) -> Option<Operation> {
+    let (top_left, bottom_right) = segment.split_once(':')?;
+    let (left, top) = parse_pair(top_left)?;
+    let (right, bottom) = parse_pair(bottom_right)?;
+    Some(Operation::Crop {
+        x: left,
+        y: top,
+        width: right.checked_sub(left).filter(|width| *width > 0)?,
+        height: bottom.checked_sub(top).filter(|height| *height > 0)?,
+    })
+}
+
+fn parse_pair(value: &str) -> Option<(u16, u16)> {
+    let (first, second) = value.split_once('x')?;
+    Some((first.parse().ok()?, second.parse().ok()?))
+}
+
+/// Requested size (`[-]WxH[-]`), negative sides flip the image.
+struct Size {
+    width: Option<u16>,
+    height: Option<u16>,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+}
+
+fn parse_size(segment: &str) -> Option<Size> {
+    let (width, height) = segment.split_once('x')?;
+    let side = |value: &str| -> Option<(Option<u16>, bool)> {
+        let (value, flip) = match value.strip_prefix('-') {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+        match value {
+            "" | "orig" => Some((None, flip)),
+            value => Some((Some(value.parse().ok()?), flip)),
+        }
+    };
+    let (width, flip_horizontal) = side(width)?;
+    let (height, flip_vertical) = side(height)?;
+    Some(Size {
+        width,
+        height,
+        flip_horizontal,
+        flip_vertical,
+    })
+}
+
+/// Translate the supported filters (`name(args):name(args)`), others are ignored.
+fn parse_filters(
+    filters: &str,
+    params: &mut HashMap<String, String>,
+    operations: &mut Vec<Operation>,
+) {
+    for filter in filters.split(':') {
+        let (name, args) = match filter.strip_suffix(')').and_then(|f| f.split_once('(')) {
+            Some((name, args)) => (name, args.split(',').map(str::trim).collect::<Vec<_>>()),
+            None => continue,
+        };
+        let number = |index: usize| args.get(index).and_then(|arg| arg.parse::<f64>().ok());
+
+        match name {
+            "quality" => {
+                if let Some(quality) = args.first() {
+                    params.insert("quality".to_string(), quality.to_string());
+                }
+            }
+            "format" => {
+                if let Some(format) = args.first() {
+                    params.insert("format".to_string(), format.to_string());
+                }
+            }
+            "grayscale" => operations.push(Operation::Grayscale),
+            // blur(radius[, sigma]), sigma defaults to the radius.
+            "blur" => {
+                if let Some(sigma) = number(1).or_else(|| number(0)) {
+                    operations.push(Operation::Blur { sigma });
+                }
+            }
+            // sharpen(amount, radius, luminance_only)
+            "sharpen" => {
+                if let Some(sigma) = number(1) {
+                    operations.push(Operation::Sharpen { sigma });
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Soft deletion.
+//!
+//! Deleted originals are moved to the 'trash' subdirectory of the upload directory
+//! and permanently removed after the retention period.
+use crate::{
+    access,
+    audit::{self, Actor},
+    cache,
+    clock::unix_now,
+    events::{self, EventKind},
+    metadata, missing, AppState,
+};
+use log::{info, warn};
+use mobc_redis::redis::aio::Connection;
+use std::{sync::Arc, time::Duration};
+
+/// How often to look for expired files.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Move the original to the trash.
+/// Cached versions are removed right away, so that the image is no longer served.
+pub async fn delete(state: &AppState, con: &mut Connection, hash: &str) -> anyhow::Result<()> {
+    tokio::fs::rename(state.get_file_path(hash), state.get_trash_file_path(hash)).await?;
+
+    metadata::set_deleted_at(con, hash, Some(unix_now())).await?;
+    cache::purge(con, hash, state.cache_offload.as_ref()).await?;
+    events::publish(&state.events, EventKind::CachePurge, hash);
+    Ok(())
+}
+
+/// Move the original back from the trash.
+pub async fn restore(state: &AppState, con: &mut Connection, hash: &str) -> anyhow::Result<()> {
+    tokio::fs::rename(state.get_trash_file_path(hash), state.get_file_path(hash)).await?;
+
+    metadata::set_deleted_at(con, hash, None).await?;
+    missing::clear(con, hash).await?;
+    Ok(())
+}
+
+/// Periodically remove files whose retention period has expired.
+pub async fn purge_loop(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = purge_expired(&state).await {
+            warn!("Failed to purge deleted images: {err}");
+        }
+    }
+}
+
+async fn purge_expired(state: &AppState) -> anyhow::Result<()> {
+    let retention_secs = state.cfg.trash_retention_hours * 60 * 60;
+    let mut redis_con = state.redis.get().await?;
+    let mut entries = tokio::fs::read_dir(state.get_trash_dir()).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let hash = match entry.file_name().to_str() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        // The image was uploaded again, only the copy in the trash is removed.
+        if state.get_file_path(&hash).exists() {
+            tokio::fs::remove_file(entry.path()).await?;
+            continue;
+        }
+
+        let meta = metadata::get(&mut redis_con, &hash).await?;
+        let deleted_at = match meta.deleted_at {
+            Some(deleted_at) => deleted_at,
+            // Deletion time is unknown, start counting from now.
+            None => {
+                metadata::set_deleted_at(&mut redis_con, &hash, Some(unix_now())).await?;
+                continue;
+            }
+        };
+        if deleted_at + retention_secs > unix_now() {
+            continue;
+        }
+
+        audit::record(
+            &mut redis_con,
+            state.cfg.audit_max_entries,
+            &Actor::system(),
+            "purge",
+            &hash,
+        )
+        .await?;
+        tokio::fs::remove_file(entry.path()).await?;
+        metadata::remove(&mut redis_con, &hash).await?;
+        access::remove(&mut redis_con, &hash).await?;
+        cache::purge(&mut redis_con, &hash, state.cache_offload.as_ref()).await?;
+        events::publish(&state.events, EventKind::Purge, &hash);
+        events::publish(&state.events, EventKind::CachePurge, &hash);
+        info!("Purged deleted image {hash}");
+    }
+
+    Ok(())
+}
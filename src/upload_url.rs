@@ -0,0 +1,114 @@
+//! Signed upload URLs.
+//!
+//! `POST /uploads/sign` returns a short-lived signed `/images` URL, a browser can
+//! upload to it without an access token. Constraints of the upload are query
+//! parameters covered by the signature. Each URL has a random nonce and can be used
+//! once: used nonces are stored in Redis under `upload-nonce:<nonce>` keys until
+//! the URL expires.
+use crate::{
+    clock::unix_now,
+    signature::{self, EXPIRES_PARAM},
+    sniff,
+};
+use mobc_redis::redis::{self, aio::Connection, RedisResult};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Path of the signed URLs.
+pub const PATH: &str = "/images";
+/// Query parameter with the random nonce.
+pub const NONCE_PARAM: &str = "nonce";
+/// Query parameter with the size limit in kilobytes.
+pub const MAX_SIZE_PARAM: &str = "max_size_kb";
+/// Query parameter with allowed MIME types, separated by commas.
+pub const TYPES_PARAM: &str = "types";
+
+/// Constraints of the upload baked into the URL.
+pub struct Constraints {
+    pub max_size_kb: Option<usize>,
+    pub types: Option<Vec<String>>,
+    nonce: String,
+    expires: u64,
+}
+
+impl Constraints {
+    /// Check if the detected MIME type of the file is allowed.
+    pub fn allows(&self, mime_type: &str) -> bool {
+        match &self.types {
+            Some(types) => types.iter().any(|allowed| allowed == mime_type),
+            None => true,
+        }
+    }
+}
+
+/// Check if the MIME type can be detected in uploads.
+pub fn is_known_type(mime_type: &str) -> bool {
+    sniff::MIME_TYPES.contains(&mime_type)
+}
+
+/// Build the query parameters of a new upload URL.
+pub fn params(
+    key: &str,
+    expires: u64,
+    max_size_kb: Option<usize>,
+    types: Option<&[String]>,
+    private: bool,
+) -> HashMap<String, String> {
+    let mut params = HashMap::from([
+        (EXPIRES_PARAM.to_string(), expires.to_string()),
+        (NONCE_PARAM.to_string(), Uuid::new_v4().simple().to_string()),
+    ]);
+    if let Some(max_size_kb) = max_size_kb {
+        params.insert(MAX_SIZE_PARAM.to_string(), max_size_kb.to_string());
+    }
+    if let Some(types) = types {
+        params.insert(TYPES_PARAM.to_string(), types.join(","));
+    }
+    if private {
+        params.insert("private".to_string(), "true".to_string());
+    }
+
+    let sig = signature::sign(key, PATH, &params);
+    params.insert(signature::SIGNATURE_PARAM.to_string(), sig);
+    params
+}
+
+/// Get the constraints of the signed upload URL.
+/// Returns `None` if the signature is invalid or expired.
+pub fn verify(key: &str, params: &HashMap<String, String>) -> Option<Constraints> {
+    if !signature::verify(key, PATH, params) {
+        return None;
+    }
+
+    let expires = params.get(EXPIRES_PARAM)?.parse().ok()?;
+    let nonce = params.get(NONCE_PARAM)?.clone();
+    let max_size_kb = match params.get(MAX_SIZE_PARAM) {
+        Some(value) => Some(value.parse().ok()?),
+        None => None,
+    };
+    let types = params
+        .get(TYPES_PARAM)
+        .map(|value| value.split(',').map(|t| t.to_string()).collect());
+
+    Some(Constraints {
+        max_size_kb,
+        types,
+        nonce,
+        expires,
+    })
+}
+
+/// Mark the URL as used.
+/// Returns false if it was used before.
+pub async fn claim(con: &mut Connection, constraints: &Constraints) -> RedisResult<bool> {
+    let ttl = constraints.expires.saturating_sub(unix_now()).max(1);
+    let result: Option<String> = redis::cmd("SET")
+        .arg(format!("upload-nonce:{}", constraints.nonce))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(con)
+        .await?;
+    Ok(result.is_some())
+}
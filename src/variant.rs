@@ -0,0 +1,257 @@
+//! Negotiated response variants.
+//!
+//! Some properties of the image can be chosen from the request headers
+//! (for example, `format=auto` uses the 'Accept' header,
+//! the size can be taken from the 'Sec-CH-Width' and 'Sec-CH-DPR' client hints).
+//! `Variant::negotiate` applies the negotiated values to the image properties
+//! and records the request headers they were taken from for the 'Vary' header.
+//! `Variant::key` is added to the cache key, see `get_image_id`.
+//! With `format_fallback`, WebP and AVIF are replaced by JPEG for clients known not to show them.
+//! Finally, the size and quality are snapped to the configured buckets, and the watermark
+//! and the overlay are dropped from outputs smaller than `watermark_min_size`.
+use crate::{
+    api::image::{ImageFormat, ImageProps},
+    app_config::AppConfig,
+    bucket,
+    smart::ContentClass,
+};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use std::{cmp, collections::HashMap};
+
+/// 'Save-Data' request header.
+const SAVE_DATA: HeaderName = HeaderName::from_static("save-data");
+/// Client hint with the layout width of the image in physical pixels.
+const SEC_CH_WIDTH: HeaderName = HeaderName::from_static("sec-ch-width");
+/// Client hint with the device pixel ratio.
+const SEC_CH_DPR: HeaderName = HeaderName::from_static("sec-ch-dpr");
+
+/// 'Accept-CH' response header, advertises the supported client hints.
+pub const ACCEPT_CH: HeaderName = HeaderName::from_static("accept-ch");
+/// Client hints used by the server.
+pub const CLIENT_HINTS: &str = "Sec-CH-Width, Sec-CH-DPR";
+/// Sizes chosen from client hints are rounded up to a multiple of this value.
+const HINT_STEP: u16 = 100;
+/// Maximum device pixel ratio taken into account.
+const MAX_DPR: f64 = 3.0;
+
+/// Negotiated values and request headers the response depends on.
+#[derive(Debug, Default)]
+pub struct Variant {
+    /// Client asked to reduce data usage ('Save-Data: on').
+    save_data: bool,
+    vary: Vec<HeaderName>,
+}
+
+impl Variant {
+    /// Resolve the negotiated properties of the image.
+    /// `params` are the requested parameters, hints are used only if the size is not given.
+    pub fn negotiate(
+        props: &mut ImageProps,
+        params: &HashMap<String, String>,
+        headers: &HeaderMap,
+        cfg: &AppConfig,
+    ) -> Variant {
+        let mut variant = Variant::default();
+
+        if !params.contains_key("width") && !params.contains_key("height") && props.edge.is_none() {
+            if let Some(size) = hinted_size(headers, props.width) {
+                props.width = size;
+                props.height = size;
+            }
+            variant.vary.push(SEC_CH_WIDTH);
+            variant.vary.push(SEC_CH_DPR);
+        }
+
+        if props.auto_format {
+            props.format = negotiate_format(headers);
+            variant.vary.push(header::ACCEPT);
+        }
+        if props.smart_format {
+            props.format = smart_format(props, headers);
+            variant.vary.push(header::ACCEPT);
+        }
+        if cfg.format_fallback && !props.auto_format && !props.smart_format {
+            let media_type = match props.format {
+                ImageFormat::Webp => Some("image/webp"),
+                ImageFormat::Avif => Some("image/avif"),
+                _ => None,
+            };
+            if let Some(media_type) = media_type {
+                if lacks_support(headers, media_type, cfg) {
+                    props.format = ImageFormat::Jpeg;
+                }
+                variant.vary.push(header::ACCEPT);
+                if cfg.format_fallback_user_agents.is_some() {
+                    variant.vary.push(header::USER_AGENT);
+                }
+            }
+        }
+
+        variant.save_data = is_save_data(headers);
+        if variant.save_data {
+            props.quality = match props.auto_quality {
+                true => cfg.save_data_quality,
+                false => cmp::min(props.quality, cfg.save_data_quality),
+            };
+            props.auto_quality = false;
+            props.width = cmp::min(props.width, cfg.save_data_max_size);
+            props.height = cmp::min(props.height, cfg.save_data_max_size);
+        }
+        // The header can change the response even if it is absent.
+        variant.vary.push(SAVE_DATA);
+
+        bucket::apply(props, cfg);
+        // Caps and buckets are applied to both sides separately.
+        props.fit_aspect_ratio();
+
+        // Marks on tiny thumbnails are unreadable smears.
+        // The cache key includes both, so such variants are cached without them.
+        if let Some(min_size) = cfg.watermark_min_size {
+            if props.width < min_size || props.height < min_size {
+                props.watermark = false;
+                props.watermark_hash = None;
+                props.overlay = None;
+                props.overlay_svg = None;
+            }
+        }
+
+        variant
+    }
+
+    /// Suffix of the cache key with the negotiated flags.
+    pub fn key(&self) -> &'static str {
+        match self.save_data {
+            true => "-save-data",
+            false => "",
+        }
+    }
+
+    /// Value of the 'Vary' header, if the response depends on request headers.
+    pub fn vary(&self) -> Option<HeaderValue> {
+        if self.vary.is_empty() {
+            return None;
+        }
+        let names: Vec<&str> = self.vary.iter().map(|name| name.as_str()).collect();
+        names.join(", ").parse().ok()
+    }
+}
+
+/// Choose the best format accepted by the client.
+fn negotiate_format(headers: &HeaderMap) -> ImageFormat {
+    #[cfg(feature = "jxl")]
+    if accepts(headers, "image/jxl") {
+        return ImageFormat::Jxl;
+    }
+    match accepts(headers, "image/webp") {
+        true => ImageFormat::Webp,
+        false => ImageFormat::Jpeg,
+    }
+}
+
+/// Format for the content of the image (`format=smart`).
+/// Without the content class, like for proxied images, it's the same as `format=auto`.
+fn smart_format(props: &mut ImageProps, headers: &HeaderMap) -> ImageFormat {
+    match props.content_class {
+        Some(ContentClass::Graphics) => match accepts(headers, "image/webp") {
+            true => {
+                props.lossless = true;
+                // Quality doesn't matter for lossless images, one variant is enough.
+                props.auto_quality = false;
+                props.quality = 100;
+                ImageFormat::Webp
+            }
+            false => ImageFormat::Png,
+        },
+        // JPEG has no alpha channel.
+        Some(ContentClass::TransparentPhoto) => match negotiate_format(headers) {
+            ImageFormat::Jpeg => ImageFormat::Png,
+            format => format,
+        },
+        _ => negotiate_format(headers),
+    }
+}
+
+/// Size of the image based on client hints, `default` is the size for DPR 1.
+/// Returns `None` if the hints are absent or invalid.
+fn hinted_size(headers: &HeaderMap, default: u16) -> Option<u16> {
+    let hint = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .filter(|value| value.is_finite() && *value > 0.0)
+    };
+
+    // The width hint already includes the pixel ratio.
+    let size = match (hint(SEC_CH_WIDTH), hint(SEC_CH_DPR)) {
+        (Some(width), _) => width,
+        (None, Some(dpr)) => f64::from(default) * dpr.min(MAX_DPR),
+        (None, None) => return None,
+    };
+
+    // Round up to limit the number of cached variants.
+    let step = f64::from(HINT_STEP);
+    let size = (size / step).ceil() * step;
+    Some(size.min(f64::from(u16::MAX - u16::MAX % HINT_STEP)) as u16)
+}
+
+/// Check if the client is known not to show the media type: its 'Accept' header lists
+/// image types but not this one (older browsers), or its 'User-Agent' matches
+/// `format_fallback_user_agents` (email clients). Clients sending only wildcards,
+/// like command line tools, get the requested format.
+fn lacks_support(headers: &HeaderMap, media_type: &str, cfg: &AppConfig) -> bool {
+    if accepts(headers, media_type) {
+        return false;
+    }
+    let lists_images = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let item = item.trim().to_ascii_lowercase();
+            item.starts_with("image/") && !item.starts_with("image/*")
+        });
+    if lists_images {
+        return true;
+    }
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    cfg.format_fallback_user_agents
+        .iter()
+        .flatten()
+        .any(|pattern| user_agent.contains(pattern.as_str()))
+}
+
+/// Check if the client asked to reduce data usage ('Save-Data: on').
+fn is_save_data(headers: &HeaderMap) -> bool {
+    headers
+        .get(SAVE_DATA)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+/// Check if the 'Accept' header lists the media type with a non-zero quality.
+pub fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(|part| part.trim());
+            let matches = parts
+                .next()
+                .is_some_and(|value| value.eq_ignore_ascii_case(media_type));
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            matches && !rejected
+        })
+}
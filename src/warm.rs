@@ -0,0 +1,113 @@
+//! Renditions generated right after upload.
+//!
+//! New images are processed with each of the `warm_presets` in the background
+//! and saved to the cache, so that the first visitor doesn't wait for the processing.
+//! Renditions are made for clients without client hints, 'Save-Data' and
+//! with the default 'Accept' header.
+use crate::{
+    access,
+    api::image::{get_image_id, process_buffer, ImageProps},
+    budget::Budget,
+    cache, cancel, metadata, policy, preset, smart,
+    variant::Variant,
+    AppConfig, AppState,
+};
+use anyhow::anyhow;
+use axum::{body::Bytes, http::HeaderMap};
+use log::{debug, warn};
+use mobc_redis::redis::AsyncCommands;
+use std::{collections::HashMap, sync::Arc};
+
+/// Check that the presets exist.
+pub fn check(cfg: &AppConfig) -> anyhow::Result<()> {
+    for name in cfg.warm_presets.iter().flatten() {
+        if !cfg.presets.contains_key(name) {
+            return Err(anyhow!("Unknown preset '{name}' in warm presets"));
+        }
+    }
+    Ok(())
+}
+
+/// Generate renditions of the new image.
+/// Failures are logged, they only mean that the first visitor waits.
+pub async fn generate(state: Arc<AppState>, hash: String, data: Bytes) {
+    let presets = match &state.cfg.warm_presets {
+        Some(presets) => presets.clone(),
+        None => return,
+    };
+    for name in presets {
+        if let Err(err) = generate_preset(&state, &hash, &data, &name).await {
+            warn!("Failed to generate rendition '{name}' of {hash}: {err}");
+        }
+    }
+}
+
+async fn generate_preset(
+    state: &Arc<AppState>,
+    hash: &str,
+    data: &Bytes,
+    name: &str,
+) -> anyhow::Result<()> {
+    let params = HashMap::from([(preset::PRESET_PARAM.to_string(), name.to_string())]);
+    // Presets can be removed by a reload.
+    let params = match preset::apply(&state.settings().presets, &params) {
+        Some(params) => params,
+        None => return Err(anyhow!("Unknown preset")),
+    };
+    // Renditions get the same policy, format and overlay as requests.
+    let mut redis_con = state.redis.get().await?;
+    let meta = metadata::get(&mut redis_con, hash).await?;
+    let policy = policy::resolve(&state.cfg.policies, &meta.tags);
+    let params = match &policy {
+        Some(policy) => policy.apply_defaults(&params),
+        None => params,
+    };
+    let mut image_props =
+        ImageProps::from_params(&params).map_err(|err| anyhow!("{}", err.message))?;
+    if image_props.smart_format {
+        let class = smart::resolve(state, &mut redis_con, hash, &meta, Some(data.clone())).await?;
+        image_props.content_class = Some(class);
+    }
+    drop(redis_con);
+    let variant = Variant::negotiate(&mut image_props, &params, &HeaderMap::new(), &state.cfg);
+    if let Some(policy) = &policy {
+        policy
+            .apply_limits(&mut image_props, &params)
+            .map_err(|err| anyhow!("{}", err.message))?;
+    }
+    image_props.render_overlay(hash, &meta.custom);
+    let image_id = get_image_id(hash, &image_props, &variant);
+
+    let cached: bool = state.redis.get().await?.exists(&image_id).await?;
+    if cached {
+        debug!("Rendition {image_id} is already cached");
+        return Ok(());
+    }
+
+    // Renditions wait for a slot like requests do, and give up if the queue is full.
+    let permit = match state.throttle.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => return Err(anyhow!("Processing queue is full")),
+    };
+    let process_state = state.clone();
+    let data = data.clone();
+    let mut budget = Budget::unlimited(&image_id);
+    let buffer = cancel::run_blocking(move |cancel| {
+        let _permit = permit;
+        process_buffer(&data, &image_props, &process_state, cancel, &mut budget)
+    })
+    .await?;
+
+    let mut redis_con = state.redis.get().await?;
+    cache::write(
+        &mut redis_con,
+        &image_id,
+        &buffer,
+        state.settings().cache_ttl_secs,
+        state.cache_offload.as_ref(),
+    )
+    .await?;
+    access::record_derivative(&mut redis_con, &image_id).await?;
+    debug!("Generated rendition {image_id}");
+    Ok(())
+}